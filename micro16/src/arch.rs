@@ -0,0 +1,244 @@
+//! What tracing and the debugger need from a machine, so that both work
+//! with the Micro16 `Cpu` and the `mic1::Mic1` alike.
+//!
+//! Every machine keeps its data in the same 16-bit memory cells; machines
+//! with wider words spread a word over consecutive cells, low half first.
+
+use cpu::{Cpu, Memory, MAR, MBR, PROGRAM_LENGTH};
+use disasm::{self, disassemble};
+use instruction::Instruction;
+use mic1;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    /// A byte read of the instruction stream, on machines that have one.
+    Fetch,
+}
+
+impl Access {
+    pub fn name(self) -> &'static str {
+        match self {
+            Access::Read => "rd",
+            Access::Write => "wr",
+            Access::Fetch => "fetch",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryOp {
+    pub access: Access,
+    /// A word address for reads and writes, a byte address for fetches.
+    pub addr: u32,
+    pub value: i32,
+    /// Whether this cycle transferred the data; the first cycle of an
+    /// access only starts it.
+    pub completed: bool,
+}
+
+impl MemoryOp {
+    /// The access as in `rd mem[7]=-5`, or `rd mem[7] ...` while it is in
+    /// progress.
+    pub fn describe(&self) -> String {
+        let space = if self.access == Access::Fetch { "byte" } else { "mem" };
+        if self.completed {
+            format!("{} {}[{}]={}", self.access.name(), space, self.addr, self.value)
+        } else {
+            format!("{} {}[{}] ...", self.access.name(), space, self.addr)
+        }
+    }
+}
+
+/// What one cycle did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cycle {
+    /// Control store address of the executed instruction.
+    pub pc: u16,
+    pub word: u64,
+    /// Registers written by the cycle, in register order, with their new
+    /// values, whether or not the value changed.
+    pub writes: Vec<(u8, i32)>,
+    pub memory: Vec<MemoryOp>,
+}
+
+/// A description of a machine, enough to interpret its traces.
+pub struct Info {
+    pub name: &'static str,
+    /// Register names, indexed like `Core::register`. At most 16.
+    pub registers: &'static [&'static str],
+    /// The registers a debugger sees, in its numbering. The flags and the
+    /// control store address follow them.
+    pub debug_registers: &'static [u8],
+    /// Debugger name of the control store address.
+    pub pc_name: &'static str,
+    /// Flag names, in the order of the bits of `Core::flags`.
+    pub flags: &'static [&'static str],
+    pub register_bits: u32,
+    /// Hex digits of a control store word.
+    pub word_digits: usize,
+    pub control_store_length: usize,
+    /// Memory cells covered by one read or write.
+    pub word_cells: u32,
+    pub disassemble: fn(u64) -> String,
+}
+
+impl Info {
+    /// The memory cells `op` touches.
+    pub fn cells(&self, op: &MemoryOp) -> (u32, u32) {
+        match op.access {
+            Access::Fetch => ((op.addr / 2) & 0xffff, (op.addr / 2) & 0xffff),
+            _ => {
+                let first = op.addr.wrapping_mul(self.word_cells) & 0xffff;
+                (first, first + self.word_cells - 1)
+            }
+        }
+    }
+
+    /// The flags in `flags` as `Core::flags` returns them, e.g. `N=0 Z=1`.
+    pub fn describe_flags(&self, flags: u8) -> String {
+        let parts: Vec<String> = self.flags
+            .iter()
+            .enumerate()
+            .map(|(i, name)| format!("{}={}", name, flags >> i & 1))
+            .collect();
+        parts.join(" ")
+    }
+
+    pub fn word_bytes(&self) -> usize {
+        self.word_digits.div_ceil(2)
+    }
+
+    pub fn register_bytes(&self) -> usize {
+        self.register_bits as usize / 8
+    }
+}
+
+fn disassemble_micro16(word: u64) -> String {
+    disassemble(&Instruction::new(word as u32))
+}
+
+pub const MICRO16: Info = Info {
+    name: "micro16",
+    registers: &disasm::NAMES,
+    debug_registers: &[4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 3, 15],
+    pc_name: "pc",
+    flags: &["N", "Z", "C", "V"],
+    register_bits: 16,
+    word_digits: 8,
+    control_store_length: PROGRAM_LENGTH,
+    word_cells: 1,
+    disassemble: disassemble_micro16,
+};
+
+/// The machine called `name`, as `--machine` and trace files name them.
+pub fn info(name: &str) -> Option<&'static Info> {
+    [&MICRO16, &mic1::INFO].iter().find(|info| info.name == name).cloned()
+}
+
+/// A machine that tracing and the debugger can drive.
+pub trait Core {
+    fn info(&self) -> &'static Info;
+    fn register(&self, idx: u8) -> i32;
+    /// Returns false for registers that cannot be written.
+    fn set_register(&mut self, idx: u8, value: i32) -> bool;
+    /// The flags, bit `i` holding the one named `Info::flags[i]`. The
+    /// negative and zero flags come first.
+    fn flags(&self) -> u8;
+    fn set_flags(&mut self, flags: u8);
+    fn program_counter(&self) -> u16;
+    /// Returns false for addresses outside the control store.
+    fn set_program_counter(&mut self, addr: u16) -> bool;
+    fn done(&self) -> bool;
+    /// Executes the remainder of the current cycle.
+    fn cycle(&mut self) -> Result<Cycle, String>;
+    fn cell(&self, idx: usize) -> i16;
+    fn set_cell(&mut self, idx: usize, value: i16);
+}
+
+impl Core for Cpu<Memory> {
+    fn info(&self) -> &'static Info {
+        &MICRO16
+    }
+
+    fn register(&self, idx: u8) -> i32 {
+        self.registers().get(idx) as i32
+    }
+
+    fn set_register(&mut self, idx: u8, value: i32) -> bool {
+        if !(MAR..=MBR).contains(&idx) {
+            return false;
+        }
+        self.registers_mut().set(idx, value as i16);
+        true
+    }
+
+    fn flags(&self) -> u8 {
+        self.negative_flag() as u8 | (self.zero_flag() as u8) << 1 |
+        (self.carry_flag() as u8) << 2 | (self.overflow_flag() as u8) << 3
+    }
+
+    fn set_flags(&mut self, flags: u8) {
+        Cpu::set_flags(self, flags & 1 != 0, flags & 2 != 0, flags & 4 != 0, flags & 8 != 0);
+    }
+
+    fn program_counter(&self) -> u16 {
+        Cpu::program_counter(self) as u16
+    }
+
+    fn set_program_counter(&mut self, addr: u16) -> bool {
+        if addr as usize >= PROGRAM_LENGTH {
+            return false;
+        }
+        Cpu::set_program_counter(self, addr as u8);
+        true
+    }
+
+    fn done(&self) -> bool {
+        Cpu::done(self)
+    }
+
+    fn cycle(&mut self) -> Result<Cycle, String> {
+        let pc = Cpu::program_counter(self);
+        let instr = self.current_instruction().ok_or("the program has finished")?;
+        self.step().map_err(|e| e.to_string())?;
+
+        // The transfer completes in the cycle that drops ready again.
+        let completed = instr.ms() && !self.memory().ready();
+        let mut written = [false; 16];
+        if instr.ens() {
+            written[instr.s_bus() as usize] = true;
+        }
+        written[MAR as usize] |= instr.mar();
+        written[MBR as usize] |= instr.mbr() || (completed && instr.rd_wr());
+        let writes = (0..16u8)
+            .filter(|&r| written[r as usize])
+            .map(|r| (r, self.registers().get(r) as i32))
+            .collect();
+        let mut memory = Vec::new();
+        if instr.ms() {
+            memory.push(MemoryOp {
+                access: if instr.rd_wr() { Access::Read } else { Access::Write },
+                addr: self.registers().get(MAR) as u16 as u32,
+                value: self.registers().get(MBR) as i32,
+                completed,
+            });
+        }
+        Ok(Cycle {
+            pc: pc as u16,
+            word: instr.raw() as u64,
+            writes,
+            memory,
+        })
+    }
+
+    fn cell(&self, idx: usize) -> i16 {
+        self.memory().get(idx)
+    }
+
+    fn set_cell(&mut self, idx: usize, value: i16) {
+        self.memory_mut().set(idx, value);
+    }
+}
@@ -0,0 +1,399 @@
+//! Assembler for the Micro16 syntax produced by `disasm`.
+//!
+//! Every line holds one microinstruction made of `;` separated statements:
+//!
+//! ```text
+//! :loop
+//! R1 <- R1 + R0; MAR <- R2; rd
+//! R0 <- R0 + -1; if Z goto .done
+//! goto .loop
+//! :done
+//! ```
+//!
+//! `#` starts a comment, as does a `;` at the start of a line. Labels are
+//! defined with `:name` and referenced as `.name`; plain numbers are
+//! control store addresses.
+//!
+//! `call .name` and `return` use the shifter's call code, so they cannot be
+//! combined with a shift and only run on a CPU with a return stack.
+//!
+//! The extended profile adds `R1 - R2`, `R1 | R2`, `R1 ^ R2`, `inc(R1)` and
+//! `rol(...)` to expressions and `if C`, `if V` and `if LT` to jumps. They
+//! need a format of the extended profile, such as micro16x; `-` must be
+//! followed by a space to tell it from the register `-1`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use cpu::{AluMode, CondMode, Profile, ShifterMode, MAR, MBR, PROGRAM_LENGTH};
+use disasm::register_index;
+use format::{self, Layout};
+use instruction::Instruction;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Expr {
+    alu: AluMode,
+    a: u8,
+    b: Option<u8>,
+    sh: ShifterMode,
+}
+
+#[derive(Debug, Clone)]
+enum Target {
+    Label(String),
+    Addr(u8),
+}
+
+#[derive(Debug, Default)]
+struct Word {
+    expr: Option<Expr>,
+    s_bus: Option<u8>,
+    mbr: bool,
+    mar: Option<u8>,
+    read: Option<bool>,
+    jump: Option<(CondMode, Target)>,
+    call: bool,
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '<' && chars.get(i + 1) == Some(&'-') {
+            tokens.push("<-".to_string());
+            i += 2;
+        } else if "+&|^~()".contains(c) {
+            tokens.push(c.to_string());
+            i += 1;
+        } else if c.is_alphanumeric() || c == '_' || c == '.' || c == '-' {
+            let start = i;
+            i += 1;
+            while i < chars.len() &&
+                  (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(chars[start..i].iter().collect());
+        } else {
+            return Err(format!("unexpected character '{}'", c));
+        }
+    }
+    Ok(tokens)
+}
+
+fn operand(name: &str) -> Result<u8, String> {
+    register_index(name).ok_or_else(|| format!("unknown register '{}'", name))
+}
+
+fn parse_expr(tokens: &[String]) -> Result<Expr, String> {
+    let t: Vec<&str> = tokens.iter().map(|t| t.as_str()).collect();
+    if t.len() >= 3 && t[1] == "(" && t[t.len() - 1] == ")" {
+        let sh = match t[0] {
+            "lsh" => Some(ShifterMode::Left),
+            "rsh" => Some(ShifterMode::Right),
+            "rol" => Some(ShifterMode::Rotate),
+            _ => None,
+        };
+        if let Some(sh) = sh {
+            let inner = parse_expr(&tokens[2..tokens.len() - 1])?;
+            if inner.sh != ShifterMode::NoOp {
+                return Err("only one shift per instruction".to_string());
+            }
+            return Ok(Expr { sh, ..inner });
+        }
+    }
+    if t.len() >= 2 && t[0] == "(" && t[t.len() - 1] == ")" {
+        return parse_expr(&tokens[1..tokens.len() - 1]);
+    }
+
+    let (alu, a, b) = match t.len() {
+        1 => (AluMode::NoOp, operand(t[0])?, None),
+        2 if t[0] == "~" => (AluMode::BitNot, operand(t[1])?, None),
+        3 if t[1] == "+" => (AluMode::Add, operand(t[0])?, Some(operand(t[2])?)),
+        3 if t[1] == "&" => (AluMode::BitAnd, operand(t[0])?, Some(operand(t[2])?)),
+        3 if t[1] == "-" => (AluMode::Sub, operand(t[0])?, Some(operand(t[2])?)),
+        3 if t[1] == "|" => (AluMode::BitOr, operand(t[0])?, Some(operand(t[2])?)),
+        3 if t[1] == "^" => (AluMode::BitXor, operand(t[0])?, Some(operand(t[2])?)),
+        4 if t[0] == "inc" && t[1] == "(" && t[3] == ")" => (AluMode::Inc, operand(t[2])?, None),
+        _ => return Err(format!("invalid expression '{}'", t.join(" "))),
+    };
+    Ok(Expr {
+        alu,
+        a,
+        b,
+        sh: ShifterMode::NoOp,
+    })
+}
+
+fn parse_target(text: &str) -> Result<Target, String> {
+    if let Some(label) = text.strip_prefix('.') {
+        Ok(Target::Label(label.to_string()))
+    } else {
+        text.parse::<u8>()
+            .map(Target::Addr)
+            .map_err(|_| format!("invalid jump target '{}'", text))
+    }
+}
+
+impl Word {
+    fn set_expr(&mut self, expr: Expr) -> Result<(), String> {
+        match self.expr {
+            Some(existing) if existing != expr => {
+                Err("conflicting ALU expressions in one instruction".to_string())
+            }
+            _ => {
+                self.expr = Some(expr);
+                Ok(())
+            }
+        }
+    }
+
+    fn set_jump(&mut self, cond: CondMode, target: &str) -> Result<(), String> {
+        if self.jump.is_some() || self.call {
+            return Err("only one jump per instruction".to_string());
+        }
+        self.jump = Some((cond, parse_target(target)?));
+        Ok(())
+    }
+
+    fn set_call(&mut self, cond: CondMode, target: &str) -> Result<(), String> {
+        self.set_jump(cond, target)?;
+        self.call = true;
+        Ok(())
+    }
+
+    fn statement(&mut self, tokens: &[String]) -> Result<(), String> {
+        let t: Vec<&str> = tokens.iter().map(|t| t.as_str()).collect();
+        match t.as_slice() {
+            ["nop"] => Ok(()),
+            ["rd"] | ["wr"] => {
+                if self.read.is_some() {
+                    return Err("only one memory access per instruction".to_string());
+                }
+                self.read = Some(t[0] == "rd");
+                Ok(())
+            }
+            ["goto", target] => self.set_jump(CondMode::GoTo, target),
+            ["if", "N", "goto", target] => self.set_jump(CondMode::IfNegative, target),
+            ["if", "Z", "goto", target] => self.set_jump(CondMode::IfZero, target),
+            ["if", "C", "goto", target] => self.set_jump(CondMode::IfCarry, target),
+            ["if", "V", "goto", target] => self.set_jump(CondMode::IfOverflow, target),
+            ["if", "LT", "goto", target] => self.set_jump(CondMode::IfLess, target),
+            ["call", target] => self.set_call(CondMode::GoTo, target),
+            ["if", "N", "call", target] => self.set_call(CondMode::IfNegative, target),
+            ["if", "Z", "call", target] => self.set_call(CondMode::IfZero, target),
+            ["return"] => {
+                if self.jump.is_some() || self.call {
+                    return Err("only one jump per instruction".to_string());
+                }
+                self.call = true;
+                Ok(())
+            }
+            [dest, "<-", ..] => {
+                let expr = parse_expr(&tokens[2..])?;
+                let dest = operand(dest)?;
+                let plain = expr.alu == AluMode::NoOp && expr.sh == ShifterMode::NoOp;
+                if dest < MAR {
+                    Err(format!("register {} is read-only", t[0]))
+                } else if dest == MAR && plain {
+                    if self.mar.is_some() {
+                        return Err("MAR is assigned twice".to_string());
+                    }
+                    self.mar = Some(expr.a);
+                    Ok(())
+                } else if dest == MBR {
+                    self.mbr = true;
+                    self.set_expr(expr)
+                } else {
+                    if self.s_bus.is_some() {
+                        return Err("only one S-bus destination per instruction".to_string());
+                    }
+                    self.s_bus = Some(dest);
+                    self.set_expr(expr)
+                }
+            }
+            ["(", ..] => parse_expr(tokens).and_then(|expr| self.set_expr(expr)),
+            _ => Err(format!("invalid statement '{}'", t.join(" "))),
+        }
+    }
+
+    fn encode(&self,
+              labels: &HashMap<String, u8>,
+              layout: &Arc<Layout>)
+              -> Result<Instruction, String> {
+        let mut expr = self.expr.unwrap_or(Expr {
+            alu: AluMode::NoOp,
+            a: 0,
+            b: None,
+            sh: ShifterMode::NoOp,
+        });
+        let cond = self.jump.as_ref().map_or(CondMode::NoOp, |&(cond, _)| cond);
+        if self.call {
+            if expr.sh != ShifterMode::NoOp {
+                return Err("a call or return cannot shift".to_string());
+            }
+            if layout.call_code().is_none() {
+                return Err("the instruction format has no call code".to_string());
+            }
+        }
+        if layout.profile() == Profile::Classic {
+            let extended = [(expr.sh.is_extended(), format::shifter_meaning(expr.sh)),
+                            (expr.alu.is_extended(), format::alu_meaning(expr.alu)),
+                            (cond.is_extended(), format::cond_meaning(cond))];
+            if let Some(&(_, op)) = extended.iter().find(|&&(extended, _)| extended) {
+                return Err(format!("{} needs a format of the extended profile, like micro16x",
+                                   op));
+            }
+        }
+        let mut instr = Instruction::with_layout(0, layout.clone());
+
+        if let Some(src) = self.mar {
+            match expr.b {
+                Some(b) if b != src => {
+                    let commutative = expr.alu == AluMode::Add || expr.alu == AluMode::BitAnd;
+                    if commutative && expr.a == src {
+                        expr.a = b;
+                        expr.b = Some(src);
+                    } else {
+                        return Err(format!("MAR <- {} conflicts with the B-bus operand", src));
+                    }
+                }
+                _ => expr.b = Some(src),
+            }
+            instr.set_mar(true);
+        }
+
+        instr.set_a_bus(expr.a);
+        instr.set_b_bus(expr.b.unwrap_or(0));
+        instr.set_sh(expr.sh);
+        if self.call {
+            instr.set_call();
+        }
+        instr.set_alu(expr.alu);
+        instr.set_cond(cond);
+        if let Some(s) = self.s_bus {
+            instr.set_s_bus(s);
+            instr.set_ens(true);
+        }
+        instr.set_mbr(self.mbr);
+        if let Some(read) = self.read {
+            instr.set_ms(true);
+            instr.set_rd_wr(read);
+        }
+        if let Some((_, ref target)) = self.jump {
+            let addr = match *target {
+                Target::Addr(addr) => addr,
+                Target::Label(ref label) => {
+                    *labels.get(label).ok_or_else(|| format!("undefined label '.{}'", label))?
+                }
+            };
+            instr.set_addr(addr);
+        }
+        Ok(instr)
+    }
+}
+
+/// Assembles a program into raw control store words.
+pub fn assemble(source: &str) -> Result<Vec<u32>, AsmError> {
+    assemble_with(source, &Layout::micro16())
+}
+
+/// Assembles a program into control store words of the format of `layout`.
+pub fn assemble_with(source: &str, layout: &Arc<Layout>) -> Result<Vec<u32>, AsmError> {
+    let mut labels = HashMap::new();
+    let mut words = Vec::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let err = |message: String| AsmError { line: i + 1, message };
+        let mut text = line.split('#').next().unwrap().trim();
+        if text.starts_with(';') {
+            continue;
+        }
+        if let Some(rest) = text.strip_prefix(':') {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let label = &rest[..end];
+            if label.is_empty() {
+                return Err(err("empty label".to_string()));
+            }
+            if words.len() >= PROGRAM_LENGTH {
+                return Err(err(format!("label '{}' is past the end of the {} word control store",
+                                       label,
+                                       PROGRAM_LENGTH)));
+            }
+            if labels.insert(label.to_string(), words.len() as u8).is_some() {
+                return Err(err(format!("label '{}' defined twice", label)));
+            }
+            text = rest[end..].trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let mut word = Word::default();
+        for statement in text.split(';') {
+            let tokens = tokenize(statement).map_err(&err)?;
+            if !tokens.is_empty() {
+                word.statement(&tokens).map_err(&err)?;
+            }
+        }
+        words.push((i + 1, word));
+        if words.len() > PROGRAM_LENGTH {
+            return Err(err(format!("program exceeds the {} word control store", PROGRAM_LENGTH)));
+        }
+    }
+
+    words.iter()
+        .map(|&(line, ref word)| {
+            word.encode(&labels, layout)
+                .map(|instr| instr.raw())
+                .map_err(|message| AsmError { line, message })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `count` copies of an instruction that does nothing.
+    fn filler(count: usize) -> String {
+        "R0 <- R0\n".repeat(count)
+    }
+
+    #[test]
+    fn a_full_control_store_assembles() {
+        let source = format!(":start\n{}goto .start\n", filler(PROGRAM_LENGTH - 1));
+        assert_eq!(assemble(&source).unwrap().len(), PROGRAM_LENGTH);
+    }
+
+    #[test]
+    fn too_long_programs_are_rejected() {
+        let err = assemble(&filler(PROGRAM_LENGTH + 1)).unwrap_err();
+        assert_eq!(err.line, PROGRAM_LENGTH + 1);
+    }
+
+    #[test]
+    fn labels_past_the_control_store_are_rejected() {
+        // The label would otherwise wrap around to address 0.
+        let source = format!("goto .end\n{}:end\n", filler(PROGRAM_LENGTH - 1));
+        let err = assemble(&source).unwrap_err();
+        assert_eq!(err.line, PROGRAM_LENGTH + 1);
+        assert!(err.message.contains("past the end"), "{}", err.message);
+    }
+}
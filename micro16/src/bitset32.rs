@@ -1,5 +1,6 @@
 use std::fmt;
 
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct BitSet32 {
     val: u32,
 }
@@ -23,6 +24,29 @@ impl BitSet32 {
         (self.val & mask) >> start
     }
 
+    #[inline]
+    pub fn set(&mut self, i: usize, value: bool) {
+        if value {
+            self.val |= 1 << i;
+        } else {
+            self.val &= !(1 << i);
+        }
+    }
+
+    #[inline]
+    pub fn set_many(&mut self, start: usize, end: usize, value: u32) {
+        let mut mask = 0;
+        for i in start..end {
+            mask |= 1 << i;
+        }
+        self.val = (self.val & !mask) | ((value << start) & mask);
+    }
+
+    #[inline]
+    pub fn value(&self) -> u32 {
+        self.val
+    }
+
     #[inline]
     pub fn and(&self, other: BitSet32) -> BitSet32 {
         BitSet32 { val: self.val & other.val }
@@ -0,0 +1,623 @@
+//! A configurable cache between the Cpu and its memory.
+//!
+//! The cache is a `MemoryPort`, so the Cpu keeps its two cycle handshake and
+//! the lookup happens on the transfer cycle. Latency is charged as stall
+//! cycles on top of the Cpu's own: filling a line costs `miss_penalty`, and
+//! every write that reaches memory costs `write_penalty`, whether it is
+//! written through or a dirty line written back on eviction.
+//!
+//! Write-back caches allocate a line on a write miss, write-through caches
+//! write around the cache instead. Hits, misses and evictions are counted in
+//! total and for every configured address region.
+
+use std::fmt;
+
+use cli;
+use cpu::{Memory, MemoryPort};
+use loader;
+use machine::{self, Assignment, Machine, Status};
+use rng::Rng;
+use state::{self, Location};
+
+const MEMORY_SIZE: usize = 1 << 16;
+const DEFAULT_MAX_CYCLES: u64 = 100_000;
+
+pub const USAGE: &str = "Usage: micro16 cache --program FILE [--size N] [--line N] [--ways N] \
+                         [--write-back | --write-through] [--replacement lru|fifo|random] \
+                         [--seed N] [--miss-penalty N] [--write-penalty N] \
+                         [--region NAME=START..END]... [--init ASSIGNMENTS] [--cycles N]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    WriteBack,
+    WriteThrough,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replacement {
+    /// Evicts the line that was used longest ago.
+    Lru,
+    /// Evicts the line that was filled first.
+    Fifo,
+    Random,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub start: u16,
+    pub end: u16,
+}
+
+impl Region {
+    /// Parses `NAME=START..END`, both ends inclusive.
+    pub fn parse(text: &str) -> Result<Region, String> {
+        let err = || format!("invalid region '{}', expected NAME=START..END", text);
+        let (name, range) = text.split_once('=').ok_or_else(err)?;
+        let (start, end) = range.split_once("..").ok_or_else(err)?;
+        let (start, end) = (state::parse_value(start)? as u16, state::parse_value(end)? as u16);
+        if name.is_empty() || start > end {
+            return Err(err());
+        }
+        Ok(Region {
+            name: name.to_string(),
+            start,
+            end,
+        })
+    }
+
+    fn contains(&self, addr: u16) -> bool {
+        self.start <= addr && addr <= self.end
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Capacity in words.
+    pub size: usize,
+    /// Words per line.
+    pub line: usize,
+    /// Lines per set.
+    pub ways: usize,
+    pub write_policy: WritePolicy,
+    pub replacement: Replacement,
+    pub seed: u64,
+    pub miss_penalty: u64,
+    pub write_penalty: u64,
+    pub regions: Vec<Region>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            size: 64,
+            line: 4,
+            ways: 2,
+            write_policy: WritePolicy::WriteBack,
+            replacement: Replacement::Lru,
+            seed: 0,
+            miss_penalty: 10,
+            write_penalty: 10,
+            regions: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub read_hits: u64,
+    pub read_misses: u64,
+    pub write_hits: u64,
+    pub write_misses: u64,
+    pub evictions: u64,
+    /// Dirty lines written back to memory.
+    pub writebacks: u64,
+}
+
+impl Stats {
+    pub fn accesses(&self) -> u64 {
+        self.read_hits + self.read_misses + self.write_hits + self.write_misses
+    }
+
+    /// Share of accesses that hit, in percent.
+    pub fn hit_rate(&self) -> f64 {
+        match self.accesses() {
+            0 => 0.0,
+            n => 100.0 * (self.read_hits + self.write_hits) as f64 / n as f64,
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{} accesses, {:.1}% hits (reads {}/{}, writes {}/{} hit/miss), {} evictions, {} \
+                writebacks",
+               self.accesses(),
+               self.hit_rate(),
+               self.read_hits,
+               self.read_misses,
+               self.write_hits,
+               self.write_misses,
+               self.evictions,
+               self.writebacks)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: usize,
+    data: Vec<i16>,
+    /// Clock values of the fill and the last use, for FIFO and LRU.
+    filled: u64,
+    used: u64,
+}
+
+pub struct Cache {
+    config: Config,
+    sets: Vec<Vec<Line>>,
+    memory: Memory,
+    ready: bool,
+    rng: Rng,
+    clock: u64,
+    pub stats: Stats,
+    /// Statistics per configured region, in order. Evictions count towards
+    /// the region of the access that caused them, writebacks towards the
+    /// region of the line written back.
+    pub region_stats: Vec<Stats>,
+    /// Cycles the Cpu would have waited for memory.
+    pub stall_cycles: u64,
+}
+
+impl Cache {
+    pub fn new(config: Config) -> Result<Cache, String> {
+        if config.line == 0 || config.ways == 0 ||
+           !config.size.is_multiple_of(config.line * config.ways) || config.size == 0 {
+            return Err(format!("a cache of {} words cannot have {}-way sets of {} word lines",
+                               config.size,
+                               config.ways,
+                               config.line));
+        }
+        if !MEMORY_SIZE.is_multiple_of(config.line) {
+            return Err(format!("line size {} does not divide the memory size", config.line));
+        }
+        let line = Line {
+            valid: false,
+            dirty: false,
+            tag: 0,
+            data: vec![0; config.line],
+            filled: 0,
+            used: 0,
+        };
+        let set_count = config.size / (config.line * config.ways);
+        Ok(Cache {
+            sets: vec![vec![line; config.ways]; set_count],
+            memory: Memory::new(),
+            ready: false,
+            rng: Rng::new(config.seed),
+            clock: 0,
+            stats: Stats::default(),
+            region_stats: vec![Stats::default(); config.regions.len()],
+            stall_cycles: 0,
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The memory behind the cache, which lags behind for dirty lines.
+    pub fn backing_memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    fn locate(&self, idx: usize) -> (usize, usize, usize) {
+        let block = idx / self.config.line;
+        (block % self.sets.len(), block / self.sets.len(), idx % self.config.line)
+    }
+
+    fn find(&self, set: usize, tag: usize) -> Option<usize> {
+        self.sets[set].iter().position(|l| l.valid && l.tag == tag)
+    }
+
+    fn count<F: Fn(&mut Stats)>(&mut self, idx: usize, update: F) {
+        update(&mut self.stats);
+        for (region, stats) in self.config.regions.iter().zip(self.region_stats.iter_mut()) {
+            if region.contains(idx as u16) {
+                update(stats);
+            }
+        }
+    }
+
+    fn write_back(&mut self, set: usize, way: usize) {
+        let line = &self.sets[set][way];
+        if !(line.valid && line.dirty) {
+            return;
+        }
+        let base = (line.tag * self.sets.len() + set) * self.config.line;
+        for (i, &value) in line.data.iter().enumerate() {
+            self.memory.set(base + i, value);
+        }
+        self.sets[set][way].dirty = false;
+        self.count(base, |s| s.writebacks += 1);
+        self.stall_cycles += self.config.write_penalty;
+    }
+
+    /// Brings the line holding `idx` into the cache and returns its way.
+    fn fill(&mut self, idx: usize) -> usize {
+        let (set, tag, _) = self.locate(idx);
+        let way = match self.sets[set].iter().position(|l| !l.valid) {
+            Some(way) => way,
+            None => {
+                let lines = &self.sets[set];
+                let way = match self.config.replacement {
+                    Replacement::Lru => (0..lines.len()).min_by_key(|&w| lines[w].used).unwrap(),
+                    Replacement::Fifo => {
+                        (0..lines.len()).min_by_key(|&w| lines[w].filled).unwrap()
+                    }
+                    Replacement::Random => self.rng.below(lines.len() as u64) as usize,
+                };
+                self.write_back(set, way);
+                self.count(idx, |s| s.evictions += 1);
+                way
+            }
+        };
+        let base = idx - idx % self.config.line;
+        let data = (0..self.config.line).map(|i| self.memory.get(base + i)).collect();
+        self.sets[set][way] = Line {
+            valid: true,
+            dirty: false,
+            tag,
+            data,
+            filled: self.clock,
+            used: self.clock,
+        };
+        self.stall_cycles += self.config.miss_penalty;
+        way
+    }
+
+    fn transfer_read(&mut self, idx: usize) -> i16 {
+        self.clock += 1;
+        let (set, tag, offset) = self.locate(idx);
+        let way = match self.find(set, tag) {
+            Some(way) => {
+                self.count(idx, |s| s.read_hits += 1);
+                way
+            }
+            None => {
+                self.count(idx, |s| s.read_misses += 1);
+                self.fill(idx)
+            }
+        };
+        self.sets[set][way].used = self.clock;
+        self.sets[set][way].data[offset]
+    }
+
+    fn transfer_write(&mut self, idx: usize, value: i16) {
+        self.clock += 1;
+        let (set, tag, offset) = self.locate(idx);
+        let way = match self.find(set, tag) {
+            Some(way) => {
+                self.count(idx, |s| s.write_hits += 1);
+                Some(way)
+            }
+            None => {
+                self.count(idx, |s| s.write_misses += 1);
+                match self.config.write_policy {
+                    WritePolicy::WriteBack => Some(self.fill(idx)),
+                    WritePolicy::WriteThrough => None,
+                }
+            }
+        };
+        if let Some(way) = way {
+            let line = &mut self.sets[set][way];
+            line.data[offset] = value;
+            line.used = self.clock;
+            line.dirty = self.config.write_policy == WritePolicy::WriteBack;
+        }
+        if self.config.write_policy == WritePolicy::WriteThrough {
+            self.memory.set(idx, value);
+            self.stall_cycles += self.config.write_penalty;
+        }
+    }
+
+    /// Writes all dirty lines back to memory.
+    pub fn flush(&mut self) {
+        for set in 0..self.sets.len() {
+            for way in 0..self.config.ways {
+                self.write_back(set, way);
+            }
+        }
+    }
+}
+
+impl MemoryPort for Cache {
+    fn read(&mut self, idx: usize) -> Option<i16> {
+        self.ready = !self.ready;
+        if self.ready {
+            None
+        } else {
+            Some(self.transfer_read(idx))
+        }
+    }
+
+    fn write(&mut self, idx: usize, value: i16) -> bool {
+        self.ready = !self.ready;
+        if !self.ready {
+            self.transfer_write(idx, value);
+        }
+        !self.ready
+    }
+
+    /// The value the Cpu would read, without touching the statistics.
+    fn get(&self, idx: usize) -> i16 {
+        let (set, tag, offset) = self.locate(idx);
+        match self.find(set, tag) {
+            Some(way) => self.sets[set][way].data[offset],
+            None => self.memory.get(idx),
+        }
+    }
+
+    fn set(&mut self, idx: usize, value: i16) {
+        self.memory.set(idx, value);
+        let (set, tag, offset) = self.locate(idx);
+        if let Some(way) = self.find(set, tag) {
+            self.sets[set][way].data[offset] = value;
+        }
+    }
+
+    /// Keeping the contents keeps the cache warm as well.
+    fn reset(&mut self, keep_contents: bool) {
+        self.ready = false;
+        if !keep_contents {
+            let config = self.config.clone();
+            let stats = (self.stats, self.region_stats.clone(), self.stall_cycles);
+            *self = Cache::new(config).unwrap();
+            self.stats = stats.0;
+            self.region_stats = stats.1;
+            self.stall_cycles = stats.2;
+        }
+    }
+}
+
+/// Entry point of `micro16 cache`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    let mut config = Config::default();
+    let mut program = None;
+    let mut init = Vec::new();
+    let mut max_cycles = DEFAULT_MAX_CYCLES;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--program" => program = Some(iter.next().ok_or(USAGE)?.clone()),
+            "--size" => config.size = cli::parse_arg(iter.next(), USAGE)?,
+            "--line" => config.line = cli::parse_arg(iter.next(), USAGE)?,
+            "--ways" => config.ways = cli::parse_arg(iter.next(), USAGE)?,
+            "--write-back" => config.write_policy = WritePolicy::WriteBack,
+            "--write-through" => config.write_policy = WritePolicy::WriteThrough,
+            "--replacement" => {
+                config.replacement = match iter.next().map(|s| s.as_str()) {
+                    Some("lru") => Replacement::Lru,
+                    Some("fifo") => Replacement::Fifo,
+                    Some("random") => Replacement::Random,
+                    _ => return Err(USAGE.to_string()),
+                }
+            }
+            "--seed" => config.seed = cli::parse_arg(iter.next(), USAGE)?,
+            "--miss-penalty" => config.miss_penalty = cli::parse_arg(iter.next(), USAGE)?,
+            "--write-penalty" => config.write_penalty = cli::parse_arg(iter.next(), USAGE)?,
+            "--region" => config.regions.push(Region::parse(iter.next().ok_or(USAGE)?)?),
+            "--init" => init.extend(Assignment::parse_list(iter.next().ok_or(USAGE)?)?),
+            "--cycles" => max_cycles = cli::parse_arg(iter.next(), USAGE)?,
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let program = loader::read_program(&program.ok_or(USAGE)?)?;
+
+    let machine_config = machine::Config {
+        max_cycles: Some(max_cycles),
+        ..machine::Config::default()
+    };
+    let mut machine = Machine::with_memory(&program, machine_config, Cache::new(config)?)
+        .map_err(|e| e.to_string())?;
+    machine.apply(&init);
+    let status = machine.run().map_err(|e| format!("cycle {}: {}", machine.cycles(), e))?;
+
+    let cycles = machine.cycles();
+    let cache = machine.cpu().memory();
+    let config = cache.config();
+    println!("{} words, {} word lines, {}-way, {:?}, {:?}",
+             config.size,
+             config.line,
+             config.ways,
+             config.write_policy,
+             config.replacement);
+    println!("{} cycles + {} stall cycles = {}{}",
+             cycles,
+             cache.stall_cycles,
+             cycles + cache.stall_cycles,
+             if status == Status::Finished { "" } else { ", cycle limit reached" });
+    println!("total: {}", cache.stats);
+    for (region, stats) in config.regions.iter().zip(&cache.region_stats) {
+        println!("{} [{}..{}]: {}", region.name, region.start, region.end, stats);
+    }
+    let regs: Vec<String> = (4..15)
+        .map(|r| {
+            let location = machine::Location::from(Location::Register(r));
+            format!("{}={}", location, machine.read(location))
+        })
+        .collect();
+    println!("{}", regs.join(" "));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(size: usize, line: usize, ways: usize, replacement: Replacement) -> Cache {
+        Cache::new(Config {
+                size,
+                line,
+                ways,
+                replacement,
+                ..Config::default()
+            })
+            .unwrap()
+    }
+
+    fn write_through(size: usize, line: usize, ways: usize) -> Cache {
+        Cache::new(Config {
+                size,
+                line,
+                ways,
+                write_policy: WritePolicy::WriteThrough,
+                ..Config::default()
+            })
+            .unwrap()
+    }
+
+    /// Reads through the two cycle handshake.
+    fn read(cache: &mut Cache, idx: usize) -> i16 {
+        assert_eq!(cache.read(idx), None);
+        cache.read(idx).expect("the second cycle completes the read")
+    }
+
+    fn write(cache: &mut Cache, idx: usize, value: i16) {
+        assert!(!cache.write(idx, value));
+        assert!(cache.write(idx, value));
+    }
+
+    #[test]
+    fn lines_are_filled_whole() {
+        let mut cache = cache(8, 4, 1, Replacement::Lru);
+        cache.set(2, 7);
+        assert_eq!(read(&mut cache, 0), 0);
+        assert_eq!(read(&mut cache, 2), 7);
+        assert_eq!(cache.stats.read_misses, 1);
+        assert_eq!(cache.stats.read_hits, 1);
+        assert_eq!(cache.stall_cycles, 10);
+    }
+
+    #[test]
+    fn lru_evicts_the_line_used_longest_ago() {
+        // One set of two single word lines.
+        let mut cache = cache(2, 1, 2, Replacement::Lru);
+        for &idx in &[0, 1, 0, 2] {
+            read(&mut cache, idx);
+        }
+        assert_eq!(cache.stats.evictions, 1);
+        read(&mut cache, 0);
+        assert_eq!(cache.stats.read_hits, 2);
+        read(&mut cache, 1);
+        assert_eq!(cache.stats.read_misses, 4);
+    }
+
+    #[test]
+    fn fifo_evicts_the_line_filled_first() {
+        let mut cache = cache(2, 1, 2, Replacement::Fifo);
+        for &idx in &[0, 1, 0, 2] {
+            read(&mut cache, idx);
+        }
+        assert_eq!(cache.stats.evictions, 1);
+        read(&mut cache, 1);
+        assert_eq!(cache.stats.read_hits, 2);
+        read(&mut cache, 0);
+        assert_eq!(cache.stats.read_misses, 4);
+    }
+
+    /// The ways that `addresses` evict in a single set of four.
+    fn random_victims(seed: u64, addresses: usize) -> Vec<usize> {
+        let mut cache = Cache::new(Config {
+                size: 4,
+                line: 1,
+                ways: 4,
+                replacement: Replacement::Random,
+                seed,
+                ..Config::default()
+            })
+            .unwrap();
+        let mut victims = Vec::new();
+        for idx in 0..addresses {
+            let before: Vec<usize> = cache.sets[0].iter().map(|l| l.tag).collect();
+            read(&mut cache, idx);
+            if idx >= 4 {
+                let after = cache.sets[0].iter().map(|l| l.tag);
+                victims.push(before.iter().zip(after).position(|(&a, b)| a != b).unwrap());
+            }
+        }
+        assert_eq!(cache.stats.evictions, addresses as u64 - 4);
+        victims
+    }
+
+    #[test]
+    fn random_replacement_depends_on_the_seed() {
+        let victims = random_victims(1, 36);
+        assert_eq!(victims, random_victims(1, 36));
+        assert!((0..4).all(|way| victims.contains(&way)), "victims {:?}", victims);
+        assert_ne!(victims, random_victims(2, 36));
+    }
+
+    #[test]
+    fn write_back_defers_memory_until_flush() {
+        let mut cache = cache(4, 2, 2, Replacement::Lru);
+        write(&mut cache, 0, 5);
+        write(&mut cache, 3, 6);
+        assert_eq!(cache.stats.write_misses, 2);
+        assert_eq!(cache.get(0), 5);
+        assert_eq!(cache.backing_memory().get(0), 0);
+        assert_eq!(cache.backing_memory().get(3), 0);
+
+        cache.flush();
+        assert_eq!(cache.backing_memory().get(0), 5);
+        assert_eq!(cache.backing_memory().get(3), 6);
+        assert_eq!(cache.stats.writebacks, 2);
+
+        // Flushed lines are clean.
+        cache.flush();
+        assert_eq!(cache.stats.writebacks, 2);
+        assert_eq!(read(&mut cache, 0), 5);
+        assert_eq!(cache.stats.read_hits, 1);
+    }
+
+    #[test]
+    fn write_back_writes_dirty_lines_on_eviction() {
+        let mut cache = cache(1, 1, 1, Replacement::Lru);
+        write(&mut cache, 0, 5);
+        read(&mut cache, 1);
+        assert_eq!(cache.stats.evictions, 1);
+        assert_eq!(cache.stats.writebacks, 1);
+        assert_eq!(cache.backing_memory().get(0), 5);
+        // Fill on the write miss, writeback, fill on the read miss.
+        assert_eq!(cache.stall_cycles, 30);
+
+        // Clean lines are dropped without a writeback.
+        read(&mut cache, 0);
+        assert_eq!(cache.stats.evictions, 2);
+        assert_eq!(cache.stats.writebacks, 1);
+    }
+
+    #[test]
+    fn write_through_writes_around_the_cache() {
+        let mut cache = write_through(4, 2, 2);
+        write(&mut cache, 0, 5);
+        assert_eq!(cache.backing_memory().get(0), 5);
+        assert_eq!(cache.stats.write_misses, 1);
+        assert_eq!(cache.stall_cycles, 10);
+
+        // The write miss did not allocate a line.
+        assert_eq!(read(&mut cache, 0), 5);
+        assert_eq!(cache.stats.read_misses, 1);
+
+        write(&mut cache, 1, 6);
+        assert_eq!(cache.stats.write_hits, 1);
+        assert_eq!(cache.get(1), 6);
+        assert_eq!(cache.backing_memory().get(1), 6);
+        cache.flush();
+        assert_eq!(cache.stats.writebacks, 0);
+    }
+}
@@ -0,0 +1,70 @@
+//! Argument handling shared by the `micro16` subcommands.
+//!
+//! Each subcommand walks its own arguments; `parse_arg` reads the value of
+//! an option and `MachineOptions` collects the options that set up a
+//! `machine::Machine` before it runs.
+
+use std::str::FromStr;
+
+use cpu::MemoryPort;
+use machine::{Assignment, Config, Machine};
+use state;
+
+/// Parses the value of an option, or fails with `usage` if it is missing or
+/// malformed.
+pub fn parse_arg<T: FromStr>(value: Option<&String>, usage: &str) -> Result<T, String> {
+    value.and_then(|v| v.parse().ok()).ok_or_else(|| usage.to_string())
+}
+
+/// `--init`, `--map-control-store` and `--call-stack`.
+#[derive(Clone, Debug, Default)]
+pub struct MachineOptions {
+    pub init: Vec<Assignment>,
+    pub control_store_window: Option<u16>,
+    pub call_stack_depth: Option<usize>,
+}
+
+impl MachineOptions {
+    /// Consumes `arg` and its value from `iter` if it is one of these
+    /// options, and returns whether it was.
+    pub fn parse<'a, I>(&mut self, arg: &str, iter: &mut I, usage: &str) -> Result<bool, String>
+        where I: Iterator<Item = &'a String>
+    {
+        match arg {
+            "--init" => self.init.extend(Assignment::parse_list(iter.next().ok_or(usage)?)?),
+            "--map-control-store" => {
+                let base = state::parse_value(iter.next().ok_or(usage)?)?;
+                self.control_store_window = Some(base as u16);
+            }
+            "--call-stack" => self.call_stack_depth = Some(parse_arg(iter.next(), usage)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Whether none of the options was given, as machines other than a
+    /// Micro16 require.
+    pub fn is_empty(&self) -> bool {
+        self.init.is_empty() && self.control_store_window.is_none() &&
+        self.call_stack_depth.is_none()
+    }
+
+    pub fn config(&self) -> Config {
+        Config {
+            control_store_window: self.control_store_window,
+            call_stack_depth: self.call_stack_depth,
+            ..Config::default()
+        }
+    }
+
+    /// A Machine running `program` on `memory`, set up as the options ask.
+    pub fn machine<M: MemoryPort>(&self,
+                                  program: &[u32],
+                                  memory: M)
+                                  -> Result<Machine<M>, String> {
+        let mut machine = Machine::with_memory(program, self.config(), memory)
+            .map_err(|e| e.to_string())?;
+        machine.apply(&self.init);
+        Ok(machine)
+    }
+}
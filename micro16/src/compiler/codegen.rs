@@ -0,0 +1,396 @@
+//! Code generation from the syntax tree to Micro16 assembler.
+//!
+//! Variables live in R0 to R10 for the whole program, in order of
+//! declaration or first assignment; expression temporaries are taken from
+//! the registers left over and released at the end of the expression.
+
+use std::collections::HashMap;
+
+use cpu::PROGRAM_LENGTH;
+use disasm::register_name;
+
+use super::parser::{Condition, Decl, Expr, Program, Relation, Stmt, StmtKind};
+use super::CompileError;
+
+const FIRST_REGISTER: u8 = 4;
+const LAST_REGISTER: u8 = 14;
+
+/// A register holding an intermediate value; temporaries must be released.
+#[derive(Debug, Clone, Copy)]
+struct Value {
+    reg: u8,
+    temp: bool,
+}
+
+pub struct Codegen {
+    lines: Vec<String>,
+    words: usize,
+    variables: Vec<(String, u8)>,
+    arrays: HashMap<String, (u16, u16)>,
+    free: Vec<u8>,
+    labels: usize,
+    line: usize,
+}
+
+fn name(reg: u8) -> &'static str {
+    register_name(reg)
+}
+
+fn mirror(relation: Relation) -> Relation {
+    match relation {
+        Relation::Less => Relation::Greater,
+        Relation::Greater => Relation::Less,
+        Relation::LessEqual => Relation::GreaterEqual,
+        Relation::GreaterEqual => Relation::LessEqual,
+        r => r,
+    }
+}
+
+impl Codegen {
+    fn new() -> Codegen {
+        Codegen {
+            lines: Vec::new(),
+            words: 0,
+            variables: Vec::new(),
+            arrays: HashMap::new(),
+            free: (FIRST_REGISTER..LAST_REGISTER + 1).rev().collect(),
+            labels: 0,
+            line: 0,
+        }
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, CompileError> {
+        Err(CompileError::new(self.line, message))
+    }
+
+    fn emit(&mut self, text: String) {
+        self.lines.push(text);
+        self.words += 1;
+    }
+
+    fn new_label(&mut self) -> String {
+        self.labels += 1;
+        format!("L{}", self.labels)
+    }
+
+    fn place_label(&mut self, label: &str) {
+        self.lines.push(format!(":{}", label));
+    }
+
+    fn take_register(&mut self) -> Result<u8, CompileError> {
+        // Keep the lowest registers for variables declared later on.
+        self.free.sort_by(|a, b| b.cmp(a));
+        match self.free.pop() {
+            Some(reg) => Ok(reg),
+            None => {
+                self.error(format!("out of registers: {} variables and the temporaries of this \
+                                    statement need more than R0-R10",
+                                   self.variables.len()))
+            }
+        }
+    }
+
+    fn temp(&mut self) -> Result<u8, CompileError> {
+        self.free.sort();
+        match self.free.pop() {
+            Some(reg) => Ok(reg),
+            None => self.take_register(),
+        }
+    }
+
+    fn release(&mut self, value: Value) {
+        if value.temp {
+            self.free.push(value.reg);
+        }
+    }
+
+    /// Picks the register of a new variable without declaring it yet.
+    fn allocate(&mut self, var: &str) -> Result<u8, CompileError> {
+        if self.arrays.contains_key(var) {
+            return self.error(format!("'{}' is an array", var));
+        }
+        self.take_register()
+    }
+
+    fn declare(&mut self, var: &str) -> Result<u8, CompileError> {
+        let reg = self.allocate(var)?;
+        self.variables.push((var.to_string(), reg));
+        Ok(reg)
+    }
+
+    fn variable(&self, var: &str) -> Option<u8> {
+        self.variables.iter().find(|&(n, _)| n == var).map(|&(_, reg)| reg)
+    }
+
+    fn constant_into(&mut self, n: i64, dest: u8) -> Result<(), CompileError> {
+        if !(i16::MIN as i64..=u16::MAX as i64).contains(&n) {
+            return self.error(format!("{} does not fit into 16 bits", n));
+        }
+        let value = n as u16 as i16;
+        let dest_name = name(dest);
+        match value {
+            0 => self.emit(format!("{} <- 0", dest_name)),
+            1 => self.emit(format!("{} <- 1", dest_name)),
+            -1 => self.emit(format!("{} <- -1", dest_name)),
+            v if v < 0 => {
+                self.constant_into(!v as i64, dest)?;
+                self.emit(format!("{0} <- ~{0}", dest_name));
+            }
+            v => {
+                let top = 15 - v.leading_zeros() as i32;
+                self.emit(format!("{} <- 1", dest_name));
+                for bit in (0..top).rev() {
+                    if v & (1 << bit) != 0 {
+                        self.emit(format!("{0} <- lsh({0})", dest_name));
+                        self.emit(format!("{0} <- {0} + 1", dest_name));
+                    } else {
+                        self.emit(format!("{0} <- lsh({0})", dest_name));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluates an expression into some register, avoiding copies of
+    /// variables and constants.
+    fn operand(&mut self, e: &Expr) -> Result<Value, CompileError> {
+        match *e {
+            Expr::Number(0) => Ok(Value { reg: 0, temp: false }),
+            Expr::Number(1) => Ok(Value { reg: 1, temp: false }),
+            Expr::Number(-1) => Ok(Value { reg: 2, temp: false }),
+            Expr::Var(ref var) => {
+                match self.variable(var) {
+                    Some(reg) => Ok(Value { reg, temp: false }),
+                    None => self.error(format!("undefined variable '{}'", var)),
+                }
+            }
+            _ => {
+                let reg = self.temp()?;
+                self.expr_into(e, reg)?;
+                Ok(Value { reg, temp: true })
+            }
+        }
+    }
+
+    fn expr_into(&mut self, e: &Expr, dest: u8) -> Result<(), CompileError> {
+        let dest_name = name(dest);
+        match *e {
+            Expr::Number(n) => self.constant_into(n, dest)?,
+            Expr::Var(_) => {
+                let v = self.operand(e)?;
+                if v.reg != dest {
+                    self.emit(format!("{} <- {}", dest_name, name(v.reg)));
+                }
+            }
+            Expr::Index(ref array, ref index) => {
+                let addr = self.address(array, index)?;
+                self.emit(format!("MAR <- {}; rd", name(addr.reg)));
+                self.emit("rd".to_string());
+                self.emit(format!("{} <- MBR", dest_name));
+                self.release(addr);
+            }
+            Expr::Not(ref x) => {
+                let v = self.operand(x)?;
+                self.emit(format!("{} <- ~{}", dest_name, name(v.reg)));
+                self.release(v);
+            }
+            Expr::Add(ref a, ref b) | Expr::And(ref a, ref b) => {
+                let op = if let Expr::Add(..) = *e { "+" } else { "&" };
+                let va = self.operand(a)?;
+                let vb = self.operand(b)?;
+                self.emit(format!("{} <- {} {} {}", dest_name, name(va.reg), op, name(vb.reg)));
+                self.release(va);
+                self.release(vb);
+            }
+            Expr::ShiftLeft(ref x, n) | Expr::ShiftRight(ref x, n) => {
+                let shift = if let Expr::ShiftLeft(..) = *e { "lsh" } else { "rsh" };
+                if n == 0 {
+                    return self.expr_into(x, dest);
+                }
+                let v = self.operand(x)?;
+                self.emit(format!("{} <- {}({})", dest_name, shift, name(v.reg)));
+                self.release(v);
+                for _ in 1..n {
+                    self.emit(format!("{0} <- {1}({0})", dest_name, shift));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Computes the address of `array[index]`.
+    fn address(&mut self, array: &str, index: &Expr) -> Result<Value, CompileError> {
+        let (size, base) = match self.arrays.get(array) {
+            Some(&entry) => entry,
+            None => return self.error(format!("undefined array '{}'", array)),
+        };
+        if let Expr::Number(i) = *index {
+            if i < 0 || i >= size as i64 {
+                return self.error(format!("index {} out of bounds for {}[{}]", i, array, size));
+            }
+            return self.operand(&Expr::Number(base as i64 + i));
+        }
+        let vi = self.operand(index)?;
+        if base == 0 {
+            return Ok(vi);
+        }
+        let vb = self.operand(&Expr::Number(base as i64))?;
+        let reg = if vi.temp { vi.reg } else { self.temp()? };
+        self.emit(format!("{} <- {} + {}", name(reg), name(vi.reg), name(vb.reg)));
+        self.release(vb);
+        Ok(Value { reg, temp: true })
+    }
+
+    /// Jumps to `target` if the value in `v` compared to zero satisfies
+    /// `relation`, and falls through otherwise.
+    fn jump_if(&mut self, v: u8, relation: Relation, target: &str) {
+        let test = format!("({})", name(v));
+        match relation {
+            Relation::Equal => self.emit(format!("{}; if Z goto .{}", test, target)),
+            Relation::Less => self.emit(format!("{}; if N goto .{}", test, target)),
+            Relation::LessEqual => {
+                self.emit(format!("{}; if N goto .{}", test, target));
+                self.emit(format!("{}; if Z goto .{}", test, target));
+            }
+            Relation::NotEqual | Relation::GreaterEqual | Relation::Greater => {
+                let skip = self.new_label();
+                if relation != Relation::GreaterEqual {
+                    self.emit(format!("{}; if Z goto .{}", test, skip));
+                }
+                if relation != Relation::NotEqual {
+                    self.emit(format!("{}; if N goto .{}", test, skip));
+                }
+                self.emit(format!("goto .{}", target));
+                self.place_label(&skip);
+            }
+        }
+    }
+
+    /// Jumps to `target` if the condition evaluates to `when`.
+    fn branch(&mut self, cond: &Condition, when: bool, target: &str) -> Result<(), CompileError> {
+        let relation = if when {
+            cond.relation
+        } else {
+            cond.relation.negate()
+        };
+        let (v, relation) = if cond.right == Expr::Number(0) {
+            (self.operand(&cond.left)?, relation)
+        } else if cond.left == Expr::Number(0) {
+            (self.operand(&cond.right)?, mirror(relation))
+        } else {
+            // left - right = left + ~right + 1, wrapping like the ALU does.
+            let reg = self.temp()?;
+            let vr = self.operand(&cond.right)?;
+            self.emit(format!("{} <- ~{}", name(reg), name(vr.reg)));
+            self.release(vr);
+            self.emit(format!("{0} <- {0} + 1", name(reg)));
+            let vl = self.operand(&cond.left)?;
+            self.emit(format!("{0} <- {1} + {0}", name(reg), name(vl.reg)));
+            self.release(vl);
+            (Value { reg, temp: true }, relation)
+        };
+        self.jump_if(v.reg, relation, target);
+        self.release(v);
+        Ok(())
+    }
+
+    fn statements(&mut self, stmts: &[Stmt]) -> Result<(), CompileError> {
+        for stmt in stmts {
+            self.statement(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> Result<(), CompileError> {
+        self.line = stmt.line;
+        match stmt.kind {
+            StmtKind::Assign(ref var, ref e) => {
+                if let Some(reg) = self.variable(var) {
+                    self.expr_into(e, reg)?;
+                } else {
+                    // The first assignment declares the variable, so its
+                    // expression cannot read it yet.
+                    let reg = self.allocate(var)?;
+                    self.expr_into(e, reg)?;
+                    self.variables.push((var.to_string(), reg));
+                }
+            }
+            StmtKind::Store(ref array, ref index, ref e) => {
+                let value = self.operand(e)?;
+                let addr = self.address(array, index)?;
+                self.emit(format!("MAR <- {}; MBR <- {}; wr", name(addr.reg), name(value.reg)));
+                self.emit("wr".to_string());
+                self.release(addr);
+                self.release(value);
+            }
+            StmtKind::If(ref cond, ref then, ref otherwise) => {
+                let else_label = self.new_label();
+                let end_label = self.new_label();
+                self.branch(cond, false, &else_label)?;
+                self.statements(then)?;
+                if !otherwise.is_empty() {
+                    self.emit(format!("goto .{}", end_label));
+                }
+                self.place_label(&else_label);
+                self.statements(otherwise)?;
+                self.place_label(&end_label);
+            }
+            StmtKind::While(ref cond, ref body) => {
+                let top = self.new_label();
+                let end = self.new_label();
+                self.place_label(&top);
+                self.branch(cond, false, &end)?;
+                self.statements(body)?;
+                self.emit(format!("goto .{}", top));
+                self.place_label(&end);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Generates assembler text and returns it with the register of every
+/// variable.
+pub fn generate(program: &Program) -> Result<(String, Vec<(String, u8)>), CompileError> {
+    let mut gen = Codegen::new();
+    for &(ref decl, line) in &program.decls {
+        gen.line = line;
+        match *decl {
+            Decl::Var(ref var) => {
+                if gen.variable(var).is_some() {
+                    return gen.error(format!("variable '{}' declared twice", var));
+                }
+                gen.declare(var)?;
+            }
+            Decl::Array { ref name, size, base } => {
+                if gen.arrays.insert(name.clone(), (size, base)).is_some() ||
+                   gen.variable(name).is_some() {
+                    return gen.error(format!("'{}' declared twice", name));
+                }
+            }
+        }
+    }
+    gen.statements(&program.body)?;
+
+    if gen.words > PROGRAM_LENGTH {
+        return Err(CompileError::new(0,
+                                     format!("program needs {} words, the control store holds \
+                                              {}",
+                                             gen.words,
+                                             PROGRAM_LENGTH)));
+    }
+
+    let mut text = String::new();
+    for &(ref var, reg) in &gen.variables {
+        text.push_str(&format!("# {} -> {}\n", var, name(reg)));
+    }
+    for line in &gen.lines {
+        if !line.starts_with(':') {
+            text.push_str("    ");
+        }
+        text.push_str(line.trim_end());
+        text.push('\n');
+    }
+    Ok((text, gen.variables))
+}
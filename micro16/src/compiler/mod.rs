@@ -0,0 +1,248 @@
+//! Compiler for a tiny structured language down to Micro16 microcode.
+//!
+//! ```text
+//! var n, sum;
+//! array data[8] at 100;
+//!
+//! n = 0;
+//! sum = 0;
+//! while (n < 8) {
+//!     sum = sum + data[n];
+//!     n = n + 1;
+//! }
+//! data[0] = sum;
+//! ```
+//!
+//! Values are 16 bit integers. Expressions support `+`, `&`, `~`, shifts by
+//! constant amounts and array reads; conditions compare two expressions
+//! with `==`, `!=`, `<`, `<=`, `>` or `>=` by subtracting them, so ordering
+//! wraps around for operands more than 32767 apart. `//` and `#` start a
+//! comment, as does a `;` at the start of a line. The output is assembler
+//! text for `asm`.
+
+pub mod codegen;
+pub mod parser;
+
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+
+use asm;
+use loader;
+
+pub const USAGE: &str = "Usage: micro16 compile FILE [--hex] [-o OUT]";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl CompileError {
+    pub fn new(line: usize, message: String) -> CompileError {
+        CompileError { line, message }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "line {}: {}", self.line, self.message)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Compiled {
+    pub assembly: String,
+    pub program: Vec<u32>,
+    /// Register index of every variable.
+    pub variables: Vec<(String, u8)>,
+}
+
+pub fn compile(source: &str) -> Result<Compiled, CompileError> {
+    let ast = parser::parse(source)?;
+    let (assembly, variables) = codegen::generate(&ast)?;
+    let program = asm::assemble(&assembly)
+        .map_err(|e| CompileError::new(0, format!("generated code does not assemble: {}", e)))?;
+    Ok(Compiled {
+        assembly,
+        program,
+        variables,
+    })
+}
+
+/// Entry point of `micro16 compile`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    let mut input = None;
+    let mut output = None;
+    let mut hex = false;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--hex" => hex = true,
+            "-o" => output = Some(iter.next().ok_or(USAGE)?.clone()),
+            _ if input.is_none() && !arg.starts_with('-') => input = Some(arg.clone()),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let input = input.ok_or(USAGE)?;
+
+    let source = loader::read_source(&input)?;
+    let compiled = compile(&source).map_err(|e| format!("{}: {}", input, e))?;
+    let text = if hex {
+        let mut text = String::new();
+        for &word in &compiled.program {
+            text.push_str(&format!("{:08x}\n", word));
+        }
+        text
+    } else {
+        compiled.assembly
+    };
+
+    match output {
+        Some(path) => {
+            File::create(&path)
+                .and_then(|mut f| f.write_all(text.as_bytes()))
+                .map_err(|e| format!("{}: {}", path, e))
+        }
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disasm::register_name;
+    use machine::{Assignment, Config, Machine, Status};
+
+    /// Compiles and runs `source` after applying `init`, and returns the
+    /// machine along with the registers of the variables.
+    fn run(source: &str, init: &str) -> (Machine, Vec<(String, u8)>) {
+        let compiled = compile(source).unwrap_or_else(|e| panic!("{}", e));
+        let config = Config {
+            max_cycles: Some(10_000),
+            ..Config::default()
+        };
+        let mut machine = Machine::new(&compiled.program, config).unwrap();
+        machine.apply(&Assignment::parse_list(init).unwrap());
+        assert_eq!(machine.run(), Ok(Status::Finished));
+        (machine, compiled.variables)
+    }
+
+    fn value(run: &(Machine, Vec<(String, u8)>), var: &str) -> i16 {
+        let reg = run.1.iter().find(|&(n, _)| n == var).unwrap().1;
+        run.0.register(register_name(reg)).unwrap()
+    }
+
+    fn error(source: &str) -> String {
+        compile(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn expressions() {
+        let run = run("var a, b, c, d, e;
+                       a = 5;
+                       b = a + 3 + a;
+                       c = ~a & 0xff;
+                       d = (b << 3) + 1;
+                       e = d >> 2;",
+                      "");
+        assert_eq!(value(&run, "b"), 13);
+        assert_eq!(value(&run, "c"), 250);
+        assert_eq!(value(&run, "d"), 105);
+        assert_eq!(value(&run, "e"), 26);
+    }
+
+    #[test]
+    fn negative_constants() {
+        let run = run("a = -1; b = -300; c = -32768; d = 65535; e = 0x7fff;", "");
+        assert_eq!(value(&run, "a"), -1);
+        assert_eq!(value(&run, "b"), -300);
+        assert_eq!(value(&run, "c"), i16::MIN);
+        assert_eq!(value(&run, "d"), -1);
+        assert_eq!(value(&run, "e"), i16::MAX);
+    }
+
+    #[test]
+    fn relations() {
+        let holds = |op: &str, x: i16, y: i16| match op {
+            "==" => x == y,
+            "!=" => x != y,
+            "<" => x < y,
+            "<=" => x <= y,
+            ">" => x > y,
+            _ => x >= y,
+        };
+        // Comparisons with zero on either side test the other operand
+        // directly, the others subtract.
+        let operands = [("x", "y"), ("x", "0"), ("0", "y")];
+        let pairs = [(3, 5), (5, 3), (4, 4), (-2, 3), (0, -1), (-1, 0), (0, 0), (7, 0)];
+        for &op in &["==", "!=", "<", "<=", ">", ">="] {
+            for &(left, right) in &operands {
+                let source = format!("var x, y, r;\nif ({} {} {}) {{ r = 1; }} else {{ r = 2; }}",
+                                     left,
+                                     op,
+                                     right);
+                for &(x, y) in &pairs {
+                    let run = run(&source, &format!("R0={} R1={}", x, y));
+                    let l = if left == "x" { x } else { 0 };
+                    let r = if right == "y" { y } else { 0 };
+                    assert_eq!(value(&run, "r") == 1,
+                               holds(op, l, r),
+                               "{} with x={} y={}",
+                               source,
+                               x,
+                               y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn loops() {
+        let counted = run("var n, sum, odd;
+                       array data[10] at 50;
+                       n = 0;
+                       sum = 0;
+                       odd = 0;
+                       while (n < 10) {
+                           data[n] = n << 1;
+                           if ((n & 1) == 1) {
+                               odd = odd + data[n];
+                           }
+                           sum = sum + n;
+                           n = n + 1;
+                       }",
+                      "");
+        assert_eq!(value(&counted, "n"), 10);
+        assert_eq!(value(&counted, "sum"), 45);
+        assert_eq!(value(&counted, "odd"), 50);
+        assert_eq!(counted.0.read_memory(50, 10).unwrap(),
+                   vec![0, 2, 4, 6, 8, 10, 12, 14, 16, 18]);
+
+        // The body never runs.
+        let skipped = run("n = 5; while (n < 5) { n = n + 1; }", "");
+        assert_eq!(value(&skipped, "n"), 5);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("x = x + 1;"), "line 1: undefined variable 'x'");
+        assert_eq!(error("var x;\nx = y;"), "line 2: undefined variable 'y'");
+        assert_eq!(error("x = data[0];"), "line 1: undefined array 'data'");
+        assert_eq!(error("array data[4] at 0;\ndata[4] = 1;"),
+                   "line 2: index 4 out of bounds for data[4]");
+        assert_eq!(error("array data[4] at 0;\ndata = 1;"), "line 2: 'data' is an array");
+        assert_eq!(error("var x, x;"), "line 1: variable 'x' declared twice");
+        assert_eq!(error("x = 70000;"), "line 1: 70000 does not fit into 16 bits");
+        assert!(error("var a, b, c, d, e, f, g, h, i, j, k, l;").contains("out of registers"));
+        assert!(error("x = ;").starts_with("line 1: "));
+    }
+}
@@ -0,0 +1,391 @@
+//! Lexer and recursive descent parser for the structured language.
+
+use super::CompileError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Punct(&'static str),
+}
+
+const PUNCTUATION: [&str; 21] = ["==", "!=", "<=", ">=", "<<", ">>", "=", "<", ">", "+", "-",
+                                 "&", "~", "(", ")", "{", "}", "[", "]", ";", ","];
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, CompileError> {
+    let mut tokens = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.split("//").next().unwrap();
+        let line = line.split('#').next().unwrap();
+        // Lines starting with ';' carry golden test annotations.
+        if line.trim_start().starts_with(';') {
+            continue;
+        }
+        let chars: Vec<char> = line.chars().collect();
+        let mut pos = 0;
+        while pos < chars.len() {
+            let c = chars[pos];
+            if c.is_whitespace() {
+                pos += 1;
+            } else if c.is_ascii_digit() {
+                let start = pos;
+                while pos < chars.len() && chars[pos].is_ascii_alphanumeric() {
+                    pos += 1;
+                }
+                let text: String = chars[start..pos].iter().collect();
+                let value = if let Some(hex) = text.strip_prefix("0x") {
+                    i64::from_str_radix(hex, 16)
+                } else {
+                    text.parse()
+                };
+                let value = value.map_err(|_| {
+                        CompileError::new(line_no, format!("invalid number '{}'", text))
+                    })?;
+                tokens.push((Token::Number(value), line_no));
+            } else if c.is_alphabetic() || c == '_' {
+                let start = pos;
+                while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '_') {
+                    pos += 1;
+                }
+                tokens.push((Token::Ident(chars[start..pos].iter().collect()), line_no));
+            } else {
+                let rest: String = chars[pos..].iter().take(2).collect();
+                match PUNCTUATION.iter().find(|p| rest.starts_with(*p)) {
+                    Some(p) => {
+                        tokens.push((Token::Punct(p), line_no));
+                        pos += p.len();
+                    }
+                    None => {
+                        return Err(CompileError::new(line_no,
+                                                     format!("unexpected character '{}'", c)))
+                    }
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i64),
+    Var(String),
+    Index(String, Box<Expr>),
+    Not(Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    ShiftLeft(Box<Expr>, u32),
+    ShiftRight(Box<Expr>, u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relation {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl Relation {
+    pub fn negate(self) -> Relation {
+        match self {
+            Relation::Equal => Relation::NotEqual,
+            Relation::NotEqual => Relation::Equal,
+            Relation::Less => Relation::GreaterEqual,
+            Relation::GreaterEqual => Relation::Less,
+            Relation::Greater => Relation::LessEqual,
+            Relation::LessEqual => Relation::Greater,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub left: Expr,
+    pub relation: Relation,
+    pub right: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StmtKind {
+    Assign(String, Expr),
+    Store(String, Expr, Expr),
+    If(Condition, Vec<Stmt>, Vec<Stmt>),
+    While(Condition, Vec<Stmt>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stmt {
+    pub line: usize,
+    pub kind: StmtKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decl {
+    Var(String),
+    Array { name: String, size: u16, base: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub decls: Vec<(Decl, usize)>,
+    pub body: Vec<Stmt>,
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or_else(|| self.tokens.last())
+            .map_or(1, |&(_, line)| line)
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, CompileError> {
+        Err(CompileError::new(self.line(), message))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(t, _)| t)
+    }
+
+    fn peek_punct(&self, p: &str) -> bool {
+        matches!(self.peek(), Some(&Token::Punct(q)) if q == p)
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(name)) if name == keyword)
+    }
+
+    fn eat_punct(&mut self, p: &str) -> bool {
+        if self.peek_punct(p) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_punct(&mut self, p: &str) -> Result<(), CompileError> {
+        if self.eat_punct(p) {
+            Ok(())
+        } else {
+            self.error(format!("expected '{}'", p))
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), CompileError> {
+        if self.peek_keyword(keyword) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            self.error(format!("expected '{}'", keyword))
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, CompileError> {
+        match self.peek().cloned() {
+            Some(Token::Ident(name)) => {
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => self.error("expected a name".to_string()),
+        }
+    }
+
+    fn number(&mut self) -> Result<i64, CompileError> {
+        let negative = self.eat_punct("-");
+        match self.peek().cloned() {
+            Some(Token::Number(n)) => {
+                self.pos += 1;
+                Ok(if negative { -n } else { n })
+            }
+            _ => self.error("expected a number".to_string()),
+        }
+    }
+
+    fn address(&mut self) -> Result<u16, CompileError> {
+        let n = self.number()?;
+        if !(0..=0xffff).contains(&n) {
+            return self.error(format!("{} is not a memory address", n));
+        }
+        Ok(n as u16)
+    }
+
+    fn program(&mut self) -> Result<Program, CompileError> {
+        let mut decls = Vec::new();
+        let mut body = Vec::new();
+        while self.peek().is_some() {
+            let line = self.line();
+            if self.peek_keyword("var") {
+                self.pos += 1;
+                loop {
+                    decls.push((Decl::Var(self.ident()?), line));
+                    if !self.eat_punct(",") {
+                        break;
+                    }
+                }
+                self.expect_punct(";")?;
+            } else if self.peek_keyword("array") {
+                self.pos += 1;
+                let name = self.ident()?;
+                self.expect_punct("[")?;
+                let size = self.address()?;
+                self.expect_punct("]")?;
+                self.expect_keyword("at")?;
+                let base = self.address()?;
+                self.expect_punct(";")?;
+                decls.push((Decl::Array { name, size, base }, line));
+            } else {
+                body.push(self.statement()?);
+            }
+        }
+        Ok(Program { decls, body })
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, CompileError> {
+        self.expect_punct("{")?;
+        let mut stmts = Vec::new();
+        while !self.eat_punct("}") {
+            if self.peek().is_none() {
+                return self.error("expected '}'".to_string());
+            }
+            stmts.push(self.statement()?);
+        }
+        Ok(stmts)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, CompileError> {
+        let line = self.line();
+        let kind = if self.peek_keyword("if") {
+            self.pos += 1;
+            let cond = self.condition()?;
+            let then = self.block()?;
+            let otherwise = if self.peek_keyword("else") {
+                self.pos += 1;
+                if self.peek_keyword("if") {
+                    vec![self.statement()?]
+                } else {
+                    self.block()?
+                }
+            } else {
+                Vec::new()
+            };
+            StmtKind::If(cond, then, otherwise)
+        } else if self.peek_keyword("while") {
+            self.pos += 1;
+            let cond = self.condition()?;
+            StmtKind::While(cond, self.block()?)
+        } else {
+            let name = self.ident()?;
+            let kind = if self.eat_punct("[") {
+                let index = self.expr()?;
+                self.expect_punct("]")?;
+                self.expect_punct("=")?;
+                StmtKind::Store(name, index, self.expr()?)
+            } else {
+                self.expect_punct("=")?;
+                StmtKind::Assign(name, self.expr()?)
+            };
+            self.expect_punct(";")?;
+            kind
+        };
+        Ok(Stmt { line, kind })
+    }
+
+    fn condition(&mut self) -> Result<Condition, CompileError> {
+        self.expect_punct("(")?;
+        let left = self.expr()?;
+        let relation = match self.peek() {
+            Some(&Token::Punct("==")) => Relation::Equal,
+            Some(&Token::Punct("!=")) => Relation::NotEqual,
+            Some(&Token::Punct("<")) => Relation::Less,
+            Some(&Token::Punct("<=")) => Relation::LessEqual,
+            Some(&Token::Punct(">")) => Relation::Greater,
+            Some(&Token::Punct(">=")) => Relation::GreaterEqual,
+            _ => return self.error("expected a comparison".to_string()),
+        };
+        self.pos += 1;
+        let right = self.expr()?;
+        self.expect_punct(")")?;
+        Ok(Condition { left, relation, right })
+    }
+
+    /// expr := shift (('+' | '&') shift)*
+    fn expr(&mut self) -> Result<Expr, CompileError> {
+        let mut left = self.shift()?;
+        loop {
+            if self.eat_punct("+") {
+                left = Expr::Add(Box::new(left), Box::new(self.shift()?));
+            } else if self.eat_punct("&") {
+                left = Expr::And(Box::new(left), Box::new(self.shift()?));
+            } else {
+                return Ok(left);
+            }
+        }
+    }
+
+    /// shift := unary (('<<' | '>>') number)*
+    fn shift(&mut self) -> Result<Expr, CompileError> {
+        let mut value = self.unary()?;
+        loop {
+            let left = if self.eat_punct("<<") {
+                true
+            } else if self.eat_punct(">>") {
+                false
+            } else {
+                return Ok(value);
+            };
+            let n = self.number()?;
+            if !(0..16).contains(&n) {
+                return self.error(format!("shift amount {} out of range", n));
+            }
+            value = if left {
+                Expr::ShiftLeft(Box::new(value), n as u32)
+            } else {
+                Expr::ShiftRight(Box::new(value), n as u32)
+            };
+        }
+    }
+
+    /// unary := '~' unary | number | name | name '[' expr ']' | '(' expr ')'
+    fn unary(&mut self) -> Result<Expr, CompileError> {
+        if self.eat_punct("~") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat_punct("(") {
+            let e = self.expr()?;
+            self.expect_punct(")")?;
+            return Ok(e);
+        }
+        match self.peek().cloned() {
+            Some(Token::Number(_)) | Some(Token::Punct("-")) => Ok(Expr::Number(self.number()?)),
+            Some(Token::Ident(_)) => {
+                let name = self.ident()?;
+                if self.eat_punct("[") {
+                    let index = self.expr()?;
+                    self.expect_punct("]")?;
+                    Ok(Expr::Index(name, Box::new(index)))
+                } else {
+                    Ok(Expr::Var(name))
+                }
+            }
+            _ => self.error("expected an expression".to_string()),
+        }
+    }
+}
+
+pub fn parse(source: &str) -> Result<Program, CompileError> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        pos: 0,
+    };
+    parser.program()
+}
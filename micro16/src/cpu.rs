@@ -1,146 +1,681 @@
+use std::error::Error;
 use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+use format::Layout;
 use instruction::Instruction;
-use std::mem;
 
 const MEMORY_SIZE: usize = 1 << 16;
-const PROGRAM_LENGTH: usize = 256;
-const MAR_REGISTER_IDX: u8 = 3;
-const MBR_REGISTER_IDX: u8 = 15;
+/// Memory cells per page; pages are allocated and copied as a whole.
+const PAGE_SIZE: usize = 256;
+pub const PROGRAM_LENGTH: usize = 256;
+/// Bus index of the memory address register.
+pub const MAR: u8 = 3;
+/// Bus index of the memory buffer register.
+pub const MBR: u8 = 15;
+/// Memory cells per control store word in the memory-mapped window.
+const CONTROL_STORE_WINDOW: usize = 2 * PROGRAM_LENGTH;
 
 #[repr(u8)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AluMode {
     NoOp = 0,
     Add = 1,
     BitAnd = 2,
     BitNot = 3,
+    Sub = 4,
+    BitOr = 5,
+    BitXor = 6,
+    Inc = 7,
 }
 
 impl AluMode {
-    pub fn from_u8(value: u8) -> AluMode {
-        unsafe { mem::transmute(value) }
+    pub fn from_u8(value: u8) -> Result<AluMode, CpuError> {
+        Ok(match value {
+            0 => AluMode::NoOp,
+            1 => AluMode::Add,
+            2 => AluMode::BitAnd,
+            3 => AluMode::BitNot,
+            4 => AluMode::Sub,
+            5 => AluMode::BitOr,
+            6 => AluMode::BitXor,
+            7 => AluMode::Inc,
+            _ => return Err(CpuError::UndefinedCode { field: "alu", code: value as u32 }),
+        })
+    }
+
+    /// Whether the mode is only available in `Profile::Extended`.
+    pub fn is_extended(self) -> bool {
+        self as u8 > AluMode::BitNot as u8
     }
 }
 
 #[repr(u8)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ShifterMode {
     NoOp = 0,
     Left = 1,
     Right = 2,
+    /// Rotates left by one bit. Its number leaves out 3, the code of
+    /// `call` in Micro16, which is no shifter mode.
+    Rotate = 4,
 }
 
 impl ShifterMode {
-    pub fn from_u8(value: u8) -> ShifterMode {
-        unsafe { mem::transmute(value) }
+    pub fn from_u8(value: u8) -> Result<ShifterMode, CpuError> {
+        Ok(match value {
+            0 => ShifterMode::NoOp,
+            1 => ShifterMode::Left,
+            2 => ShifterMode::Right,
+            4 => ShifterMode::Rotate,
+            _ => return Err(CpuError::UndefinedCode { field: "sh", code: value as u32 }),
+        })
+    }
+
+    /// Whether the mode is only available in `Profile::Extended`.
+    pub fn is_extended(self) -> bool {
+        self == ShifterMode::Rotate
     }
 }
 
 #[repr(u8)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CondMode {
     NoOp = 0,
     IfNegative = 1,
     IfZero = 2,
     GoTo = 3,
+    IfCarry = 4,
+    IfOverflow = 5,
+    /// Jumps if the last subtraction found A less than B as signed numbers,
+    /// i.e. if N differs from V.
+    IfLess = 6,
 }
 
 impl CondMode {
-    pub fn from_u8(value: u8) -> CondMode {
-        unsafe { mem::transmute(value) }
+    pub fn from_u8(value: u8) -> Result<CondMode, CpuError> {
+        Ok(match value {
+            0 => CondMode::NoOp,
+            1 => CondMode::IfNegative,
+            2 => CondMode::IfZero,
+            3 => CondMode::GoTo,
+            4 => CondMode::IfCarry,
+            5 => CondMode::IfOverflow,
+            6 => CondMode::IfLess,
+            _ => return Err(CpuError::UndefinedCode { field: "cond", code: value as u32 }),
+        })
+    }
+
+    /// Whether the mode is only available in `Profile::Extended`.
+    pub fn is_extended(self) -> bool {
+        self as u8 > CondMode::GoTo as u8
+    }
+}
+
+/// The operations a Cpu implements.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Profile {
+    /// The ALU and conditions of the original Micro16.
+    #[default]
+    Classic,
+    /// Adds subtract, or, xor and increment to the ALU, rotation to the
+    /// shifter, the carry and overflow flags, and jumps on carry, overflow
+    /// and signed less than.
+    Extended,
+}
+
+impl Profile {
+    pub fn parse(name: &str) -> Result<Profile, String> {
+        match name {
+            "classic" => Ok(Profile::Classic),
+            "extended" => Ok(Profile::Extended),
+            _ => Err(format!("unknown profile '{}', expected classic or extended", name)),
+        }
+    }
+
+    /// The name that `parse` accepts.
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::Classic => "classic",
+            Profile::Extended => "extended",
+        }
     }
 }
 
-pub struct Cpu<'a> {
+/// Errors raised while decoding or executing a microinstruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    /// The instruction at `addr` writes to one of the constant registers 0, 1 or -1.
+    ReadOnlyRegister { addr: u8, register: u8 },
+    /// The instruction at `addr` uses the reserved shifter encoding.
+    InvalidShifterMode { addr: u8 },
+    /// The instruction at `addr` holds a code its format leaves undefined in
+    /// `field`, or sets bits outside all fields if `field` is `unused`.
+    InvalidEncoding { addr: u8, field: &'static str },
+    /// A program of `len` words does not fit into the control store.
+    ProgramTooLong { len: usize },
+    /// The instruction at `addr` calls with all `depth` entries of the
+    /// return stack in use.
+    CallStackOverflow { addr: u8, depth: usize },
+    /// The instruction at `addr` returns with an empty return stack.
+    CallStackUnderflow { addr: u8 },
+    /// The instruction at `addr` combines the call code with a conditional
+    /// jump; calls and returns are unconditional.
+    ConditionalCall { addr: u8 },
+    /// `code` means no mode in `field`, for an instruction decoded outside
+    /// the Cpu.
+    UndefinedCode { field: &'static str, code: u32 },
+    /// The program has finished: the program counter `addr` is past its
+    /// end.
+    Finished { addr: u8 },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CpuError::ReadOnlyRegister { addr, register } => {
+                write!(f,
+                       "instruction {} writes to read-only register {}",
+                       addr,
+                       register)
+            }
+            CpuError::InvalidShifterMode { addr } => {
+                write!(f, "instruction {} uses reserved shifter mode 3", addr)
+            }
+            CpuError::InvalidEncoding { addr, field: "unused" } => {
+                write!(f, "instruction {} sets bits outside all fields", addr)
+            }
+            CpuError::InvalidEncoding { addr, field } => {
+                write!(f, "instruction {} holds an undefined {} code", addr, field)
+            }
+            CpuError::ProgramTooLong { len } => {
+                write!(f,
+                       "program has {} words, the control store holds {}",
+                       len,
+                       PROGRAM_LENGTH)
+            }
+            CpuError::CallStackOverflow { addr, depth } => {
+                write!(f,
+                       "instruction {} overflows the return stack of depth {}",
+                       addr,
+                       depth)
+            }
+            CpuError::CallStackUnderflow { addr } => {
+                write!(f, "instruction {} returns with an empty return stack", addr)
+            }
+            CpuError::ConditionalCall { addr } => {
+                write!(f, "instruction {} combines a call with a conditional jump", addr)
+            }
+            CpuError::UndefinedCode { field, code } => {
+                write!(f, "{} code {} is undefined", field, code)
+            }
+            CpuError::Finished { addr } => {
+                write!(f, "the program has finished, there is no instruction {}", addr)
+            }
+        }
+    }
+}
+
+impl Error for CpuError {}
+
+/// The four clock phases of a Micro16 cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// MIR <- control store[MIC]
+    LoadMir,
+    /// A-latch <- A-bus, B-latch <- B-bus
+    LatchAB,
+    /// ALU and shifter compute, MAR <- B-latch
+    Alu,
+    /// S-bus write back, MBR, memory access and next MIC
+    WriteBack,
+}
+
+impl Phase {
+    fn next(self) -> Phase {
+        match self {
+            Phase::LoadMir => Phase::LatchAB,
+            Phase::LatchAB => Phase::Alu,
+            Phase::Alu => Phase::WriteBack,
+            Phase::WriteBack => Phase::LoadMir,
+        }
+    }
+}
+
+/// Intermediate bus and latch values of the current cycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Datapath {
+    pub mir: u32,
+    pub a_bus: i16,
+    pub b_bus: i16,
+    pub a_latch: i16,
+    pub b_latch: i16,
+    pub alu_out: i16,
+    pub shifter_out: i16,
+    pub s_bus: i16,
+}
+
+/// The Cpu's side of the memory handshake. Every cycle with `ms` set makes
+/// one call to `read` or `write`, which report when the access completes.
+pub trait MemoryPort {
+    fn read(&mut self, idx: usize) -> Option<i16>;
+    fn write(&mut self, idx: usize, value: i16) -> bool;
+    /// Reads a cell directly, bypassing the handshake.
+    fn get(&self, idx: usize) -> i16;
+    /// Writes a cell directly, bypassing the handshake.
+    fn set(&mut self, idx: usize, value: i16);
+    /// Aborts any access in progress and, unless `keep_contents` is set,
+    /// clears the memory behind the port.
+    fn reset(&mut self, keep_contents: bool);
+}
+
+/// Cloning a Cpu is cheap with the default `Memory`, whose pages the clone
+/// shares until either side writes them, so clones serve as snapshots.
+#[derive(Clone)]
+pub struct Cpu<M: MemoryPort = Memory> {
     registers: RegisterSet,
-    memory: Memory,
-    program: &'a [u32],
+    memory: M,
+    /// The control store; execution ends when the program counter leaves it.
+    program: Vec<u32>,
+    /// First memory cell of the window through which memory writes patch
+    /// the control store, if enabled.
+    control_store_window: Option<u16>,
+    /// How control store words are decoded.
+    layout: Arc<Layout>,
+    /// Capacity of the return stack; `None` leaves the call code reserved.
+    call_stack_depth: Option<usize>,
+    /// Return addresses of the micro-calls in progress, innermost last.
+    call_stack: Vec<u8>,
+    /// Control word bits held at 0 and at 1 while words are loaded into the
+    /// MIR, see `set_stuck_bits`.
+    stuck_zero: u32,
+    stuck_one: u32,
     program_counter: u8,
     negative_flag: bool,
     zero_flag: bool,
+    /// Carry out of, or borrow into, the last addition or subtraction.
+    carry_flag: bool,
+    overflow_flag: bool,
+    phase: Phase,
+    datapath: Datapath,
 }
 
-impl<'a> Cpu<'a> {
-    pub fn new(prog: &'a [u32]) -> Cpu<'a> {
+impl Cpu {
+    /// Creates a Cpu with a copy of `prog` in its control store and its own
+    /// zeroed memory.
+    ///
+    /// Panics if the program is longer than `PROGRAM_LENGTH` words.
+    pub fn new(prog: &[u32]) -> Cpu {
+        Cpu::with_memory(prog, Memory::new())
+    }
+}
+
+impl<M: MemoryPort> Cpu<M> {
+    /// Creates a Cpu that accesses memory through `memory`.
+    ///
+    /// Panics if the program is longer than `PROGRAM_LENGTH` words.
+    pub fn with_memory(prog: &[u32], memory: M) -> Cpu<M> {
         if prog.len() > PROGRAM_LENGTH {
             panic!("Program too long!");
         }
         Cpu {
             registers: RegisterSet::new(),
-            memory: Memory::new(),
-            program: prog,
+            memory,
+            program: prog.to_vec(),
+            control_store_window: None,
+            layout: Layout::micro16(),
+            call_stack_depth: None,
+            call_stack: Vec::new(),
+            stuck_zero: 0,
+            stuck_one: 0,
             program_counter: 0,
             zero_flag: false,
             negative_flag: false,
+            carry_flag: false,
+            overflow_flag: false,
+            phase: Phase::LoadMir,
+            datapath: Datapath::default(),
         }
     }
 
+    /// Decodes the control store through `layout` from the next cycle on,
+    /// which also selects the profile of the format.
+    pub fn set_layout(&mut self, layout: Arc<Layout>) {
+        self.layout = layout;
+    }
+
+    pub fn layout(&self) -> &Arc<Layout> {
+        &self.layout
+    }
+
+    /// Enables micro-calls and returns with a return stack of `depth`
+    /// entries, or disables them for `None`, which makes the call code an
+    /// invalid shifter mode again. Return addresses already on the stack
+    /// are kept.
+    pub fn set_call_stack_depth(&mut self, depth: Option<usize>) {
+        self.call_stack_depth = depth;
+    }
+
+    pub fn call_stack_depth(&self) -> Option<usize> {
+        self.call_stack_depth
+    }
+
+    /// Return addresses of the calls in progress, innermost last.
+    pub fn call_stack(&self) -> &[u8] {
+        &self.call_stack
+    }
+
+    /// Selects the operations the Cpu executes by decoding the control
+    /// store in the built-in format of `profile`.
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.layout = Layout::for_profile(profile);
+    }
+
+    /// The profile of the layout, see `Layout::profile`.
+    pub fn profile(&self) -> Profile {
+        self.layout.profile()
+    }
+
+    /// Models stuck-at faults on control signals: from the next cycle on,
+    /// the bits set in `zero` read as 0 and those set in `one` as 1 in every
+    /// word loaded into the MIR. The control store itself is unchanged, and
+    /// the bits stay stuck across `reset`.
+    pub fn set_stuck_bits(&mut self, zero: u32, one: u32) {
+        self.stuck_zero = zero;
+        self.stuck_one = one;
+    }
+
+    /// The word the MIR receives from control store address `addr`.
+    fn fetch(&self, addr: u8) -> Option<u32> {
+        self.program.get(addr as usize).map(|&raw| (raw & !self.stuck_zero) | self.stuck_one)
+    }
+
     pub fn done(&self) -> bool {
-        self.program_counter >= self.program.len() as u8
+        self.phase == Phase::LoadMir && self.program_counter as usize >= self.program.len()
     }
 
-    pub fn step(&mut self) {
-        self.negative_flag = false;
-        self.zero_flag = false;
+    pub fn registers(&self) -> &RegisterSet {
+        &self.registers
+    }
 
-        let next_instruction = self.program[self.program_counter as usize];
-        if next_instruction == 0 {
-            return;
-        }
-        let instr = Instruction::new(next_instruction);
-        println!("{:?}", instr);
-        let a_bus = if instr.a_mux() {
-            MAR_REGISTER_IDX
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn program_counter(&self) -> u8 {
+        self.program_counter
+    }
+
+    pub fn negative_flag(&self) -> bool {
+        self.negative_flag
+    }
+
+    pub fn zero_flag(&self) -> bool {
+        self.zero_flag
+    }
+
+    pub fn carry_flag(&self) -> bool {
+        self.carry_flag
+    }
+
+    pub fn overflow_flag(&self) -> bool {
+        self.overflow_flag
+    }
+
+    /// The phase that the next call to `step_phase` will execute.
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Bus and latch values as of the last executed phase.
+    pub fn datapath(&self) -> &Datapath {
+        &self.datapath
+    }
+
+    /// The instruction that the next call to `step` will execute, if any.
+    pub fn current_instruction(&self) -> Option<Instruction> {
+        let raw = if self.phase == Phase::LoadMir {
+            self.fetch(self.program_counter)?
         } else {
-            instr.a_bus()
+            self.datapath.mir
         };
-        let b_bus = instr.b_bus();
-        let s_bus = instr.s_bus();
+        Some(Instruction::with_layout(raw, self.layout.clone()))
+    }
+
+    pub fn control_store(&self) -> &[u32] {
+        &self.program
+    }
 
-        if instr.ens() && s_bus < 3 {
-            panic!("Can't write to read-only registers!");
+    /// Replaces the whole control store. Registers, memory and the program
+    /// counter are left alone; see `reset`.
+    pub fn load_program(&mut self, prog: &[u32]) -> Result<(), CpuError> {
+        if prog.len() > PROGRAM_LENGTH {
+            return Err(CpuError::ProgramTooLong { len: prog.len() });
         }
+        self.program = prog.to_vec();
+        Ok(())
+    }
 
-        if instr.mar() {
-            let val = self.registers.get(b_bus);
-            self.registers.set(MAR_REGISTER_IDX, val);
+    /// Overwrites one control store word. Patching past the end of the
+    /// program extends it, filling the gap with words that do nothing.
+    ///
+    /// The instruction currently in the MIR is not affected.
+    pub fn patch(&mut self, addr: u8, word: u32) {
+        let idx = addr as usize;
+        if idx >= self.program.len() {
+            self.program.resize(idx + 1, 0);
         }
+        self.program[idx] = word;
+    }
+
+    /// Maps the control store into memory from `base` on, or unmaps it for
+    /// `None`. Word `i` appears as two cells, its low half at `base + 2 * i`
+    /// and its high half at `base + 2 * i + 1`. Completed memory writes into
+    /// the window still update memory and also patch that half of the word.
+    pub fn map_control_store(&mut self, base: Option<u16>) {
+        self.control_store_window = base;
+    }
+
+    /// Returns to the initial state: registers, flags, program counter,
+    /// return stack, clock phase and the memory handshake are cleared.
+    /// Memory is cleared as well unless `keep_memory` is set; the control
+    /// store and its mapping are kept.
+    pub fn reset(&mut self, keep_memory: bool) {
+        self.registers = RegisterSet::new();
+        self.call_stack.clear();
+        self.memory.reset(keep_memory);
+        self.program_counter = 0;
+        self.negative_flag = false;
+        self.zero_flag = false;
+        self.carry_flag = false;
+        self.overflow_flag = false;
+        self.phase = Phase::LoadMir;
+        self.datapath = Datapath::default();
+    }
+
+    pub fn registers_mut(&mut self) -> &mut RegisterSet {
+        &mut self.registers
+    }
+
+    pub fn memory_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
+    /// Moves execution to `addr`. Only takes effect between cycles; the
+    /// instruction of a cycle in flight continues to completion.
+    pub fn set_program_counter(&mut self, addr: u8) {
+        self.program_counter = addr;
+    }
+
+    pub fn set_flags(&mut self, negative: bool, zero: bool, carry: bool, overflow: bool) {
+        self.negative_flag = negative;
+        self.zero_flag = zero;
+        self.carry_flag = carry;
+        self.overflow_flag = overflow;
+    }
+
+    /// Executes the remainder of the current cycle.
+    pub fn step(&mut self) -> Result<(), CpuError> {
+        while self.step_phase()? != Phase::WriteBack {}
+        Ok(())
+    }
+
+    /// Executes a single clock phase and returns the phase that was executed.
+    ///
+    /// Malformed instructions are rejected when they are loaded into the MIR,
+    /// before any state has been modified.
+    pub fn step_phase(&mut self) -> Result<Phase, CpuError> {
+        let phase = self.phase;
+        match phase {
+            Phase::LoadMir => {
+                let addr = self.program_counter;
+                let mir = self.fetch(addr).ok_or(CpuError::Finished { addr })?;
+                let instr = Instruction::with_layout(mir, self.layout.clone());
+                if instr.ens() && instr.s_bus() < MAR {
+                    return Err(CpuError::ReadOnlyRegister {
+                        addr,
+                        register: instr.s_bus(),
+                    });
+                }
+                if instr.call() && self.call_stack_depth.is_none() {
+                    return Err(CpuError::InvalidShifterMode { addr });
+                }
+                match instr.invalid_field() {
+                    Some("sh") => return Err(CpuError::InvalidShifterMode { addr }),
+                    Some(field) => return Err(CpuError::InvalidEncoding { addr, field }),
+                    None => (),
+                }
+                if instr.call() {
+                    self.check_call(addr, &instr)?;
+                }
+                self.datapath = Datapath { mir, ..Datapath::default() };
+            }
+            Phase::LatchAB => {
+                let instr = Instruction::with_layout(self.datapath.mir, self.layout.clone());
+                self.datapath.a_bus = self.registers.get(instr.a_bus());
+                self.datapath.b_bus = self.registers.get(instr.b_bus());
+                self.datapath.a_latch = self.datapath.a_bus;
+                self.datapath.b_latch = self.datapath.b_bus;
+            }
+            Phase::Alu => {
+                let instr = Instruction::with_layout(self.datapath.mir, self.layout.clone());
+                let a = if instr.a_mux() {
+                    self.registers.get(MAR)
+                } else {
+                    self.datapath.a_latch
+                };
+                let (alu_result, carry, overflow) =
+                    self.alu_op(instr.alu()?, a, self.datapath.b_latch);
 
-        let alu_result = self.alu_op(instr.alu(), a_bus, b_bus);
+                self.negative_flag = alu_result < 0;
+                self.zero_flag = alu_result == 0;
+                self.carry_flag = carry;
+                self.overflow_flag = overflow;
 
-        self.negative_flag = alu_result < 0;
-        self.zero_flag = alu_result == 0;
+                self.datapath.alu_out = alu_result;
+                self.datapath.shifter_out = self.shifter_op(instr.sh()?, alu_result);
 
-        let shifter_result = self.shifter_op(instr.sh(), alu_result);
-        self.cond_op(instr.cond(), instr.addr());
+                if instr.mar() {
+                    self.registers.set(MAR, self.datapath.b_latch);
+                }
+            }
+            Phase::WriteBack => self.write_back()?,
+        }
+        self.phase = phase.next();
+        Ok(phase)
+    }
 
+    fn write_back(&mut self) -> Result<(), CpuError> {
+        let instr = Instruction::with_layout(self.datapath.mir, self.layout.clone());
+        let s_bus = instr.s_bus();
+
+        self.datapath.s_bus = self.datapath.shifter_out;
         if instr.ens() {
-            self.registers.set(s_bus, shifter_result);
+            self.registers.set(s_bus, self.datapath.s_bus);
+        }
+        if instr.mbr() {
+            self.registers.set(MBR, self.datapath.s_bus);
         }
         if instr.ms() {
-            let mar = self.registers.mar;
+            let mar = self.registers.mar as u16;
             let mbr = self.registers.mbr;
 
             if instr.rd_wr() {
-                match self.memory.read(mar as usize) {
-                    Some(val) => self.registers.set(MBR_REGISTER_IDX, val), 
-                    None => (),
+                if let Some(val) = self.memory.read(mar as usize) {
+                    self.registers.set(MBR, val);
                 }
-            } else {
-                let wrote = self.memory.write(mar as usize, mbr);
-                println!("wrote data to memory: {}", wrote);
+            } else if self.memory.write(mar as usize, mbr) {
+                self.write_control_store(mar, mbr);
             }
         }
 
-        self.program_counter += 1;
+        let addr = self.program_counter;
+        self.program_counter = self.program_counter.wrapping_add(1);
+        if !instr.call() {
+            self.cond_op(instr.cond()?, instr.addr());
+        } else if instr.cond()? == CondMode::GoTo {
+            self.call_stack.push(self.program_counter);
+            self.program_counter = instr.addr();
+        } else {
+            self.program_counter =
+                self.call_stack.pop().ok_or(CpuError::CallStackUnderflow { addr })?;
+        }
+        Ok(())
+    }
+
+    /// Rejects a call or return that the return stack cannot take.
+    fn check_call(&self, addr: u8, instr: &Instruction) -> Result<(), CpuError> {
+        let depth = self.call_stack_depth.unwrap_or(0);
+        match instr.cond()? {
+            CondMode::GoTo if self.call_stack.len() >= depth => {
+                Err(CpuError::CallStackOverflow { addr, depth })
+            }
+            CondMode::NoOp if self.call_stack.is_empty() => {
+                Err(CpuError::CallStackUnderflow { addr })
+            }
+            CondMode::GoTo | CondMode::NoOp => Ok(()),
+            _ => Err(CpuError::ConditionalCall { addr }),
+        }
+    }
+
+    fn write_control_store(&mut self, cell: u16, value: i16) {
+        let offset = match self.control_store_window {
+            Some(base) => cell.wrapping_sub(base) as usize,
+            None => return,
+        };
+        if offset >= CONTROL_STORE_WINDOW {
+            return;
+        }
+        let addr = (offset / 2) as u8;
+        let old = self.program.get(addr as usize).cloned().unwrap_or(0);
+        let word = if offset % 2 == 0 {
+            (old & 0xffff_0000) | value as u16 as u32
+        } else {
+            (old & 0xffff) | ((value as u16 as u32) << 16)
+        };
+        self.patch(addr, word);
     }
 
-    fn alu_op(&self, alu_mode: AluMode, a_bus: u8, b_bus: u8) -> i16 {
+    /// The ALU result with its carry and overflow flags.
+    fn alu_op(&self, alu_mode: AluMode, a: i16, b: i16) -> (i16, bool, bool) {
         match alu_mode {
-            AluMode::NoOp => self.registers.get(a_bus),
-            AluMode::Add => self.registers.get(a_bus) + self.registers.get(b_bus),
-            AluMode::BitAnd => self.registers.get(a_bus) & self.registers.get(b_bus),
-            AluMode::BitNot => !self.registers.get(a_bus),
+            AluMode::NoOp => (a, false, false),
+            AluMode::Add => {
+                let (result, overflow) = a.overflowing_add(b);
+                (result, (a as u16).overflowing_add(b as u16).1, overflow)
+            }
+            AluMode::BitAnd => (a & b, false, false),
+            AluMode::BitNot => (!a, false, false),
+            AluMode::Sub => {
+                let (result, overflow) = a.overflowing_sub(b);
+                (result, (a as u16) < (b as u16), overflow)
+            }
+            AluMode::BitOr => (a | b, false, false),
+            AluMode::BitXor => (a ^ b, false, false),
+            AluMode::Inc => {
+                let (result, overflow) = a.overflowing_add(1);
+                (result, a == -1, overflow)
+            }
         }
     }
 
@@ -149,6 +684,7 @@ impl<'a> Cpu<'a> {
             ShifterMode::NoOp => alu_result,
             ShifterMode::Left => alu_result << 1,
             ShifterMode::Right => alu_result >> 1,
+            ShifterMode::Rotate => (alu_result as u16).rotate_left(1) as i16,
         }
     }
 
@@ -166,21 +702,36 @@ impl<'a> Cpu<'a> {
                 }
             }
             CondMode::GoTo => self.program_counter = addr,
+            CondMode::IfCarry => {
+                if self.carry_flag {
+                    self.program_counter = addr;
+                }
+            }
+            CondMode::IfOverflow => {
+                if self.overflow_flag {
+                    self.program_counter = addr;
+                }
+            }
+            CondMode::IfLess => {
+                if self.negative_flag != self.overflow_flag {
+                    self.program_counter = addr;
+                }
+            }
         }
     }
 }
 
-impl<'a> fmt::Debug for Cpu<'a> {
+impl<M: MemoryPort + fmt::Debug> fmt::Debug for Cpu<M> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "CPU {{\n").unwrap();
-        write!(f, "{:#?},\n", self.registers).unwrap();
-        write!(f, "\t{:?},\n", self.memory).unwrap();
-        write!(f, "\tprogram_counter: {}\n", self.program_counter).unwrap();
+        writeln!(f, "CPU {{").unwrap();
+        writeln!(f, "{:#?},", self.registers).unwrap();
+        writeln!(f, "\t{:?},", self.memory).unwrap();
+        writeln!(f, "\tprogram_counter: {}", self.program_counter).unwrap();
         write!(f, "}}")
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RegisterSet {
     zero: i16,
     one: i16,
@@ -224,9 +775,9 @@ impl RegisterSet {
 
     pub fn get(&self, index: u8) -> i16 {
         match index {
-            0 => 0,
-            1 => 1,
-            2 => -1,
+            0 => self.zero,
+            1 => self.one,
+            2 => self.minus_one,
             3 => self.mar,
             4 => self.r0,
             5 => self.r1,
@@ -267,15 +818,28 @@ impl RegisterSet {
     }
 }
 
+impl Default for RegisterSet {
+    fn default() -> RegisterSet {
+        RegisterSet::new()
+    }
+}
+
+type Page = [i16; PAGE_SIZE];
+
+/// The 64K cells of main memory, kept in heap pages that are allocated on
+/// the first non-zero write. Clones share their pages and copy a page only
+/// when writing to it, so cloning costs one reference per page.
+#[derive(Clone)]
 pub struct Memory {
-    data: [i16; MEMORY_SIZE],
+    /// `None` for pages that were never written, which read as zero.
+    pages: Vec<Option<Arc<Page>>>,
     ready: bool,
 }
 
 impl Memory {
     pub fn new() -> Memory {
         Memory {
-            data: [0i16; MEMORY_SIZE],
+            pages: vec![None; MEMORY_SIZE / PAGE_SIZE],
             ready: false,
         }
     }
@@ -286,7 +850,7 @@ impl Memory {
             None
         } else {
             self.ready = false;
-            let result = self.data[idx];
+            let result = self.get(idx);
             Some(result)
         }
     }
@@ -297,22 +861,249 @@ impl Memory {
             false
         } else {
             self.ready = false;
-            self.data[idx] = value;
-            println!("Data at {}: {}", idx, value);
+            self.set(idx, value);
             true
         }
     }
+
+    /// Reads a cell directly, bypassing the ready handshake.
+    pub fn get(&self, idx: usize) -> i16 {
+        match self.pages[idx / PAGE_SIZE] {
+            Some(ref page) => page[idx % PAGE_SIZE],
+            None => 0,
+        }
+    }
+
+    /// Writes a cell directly, bypassing the ready handshake.
+    pub fn set(&mut self, idx: usize, value: i16) {
+        let page = &mut self.pages[idx / PAGE_SIZE];
+        if page.is_none() && value == 0 {
+            return;
+        }
+        let page = page.get_or_insert_with(|| Arc::new([0; PAGE_SIZE]));
+        Arc::make_mut(page)[idx % PAGE_SIZE] = value;
+    }
+
+    pub fn ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Zeroes the cells in `range`, e.g. to reset part of memory between
+    /// runs.
+    pub fn clear(&mut self, range: Range<usize>) {
+        let mut idx = range.start;
+        while idx < range.end {
+            let page = idx / PAGE_SIZE;
+            let end = range.end.min((page + 1) * PAGE_SIZE);
+            if end - idx == PAGE_SIZE {
+                self.pages[page] = None;
+            } else {
+                for cell in idx..end {
+                    self.set(cell, 0);
+                }
+            }
+            idx = end;
+        }
+    }
+}
+
+impl MemoryPort for Memory {
+    fn read(&mut self, idx: usize) -> Option<i16> {
+        Memory::read(self, idx)
+    }
+
+    fn write(&mut self, idx: usize, value: i16) -> bool {
+        Memory::write(self, idx, value)
+    }
+
+    fn get(&self, idx: usize) -> i16 {
+        Memory::get(self, idx)
+    }
+
+    fn set(&mut self, idx: usize, value: i16) {
+        Memory::set(self, idx, value)
+    }
+
+    fn reset(&mut self, keep_contents: bool) {
+        if keep_contents {
+            self.ready = false;
+        } else {
+            *self = Memory::new();
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Memory {
+        Memory::new()
+    }
 }
 
 impl fmt::Debug for Memory {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Memory {{").unwrap();
-        for (i, &val) in self.data.iter().enumerate() {
+        for i in 0..MEMORY_SIZE {
+            let val = self.get(i);
             if val != 0 {
-                write!(f, "\t{}: {}\n", i, val).unwrap();
+                writeln!(f, "\t{}: {}", i, val).unwrap();
             }
         }
         write!(f, "}}").unwrap();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(cpu: &mut Cpu) {
+        for _ in 0..100 {
+            if cpu.done() {
+                return;
+            }
+            cpu.step().unwrap();
+        }
+        panic!("program did not finish within 100 cycles");
+    }
+
+    #[test]
+    fn mbr_latches_shifter_output() {
+        // MBR <- lsh(1 + 1)
+        let program = [0x0b001100];
+        let mut cpu = Cpu::new(&program);
+        run(&mut cpu);
+        assert_eq!(cpu.registers().get(MBR), 4);
+    }
+
+    #[test]
+    fn jump_lands_on_target() {
+        // goto 2; R0 <- 1; R1 <- 1
+        let program = [0x60000002, 0x00140100, 0x00150100];
+        let mut cpu = Cpu::new(&program);
+        run(&mut cpu);
+        assert_eq!(cpu.registers().get(4), 0);
+        assert_eq!(cpu.registers().get(5), 1);
+    }
+
+    #[test]
+    fn addition_wraps() {
+        // R0 <- R0 + 1
+        let program = [0x08141400];
+        let mut cpu = Cpu::new(&program);
+        cpu.registers.r0 = i16::MAX;
+        run(&mut cpu);
+        assert_eq!(cpu.registers().get(4), i16::MIN);
+    }
+
+    #[test]
+    fn zero_word_is_a_no_op() {
+        // (empty); R0 <- 1
+        let program = [0, 0x00140100];
+        let mut cpu = Cpu::new(&program);
+        run(&mut cpu);
+        assert_eq!(cpu.registers().get(4), 1);
+    }
+
+    #[test]
+    fn negative_mar_addresses_top_of_memory() {
+        // MBR <- 1 + 1; MAR <- -1; wr; wr; MBR <- 0; rd; rd
+        let program = [0x09001100, 0x00802000, 0x00200000, 0x00200000, 0x01000000, 0x00600000,
+                       0x00600000];
+        let mut cpu = Cpu::new(&program);
+        run(&mut cpu);
+        assert_eq!(cpu.memory().get(0xffff), 2);
+        assert_eq!(cpu.registers().get(MBR), 2);
+    }
+
+    #[test]
+    fn reserved_shifter_mode_is_rejected() {
+        let program = [0x06000000];
+        let mut cpu = Cpu::new(&program);
+        assert_eq!(cpu.step(), Err(CpuError::InvalidShifterMode { addr: 0 }));
+    }
+
+    #[test]
+    fn undefined_codes_are_errors() {
+        assert_eq!(AluMode::from_u8(8), Err(CpuError::UndefinedCode { field: "alu", code: 8 }));
+        assert_eq!(ShifterMode::from_u8(3), Err(CpuError::UndefinedCode { field: "sh", code: 3 }));
+        assert_eq!(CondMode::from_u8(7), Err(CpuError::UndefinedCode { field: "cond", code: 7 }));
+        assert_eq!(ShifterMode::from_u8(4), Ok(ShifterMode::Rotate));
+
+        // micro16x leaves cond code 7 undefined.
+        let instr = Instruction::with_layout(7 << 29, Layout::micro16x());
+        assert_eq!(instr.cond(), Err(CpuError::UndefinedCode { field: "cond", code: 7 }));
+        assert_eq!(instr.alu(), Ok(AluMode::NoOp));
+    }
+
+    #[test]
+    fn stepping_a_finished_program_is_an_error() {
+        // R0 <- 1
+        let program = [0x00140100];
+        let mut cpu = Cpu::new(&program);
+        run(&mut cpu);
+        assert_eq!(cpu.step(), Err(CpuError::Finished { addr: 1 }));
+        assert_eq!(cpu.step_phase(), Err(CpuError::Finished { addr: 1 }));
+        assert_eq!(cpu.registers().get(4), 1);
+    }
+
+    fn shares_page(a: &Memory, b: &Memory, page: usize) -> bool {
+        match (&a.pages[page], &b.pages[page]) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn memory_pages_are_allocated_on_demand() {
+        let mut memory = Memory::new();
+        memory.set(300, 0);
+        assert!(memory.pages.iter().all(|page| page.is_none()));
+        memory.set(300, 7);
+        assert_eq!(memory.pages.iter().filter(|page| page.is_some()).count(), 1);
+        assert_eq!(memory.get(300), 7);
+        assert_eq!(memory.get(301), 0);
+    }
+
+    #[test]
+    fn clones_share_pages_until_written() {
+        let mut memory = Memory::new();
+        memory.set(10, 1);
+        memory.set(PAGE_SIZE + 10, 2);
+        let mut copy = memory.clone();
+        assert!(shares_page(&memory, &copy, 0) && shares_page(&memory, &copy, 1));
+
+        copy.set(11, 3);
+        assert!(!shares_page(&memory, &copy, 0) && shares_page(&memory, &copy, 1));
+        assert_eq!((memory.get(11), copy.get(11)), (0, 3));
+        assert_eq!(copy.get(10), 1);
+
+        // The original copies on write just the same.
+        memory.set(PAGE_SIZE + 10, 4);
+        assert!(!shares_page(&memory, &copy, 1));
+        assert_eq!((memory.get(PAGE_SIZE + 10), copy.get(PAGE_SIZE + 10)), (4, 2));
+    }
+
+    #[test]
+    fn clearing_leaves_clones_unchanged() {
+        let mut memory = Memory::new();
+        for idx in 0..3 * PAGE_SIZE {
+            memory.set(idx, idx as i16 + 1);
+        }
+        let copy = memory.clone();
+        // Drops page 1 and zeroes the ends of pages 0 and 2.
+        memory.clear(PAGE_SIZE - 2..2 * PAGE_SIZE + 2);
+        assert!(memory.pages[1].is_none());
+        for idx in 0..3 * PAGE_SIZE {
+            let expected = if (PAGE_SIZE - 2..2 * PAGE_SIZE + 2).contains(&idx) {
+                0
+            } else {
+                idx as i16 + 1
+            };
+            assert_eq!(memory.get(idx), expected);
+        }
+        for idx in 0..3 * PAGE_SIZE {
+            assert_eq!(copy.get(idx), idx as i16 + 1);
+        }
+    }
+}
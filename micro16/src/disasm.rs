@@ -0,0 +1,95 @@
+//! Disassembly of microinstructions into Micro16 assembler syntax, e.g.
+//! `R0 <- lsh(R1 + R2); MAR <- R3; rd; if Z goto 5`. Words using the
+//! optional call code disassemble to `call 5` or `return`, the modes of the
+//! extended profile to `R0 <- rol(R1 - R2); if LT goto 5` and the like.
+//! Words with codes their format leaves undefined disassemble to a note
+//! that says so.
+
+use cpu::{AluMode, CondMode, CpuError, ShifterMode};
+use instruction::Instruction;
+
+pub const NAMES: [&str; 16] = ["0", "1", "-1", "MAR", "R0", "R1", "R2", "R3", "R4", "R5", "R6",
+                               "R7", "R8", "R9", "R10", "MBR"];
+
+/// Name of the register with the given bus index.
+pub fn register_name(idx: u8) -> &'static str {
+    NAMES[idx as usize & 0xf]
+}
+
+/// Bus index of the register with the given name, ignoring case.
+pub fn register_index(name: &str) -> Option<u8> {
+    NAMES.iter().position(|n| n.eq_ignore_ascii_case(name)).map(|i| i as u8)
+}
+
+/// The ALU and shifter expression computed by an instruction.
+pub fn expression(instr: &Instruction) -> Result<String, CpuError> {
+    let a = if instr.a_mux() {
+        "MAR"
+    } else {
+        register_name(instr.a_bus())
+    };
+    let b = register_name(instr.b_bus());
+    let alu = match instr.alu()? {
+        AluMode::NoOp => a.to_string(),
+        AluMode::Add => format!("{} + {}", a, b),
+        AluMode::BitAnd => format!("{} & {}", a, b),
+        AluMode::BitNot => format!("~{}", a),
+        AluMode::Sub => format!("{} - {}", a, b),
+        AluMode::BitOr => format!("{} | {}", a, b),
+        AluMode::BitXor => format!("{} ^ {}", a, b),
+        AluMode::Inc => format!("inc({})", a),
+    };
+    Ok(match instr.sh()? {
+        ShifterMode::NoOp => alu,
+        ShifterMode::Left => format!("lsh({})", alu),
+        ShifterMode::Right => format!("rsh({})", alu),
+        ShifterMode::Rotate => format!("rol({})", alu),
+    })
+}
+
+pub fn disassemble(instr: &Instruction) -> String {
+    match statements(instr) {
+        Ok(text) => text,
+        Err(e) => format!("invalid word: {}", e),
+    }
+}
+
+fn statements(instr: &Instruction) -> Result<String, CpuError> {
+    let mut parts = Vec::new();
+    let expr = expression(instr)?;
+    let cond = instr.cond()?;
+
+    if instr.ens() {
+        parts.push(format!("{} <- {}", register_name(instr.s_bus()), expr));
+    }
+    if instr.mbr() {
+        parts.push(format!("MBR <- {}", expr));
+    }
+    let tests_flags = !matches!(cond, CondMode::NoOp | CondMode::GoTo);
+    if !instr.ens() && !instr.mbr() && tests_flags {
+        parts.push(format!("({})", expr));
+    }
+    if instr.mar() {
+        parts.push(format!("MAR <- {}", register_name(instr.b_bus())));
+    }
+    if instr.ms() {
+        parts.push(if instr.rd_wr() { "rd" } else { "wr" }.to_string());
+    }
+    let jump = if instr.call() { "call" } else { "goto" };
+    match cond {
+        CondMode::NoOp if instr.call() => parts.push("return".to_string()),
+        CondMode::NoOp => (),
+        CondMode::IfNegative => parts.push(format!("if N {} {}", jump, instr.addr())),
+        CondMode::IfZero => parts.push(format!("if Z {} {}", jump, instr.addr())),
+        CondMode::GoTo => parts.push(format!("{} {}", jump, instr.addr())),
+        CondMode::IfCarry => parts.push(format!("if C {} {}", jump, instr.addr())),
+        CondMode::IfOverflow => parts.push(format!("if V {} {}", jump, instr.addr())),
+        CondMode::IfLess => parts.push(format!("if LT {} {}", jump, instr.addr())),
+    }
+
+    if parts.is_empty() {
+        Ok("nop".to_string())
+    } else {
+        Ok(parts.join("; "))
+    }
+}
@@ -0,0 +1,421 @@
+//! Bounded equivalence checking of two microprograms.
+//!
+//! Both programs run on `Cpu` from the same inputs, each input location
+//! taking values from its own domain:
+//!
+//! ```text
+//! micro16 equiv old.m16 new.m16 --input R0=0..15 --input R1=-1,0,1 --input mem[10]=*
+//! ```
+//!
+//! If the domains hold at most `--limit` combinations all of them are
+//! tried, which proves the programs equal on those inputs within the cycle
+//! bound. Otherwise `--limit` combinations are sampled at random. Locations
+//! that are not inputs start out as zero.
+//!
+//! The final states are compared on the `--observe` locations, or by default
+//! on MAR, R0 to R10, MBR, the input cells and every memory cell either
+//! program accessed. An input on which the programs fail with different
+//! kinds of error, or only one of them fails, is a counterexample too. An
+//! input on which either program runs into the cycle bound decides nothing,
+//! and the verdict is inconclusive unless another input is a
+//! counterexample.
+
+use std::fmt;
+use std::mem;
+
+use cli;
+use cpu::{Cpu, CpuError, MAR, MBR};
+use loader;
+use rng::Rng;
+use state::{self, Assignment, Location};
+
+const DEFAULT_MAX_CYCLES: u64 = 10_000;
+const DEFAULT_LIMIT: u64 = 65_536;
+
+pub const USAGE: &str = "Usage: micro16 equiv A B [--input LOC=VALUES]... [--observe LOC,...] \
+                         [--cycles N] [--limit N] [--seed N]
+VALUES is a value, a list like 1,2,5, a range like -4..4 or * for all values";
+
+/// The values an input location may take.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Domain {
+    pub location: Location,
+    pub values: Vec<i16>,
+}
+
+impl Domain {
+    /// Parses `LOC=VALUES` as described in `USAGE`.
+    pub fn parse(text: &str) -> Result<Domain, String> {
+        let mut parts = text.splitn(2, '=');
+        let location = state::parse_location(parts.next().unwrap())?;
+        let values = parts.next().ok_or_else(|| format!("expected '=' in '{}'", text))?;
+        let values = if values == "*" {
+            (i16::MIN..=i16::MAX).collect()
+        } else if let Some((lo, hi)) = values.split_once("..") {
+            let (lo, hi) = (state::parse_value(lo)?, state::parse_value(hi)?);
+            if lo > hi {
+                return Err(format!("empty range '{}'", values));
+            }
+            (lo..=hi).collect()
+        } else {
+            values.split(',').map(state::parse_value).collect::<Result<_, _>>()?
+        };
+        Ok(Domain { location, values })
+    }
+}
+
+/// Every register the programs can change.
+pub fn all_registers() -> Vec<Location> {
+    (MAR..MBR + 1).map(Location::Register).collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub inputs: Vec<Domain>,
+    /// Locations to compare; `None` compares the default set.
+    pub observe: Option<Vec<Location>>,
+    pub max_cycles: u64,
+    pub limit: u64,
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            inputs: Vec::new(),
+            observe: None,
+            max_cycles: DEFAULT_MAX_CYCLES,
+            limit: DEFAULT_LIMIT,
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counterexample {
+    pub input: Vec<Assignment>,
+    pub differences: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// No difference on any of the inputs tried; `exhaustive` means every
+    /// combination was tried.
+    Equivalent { inputs: u64, exhaustive: bool },
+    /// No difference either, but on `unfinished` of the inputs a program
+    /// ran into the cycle bound, `example` being the first of them.
+    Inconclusive {
+        inputs: u64,
+        exhaustive: bool,
+        unfinished: u64,
+        example: Vec<Assignment>,
+    },
+    Counterexample(Counterexample),
+}
+
+fn describe_input(input: &[Assignment]) -> String {
+    let input: Vec<String> = input.iter().map(|a| a.to_string()).collect();
+    input.join(" ")
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Verdict::Equivalent { inputs, exhaustive: true } => {
+                write!(f, "equivalent on all {} inputs", inputs)
+            }
+            Verdict::Equivalent { inputs, exhaustive: false } => {
+                write!(f, "no difference on {} random inputs", inputs)
+            }
+            Verdict::Inconclusive { inputs, unfinished, ref example, .. } => {
+                write!(f,
+                       "inconclusive: no difference on {} inputs, but on {} of them a program \
+                        hit the cycle bound, e.g. {}",
+                       inputs,
+                       unfinished,
+                       describe_input(example))
+            }
+            Verdict::Counterexample(ref c) => {
+                write!(f, "counterexample: {}", describe_input(&c.input))?;
+                for d in &c.differences {
+                    write!(f, "\n  {}", d)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+enum Run {
+    /// The final state and the memory cells that were accessed.
+    Finished(Cpu, Vec<u16>),
+    Failed(CpuError),
+    Unfinished,
+}
+
+impl Run {
+    fn describe(&self) -> String {
+        match *self {
+            Run::Finished(..) => "finishes".to_string(),
+            Run::Failed(ref e) => format!("fails: {}", e),
+            Run::Unfinished => "does not finish".to_string(),
+        }
+    }
+}
+
+fn run(program: &[u32], input: &[Assignment], max_cycles: u64) -> Run {
+    let mut cpu = Cpu::new(program);
+    state::apply(&mut cpu, input);
+    let mut accessed = Vec::new();
+    let mut cycles = 0;
+    while !cpu.done() {
+        if cycles == max_cycles {
+            return Run::Unfinished;
+        }
+        let access = cpu.current_instruction().is_some_and(|i| i.ms());
+        if let Err(e) = cpu.step() {
+            return Run::Failed(e);
+        }
+        if access {
+            // The access uses MAR as left behind by the word.
+            accessed.push(cpu.registers().get(MAR) as u16);
+        }
+        cycles += 1;
+    }
+    Run::Finished(cpu, accessed)
+}
+
+/// Runs both programs on one input, returning the differences or whether
+/// either ran into the cycle bound.
+fn compare(a: &[u32],
+           b: &[u32],
+           input: &[Assignment],
+           config: &Config)
+           -> Result<bool, Vec<String>> {
+    let (run_a, run_b) = (run(a, input, config.max_cycles), run(b, input, config.max_cycles));
+    let (cpu_a, cpu_b, locations) = match (run_a, run_b) {
+        (Run::Finished(cpu_a, accessed_a), Run::Finished(cpu_b, accessed_b)) => {
+            let locations = match config.observe {
+                Some(ref observe) => observe.clone(),
+                None => {
+                    let mut cells: Vec<u16> = accessed_a.into_iter().chain(accessed_b).collect();
+                    cells.extend(input.iter().filter_map(|a| match a.location {
+                        Location::Memory(addr) => Some(addr),
+                        Location::Register(_) => None,
+                    }));
+                    cells.sort();
+                    cells.dedup();
+                    let mut locations = all_registers();
+                    locations.extend(cells.into_iter().map(Location::Memory));
+                    locations
+                }
+            };
+            (cpu_a, cpu_b, locations)
+        }
+        // Messages name addresses, which rewrites move.
+        (Run::Failed(ref e1), Run::Failed(ref e2)) if mem::discriminant(e1) ==
+                                                       mem::discriminant(e2) => return Ok(false),
+        (Run::Unfinished, _) | (_, Run::Unfinished) => return Ok(true),
+        (run_a, run_b) => {
+            return Err(vec![format!("A {}, B {}", run_a.describe(), run_b.describe())])
+        }
+    };
+
+    let differences: Vec<String> = locations.iter()
+        .filter_map(|&location| {
+            let (va, vb) = (state::read(&cpu_a, location), state::read(&cpu_b, location));
+            if va == vb {
+                None
+            } else {
+                Some(format!("{}: A {}, B {}", location, va, vb))
+            }
+        })
+        .collect();
+    if differences.is_empty() {
+        Ok(false)
+    } else {
+        Err(differences)
+    }
+}
+
+/// Checks two programs for equal final states on the configured inputs.
+pub fn check(a: &[u32], b: &[u32], config: &Config) -> Verdict {
+    let combinations = config.inputs
+        .iter()
+        .try_fold(1u64, |n, d| n.checked_mul(d.values.len() as u64));
+    let exhaustive = combinations.is_some_and(|n| n <= config.limit);
+    let inputs = if exhaustive {
+        combinations.unwrap()
+    } else {
+        config.limit
+    };
+
+    let mut rng = Rng::new(config.seed);
+    let mut digits = vec![0; config.inputs.len()];
+    let mut unfinished = 0;
+    let mut example = None;
+    for _ in 0..inputs {
+        if !exhaustive {
+            for (digit, domain) in digits.iter_mut().zip(&config.inputs) {
+                *digit = rng.below(domain.values.len() as u64) as usize;
+            }
+        }
+        let input: Vec<Assignment> = digits.iter()
+            .zip(&config.inputs)
+            .map(|(&i, d)| {
+                Assignment {
+                    location: d.location,
+                    value: d.values[i],
+                }
+            })
+            .collect();
+
+        match compare(a, b, &input, config) {
+            Ok(true) => {
+                unfinished += 1;
+                example.get_or_insert(input);
+            }
+            Ok(false) => (),
+            Err(differences) => {
+                return Verdict::Counterexample(Counterexample { input, differences })
+            }
+        }
+
+        if exhaustive {
+            // Count through the domains like an odometer.
+            for (digit, domain) in digits.iter_mut().zip(&config.inputs) {
+                *digit += 1;
+                if *digit < domain.values.len() {
+                    break;
+                }
+                *digit = 0;
+            }
+        }
+    }
+    match example {
+        Some(example) => {
+            Verdict::Inconclusive {
+                inputs,
+                exhaustive,
+                unfinished,
+                example,
+            }
+        }
+        None => Verdict::Equivalent { inputs, exhaustive },
+    }
+}
+
+/// Entry point of `micro16 equiv`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    let mut positional = Vec::new();
+    let mut config = Config::default();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--input" => config.inputs.push(Domain::parse(iter.next().ok_or(USAGE)?)?),
+            "--observe" => {
+                let list = iter.next().ok_or(USAGE)?;
+                let locations = list.split(',').map(state::parse_location);
+                config.observe = Some(locations.collect::<Result<_, _>>()?);
+            }
+            "--cycles" => config.max_cycles = cli::parse_arg(iter.next(), USAGE)?,
+            "--limit" => config.limit = cli::parse_arg(iter.next(), USAGE)?,
+            "--seed" => config.seed = cli::parse_arg(iter.next(), USAGE)?,
+            _ if !arg.starts_with('-') => positional.push(arg.clone()),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    if positional.len() != 2 {
+        return Err(USAGE.to_string());
+    }
+
+    let a = loader::read_program(&positional[0])?;
+    let b = loader::read_program(&positional[1])?;
+    let verdict = check(&a, &b, &config);
+    println!("{}", verdict);
+    match verdict {
+        Verdict::Equivalent { .. } => Ok(()),
+        Verdict::Inconclusive { .. } => Err("raise --cycles to decide".to_string()),
+        Verdict::Counterexample(_) => Err("programs differ".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+
+    fn check_sources(a: &str, b: &str, inputs: &[&str]) -> Verdict {
+        let config = Config {
+            inputs: inputs.iter().map(|d| Domain::parse(d).unwrap()).collect(),
+            max_cycles: 100,
+            ..Config::default()
+        };
+        check(&assemble(a).unwrap(), &assemble(b).unwrap(), &config)
+    }
+
+    #[test]
+    fn equal_programs_are_equivalent_on_every_input() {
+        let verdict = check_sources("R1 <- R0 + R0", "R1 <- lsh(R0)", &["R0=*"]);
+        assert_eq!(verdict,
+                   Verdict::Equivalent {
+                       inputs: 65536,
+                       exhaustive: true,
+                   });
+    }
+
+    #[test]
+    fn differences_are_counterexamples() {
+        let verdict = check_sources("R1 <- R0 + R0", "R1 <- R0 + 1", &["R0=0..3"]);
+        match verdict {
+            Verdict::Counterexample(c) => {
+                assert_eq!(c.input.len(), 1);
+                assert_eq!(c.input[0].value, 0);
+                assert_eq!(c.differences, vec!["R1: A 0, B 1".to_string()]);
+            }
+            v => panic!("expected a counterexample, got {}", v),
+        }
+    }
+
+    #[test]
+    fn errors_compare_by_kind() {
+        // Both return without a return stack, at different addresses.
+        let verdict = check_sources("return", "R0 <- R0\nreturn", &[]);
+        assert!(matches!(verdict, Verdict::Equivalent { .. }), "{}", verdict);
+
+        // A write to the constant register 0 is another kind of error.
+        let config = Config::default();
+        let verdict = check(&assemble("return").unwrap(), &[0x00100000], &config);
+        assert!(matches!(verdict, Verdict::Counterexample(_)), "{}", verdict);
+    }
+
+    #[test]
+    fn runs_into_the_bound_are_inconclusive() {
+        let verdict = check_sources(":loop\ngoto .loop", ":loop\ngoto .loop", &[]);
+        assert_eq!(verdict,
+                   Verdict::Inconclusive {
+                       inputs: 1,
+                       exhaustive: true,
+                       unfinished: 1,
+                       example: Vec::new(),
+                   });
+
+        // Only one program finishing proves nothing either.
+        let verdict = check_sources("R1 <- R0",
+                                    ":loop\n(R0); if Z goto .loop\nR1 <- R0",
+                                    &["R0=0..1"]);
+        match verdict {
+            Verdict::Inconclusive { inputs: 2, unfinished: 1, ref example, .. } => {
+                assert_eq!(example[0].value, 0);
+            }
+            v => panic!("expected an inconclusive verdict, got {}", v),
+        }
+
+        // A counterexample on another input still decides.
+        let verdict = check_sources(":loop\n(R0); if Z goto .loop\nR1 <- R0",
+                                    ":loop\n(R0); if Z goto .loop\nR1 <- R0 + R0",
+                                    &["R0=0..1"]);
+        assert!(matches!(verdict, Verdict::Counterexample(_)), "{}", verdict);
+    }
+}
@@ -0,0 +1,800 @@
+//! Fault injection campaigns.
+//!
+//! A campaign first runs the program without faults for its reference
+//! result, then once per fault, injecting that fault into an otherwise
+//! identical run:
+//!
+//! ```text
+//! micro16 faults sum.m16 --init R0=10 --random 1000 --seed 7
+//! micro16 faults sum.m16 --enumerate --kinds stuck,drop
+//! micro16 faults sum.m16 --fault R1.3@4 --fault ens=0 --verbose
+//! ```
+//!
+//! Faults are written as follows:
+//!
+//! ```text
+//! R3.5@10       flip bit 5 of R3 before cycle 10
+//! mem[16].0@4   flip bit 0 of a memory cell
+//! cs[2].20@0    flip bit 20 of control store word 2
+//! ens=0         hold control signal ens at 0 for the whole run
+//! alu.1=1       hold bit 1 of the alu field at 1; a field without a bit
+//!               is held entirely, e.g. alu=0
+//! drop@12       drop the memory ready handshake before cycle 12, so the
+//!               access in progress starts over
+//! ```
+//!
+//! Every run ends in one of four outcomes: the fault is masked if the
+//! program finishes with the reference values in all observed locations,
+//! a wrong result if it finishes with others, a crash if the Cpu rejects an
+//! instruction, and a hang if the program does not finish within the cycle
+//! bound. Observed are `--observe` or the registers and memory cells the
+//! reference run writes, its results, together with any cell a faulty run
+//! writes. A flipped bit that is overwritten or never read again is masked.
+//!
+//! `--random N` draws `N` faults, the kind uniformly among `--kinds` and
+//! then the target and cycle uniformly among those the reference run can be
+//! hit at: register flips and flips of the cells it reads, writes or starts
+//! with at any of its cycles, control store flips in any word of the
+//! program, stuck-at faults on any bit of the format, dropped handshakes
+//! while an access is in progress. `--enumerate`
+//! injects every one of these faults instead, flipping control store bits
+//! only before the first cycle.
+
+use std::fmt;
+
+use cli;
+use cpu::{MemoryPort, MAR, MBR, PROGRAM_LENGTH};
+use equiv;
+use format::Layout;
+use loader;
+use machine::{self, Machine, MachineError, Status};
+use rng::Rng;
+use state::{self, Assignment, Location};
+
+const DEFAULT_MAX_CYCLES: u64 = 10_000;
+const DEFAULT_RANDOM: u64 = 1000;
+const WORD_BITS: u8 = 32;
+
+pub const USAGE: &str = "Usage: micro16 faults PROGRAM [--init ASSIGNMENTS] [--cycles N] \
+                         [--observe LOC,...] [--verbose]
+                      [--fault FAULT]... | [--random N] [--seed N] [--kinds KIND,...] | \
+                         [--enumerate] [--kinds KIND,...]
+FAULT is LOC.BIT@CYCLE, cs[ADDR].BIT@CYCLE, SIGNAL[.BIT]=0|1 or drop@CYCLE
+KIND is one of register, memory, cs, stuck, drop";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Register,
+    Memory,
+    ControlStore,
+    Stuck,
+    Drop,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 5] = [Kind::Register,
+                                Kind::Memory,
+                                Kind::ControlStore,
+                                Kind::Stuck,
+                                Kind::Drop];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Register => "register",
+            Kind::Memory => "memory",
+            Kind::ControlStore => "cs",
+            Kind::Stuck => "stuck",
+            Kind::Drop => "drop",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Kind, String> {
+        Kind::ALL
+            .iter()
+            .cloned()
+            .find(|k| k.name() == name)
+            .ok_or_else(|| format!("unknown fault kind '{}'", name))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Flips `bit` of a register or memory cell before cycle `cycle`.
+    Flip { location: Location, bit: u8, cycle: u64 },
+    /// Flips `bit` of control store word `addr` before cycle `cycle`.
+    ControlStoreFlip { addr: u8, bit: u8, cycle: u64 },
+    /// Holds bit `bit` of the control field `signal`, or the whole field
+    /// for `None`, at `value` for the whole run.
+    StuckAt { signal: String, bit: Option<u8>, value: bool },
+    /// Aborts the memory access in progress before cycle `cycle`, as if its
+    /// ready signal was lost.
+    DroppedReady { cycle: u64 },
+}
+
+impl Fault {
+    pub fn kind(&self) -> Kind {
+        match *self {
+            Fault::Flip { location: Location::Register(_), .. } => Kind::Register,
+            Fault::Flip { location: Location::Memory(_), .. } => Kind::Memory,
+            Fault::ControlStoreFlip { .. } => Kind::ControlStore,
+            Fault::StuckAt { .. } => Kind::Stuck,
+            Fault::DroppedReady { .. } => Kind::Drop,
+        }
+    }
+
+    /// The cycle before which a transient fault strikes; stuck-at faults
+    /// are present throughout.
+    pub fn cycle(&self) -> Option<u64> {
+        match *self {
+            Fault::Flip { cycle, .. } |
+            Fault::ControlStoreFlip { cycle, .. } |
+            Fault::DroppedReady { cycle } => Some(cycle),
+            Fault::StuckAt { .. } => None,
+        }
+    }
+
+    /// Parses the notation of the module documentation.
+    pub fn parse(text: &str) -> Result<Fault, String> {
+        let err = || format!("invalid fault '{}'", text);
+        let number = |n: &str| n.parse().map_err(|_| err());
+        if let Some(cycle) = text.strip_prefix("drop@") {
+            return Ok(Fault::DroppedReady { cycle: number(cycle)? });
+        }
+        if let Some((target, cycle)) = text.rsplit_once('@') {
+            let cycle = number(cycle)?;
+            let (target, bit) = target.rsplit_once('.').ok_or_else(err)?;
+            let bit: u8 = bit.parse().map_err(|_| err())?;
+            if let Some(addr) = target.strip_prefix("cs[").and_then(|t| t.strip_suffix(']')) {
+                let addr = state::parse_value(addr)?;
+                if bit >= WORD_BITS || addr < 0 || addr as usize >= PROGRAM_LENGTH {
+                    return Err(err());
+                }
+                return Ok(Fault::ControlStoreFlip { addr: addr as u8, bit, cycle });
+            }
+            if bit >= 16 {
+                return Err(err());
+            }
+            let location = state::parse_location(target)?;
+            return Ok(Fault::Flip { location, bit, cycle });
+        }
+        let (signal, value) = text.split_once('=').ok_or_else(err)?;
+        let value = match value {
+            "0" => false,
+            "1" => true,
+            _ => return Err(err()),
+        };
+        let (signal, bit) = match signal.split_once('.') {
+            Some((signal, bit)) => (signal, Some(bit.parse().map_err(|_| err())?)),
+            None => (signal, None),
+        };
+        Ok(Fault::StuckAt { signal: signal.to_string(), bit, value })
+    }
+
+    /// The bits a stuck-at fault holds in words of `layout`.
+    fn stuck_mask(&self, layout: &Layout) -> Result<u32, String> {
+        let (signal, bit) = match *self {
+            Fault::StuckAt { ref signal, bit, .. } => (signal, bit),
+            _ => return Ok(0),
+        };
+        let field = layout.format()
+            .field(signal)
+            .ok_or_else(|| format!("the format has no control signal {}", signal))?;
+        match bit {
+            Some(bit) if bit as u32 >= field.width() => {
+                Err(format!("{} has no bit {}", signal, bit))
+            }
+            Some(bit) => Ok(1 << (field.lo + bit as u32)),
+            None => Ok(field.set(0, u32::MAX)),
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::Flip { location, bit, cycle } => write!(f, "{}.{}@{}", location, bit, cycle),
+            Fault::ControlStoreFlip { addr, bit, cycle } => {
+                write!(f, "cs[{}].{}@{}", addr, bit, cycle)
+            }
+            Fault::StuckAt { ref signal, bit: Some(bit), value } => {
+                write!(f, "{}.{}={}", signal, bit, value as u8)
+            }
+            Fault::StuckAt { ref signal, bit: None, value } => {
+                write!(f, "{}={}", signal, value as u8)
+            }
+            Fault::DroppedReady { cycle } => write!(f, "drop@{}", cycle),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Masked,
+    /// The observed locations that differ from the reference run.
+    WrongResult(Vec<String>),
+    Crash(String),
+    Hang,
+}
+
+impl Outcome {
+    fn index(&self) -> usize {
+        match *self {
+            Outcome::Masked => 0,
+            Outcome::WrongResult(_) => 1,
+            Outcome::Crash(_) => 2,
+            Outcome::Hang => 3,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Outcome::Masked => write!(f, "masked"),
+            Outcome::WrongResult(ref differences) => {
+                write!(f, "wrong result: {}", differences.join(", "))
+            }
+            Outcome::Crash(ref e) => write!(f, "crash: {}", e),
+            Outcome::Hang => write!(f, "hang"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub init: Vec<Assignment>,
+    /// Locations to compare; `None` compares the default set.
+    pub observe: Option<Vec<Location>>,
+    /// Cycles after which a run counts as hung.
+    pub max_cycles: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            init: Vec::new(),
+            observe: None,
+            max_cycles: DEFAULT_MAX_CYCLES,
+        }
+    }
+}
+
+/// Steps `machine` one cycle, adding the locations the cycle writes to
+/// `written`.
+fn step(machine: &mut Machine, written: &mut Vec<Location>) -> Result<(), MachineError> {
+    let instr = machine.cpu().current_instruction();
+    machine.step()?;
+    let instr = match instr {
+        Some(instr) => instr,
+        None => return Ok(()),
+    };
+    let mut add = |location| if !written.contains(&location) {
+        written.push(location);
+    };
+    if instr.ens() {
+        add(Location::Register(instr.s_bus()));
+    }
+    if instr.mar() {
+        add(Location::Register(MAR));
+    }
+    if instr.mbr() || instr.ms() && instr.rd_wr() {
+        add(Location::Register(MBR));
+    }
+    if instr.ms() && !instr.rd_wr() {
+        // The access uses MAR as left behind by the word.
+        add(Location::Memory(machine.cpu().registers().get(MAR) as u16));
+    }
+    Ok(())
+}
+
+/// The fault-free run a campaign compares against.
+#[derive(Debug)]
+pub struct Reference {
+    pub cycles: u64,
+    /// The locations compared after faulty runs.
+    pub observed: Vec<Location>,
+    /// The memory cells the run reads or writes, and the initialized ones.
+    pub cells: Vec<u16>,
+    /// Cycles that start with a memory access in progress.
+    pub access_cycles: Vec<u64>,
+    /// The state at the start of every cycle and the final one. Faulty runs
+    /// fork from the state their fault strikes.
+    states: Vec<Machine>,
+}
+
+impl Reference {
+    /// Runs `program` without faults.
+    pub fn new(program: &[u32], config: &Config) -> Result<Reference, String> {
+        let machine_config = machine::Config {
+            max_cycles: Some(config.max_cycles),
+            ..machine::Config::default()
+        };
+        let mut machine = Machine::new(program, machine_config).map_err(|e| e.to_string())?;
+        let init: Vec<machine::Assignment> = config.init.iter().map(|&a| a.into()).collect();
+        machine.apply(&init);
+        let mut written = Vec::new();
+        let mut cells: Vec<u16> = config.init
+            .iter()
+            .filter_map(|a| match a.location {
+                Location::Memory(addr) => Some(addr),
+                Location::Register(_) => None,
+            })
+            .collect();
+        let mut access_cycles = Vec::new();
+        let mut states = Vec::new();
+        loop {
+            states.push(machine.clone());
+            match machine.status() {
+                Status::Finished => break,
+                Status::CycleLimit => {
+                    return Err(format!("the program does not finish within {} cycles",
+                                       config.max_cycles))
+                }
+                Status::Running => (),
+            }
+            let cycles = machine.cycles();
+            if machine.cpu().memory().ready() {
+                access_cycles.push(cycles);
+            }
+            let access = machine.cpu().current_instruction().is_some_and(|i| i.ms());
+            step(&mut machine, &mut written).map_err(|e| format!("cycle {}: {}", cycles, e))?;
+            if access {
+                cells.push(machine.cpu().registers().get(MAR) as u16);
+            }
+        }
+        cells.sort();
+        cells.dedup();
+
+        Ok(Reference {
+            cycles: machine.cycles(),
+            observed: config.observe.clone().unwrap_or(written),
+            cells,
+            access_cycles,
+            states,
+        })
+    }
+
+    fn final_state(&self) -> &Machine {
+        self.states.last().unwrap()
+    }
+}
+
+fn strike(machine: &mut Machine, fault: &Fault) {
+    match *fault {
+        Fault::Flip { location, bit, .. } => {
+            let location = machine::Location::from(location);
+            let value = machine.read(location) ^ (1 << bit);
+            machine.apply(&[machine::Assignment { location, value }]);
+        }
+        Fault::ControlStoreFlip { addr, bit, .. } => {
+            let cpu = machine.cpu_mut();
+            let word = cpu.control_store().get(addr as usize).cloned().unwrap_or(0);
+            cpu.patch(addr, word ^ (1 << bit));
+        }
+        Fault::DroppedReady { .. } => machine.cpu_mut().memory_mut().reset(true),
+        Fault::StuckAt { .. } => (),
+    }
+}
+
+/// Reruns the reference program with `fault` injected and classifies the outcome.
+pub fn inject(fault: &Fault, reference: &Reference, config: &Config) -> Result<Outcome, String> {
+    // Up to the fault the run is the reference run.
+    let cycles = fault.cycle().unwrap_or(0).min(reference.cycles);
+    let mut machine = reference.states[cycles as usize].clone();
+    machine.set_max_cycles(Some(config.max_cycles));
+    let mask = fault.stuck_mask(machine.cpu().layout())?;
+    match *fault {
+        Fault::StuckAt { value: true, .. } => machine.cpu_mut().set_stuck_bits(0, mask),
+        Fault::StuckAt { value: false, .. } => machine.cpu_mut().set_stuck_bits(mask, 0),
+        _ => (),
+    }
+
+    let mut written = Vec::new();
+    loop {
+        if fault.cycle() == Some(machine.cycles()) {
+            strike(&mut machine, fault);
+        }
+        match machine.status() {
+            Status::Finished => break,
+            Status::CycleLimit => return Ok(Outcome::Hang),
+            Status::Running => (),
+        }
+        if let Err(e) = step(&mut machine, &mut written) {
+            return Ok(Outcome::Crash(e.to_string()));
+        }
+    }
+
+    // Without --observe, whatever the faulty run wrote counts as well.
+    let mut locations = reference.observed.clone();
+    if config.observe.is_none() {
+        locations.extend(written.into_iter().filter(|l| !reference.observed.contains(l)));
+    }
+    let differences: Vec<String> = locations.into_iter()
+        .filter_map(|location| {
+            let expected = reference.final_state().read(location.into());
+            let actual = machine.read(location.into());
+            if actual == expected {
+                None
+            } else {
+                Some(format!("{}: expected {}, got {}", location, expected, actual))
+            }
+        })
+        .collect();
+    if differences.is_empty() {
+        Ok(Outcome::Masked)
+    } else {
+        Ok(Outcome::WrongResult(differences))
+    }
+}
+
+/// Every fault of `kinds` the reference run can be hit by.
+pub fn enumerate(program: &[u32], reference: &Reference, kinds: &[Kind]) -> Vec<Fault> {
+    let mut faults = Vec::new();
+    for &kind in kinds {
+        match kind {
+            Kind::Register | Kind::Memory => {
+                let locations = if kind == Kind::Register {
+                    equiv::all_registers()
+                } else {
+                    reference.cells.iter().map(|&cell| Location::Memory(cell)).collect()
+                };
+                for cycle in 0..reference.cycles {
+                    for &location in &locations {
+                        for bit in 0..16 {
+                            faults.push(Fault::Flip { location, bit, cycle });
+                        }
+                    }
+                }
+            }
+            Kind::ControlStore => {
+                for addr in 0..program.len() {
+                    for bit in 0..WORD_BITS {
+                        faults.push(Fault::ControlStoreFlip { addr: addr as u8, bit, cycle: 0 });
+                    }
+                }
+            }
+            Kind::Stuck => {
+                for field in &Layout::micro16().format().fields {
+                    for bit in 0..field.width() as u8 {
+                        for &value in &[false, true] {
+                            faults.push(Fault::StuckAt {
+                                signal: field.name.clone(),
+                                bit: if field.width() == 1 { None } else { Some(bit) },
+                                value,
+                            });
+                        }
+                    }
+                }
+            }
+            Kind::Drop => {
+                faults.extend(reference.access_cycles
+                    .iter()
+                    .map(|&cycle| Fault::DroppedReady { cycle }));
+            }
+        }
+    }
+    faults
+}
+
+/// Draws `count` faults as described in the module documentation. Kinds
+/// the reference run offers no target for are skipped.
+pub fn random(program: &[u32],
+              reference: &Reference,
+              kinds: &[Kind],
+              count: u64,
+              seed: u64)
+              -> Vec<Fault> {
+    let cells = &reference.cells;
+    let kinds: Vec<Kind> = kinds.iter()
+        .cloned()
+        .filter(|&kind| match kind {
+            Kind::Register => reference.cycles > 0,
+            Kind::Memory => reference.cycles > 0 && !cells.is_empty(),
+            Kind::ControlStore => !program.is_empty() && reference.cycles > 0,
+            Kind::Stuck => true,
+            Kind::Drop => !reference.access_cycles.is_empty(),
+        })
+        .collect();
+    if kinds.is_empty() {
+        return Vec::new();
+    }
+
+    let layout = Layout::micro16();
+    let fields = &layout.format().fields;
+    let mut rng = Rng::new(seed);
+    (0..count)
+        .map(|_| {
+            let kind = kinds[rng.below(kinds.len() as u64) as usize];
+            let cycle = rng.below(reference.cycles.max(1));
+            match kind {
+                Kind::Register | Kind::Memory => {
+                    let location = if kind == Kind::Register {
+                        let registers = equiv::all_registers();
+                        registers[rng.below(registers.len() as u64) as usize]
+                    } else {
+                        Location::Memory(cells[rng.below(cells.len() as u64) as usize])
+                    };
+                    let bit = rng.below(16) as u8;
+                    Fault::Flip { location, bit, cycle }
+                }
+                Kind::ControlStore => {
+                    Fault::ControlStoreFlip {
+                        addr: rng.below(program.len() as u64) as u8,
+                        bit: rng.below(WORD_BITS as u64) as u8,
+                        cycle,
+                    }
+                }
+                Kind::Stuck => {
+                    let field = &fields[rng.below(fields.len() as u64) as usize];
+                    let bit = rng.below(field.width() as u64) as u8;
+                    Fault::StuckAt {
+                        signal: field.name.clone(),
+                        bit: if field.width() == 1 { None } else { Some(bit) },
+                        value: rng.chance(1, 2),
+                    }
+                }
+                Kind::Drop => {
+                    let cycles = &reference.access_cycles;
+                    Fault::DroppedReady { cycle: cycles[rng.below(cycles.len() as u64) as usize] }
+                }
+            }
+        })
+        .collect()
+}
+
+/// Outcome counts of a campaign, per fault kind.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// Masked, wrong result, crash and hang counts, indexed like `Kind::ALL`.
+    pub counts: [[u64; 4]; 5],
+}
+
+impl Summary {
+    pub fn add(&mut self, fault: &Fault, outcome: &Outcome) {
+        let kind = Kind::ALL.iter().position(|&k| k == fault.kind()).unwrap();
+        self.counts[kind][outcome.index()] += 1;
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f,
+                 "{:<10} {:>8} {:>8} {:>8} {:>8} {:>8}",
+                 "kind",
+                 "faults",
+                 "masked",
+                 "wrong",
+                 "crash",
+                 "hang")?;
+        let mut total = [0; 4];
+        for (kind, counts) in Kind::ALL.iter().zip(&self.counts) {
+            if counts.iter().sum::<u64>() == 0 {
+                continue;
+            }
+            for (t, c) in total.iter_mut().zip(counts) {
+                *t += c;
+            }
+            writeln!(f,
+                     "{:<10} {:>8} {:>8} {:>8} {:>8} {:>8}",
+                     kind.name(),
+                     counts.iter().sum::<u64>(),
+                     counts[0],
+                     counts[1],
+                     counts[2],
+                     counts[3])?;
+        }
+        let faults: u64 = total.iter().sum();
+        write!(f,
+               "{:<10} {:>8} {:>8} {:>8} {:>8} {:>8}",
+               "total",
+               faults,
+               total[0],
+               total[1],
+               total[2],
+               total[3])?;
+        if faults > 0 {
+            let percent = |n: u64| 100.0 * n as f64 / faults as f64;
+            write!(f,
+                   "\n{:<10} {:>8} {:>7.1}% {:>7.1}% {:>7.1}% {:>7.1}%",
+                   "",
+                   "",
+                   percent(total[0]),
+                   percent(total[1]),
+                   percent(total[2]),
+                   percent(total[3]))?;
+        }
+        Ok(())
+    }
+}
+
+/// Entry point of `micro16 faults`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    let mut program = None;
+    let mut config = Config::default();
+    let mut faults = Vec::new();
+    let mut random_count = None;
+    let mut exhaustive = false;
+    let mut kinds = Kind::ALL.to_vec();
+    let mut seed = 0;
+    let mut verbose = false;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--init" => config.init.extend(state::parse_assignments(iter.next().ok_or(USAGE)?)?),
+            "--observe" => {
+                let list = iter.next().ok_or(USAGE)?;
+                let locations = list.split(',').map(state::parse_location);
+                config.observe = Some(locations.collect::<Result<_, _>>()?);
+            }
+            "--cycles" => config.max_cycles = cli::parse_arg(iter.next(), USAGE)?,
+            "--fault" => faults.push(Fault::parse(iter.next().ok_or(USAGE)?)?),
+            "--random" => random_count = Some(cli::parse_arg(iter.next(), USAGE)?),
+            "--enumerate" => exhaustive = true,
+            "--kinds" => {
+                let list = iter.next().ok_or(USAGE)?;
+                kinds = list.split(',').map(Kind::parse).collect::<Result<_, _>>()?;
+            }
+            "--seed" => seed = cli::parse_arg(iter.next(), USAGE)?,
+            "--verbose" => verbose = true,
+            _ if !arg.starts_with('-') && program.is_none() => program = Some(arg.clone()),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    if exhaustive && random_count.is_some() {
+        return Err(USAGE.to_string());
+    }
+
+    let program = loader::read_program(&program.ok_or(USAGE)?)?;
+    let reference = Reference::new(&program, &config)?;
+    if exhaustive {
+        faults.extend(enumerate(&program, &reference, &kinds));
+    } else if random_count.is_some() || faults.is_empty() {
+        let count = random_count.unwrap_or(DEFAULT_RANDOM);
+        faults.extend(random(&program, &reference, &kinds, count, seed));
+    }
+
+    println!("reference run: {} cycles, {} observed locations",
+             reference.cycles,
+             reference.observed.len());
+    let mut summary = Summary::default();
+    for fault in &faults {
+        let outcome = inject(fault, &reference, &config)?;
+        if verbose {
+            println!("{:<16} {}", fault.to_string(), outcome);
+        }
+        summary.add(fault, &outcome);
+    }
+    println!("{}", summary);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+
+    // mem[2 * R0 + 1] <- 2 * R0, in four cycles.
+    const PROGRAM: &str = "R1 <- R0 + R0\nR2 <- R1 + 1\nMAR <- R2; MBR <- R1; wr\nwr";
+
+    fn campaign() -> (Vec<u32>, Reference, Config) {
+        let program = assemble(PROGRAM).unwrap();
+        let config = Config {
+            init: state::parse_assignments("R0=3").unwrap(),
+            max_cycles: 100,
+            ..Config::default()
+        };
+        let reference = Reference::new(&program, &config).unwrap();
+        (program, reference, config)
+    }
+
+    fn outcome(fault: &str) -> Outcome {
+        let (_, reference, config) = campaign();
+        inject(&Fault::parse(fault).unwrap(), &reference, &config).unwrap()
+    }
+
+    #[test]
+    fn faults_parse_and_print() {
+        for text in &["R3.5@10", "mem[16].0@4", "cs[2].20@0", "ens=0", "alu.1=1", "drop@12"] {
+            assert_eq!(Fault::parse(text).unwrap().to_string(), *text);
+        }
+        assert_eq!(Fault::parse("alu=0").unwrap(),
+                   Fault::StuckAt { signal: "alu".to_string(), bit: None, value: false });
+        for text in &["R3.16@0", "cs[256].0@0", "cs[0].32@0", "ens=2", "R3@1", "drop@x"] {
+            assert_eq!(Fault::parse(text).unwrap_err(), format!("invalid fault '{}'", text));
+        }
+    }
+
+    #[test]
+    fn the_reference_run_records_its_targets() {
+        let (_, reference, _) = campaign();
+        assert_eq!(reference.cycles, 4);
+        assert_eq!(reference.observed,
+                   vec![Location::Register(5),
+                        Location::Register(6),
+                        Location::Register(MAR),
+                        Location::Register(MBR),
+                        Location::Memory(7)]);
+        assert_eq!(reference.cells, vec![7]);
+        assert_eq!(reference.access_cycles, vec![3]);
+    }
+
+    #[test]
+    fn flips_strike_before_their_cycle() {
+        // R0 is only read in the first cycle.
+        assert_eq!(outcome("R0.0@1"), Outcome::Masked);
+        match outcome("R0.0@0") {
+            Outcome::WrongResult(differences) => {
+                assert_eq!(differences[0], "R1: expected 6, got 4");
+                // Cells the faulty run writes are compared as well.
+                assert_eq!(differences.last().unwrap(), "mem[5]: expected 0, got 4");
+            }
+            outcome => panic!("{}", outcome),
+        }
+        assert_eq!(outcome("R1.0@3"),
+                   Outcome::WrongResult(vec!["R1: expected 6, got 7".to_string()]));
+        assert_eq!(outcome("mem[7].0@4"),
+                   Outcome::WrongResult(vec!["mem[7]: expected 6, got 7".to_string()]));
+        // The last word jumps back to the start if Z is set.
+        assert_eq!(outcome("cs[3].30@0"), Outcome::Hang);
+    }
+
+    #[test]
+    fn stuck_bits_hold_for_the_whole_run() {
+        assert_eq!(outcome("alu.0=1"),
+                   Outcome::WrongResult(vec!["MBR: expected 6, got 13".to_string(),
+                                             "mem[7]: expected 6, got 13".to_string()]));
+        assert_eq!(outcome("sh=1"),
+                   Outcome::Crash("instruction 0 uses reserved shifter mode 3".to_string()));
+        assert_eq!(outcome("ms=0"),
+                   Outcome::WrongResult(vec!["mem[7]: expected 6, got 0".to_string()]));
+
+        let (_, reference, config) = campaign();
+        let fault = Fault::parse("foo=1").unwrap();
+        assert_eq!(inject(&fault, &reference, &config).unwrap_err(),
+                   "the format has no control signal foo");
+        let fault = Fault::parse("ens.1=1").unwrap();
+        assert_eq!(inject(&fault, &reference, &config).unwrap_err(), "ens has no bit 1");
+    }
+
+    #[test]
+    fn dropped_handshakes_restart_the_access() {
+        assert_eq!(outcome("drop@3"),
+                   Outcome::WrongResult(vec!["mem[7]: expected 6, got 0".to_string()]));
+    }
+
+    #[test]
+    fn enumeration_covers_every_target() {
+        let (program, reference, config) = campaign();
+        let count = |kind| enumerate(&program, &reference, &[kind]).len();
+        assert_eq!(count(Kind::Register), 4 * equiv::all_registers().len() * 16);
+        assert_eq!(count(Kind::Memory), 4 * 16);
+        assert_eq!(count(Kind::ControlStore), 4 * 32);
+        assert_eq!(count(Kind::Stuck), 32 * 2);
+        assert_eq!(enumerate(&program, &reference, &[Kind::Drop]),
+                   vec![Fault::DroppedReady { cycle: 3 }]);
+
+        let faults = enumerate(&program, &reference, &Kind::ALL);
+        let mut summary = Summary::default();
+        for fault in &faults {
+            summary.add(fault, &inject(fault, &reference, &config).unwrap());
+        }
+        let total: u64 = summary.counts.iter().flat_map(|c| c.iter()).sum();
+        assert_eq!(total, faults.len() as u64);
+        assert_eq!(summary.counts[4], [0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn random_faults_follow_the_seed() {
+        let (program, reference, _) = campaign();
+        let faults = random(&program, &reference, &Kind::ALL, 50, 7);
+        assert_eq!(faults.len(), 50);
+        assert_eq!(random(&program, &reference, &Kind::ALL, 50, 7), faults);
+        for fault in &faults {
+            assert!(fault.cycle().unwrap_or(0) < reference.cycles, "{}", fault);
+        }
+        assert!(random(&program, &reference, &[Kind::Drop], 5, 0)
+            .iter()
+            .all(|f| *f == Fault::DroppedReady { cycle: 3 }));
+    }
+}
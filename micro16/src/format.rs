@@ -0,0 +1,845 @@
+//! Declarative microinstruction formats.
+//!
+//! A format names the fields of a 32-bit microinstruction, their bit ranges
+//! and, for coded fields, the meaning of every code. Formats are written in
+//! code or read from a text file like this description of Micro16:
+//!
+//! ```text
+//! # Micro16
+//! name micro16
+//! field addr  0..8
+//! field a_bus 8..12
+//! field b_bus 12..16
+//! field s_bus 16..20
+//! field ens   20
+//! field ms    21
+//! field rd_wr 22
+//! field mar   23
+//! field mbr   24
+//! field sh    25..27 noop=0 left=1 right=2 call=3
+//! field alu   27..29 noop=0 add=1 and=2 not=3
+//! field cond  29..31 noop=0 if_n=1 if_z=2 goto=3
+//! field a_mux 31
+//! ```
+//!
+//! Ranges exclude their end, a single number is a one bit field. `micro16
+//! format` prints the built-in format as a starting point for variants.
+//! Wherever a format file is read, the names `micro16` and `micro16x` stand
+//! for the built-in formats.
+//!
+//! The `call` code of `sh` is optional. It turns the jump of the word into a
+//! micro-call, or a return if the word does not jump, and only executes on
+//! a Cpu with a return stack, see `Cpu::set_call_stack_depth`.
+//!
+//! So are the modes of the extended profile, `rol` for `sh`, `sub`, `or`,
+//! `xor` and `inc` for `alu` and `if_c`, `if_v` and `if_lt` for `cond`, but
+//! a format defines all of them or none. One that does is a format of
+//! `Profile::Extended`, see `Layout::profile`. Micro16 has no room for
+//! them; the built-in `micro16x` format widens `alu` and `cond` to 3 bits
+//! and drops `ens` and `a_mux`, which are optional:
+//!
+//! ```text
+//! # Micro16 with the extended profile
+//! name micro16x
+//! field addr  0..8
+//! field a_bus 8..12
+//! field b_bus 12..16
+//! field s_bus 16..20
+//! field ms    20
+//! field rd_wr 21
+//! field mar   22
+//! field mbr   23
+//! field sh    24..26 noop=0 left=1 right=2 rol=3
+//! field alu   26..29 noop=0 add=1 and=2 not=3 sub=4 or=5 xor=6 inc=7
+//! field cond  29..32 noop=0 if_n=1 if_z=2 goto=3 if_c=4 if_v=5 if_lt=6
+//! ```
+//!
+//! Without `ens` writing to S-bus 0 means no write, and without `a_mux` the
+//! ALU reads MAR as A-bus register 3. Every bit of micro16x is taken, so
+//! `rol` has the code that Micro16 gives to `call`, and the extended
+//! profile has no micro-calls.
+//!
+//! A `Format` on its own encodes, decodes and validates words field by
+//! field. A `Layout` resolves the fields the Micro16 datapath needs, and is
+//! what `Instruction` decodes through, so the Cpu, assembler and disassembler
+//! all follow the format.
+
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+use cpu::{AluMode, CondMode, Profile, ShifterMode};
+use disasm::disassemble;
+use instruction::Instruction;
+use loader;
+
+pub const USAGE: &str = "Usage: micro16 format [--format FILE]
+       micro16 format [--format FILE] decode HEXWORD...
+       micro16 format [--format FILE] encode FIELD=VALUE...";
+
+const WORD_BITS: u32 = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatError {
+    /// Line of the description, 0 for formats built in code.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "line {}: {}", self.line, self.message)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    /// First bit of the field.
+    pub lo: u32,
+    /// One past the last bit of the field.
+    pub hi: u32,
+    /// Meanings of the defined codes. Empty for plain numbers and flags,
+    /// which accept every value that fits.
+    pub codes: Vec<(String, u32)>,
+}
+
+impl Field {
+    pub fn new(name: &str, lo: u32, hi: u32) -> Field {
+        Field {
+            name: name.to_string(),
+            lo,
+            hi,
+            codes: Vec::new(),
+        }
+    }
+
+    pub fn coded(name: &str, lo: u32, hi: u32, codes: &[(&str, u32)]) -> Field {
+        Field {
+            codes: codes.iter().map(|&(m, c)| (m.to_string(), c)).collect(),
+            ..Field::new(name, lo, hi)
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.hi - self.lo
+    }
+
+    fn mask(&self) -> u32 {
+        (((1u64 << self.width()) - 1) as u32) << self.lo
+    }
+
+    pub fn get(&self, word: u32) -> u32 {
+        (word & self.mask()) >> self.lo
+    }
+
+    pub fn set(&self, word: u32, value: u32) -> u32 {
+        (word & !self.mask()) | ((value << self.lo) & self.mask())
+    }
+
+    /// The meaning of `code`, if the field is coded and defines it.
+    pub fn meaning(&self, code: u32) -> Option<&str> {
+        self.codes.iter().find(|&&(_, c)| c == code).map(|(m, _)| m.as_str())
+    }
+
+    pub fn code(&self, meaning: &str) -> Option<u32> {
+        self.codes.iter().find(|&(m, _)| m == meaning).map(|&(_, c)| c)
+    }
+
+    /// Whether the field accepts `value`.
+    pub fn accepts(&self, value: u32) -> bool {
+        let fits = self.width() == WORD_BITS || value >> self.width() == 0;
+        fits && (self.codes.is_empty() || self.meaning(value).is_some())
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.width() == 1 {
+            write!(f, "field {:<5} {}", self.name, self.lo)?;
+        } else {
+            write!(f, "field {:<5} {}..{}", self.name, self.lo, self.hi)?;
+        }
+        for (meaning, code) in &self.codes {
+            write!(f, " {}={}", meaning, code)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Format {
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+impl Format {
+    /// Checks that fields have distinct names, stay within the word and do
+    /// not overlap, and that their codes fit.
+    pub fn new(name: &str, fields: Vec<Field>) -> Result<Format, FormatError> {
+        if let Some((_, message)) = Format::check(&fields) {
+            return Err(FormatError { line: 0, message });
+        }
+        Ok(Format {
+            name: name.to_string(),
+            fields,
+        })
+    }
+
+    /// The first problem with `fields` and the index of the field at fault.
+    fn check(fields: &[Field]) -> Option<(usize, String)> {
+        for (i, field) in fields.iter().enumerate() {
+            if field.lo >= field.hi || field.hi > WORD_BITS {
+                return Some((i, format!("field {} has an invalid bit range", field.name)));
+            }
+            if fields[..i].iter().any(|f| f.name == field.name) {
+                return Some((i, format!("field {} is defined twice", field.name)));
+            }
+            if let Some(other) = fields[..i].iter().find(|f| f.mask() & field.mask() != 0) {
+                return Some((i, format!("fields {} and {} overlap", other.name, field.name)));
+            }
+            for (j, &(ref meaning, code)) in field.codes.iter().enumerate() {
+                if field.width() < WORD_BITS && code >> field.width() != 0 {
+                    return Some((i,
+                                 format!("code {}={} does not fit field {}",
+                                         meaning,
+                                         code,
+                                         field.name)));
+                }
+                if field.codes[..j].iter().any(|&(ref m, c)| m == meaning || c == code) {
+                    return Some((i,
+                                 format!("field {} defines {}={} twice",
+                                         field.name,
+                                         meaning,
+                                         code)));
+                }
+            }
+        }
+        None
+    }
+
+    /// The classic Micro16 format.
+    pub fn micro16() -> Format {
+        let fields = vec![Field::new("addr", 0, 8),
+                          Field::new("a_bus", 8, 12),
+                          Field::new("b_bus", 12, 16),
+                          Field::new("s_bus", 16, 20),
+                          Field::new("ens", 20, 21),
+                          Field::new("ms", 21, 22),
+                          Field::new("rd_wr", 22, 23),
+                          Field::new("mar", 23, 24),
+                          Field::new("mbr", 24, 25),
+                          Field::coded("sh",
+                                       25,
+                                       27,
+                                       &[("noop", 0), ("left", 1), ("right", 2), ("call", 3)]),
+                          Field::coded("alu",
+                                       27,
+                                       29,
+                                       &[("noop", 0), ("add", 1), ("and", 2), ("not", 3)]),
+                          Field::coded("cond",
+                                       29,
+                                       31,
+                                       &[("noop", 0), ("if_n", 1), ("if_z", 2), ("goto", 3)]),
+                          Field::new("a_mux", 31, 32)];
+        Format::new("micro16", fields).expect("the built-in format is valid")
+    }
+
+    /// The format of the extended profile: Micro16 with 3 bit `alu` and
+    /// `cond` fields in place of `ens` and `a_mux`, and `rol` in place of
+    /// `call`.
+    pub fn micro16x() -> Format {
+        let fields = vec![Field::new("addr", 0, 8),
+                          Field::new("a_bus", 8, 12),
+                          Field::new("b_bus", 12, 16),
+                          Field::new("s_bus", 16, 20),
+                          Field::new("ms", 20, 21),
+                          Field::new("rd_wr", 21, 22),
+                          Field::new("mar", 22, 23),
+                          Field::new("mbr", 23, 24),
+                          Field::coded("sh",
+                                       24,
+                                       26,
+                                       &[("noop", 0), ("left", 1), ("right", 2), ("rol", 3)]),
+                          Field::coded("alu",
+                                       26,
+                                       29,
+                                       &[("noop", 0),
+                                         ("add", 1),
+                                         ("and", 2),
+                                         ("not", 3),
+                                         ("sub", 4),
+                                         ("or", 5),
+                                         ("xor", 6),
+                                         ("inc", 7)]),
+                          Field::coded("cond",
+                                       29,
+                                       32,
+                                       &[("noop", 0),
+                                         ("if_n", 1),
+                                         ("if_z", 2),
+                                         ("goto", 3),
+                                         ("if_c", 4),
+                                         ("if_v", 5),
+                                         ("if_lt", 6)])];
+        Format::new("micro16x", fields).expect("the built-in format is valid")
+    }
+
+    /// Parses a format description, see the module documentation.
+    pub fn parse(text: &str) -> Result<Format, FormatError> {
+        let mut name = String::new();
+        let mut fields = Vec::new();
+        let mut lines = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let err = |message: String| FormatError { line: i + 1, message };
+            let line = line.split('#').next().unwrap().trim();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => (),
+                ["name", n] => name = n.to_string(),
+                ["field", field, bits, codes @ ..] => {
+                    let (lo, hi) = match bits.split_once("..") {
+                        Some((lo, hi)) => (lo.parse(), hi.parse()),
+                        None => (bits.parse(), bits.parse().map(|b: u32| b + 1)),
+                    };
+                    let (lo, hi) = match (lo, hi) {
+                        (Ok(lo), Ok(hi)) => (lo, hi),
+                        _ => return Err(err(format!("invalid bit range '{}'", bits))),
+                    };
+                    let mut field = Field::new(field, lo, hi);
+                    for item in codes {
+                        let code = item.split_once('=').and_then(|(m, c)| {
+                            Some((m.to_string(), parse_number(c)?))
+                        });
+                        let code = code.ok_or_else(|| {
+                            err(format!("expected MEANING=CODE, got '{}'", item))
+                        })?;
+                        field.codes.push(code);
+                    }
+                    fields.push(field);
+                    lines.push(i + 1);
+                }
+                _ => return Err(err(format!("invalid line '{}'", line))),
+            }
+        }
+        if let Some((i, message)) = Format::check(&fields) {
+            return Err(FormatError { line: lines[i], message });
+        }
+        Ok(Format { name, fields })
+    }
+
+    /// Reads a format description, or returns the built-in format named
+    /// `path`.
+    pub fn load(path: &str) -> Result<Format, String> {
+        match path {
+            "micro16" => return Ok(Format::micro16()),
+            "micro16x" => return Ok(Format::micro16x()),
+            _ => (),
+        }
+        let text = loader::read_source(path)?;
+        Format::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn field(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    /// Bits that belong to no field.
+    pub fn unused_bits(&self) -> u32 {
+        !self.fields.iter().fold(0, |mask, f| mask | f.mask())
+    }
+
+    /// Splits `word` into its field values, rejecting undefined codes and
+    /// set bits outside all fields.
+    pub fn decode(&self, word: u32) -> Result<Vec<(&str, u32)>, String> {
+        if word & self.unused_bits() != 0 {
+            return Err(format!("bits {:#010x} are not part of any field",
+                               word & self.unused_bits()));
+        }
+        self.fields
+            .iter()
+            .map(|f| {
+                let value = f.get(word);
+                if f.accepts(value) {
+                    Ok((f.name.as_str(), value))
+                } else {
+                    Err(format!("field {} has undefined code {}", f.name, value))
+                }
+            })
+            .collect()
+    }
+
+    /// Builds a word from field values; fields not mentioned are zero.
+    /// Values may be numbers or, for coded fields, meanings.
+    pub fn encode(&self, values: &[(&str, &str)]) -> Result<u32, String> {
+        let mut word = 0;
+        for &(name, value) in values {
+            let field = self.field(name).ok_or_else(|| format!("unknown field '{}'", name))?;
+            let code = field.code(value)
+                .or_else(|| parse_number(value))
+                .ok_or_else(|| format!("invalid value '{}' for field {}", value, name))?;
+            if !field.accepts(code) {
+                return Err(format!("field {} does not accept {}", name, value));
+            }
+            word = field.set(word, code);
+        }
+        Ok(word)
+    }
+
+    /// Lists the fields of `word` that are not zero, e.g. `a_bus=4 alu=add`.
+    pub fn describe(&self, word: u32) -> String {
+        let parts: Vec<String> = self.fields
+            .iter()
+            .filter(|f| f.get(word) != 0)
+            .map(|f| {
+                let value = f.get(word);
+                match f.meaning(value) {
+                    Some(meaning) => format!("{}={}", f.name, meaning),
+                    None => format!("{}={}", f.name, value),
+                }
+            })
+            .collect();
+        if parts.is_empty() {
+            "(all zero)".to_string()
+        } else {
+            parts.join(" ")
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.name.is_empty() {
+            writeln!(f, "name {}", self.name)?;
+        }
+        for field in &self.fields {
+            writeln!(f, "{}", field)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Where a `Layout` finds one field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bits {
+    lo: u32,
+    hi: u32,
+}
+
+impl Bits {
+    #[inline]
+    pub fn get(self, word: u32) -> u32 {
+        let mask = (((1u64 << (self.hi - self.lo)) - 1) as u32) << self.lo;
+        (word & mask) >> self.lo
+    }
+
+    #[inline]
+    pub fn set(self, word: u32, value: u32) -> u32 {
+        let mask = (((1u64 << (self.hi - self.lo)) - 1) as u32) << self.lo;
+        (word & !mask) | ((value << self.lo) & mask)
+    }
+}
+
+/// A format resolved against the Micro16 datapath: where every control
+/// field lives and which code selects which mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    format: Format,
+    pub addr: Bits,
+    pub a_bus: Bits,
+    pub b_bus: Bits,
+    pub s_bus: Bits,
+    pub ens: Option<Bits>,
+    pub ms: Bits,
+    pub rd_wr: Bits,
+    pub mar: Bits,
+    pub mbr: Bits,
+    pub sh: Bits,
+    pub alu: Bits,
+    pub cond: Bits,
+    pub a_mux: Option<Bits>,
+    sh_codes: Vec<(u32, ShifterMode)>,
+    call_code: Option<u32>,
+    alu_codes: Vec<(u32, AluMode)>,
+    cond_codes: Vec<(u32, CondMode)>,
+    profile: Profile,
+    unused: u32,
+}
+
+// The classic modes come first, the extended ones after them.
+const SHIFTER_MODES: [(&str, ShifterMode); 4] = [("noop", ShifterMode::NoOp),
+                                                 ("left", ShifterMode::Left),
+                                                 ("right", ShifterMode::Right),
+                                                 ("rol", ShifterMode::Rotate)];
+const ALU_MODES: [(&str, AluMode); 8] = [("noop", AluMode::NoOp),
+                                         ("add", AluMode::Add),
+                                         ("and", AluMode::BitAnd),
+                                         ("not", AluMode::BitNot),
+                                         ("sub", AluMode::Sub),
+                                         ("or", AluMode::BitOr),
+                                         ("xor", AluMode::BitXor),
+                                         ("inc", AluMode::Inc)];
+const COND_MODES: [(&str, CondMode); 7] = [("noop", CondMode::NoOp),
+                                           ("if_n", CondMode::IfNegative),
+                                           ("if_z", CondMode::IfZero),
+                                           ("goto", CondMode::GoTo),
+                                           ("if_c", CondMode::IfCarry),
+                                           ("if_v", CondMode::IfOverflow),
+                                           ("if_lt", CondMode::IfLess)];
+
+fn meaning<T: Copy + PartialEq>(modes: &[(&'static str, T)], mode: T) -> &'static str {
+    modes.iter().find(|&&(_, m)| m == mode).unwrap().0
+}
+
+/// The name of `mode` in format descriptions, e.g. `rol`.
+pub fn shifter_meaning(mode: ShifterMode) -> &'static str {
+    meaning(&SHIFTER_MODES, mode)
+}
+
+pub fn alu_meaning(mode: AluMode) -> &'static str {
+    meaning(&ALU_MODES, mode)
+}
+
+pub fn cond_meaning(mode: CondMode) -> &'static str {
+    meaning(&COND_MODES, mode)
+}
+
+fn resolve(format: &Format, name: &str, min: u32, max: u32) -> Result<Bits, String> {
+    let field = format.field(name).ok_or_else(|| format!("format lacks field {}", name))?;
+    if field.width() < min || field.width() > max {
+        return Err(if min == max {
+            format!("field {} must be {} bit(s) wide", name, min)
+        } else {
+            format!("field {} must be {} to {} bits wide", name, min, max)
+        });
+    }
+    Ok(Bits {
+        lo: field.lo,
+        hi: field.hi,
+    })
+}
+
+fn resolve_optional(format: &Format, name: &str) -> Result<Option<Bits>, String> {
+    match format.field(name) {
+        Some(_) => resolve(format, name, 1, 1).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Resolves the codes of `modes`, the first `required` of which the field
+/// must define. It may additionally define the meanings in `optional`.
+fn resolve_codes<T: Copy>(format: &Format,
+                          name: &str,
+                          modes: &[(&str, T)],
+                          required: usize,
+                          optional: &[&str])
+                          -> Result<(Bits, Vec<(u32, T)>), String> {
+    let bits = resolve(format, name, 1, 8)?;
+    let field = format.field(name).unwrap();
+    let mut codes = Vec::new();
+    for (i, &(meaning, mode)) in modes.iter().enumerate() {
+        match field.code(meaning) {
+            Some(code) => codes.push((code, mode)),
+            None if i >= required => (),
+            None => return Err(format!("field {} lacks code {}", name, meaning)),
+        }
+    }
+    if let Some((meaning, _)) = field.codes.iter().find(|&(m, _)| {
+        !modes.iter().any(|&(known, _)| known == m) && !optional.contains(&m.as_str())
+    }) {
+        return Err(format!("field {} has unknown meaning {}", name, meaning));
+    }
+    Ok((bits, codes))
+}
+
+impl Layout {
+    /// Resolves `format`, which must define every Micro16 control field:
+    /// `addr` of up to 8 bits, the 4 bit bus selectors, the one bit flags
+    /// except the optional `ens` and `a_mux`, and `sh`, `alu` and `cond`
+    /// with the meanings of the built-in format, where `call` is optional
+    /// and the modes of the extended profile come all together or not at
+    /// all.
+    pub fn new(format: Format) -> Result<Layout, String> {
+        let (sh, sh_codes) = resolve_codes(&format, "sh", &SHIFTER_MODES, 3, &["call"])?;
+        let (alu, alu_codes) = resolve_codes(&format, "alu", &ALU_MODES, 4, &[])?;
+        let (cond, cond_codes) = resolve_codes(&format, "cond", &COND_MODES, 4, &[])?;
+        // The number of extended modes each field defines, out of its share.
+        let extended = [("sh", sh_codes.len() - 3, SHIFTER_MODES.len() - 3),
+                        ("alu", alu_codes.len() - 4, ALU_MODES.len() - 4),
+                        ("cond", cond_codes.len() - 4, COND_MODES.len() - 4)];
+        let profile = if extended.iter().all(|&(_, n, _)| n == 0) {
+            Profile::Classic
+        } else if let Some(&(name, ..)) = extended.iter().find(|&&(_, n, all)| n < all) {
+            return Err(format!("field {} lacks modes of the extended profile, which a format \
+                                defines all or none of",
+                               name));
+        } else {
+            Profile::Extended
+        };
+        Ok(Layout {
+            addr: resolve(&format, "addr", 1, 8)?,
+            a_bus: resolve(&format, "a_bus", 4, 4)?,
+            b_bus: resolve(&format, "b_bus", 4, 4)?,
+            s_bus: resolve(&format, "s_bus", 4, 4)?,
+            ens: resolve_optional(&format, "ens")?,
+            ms: resolve(&format, "ms", 1, 1)?,
+            rd_wr: resolve(&format, "rd_wr", 1, 1)?,
+            mar: resolve(&format, "mar", 1, 1)?,
+            mbr: resolve(&format, "mbr", 1, 1)?,
+            a_mux: resolve_optional(&format, "a_mux")?,
+            sh,
+            alu,
+            cond,
+            sh_codes,
+            call_code: format.field("sh").unwrap().code("call"),
+            alu_codes,
+            cond_codes,
+            profile,
+            unused: format.unused_bits(),
+            format,
+        })
+    }
+
+    /// The layout of the built-in Micro16 format.
+    pub fn micro16() -> Arc<Layout> {
+        static LAYOUT: OnceLock<Arc<Layout>> = OnceLock::new();
+        LAYOUT.get_or_init(|| {
+                Arc::new(Layout::new(Format::micro16()).expect("the built-in format resolves"))
+            })
+            .clone()
+    }
+
+    /// The layout of the built-in format of the extended profile.
+    pub fn micro16x() -> Arc<Layout> {
+        static LAYOUT: OnceLock<Arc<Layout>> = OnceLock::new();
+        LAYOUT.get_or_init(|| {
+                Arc::new(Layout::new(Format::micro16x()).expect("the built-in format resolves"))
+            })
+            .clone()
+    }
+
+    /// The layout of the built-in format of `profile`.
+    pub fn for_profile(profile: Profile) -> Arc<Layout> {
+        match profile {
+            Profile::Classic => Layout::micro16(),
+            Profile::Extended => Layout::micro16x(),
+        }
+    }
+
+    /// Loads a format description and resolves it, or returns the built-in
+    /// layout named `path`.
+    pub fn load(path: &str) -> Result<Arc<Layout>, String> {
+        match path {
+            "micro16" => return Ok(Layout::micro16()),
+            "micro16x" => return Ok(Layout::micro16x()),
+            _ => (),
+        }
+        let layout = Layout::new(Format::load(path)?).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Arc::new(layout))
+    }
+
+    pub fn format(&self) -> &Format {
+        &self.format
+    }
+
+    /// The profile whose modes the format encodes. A Cpu decoding through
+    /// the layout runs this profile.
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    /// Fails unless the format encodes `profile`, for tools that take both
+    /// a profile and a format.
+    pub fn check_profile(&self, profile: Profile) -> Result<(), String> {
+        if self.profile == profile {
+            return Ok(());
+        }
+        Err(format!("format {} encodes the {} profile, not the {} one",
+                    self.format.name,
+                    self.profile.name(),
+                    profile.name()))
+    }
+
+    /// Bits that belong to no field and must be clear.
+    pub fn unused_bits(&self) -> u32 {
+        self.unused
+    }
+
+    pub fn shifter_mode(&self, code: u32) -> Option<ShifterMode> {
+        self.sh_codes.iter().find(|&&(c, _)| c == code).map(|&(_, m)| m)
+    }
+
+    /// The `sh` code that marks micro-calls and returns, if the format has
+    /// one.
+    pub fn call_code(&self) -> Option<u32> {
+        self.call_code
+    }
+
+    pub fn alu_mode(&self, code: u32) -> Option<AluMode> {
+        self.alu_codes.iter().find(|&&(c, _)| c == code).map(|&(_, m)| m)
+    }
+
+    pub fn cond_mode(&self, code: u32) -> Option<CondMode> {
+        self.cond_codes.iter().find(|&&(c, _)| c == code).map(|&(_, m)| m)
+    }
+
+    /// The code of `mode`, if the format defines it.
+    pub fn shifter_code(&self, mode: ShifterMode) -> Option<u32> {
+        self.sh_codes.iter().find(|&&(_, m)| m == mode).map(|&(c, _)| c)
+    }
+
+    pub fn alu_code(&self, mode: AluMode) -> Option<u32> {
+        self.alu_codes.iter().find(|&&(_, m)| m == mode).map(|&(c, _)| c)
+    }
+
+    pub fn cond_code(&self, mode: CondMode) -> Option<u32> {
+        self.cond_codes.iter().find(|&&(_, m)| m == mode).map(|&(c, _)| c)
+    }
+}
+
+/// Entry point of `micro16 format`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    let mut format = None;
+    let mut rest = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--format" => format = Some(iter.next().ok_or(USAGE)?.clone()),
+            _ => rest.push(arg.as_str()),
+        }
+    }
+    let format = match format {
+        Some(path) => Format::load(&path)?,
+        None => Format::micro16(),
+    };
+    // Formats that do not fit the Micro16 datapath still encode and decode
+    // field by field, they just have no disassembly.
+    let layout = Layout::new(format.clone()).ok().map(Arc::new);
+
+    match rest.split_first() {
+        None => {
+            print!("{}", format);
+            Ok(())
+        }
+        Some((&"decode", words)) if !words.is_empty() => {
+            for word in words {
+                let raw = u32::from_str_radix(word.trim_start_matches("0x"), 16)
+                    .map_err(|_| format!("invalid word '{}'", word))?;
+                let fields = format.decode(raw).map(|_| format.describe(raw));
+                match (fields, &layout) {
+                    (Ok(fields), Some(layout)) => {
+                        let text = disassemble(&Instruction::with_layout(raw, layout.clone()));
+                        println!("{:08x}  {:<40} # {}", raw, text, fields)
+                    }
+                    (Ok(fields), None) => println!("{:08x}  {}", raw, fields),
+                    (Err(e), _) => println!("{:08x}  invalid: {}", raw, e),
+                }
+            }
+            Ok(())
+        }
+        Some((&"encode", values)) if !values.is_empty() => {
+            let values = values.iter()
+                .map(|v| {
+                    v.split_once('=').ok_or_else(|| format!("expected FIELD=VALUE, got '{}'", v))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            println!("{:08x}", format.encode(&values)?);
+            Ok(())
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble_with;
+    use cpu::Cpu;
+
+    const SHIFTS: [ShifterMode; 4] =
+        [ShifterMode::NoOp, ShifterMode::Left, ShifterMode::Right, ShifterMode::Rotate];
+
+    /// Micro16 with `field` holding the codes `codes` instead of its own.
+    fn with_codes(field: &str, codes: &[(&str, u32)]) -> Format {
+        let mut format = Format::micro16();
+        let f = format.fields.iter_mut().find(|f| f.name == field).unwrap();
+        *f = Field::coded(field, f.lo, f.hi, codes);
+        format
+    }
+
+    #[test]
+    fn every_mode_combination_decodes_to_itself() {
+        let layout = Layout::micro16x();
+        for &sh in &SHIFTS {
+            for alu in (0..8).map(|code| AluMode::from_u8(code).unwrap()) {
+                for cond in (0..7).map(|code| CondMode::from_u8(code).unwrap()) {
+                    let mut instr = Instruction::with_layout(0, layout.clone());
+                    instr.set_s_bus(5);
+                    instr.set_a_bus(6);
+                    instr.set_sh(sh);
+                    instr.set_alu(alu);
+                    instr.set_cond(cond);
+                    assert_eq!(instr.invalid_field(), None);
+                    assert_eq!(instr.modes(), Ok((sh, alu, cond)));
+                    assert!(instr.ens() && !instr.a_mux() && !instr.call());
+                    assert_eq!((instr.s_bus(), instr.a_bus()), (5, 6));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn the_codes_decide_the_profile() {
+        assert_eq!(Layout::micro16().profile(), Profile::Classic);
+        assert_eq!(Layout::micro16x().profile(), Profile::Extended);
+        assert_eq!(Layout::micro16x().call_code(), None);
+
+        // Some of the extended modes are not enough.
+        let format = with_codes("sh", &[("noop", 0), ("left", 1), ("right", 2), ("rol", 3)]);
+        let err = Layout::new(format).unwrap_err();
+        assert!(err.contains("lacks modes of the extended profile"), "{}", err);
+
+        let err = Layout::micro16x().check_profile(Profile::Classic).unwrap_err();
+        assert_eq!(err, "format micro16x encodes the extended profile, not the classic one");
+        assert!(Layout::micro16().check_profile(Profile::Classic).is_ok());
+    }
+
+    #[test]
+    fn extended_modes_need_a_format_of_the_extended_profile() {
+        let err = assemble_with("R2 <- R0 - R1", &Layout::micro16()).unwrap_err();
+        assert!(err.message.contains("sub needs a format of the extended profile"),
+                "{}",
+                err.message);
+
+        let program = assemble_with("R2 <- R0 - R1", &Layout::micro16x()).unwrap();
+        let mut cpu = Cpu::new(&program);
+        cpu.set_profile(Profile::Extended);
+        assert_eq!(cpu.layout(), &Layout::micro16x());
+        cpu.registers_mut().set(4, 7);
+        cpu.registers_mut().set(5, 2);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers().get(6), 5);
+
+        // Setting a layout sets its profile too.
+        let mut cpu = Cpu::new(&program);
+        cpu.set_layout(Layout::micro16x());
+        assert_eq!(cpu.profile(), Profile::Extended);
+    }
+
+    #[test]
+    fn descriptions_round_trip() {
+        for format in &[Format::micro16(), Format::micro16x()] {
+            assert_eq!(&Format::parse(&format.to_string()).unwrap(), format);
+        }
+    }
+}
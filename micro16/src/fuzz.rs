@@ -0,0 +1,392 @@
+//! Random program generation and differential fuzzing of `Cpu` against the
+//! independent `reference` model.
+//!
+//! Generated programs are well-formed: they never write the constant
+//! registers, never use the reserved shifter encoding and issue memory
+//! accesses as pairs of consecutive `rd` or `wr` words.
+
+use std::panic::{self, AssertUnwindSafe};
+
+use cli;
+use cpu::{AluMode, CondMode, Cpu, ShifterMode, MAR, MBR};
+use disasm::{disassemble, register_name};
+use grade::panic_message;
+use instruction::Instruction;
+use reference::Reference;
+use rng::Rng;
+
+const DEFAULT_ITERATIONS: u64 = 10_000;
+const DEFAULT_LENGTH: u64 = 16;
+const DEFAULT_CYCLES: u64 = 1_000;
+
+pub const USAGE: &str = "Usage: micro16 fuzz [--seed N] [--iterations N] [--length N] \
+                         [--cycles N]";
+
+/// Field groups that minimization tries to clear, one at a time: cond and
+/// addr, ms and rd_wr, mar, mbr, ens and s_bus, sh, alu, a_mux, a_bus and
+/// b_bus.
+const FIELD_MASKS: [u32; 10] = [(3 << 29) | 0xff,
+                                3 << 21,
+                                1 << 23,
+                                1 << 24,
+                                (1 << 20) | (0xf << 16),
+                                3 << 25,
+                                3 << 27,
+                                1 << 31,
+                                0xf << 8,
+                                0xf << 12];
+
+/// The classic modes the generator picks from, and the conditions of jumps.
+const ALU_MODES: [AluMode; 4] = [AluMode::NoOp, AluMode::Add, AluMode::BitAnd, AluMode::BitNot];
+const SHIFTER_MODES: [ShifterMode; 3] = [ShifterMode::NoOp, ShifterMode::Left, ShifterMode::Right];
+const JUMPS: [CondMode; 3] = [CondMode::IfNegative, CondMode::IfZero, CondMode::GoTo];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuzzCase {
+    pub program: Vec<u32>,
+    /// Initial values of R0 to R10.
+    pub init: [i16; 11],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub cycle: u64,
+    pub differences: Vec<String>,
+}
+
+fn datapath_op(rng: &mut Rng) -> Instruction {
+    let mut instr = Instruction::new(0);
+    instr.set_a_bus(rng.below(16) as u8);
+    instr.set_b_bus(rng.below(16) as u8);
+    instr.set_alu(ALU_MODES[rng.below(ALU_MODES.len() as u64) as usize]);
+    instr.set_sh(SHIFTER_MODES[rng.below(SHIFTER_MODES.len() as u64) as usize]);
+    instr.set_a_mux(rng.chance(1, 10));
+    if rng.chance(1, 2) {
+        instr.set_ens(true);
+        instr.set_s_bus(3 + rng.below(13) as u8);
+    }
+    instr.set_mar(rng.chance(1, 5));
+    instr.set_mbr(rng.chance(1, 6));
+    instr
+}
+
+fn maybe_jump(rng: &mut Rng, instr: &mut Instruction, len: u64) {
+    if rng.chance(1, 5) {
+        instr.set_cond(JUMPS[rng.below(JUMPS.len() as u64) as usize]);
+        instr.set_addr(rng.below(len) as u8);
+    }
+}
+
+/// Generates a random well-formed program of `len` words.
+pub fn generate(rng: &mut Rng, len: u64) -> Vec<u32> {
+    let mut program = Vec::new();
+    while (program.len() as u64) < len {
+        let mut first = datapath_op(rng);
+        if (program.len() as u64) + 2 <= len && rng.chance(1, 4) {
+            let read = rng.chance(1, 2);
+            let mut second = datapath_op(rng);
+            for instr in [&mut first, &mut second] {
+                instr.set_ms(true);
+                instr.set_rd_wr(read);
+                if read {
+                    // Keep MBR free for the value being read.
+                    instr.set_mbr(false);
+                    if instr.s_bus() == 15 {
+                        instr.set_ens(false);
+                    }
+                }
+            }
+            // The handshake needs a stable address and, for writes, data.
+            second.set_mar(false);
+            second.set_mbr(false);
+            if second.s_bus() == MAR || second.s_bus() == MBR {
+                second.set_ens(false);
+            }
+            maybe_jump(rng, &mut second, len);
+            program.push(first.raw());
+            program.push(second.raw());
+        } else {
+            maybe_jump(rng, &mut first, len);
+            program.push(first.raw());
+        }
+    }
+    program
+}
+
+pub fn random_case(rng: &mut Rng, len: u64) -> FuzzCase {
+    let program = generate(rng, len);
+    let mut init = [0; 11];
+    for r in init.iter_mut() {
+        *r = rng.next_i16();
+    }
+    FuzzCase { program, init }
+}
+
+fn differences(cpu: &Cpu, reference: &Reference) -> Vec<String> {
+    let mut diffs = Vec::new();
+    for idx in 0..16 {
+        let actual = cpu.registers().get(idx);
+        let expected = reference.regs[idx as usize];
+        if actual != expected {
+            diffs.push(format!("{}: cpu {}, reference {}", register_name(idx), actual, expected));
+        }
+    }
+    let flags = [("N", cpu.negative_flag(), reference.n),
+                 ("Z", cpu.zero_flag(), reference.z),
+                 ("memory ready", cpu.memory().ready(), reference.ready)];
+    for &(name, actual, expected) in flags.iter() {
+        if actual != expected {
+            diffs.push(format!("{}: cpu {}, reference {}", name, actual, expected));
+        }
+    }
+    if cpu.program_counter() != reference.pc {
+        diffs.push(format!("pc: cpu {}, reference {}", cpu.program_counter(), reference.pc));
+    }
+
+    let mut cells: Vec<u16> = reference.memory.keys().cloned().collect();
+    cells.push(cpu.registers().get(3) as u16);
+    cells.sort();
+    cells.dedup();
+    for cell in cells {
+        let actual = cpu.memory().get(cell as usize);
+        let expected = reference.mem(cell);
+        if actual != expected {
+            diffs.push(format!("mem[{}]: cpu {}, reference {}", cell, actual, expected));
+        }
+    }
+    diffs
+}
+
+/// Runs a case through both models in lock step and returns the first
+/// cycle after which their states differ.
+pub fn compare(case: &FuzzCase, max_cycles: u64) -> Option<Divergence> {
+    let mut cpu = Cpu::new(&case.program);
+    let mut reference = Reference::new();
+    for (i, &value) in case.init.iter().enumerate() {
+        cpu.registers_mut().set(4 + i as u8, value);
+        reference.regs[4 + i] = value;
+    }
+
+    for cycle in 0..max_cycles {
+        let divergence = |differences| Some(Divergence { cycle, differences });
+        match (cpu.done(), reference.done(&case.program)) {
+            (true, true) => return None,
+            (false, false) => (),
+            (cpu_done, reference_done) => {
+                return divergence(vec![format!("done: cpu {}, reference {}",
+                                               cpu_done,
+                                               reference_done)])
+            }
+        }
+
+        let cpu_result = panic::catch_unwind(AssertUnwindSafe(|| cpu.step()));
+        let reference_result = reference.step(&case.program);
+        match (cpu_result, reference_result) {
+            (Err(payload), _) => {
+                return divergence(vec![format!("cpu panicked: {}", panic_message(payload))])
+            }
+            (Ok(Err(_)), Err(_)) => return None,
+            (Ok(Ok(())), Ok(())) => (),
+            (Ok(cpu_result), reference_result) => {
+                return divergence(vec![format!("result: cpu {:?}, reference {:?}",
+                                               cpu_result,
+                                               reference_result)])
+            }
+        }
+
+        let diffs = differences(&cpu, &reference);
+        if !diffs.is_empty() {
+            return divergence(diffs);
+        }
+    }
+    None
+}
+
+fn remove_word(case: &FuzzCase, idx: usize) -> FuzzCase {
+    let mut program = case.program.clone();
+    program.remove(idx);
+    for word in program.iter_mut() {
+        let mut instr = Instruction::new(*word);
+        if instr.cond() != Ok(CondMode::NoOp) && instr.addr() as usize > idx {
+            let addr = instr.addr() - 1;
+            instr.set_addr(addr);
+            *word = instr.raw();
+        }
+    }
+    FuzzCase { program, ..case.clone() }
+}
+
+/// Greedily shrinks a diverging case by dropping words, clearing fields and
+/// zeroing initial registers for as long as it keeps diverging.
+pub fn minimize(case: &FuzzCase, max_cycles: u64) -> FuzzCase {
+    let diverges = |c: &FuzzCase| compare(c, max_cycles).is_some();
+    let mut case = case.clone();
+    let mut progress = true;
+    while progress {
+        progress = false;
+
+        let mut i = 0;
+        while i < case.program.len() {
+            let candidate = remove_word(&case, i);
+            if diverges(&candidate) {
+                case = candidate;
+                progress = true;
+            } else {
+                i += 1;
+            }
+        }
+
+        for i in 0..case.program.len() {
+            for &mask in FIELD_MASKS.iter() {
+                if case.program[i] & mask == 0 {
+                    continue;
+                }
+                let mut candidate = case.clone();
+                candidate.program[i] &= !mask;
+                if diverges(&candidate) {
+                    case = candidate;
+                    progress = true;
+                }
+            }
+        }
+
+        for i in 0..case.init.len() {
+            if case.init[i] != 0 {
+                let mut candidate = case.clone();
+                candidate.init[i] = 0;
+                if diverges(&candidate) {
+                    case = candidate;
+                    progress = true;
+                }
+            }
+        }
+    }
+    case
+}
+
+/// Entry point of `micro16 fuzz`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    let mut seed = 0;
+    let mut iterations = DEFAULT_ITERATIONS;
+    let mut length = DEFAULT_LENGTH;
+    let mut cycles = DEFAULT_CYCLES;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--seed" => seed = cli::parse_arg(iter.next(), USAGE)?,
+            "--iterations" => iterations = cli::parse_arg(iter.next(), USAGE)?,
+            "--length" => length = cli::parse_arg::<u64>(iter.next(), USAGE)?.clamp(1, 256),
+            "--cycles" => cycles = cli::parse_arg(iter.next(), USAGE)?,
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    let mut rng = Rng::new(seed);
+    let mut found = None;
+    for iteration in 0..iterations {
+        let case = random_case(&mut rng, length);
+        if let Some(divergence) = compare(&case, cycles) {
+            found = Some((iteration, minimize(&case, cycles), divergence));
+            break;
+        }
+    }
+
+    let (iteration, case, original) = match found {
+        Some(found) => found,
+        None => {
+            println!("{} programs, no divergence (seed {})", iterations, seed);
+            return Ok(());
+        }
+    };
+
+    println!("seed {}, iteration {}: divergence at cycle {}",
+             seed,
+             iteration,
+             original.cycle);
+    for diff in &original.differences {
+        println!("  {}", diff);
+    }
+    let minimized = compare(&case, cycles).expect("minimized case no longer diverges");
+    println!("minimized to {} words, diverging at cycle {}:",
+             case.program.len(),
+             minimized.cycle);
+    for diff in &minimized.differences {
+        println!("  {}", diff);
+    }
+    let init: Vec<String> = case.init
+        .iter()
+        .enumerate()
+        .filter(|&(_, &v)| v != 0)
+        .map(|(i, v)| format!("R{}={}", i, v))
+        .collect();
+    if !init.is_empty() {
+        println!("; @init {}", init.join(" "));
+    }
+    for (addr, &word) in case.program.iter().enumerate() {
+        println!("{:<40} # {:3}: {:08x}", disassemble(&Instruction::new(word)), addr, word);
+    }
+    Err("divergence found".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_programs_are_well_formed() {
+        let mut rng = Rng::new(7);
+        for len in 1..40 {
+            let program = generate(&mut rng, len);
+            assert_eq!(program.len() as u64, len);
+            let mut i = 0;
+            while i < program.len() {
+                let instr = Instruction::new(program[i]);
+                assert!(!instr.ens() || instr.s_bus() >= 3, "{:08x}", program[i]);
+                assert!(instr.has_valid_sh(), "{:08x}", program[i]);
+                if instr.cond() != Ok(CondMode::NoOp) {
+                    assert!((instr.addr() as u64) < len);
+                }
+                if instr.ms() {
+                    // Accesses come in pairs of the same kind.
+                    let second = Instruction::new(program[i + 1]);
+                    assert!(second.ms() && second.rd_wr() == instr.rd_wr());
+                    assert!(!second.mar());
+                    i += 1;
+                }
+                i += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn generation_follows_the_seed() {
+        let case = random_case(&mut Rng::new(3), 16);
+        assert_eq!(random_case(&mut Rng::new(3), 16), case);
+        assert_ne!(random_case(&mut Rng::new(4), 16), case);
+    }
+
+    #[test]
+    fn cpu_and_reference_agree() {
+        let mut rng = Rng::new(11);
+        for _ in 0..200 {
+            let case = random_case(&mut rng, 24);
+            assert_eq!(compare(&case, 500), None, "{:x?}", case);
+        }
+    }
+
+    #[test]
+    fn removing_a_word_retargets_later_jumps() {
+        let jump = |addr| {
+            let mut instr = Instruction::new(0);
+            instr.set_cond(CondMode::GoTo);
+            instr.set_addr(addr);
+            instr.raw()
+        };
+        let case = FuzzCase {
+            program: vec![jump(3), jump(1), 0, jump(0)],
+            init: [0; 11],
+        };
+        assert_eq!(remove_word(&case, 1).program, vec![jump(2), 0, jump(0)]);
+    }
+}
@@ -0,0 +1,793 @@
+//! GDB remote serial protocol stub.
+//!
+//! `micro16 gdb` listens on a local TCP port and serves one debugger
+//! session, e.g. `target remote :1234` from GDB. The stub describes its
+//! registers with a target description, in this order for the Micro16:
+//!
+//! ```text
+//! r0 .. r10   R0 .. R10       16 bits
+//! mar, mbr    MAR and MBR     16 bits
+//! n, z, c, v  the ALU flags   16 bits, 0 or 1
+//! pc          program counter 16 bits, a control store address
+//! ```
+//!
+//! With `--machine mic1` the registers are those of `mic1::REGISTER_NAMES`,
+//! the flags `n` and `z` and `mpc`, all 32 bits wide.
+//!
+//! Memory is word addressed while GDB counts bytes, so byte address `2 * i`
+//! is the low byte of memory cell `i` and `2 * i + 1` its high byte.
+//! Breakpoints (`Z0`, `Z1`) take control store addresses and stop before the
+//! instruction executes. Watchpoints (`Z2` to `Z4`) take byte addresses and
+//! stop after the cycle that completes a matching memory transfer. Execution
+//! always advances by whole cycles.
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+
+use arch::{self, Access, Core, Info};
+use cli::{self, MachineOptions};
+use cpu::Memory;
+use loader;
+use machine::Machine;
+use mic1;
+
+const DEFAULT_PORT: u16 = 1234;
+const PACKET_SIZE: usize = 0x1000;
+/// Cycles between checks for an interrupt from the debugger while running.
+const POLL_INTERVAL: u64 = 4096;
+
+pub const USAGE: &str = "Usage: micro16 gdb --program FILE [--port N] [--init ASSIGNMENTS] \
+                         [--map-control-store BASE] [--call-stack DEPTH]
+       micro16 gdb --machine mic1 [--program MICROPROGRAM] [--ijvm FILE] [--port N]";
+
+/// The target description of a machine: its debugger registers, the flags
+/// and the control store address.
+fn target_xml(info: &Info) -> String {
+    let bits = info.register_bits;
+    let mut xml = String::from(concat!("<?xml version=\"1.0\"?>\n",
+                                       "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+                                       "<target version=\"1.0\">\n"));
+    xml.push_str(&format!("  <feature name=\"org.{}.core\">\n", info.name));
+    let mut reg = |name: &str, kind: &str| {
+        xml.push_str(&format!("    <reg name=\"{}\" bitsize=\"{}\" type=\"{}\"/>\n",
+                              name.to_lowercase(),
+                              bits,
+                              kind));
+    };
+    for &idx in info.debug_registers {
+        let name = info.registers[idx as usize];
+        let kind = if name == "MAR" { "data_ptr".to_string() } else { format!("int{}", bits) };
+        reg(name, &kind);
+    }
+    for flag in info.flags {
+        reg(flag, &format!("uint{}", bits));
+    }
+    reg(info.pc_name, "code_ptr");
+    xml.push_str("  </feature>\n</target>\n");
+    xml
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, write: bool) -> bool {
+        match self {
+            WatchKind::Write => write,
+            WatchKind::Read => !write,
+            WatchKind::Access => true,
+        }
+    }
+
+    fn reason(self) -> &'static str {
+        match self {
+            WatchKind::Write => "watch",
+            WatchKind::Read => "rwatch",
+            WatchKind::Access => "awatch",
+        }
+    }
+}
+
+/// A watchpoint over the memory cells `first..=last`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub first: u16,
+    pub last: u16,
+}
+
+/// Why execution stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    /// A single step completed or a breakpoint was reached.
+    Trap,
+    Watch { kind: WatchKind, cell: u16 },
+    Interrupted,
+    /// The program counter left the program.
+    Exited,
+    Error(String),
+}
+
+/// The debugger's view of a machine: breakpoints, watchpoints and the
+/// protocol requests that operate on them, independent of the transport.
+pub struct Target<C: Core = Machine> {
+    pub core: C,
+    pub breakpoints: BTreeSet<u16>,
+    pub watchpoints: Vec<Watchpoint>,
+    /// Reported again when the debugger asks why the target halted.
+    pub last_stop: Stop,
+    info: &'static Info,
+}
+
+impl<C: Core> Target<C> {
+    pub fn new(core: C) -> Target<C> {
+        Target {
+            info: core.info(),
+            core,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            last_stop: Stop::Trap,
+        }
+    }
+
+    fn register_count(&self) -> usize {
+        self.info.debug_registers.len() + self.info.flags.len() + 1
+    }
+
+    /// Hex digits of a register value in a packet.
+    fn register_digits(&self) -> usize {
+        self.info.register_bits as usize / 4
+    }
+
+    fn register(&self, idx: usize) -> u32 {
+        let flags = self.info.debug_registers.len();
+        let pc = flags + self.info.flags.len();
+        match idx {
+            _ if idx < flags => self.core.register(self.info.debug_registers[idx]) as u32,
+            _ if idx < pc => (self.core.flags() >> (idx - flags) & 1) as u32,
+            _ => self.core.program_counter() as u32,
+        }
+    }
+
+    fn set_register(&mut self, idx: usize, value: u32) -> Result<(), String> {
+        let flags = self.info.debug_registers.len();
+        let pc = flags + self.info.flags.len();
+        let ok = match idx {
+            _ if idx < flags => {
+                self.core.set_register(self.info.debug_registers[idx], value as i32)
+            }
+            _ if idx < pc => {
+                let bit = 1 << (idx - flags);
+                let others = self.core.flags() & !bit;
+                self.core.set_flags(if value != 0 { others | bit } else { others });
+                true
+            }
+            _ if idx == pc && value <= u16::MAX as u32 => {
+                self.core.set_program_counter(value as u16)
+            }
+            _ => false,
+        };
+        if !ok {
+            return Err(format!("cannot set register {} to {}", idx, value));
+        }
+        Ok(())
+    }
+
+    fn hex_register(&self, value: u32) -> String {
+        let bytes = value.to_le_bytes();
+        bytes[..self.register_digits() / 2].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn parse_register(&self, text: &str) -> Option<u32> {
+        let bytes = parse_hex_bytes(text)?;
+        if bytes.len() * 2 != self.register_digits() {
+            return None;
+        }
+        Some(bytes.iter().rev().fold(0, |value, &b| value << 8 | b as u32))
+    }
+
+    fn read_byte(&self, addr: u32) -> u8 {
+        let cell = self.core.cell((addr / 2) as usize) as u16;
+        if addr.is_multiple_of(2) {
+            cell as u8
+        } else {
+            (cell >> 8) as u8
+        }
+    }
+
+    fn write_byte(&mut self, addr: u32, value: u8) {
+        let idx = (addr / 2) as usize;
+        let cell = self.core.cell(idx) as u16;
+        let cell = if addr.is_multiple_of(2) {
+            (cell & 0xff00) | value as u16
+        } else {
+            (cell & 0x00ff) | ((value as u16) << 8)
+        };
+        self.core.set_cell(idx, cell as i16);
+    }
+
+    /// The watchpoint hit by the transfers that `cycle` completed, if any.
+    fn watch_hit(&self, cycle: &arch::Cycle) -> Option<Stop> {
+        for op in cycle.memory.iter().filter(|op| op.completed) {
+            let (first, last) = self.info.cells(op);
+            let write = op.access == Access::Write;
+            let hit = self.watchpoints.iter().find(|w| {
+                w.first as u32 <= last && first <= w.last as u32 && w.kind.matches(write)
+            });
+            if let Some(w) = hit {
+                let cell = first.max(w.first as u32) as u16;
+                return Some(Stop::Watch { kind: w.kind, cell });
+            }
+        }
+        None
+    }
+
+    /// Executes cycles until something stops execution. `interrupted` is
+    /// polled every `POLL_INTERVAL` cycles.
+    pub fn resume<F: FnMut() -> bool>(&mut self, single_step: bool, interrupted: F) -> Stop {
+        let stop = self.run(single_step, interrupted);
+        self.last_stop = stop.clone();
+        stop
+    }
+
+    fn run<F: FnMut() -> bool>(&mut self, single_step: bool, mut interrupted: F) -> Stop {
+        let mut cycles = 0u64;
+        loop {
+            if self.core.done() {
+                return Stop::Exited;
+            }
+            let cycle = match self.core.cycle() {
+                Ok(cycle) => cycle,
+                Err(e) => return Stop::Error(e),
+            };
+            cycles += 1;
+            if let Some(stop) = self.watch_hit(&cycle) {
+                return stop;
+            }
+            if self.core.done() {
+                return Stop::Exited;
+            }
+            if single_step || self.breakpoints.contains(&self.core.program_counter()) {
+                return Stop::Trap;
+            }
+            if cycles.is_multiple_of(POLL_INTERVAL) && interrupted() {
+                return Stop::Interrupted;
+            }
+        }
+    }
+
+    /// Answers a request that does not resume execution. `None` means the
+    /// request is not supported, which the protocol answers with an empty
+    /// packet.
+    pub fn query(&mut self, packet: &str) -> Option<String> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => stop_reply(&self.last_stop),
+            Some(b'g') => {
+                (0..self.register_count()).map(|i| self.hex_register(self.register(i))).collect()
+            }
+            Some(b'G') => {
+                let values = &packet[1..];
+                let digits = self.register_digits();
+                if values.len() != self.register_count() * digits {
+                    return Some("E01".to_string());
+                }
+                for i in 0..self.register_count() {
+                    let value = match self.parse_register(&values[i * digits..(i + 1) * digits]) {
+                        Some(value) => value,
+                        None => return Some("E01".to_string()),
+                    };
+                    if self.set_register(i, value).is_err() {
+                        return Some("E01".to_string());
+                    }
+                }
+                "OK".to_string()
+            }
+            Some(b'p') => {
+                match usize::from_str_radix(&packet[1..], 16) {
+                    Ok(idx) if idx < self.register_count() => {
+                        self.hex_register(self.register(idx))
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            Some(b'P') => {
+                let parsed = packet[1..].split_once('=').and_then(|(idx, value)| {
+                    Some((usize::from_str_radix(idx, 16).ok()?, self.parse_register(value)?))
+                });
+                match parsed {
+                    Some((idx, value)) if self.set_register(idx, value).is_ok() => {
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            Some(b'm') => {
+                match parse_range(&packet[1..]) {
+                    Some((addr, len)) => {
+                        (addr..addr + len).map(|a| format!("{:02x}", self.read_byte(a))).collect()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            Some(b'M') => {
+                let parsed = packet[1..].split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_range(range)?;
+                    let bytes = parse_hex_bytes(data)?;
+                    if bytes.len() == len as usize {
+                        Some((addr, bytes))
+                    } else {
+                        None
+                    }
+                });
+                match parsed {
+                    Some((addr, bytes)) => {
+                        for (i, byte) in bytes.into_iter().enumerate() {
+                            self.write_byte(addr + i as u32, byte);
+                        }
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            Some(b'Z') | Some(b'z') => self.change_point(packet),
+            Some(b'H') => "OK".to_string(),
+            Some(b'T') => "OK".to_string(),
+            Some(b'q') | Some(b'Q') => return self.general_query(packet),
+            _ => return None,
+        };
+        Some(reply)
+    }
+
+    fn change_point(&mut self, packet: &str) -> String {
+        let insert = packet.starts_with('Z');
+        let mut fields = packet[1..].split(',');
+        let kind = fields.next();
+        let addr = fields.next().and_then(|a| u32::from_str_radix(a, 16).ok());
+        let len = fields.next().and_then(|k| u32::from_str_radix(k, 16).ok());
+        let (addr, len) = match (addr, len) {
+            (Some(addr), Some(len)) => (addr, len),
+            _ => return "E01".to_string(),
+        };
+        let watch = match kind {
+            Some("0") | Some("1") => {
+                if addr as usize >= self.info.control_store_length {
+                    return "E22".to_string();
+                }
+                if insert {
+                    self.breakpoints.insert(addr as u16);
+                } else {
+                    self.breakpoints.remove(&(addr as u16));
+                }
+                return "OK".to_string();
+            }
+            Some("2") => WatchKind::Write,
+            Some("3") => WatchKind::Read,
+            Some("4") => WatchKind::Access,
+            _ => return String::new(),
+        };
+        if len == 0 || addr + len > 0x20000 {
+            return "E22".to_string();
+        }
+        let point = Watchpoint {
+            kind: watch,
+            first: (addr / 2) as u16,
+            last: ((addr + len - 1) / 2) as u16,
+        };
+        if insert {
+            self.watchpoints.push(point);
+        } else if let Some(i) = self.watchpoints.iter().position(|w| *w == point) {
+            self.watchpoints.remove(i);
+        }
+        "OK".to_string()
+    }
+
+    fn general_query(&self, packet: &str) -> Option<String> {
+        let reply = if packet.starts_with("qSupported") {
+            format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE)
+        } else if let Some(request) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_range(request) {
+                Some((offset, len)) => {
+                    let xml = target_xml(self.info);
+                    let data = xml.as_bytes();
+                    let start = (offset as usize).min(data.len());
+                    let end = (start + len as usize).min(data.len());
+                    let chunk = String::from_utf8_lossy(&data[start..end]);
+                    format!("{}{}", if end == data.len() { 'l' } else { 'm' }, escape(&chunk))
+                }
+                None => "E01".to_string(),
+            }
+        } else if packet == "QStartNoAckMode" {
+            "OK".to_string()
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else if packet.starts_with("qSymbol") {
+            "OK".to_string()
+        } else {
+            return None;
+        };
+        Some(reply)
+    }
+}
+
+/// The stop reply packet for `stop`.
+pub fn stop_reply(stop: &Stop) -> String {
+    match *stop {
+        Stop::Trap => "S05".to_string(),
+        Stop::Watch { kind, cell } => {
+            format!("T05{}:{:x};", kind.reason(), cell as u32 * 2)
+        }
+        Stop::Interrupted => "S02".to_string(),
+        Stop::Exited => "W00".to_string(),
+        Stop::Error(_) => "S04".to_string(),
+    }
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Parses `ADDR,LENGTH` and rejects ranges past the 128 KiB address space.
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let (addr, len) = text.split_once(',')?;
+    let addr = u32::from_str_radix(addr, 16).ok()?;
+    let len = u32::from_str_radix(len, 16).ok()?;
+    if addr.checked_add(len)? > 0x20000 {
+        return None;
+    }
+    Some((addr, len))
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        if "#$}*".contains(c) {
+            out.push('}');
+            out.push((c as u8 ^ 0x20) as char);
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+enum Input {
+    Packet(String),
+    Interrupt,
+    Closed,
+}
+
+/// The packet layer: framing, checksums and acknowledgements.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    ack: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Connection> {
+        Ok(Connection {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+            ack: true,
+        })
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let byte = match self.reader.fill_buf()?.first() {
+            Some(&b) => b,
+            None => return Ok(None),
+        };
+        self.reader.consume(1);
+        Ok(Some(byte))
+    }
+
+    fn receive(&mut self) -> io::Result<Input> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(Input::Closed),
+                Some(0x03) => return Ok(Input::Interrupt),
+                Some(b'$') => (),
+                // Acknowledgements of our replies, and noise between packets.
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(Input::Closed),
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                }
+            }
+            let mut sum = [0u8; 2];
+            for digit in sum.iter_mut() {
+                *digit = self.read_byte()?.unwrap_or(0);
+            }
+            let valid = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok()) == Some(checksum(&data));
+            if self.ack {
+                self.writer.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Input::Packet(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.writer.write_all(packet.as_bytes())?;
+        self.writer.flush()
+    }
+
+    /// Whether the debugger sent an interrupt, without blocking.
+    fn interrupted(&mut self) -> bool {
+        if self.reader.get_ref().set_nonblocking(true).is_err() {
+            return false;
+        }
+        let pending = match self.reader.fill_buf() {
+            Ok(buf) => buf.iter().position(|&b| b == 0x03),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => None,
+            Err(_) => None,
+        };
+        let _ = self.reader.get_ref().set_nonblocking(false);
+        match pending {
+            Some(i) => {
+                self.reader.consume(i + 1);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Serves one debugger session until it detaches, kills the target or
+/// disconnects.
+pub fn serve<C: Core>(stream: TcpStream, target: &mut Target<C>) -> io::Result<()> {
+    let mut conn = Connection::new(stream)?;
+    loop {
+        let packet = match conn.receive()? {
+            Input::Packet(packet) => packet,
+            // Nothing runs between requests, so there is nothing to stop.
+            Input::Interrupt => {
+                conn.send("S02")?;
+                continue;
+            }
+            Input::Closed => return Ok(()),
+        };
+        match packet.as_bytes().first() {
+            Some(b'c') | Some(b's') => {
+                let stop = if packet.len() > 1 {
+                    Err("E01")
+                } else {
+                    Ok(target.resume(packet == "s", || conn.interrupted()))
+                };
+                match stop {
+                    Ok(Stop::Error(e)) => {
+                        let message = format!("{}: {}\n", target.info.name, e);
+                        let hex: String = message.bytes().map(|b| format!("{:02x}", b)).collect();
+                        conn.send(&format!("O{}", hex))?;
+                        conn.send(&stop_reply(&Stop::Error(e)))?;
+                    }
+                    Ok(stop) => conn.send(&stop_reply(&stop))?,
+                    Err(e) => conn.send(e)?,
+                }
+            }
+            Some(b'D') => {
+                conn.send("OK")?;
+                return Ok(());
+            }
+            Some(b'k') => return Ok(()),
+            _ => {
+                let reply = target.query(&packet).unwrap_or_default();
+                conn.send(&reply)?;
+                if packet == "QStartNoAckMode" {
+                    conn.ack = false;
+                }
+            }
+        }
+    }
+}
+
+/// Entry point of `micro16 gdb`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    let mut machine = arch::MICRO16.name.to_string();
+    let mut program = None;
+    let mut ijvm = None;
+    let mut port = DEFAULT_PORT;
+    let mut options = MachineOptions::default();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--machine" => machine = iter.next().ok_or(USAGE)?.clone(),
+            "--program" => program = Some(iter.next().ok_or(USAGE)?.clone()),
+            "--ijvm" => ijvm = Some(iter.next().ok_or(USAGE)?.clone()),
+            "--port" => port = cli::parse_arg(iter.next(), USAGE)?,
+            _ if options.parse(arg, &mut iter, USAGE)? => {}
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    if machine == mic1::INFO.name {
+        if !options.is_empty() {
+            return Err(USAGE.to_string());
+        }
+        let mic1 = mic1::load(program.as_deref(), ijvm.as_deref())?;
+        return listen(Target::new(mic1), port);
+    }
+    if machine != arch::MICRO16.name || ijvm.is_some() {
+        return Err(USAGE.to_string());
+    }
+    let program = loader::read_program(&program.ok_or(USAGE)?)?;
+    listen(Target::new(options.machine(&program, Memory::new())?), port)
+}
+
+fn listen<C: Core>(mut target: Target<C>, port: u16) -> Result<(), String> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+    println!("listening on 127.0.0.1:{}", port);
+    let (stream, peer) = listener.accept().map_err(|e| e.to_string())?;
+    println!("debugger connected from {}", peer);
+    serve(stream, &mut target).map_err(|e| e.to_string())?;
+    println!("debugger disconnected");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arch::Core;
+    use cpu::Cpu;
+
+    // MBR <- -1; MAR <- 1; wr; wr; (empty)
+    const STORE: [u32; 5] = [0x01000200, 0x00801000, 0x00200000, 0x00200000, 0x00000000];
+
+    fn target(program: &[u32]) -> Target<Cpu> {
+        Target::new(Cpu::new(program))
+    }
+
+    fn query(target: &mut Target<Cpu>, packet: &str) -> String {
+        target.query(packet).expect("the packet is supported")
+    }
+
+    #[test]
+    fn g_reads_all_registers_little_endian() {
+        let mut target = target(&STORE);
+        target.core.set_register(4, 0x1234);
+        target.core.set_register(15, -2);
+        Core::set_flags(&mut target.core, 0b0101);
+        let registers = query(&mut target, "g");
+        // R0 .. R10, MAR, MBR, n, z, c, v and pc, four digits each.
+        assert_eq!(registers.len(), 18 * 4);
+        assert_eq!(&registers[..4], "3412");
+        assert_eq!(&registers[12 * 4..13 * 4], "feff");
+        assert_eq!(&registers[13 * 4..], "01000000010000000000");
+    }
+
+    #[test]
+    fn big_g_writes_all_registers() {
+        let mut target = target(&STORE);
+        let mut values = String::new();
+        for i in 0..13 {
+            values.push_str(&format!("{:02x}00", i + 1));
+        }
+        // n = 0, z = 1, c = 0, v = 1, pc = 3
+        values.push_str("00000100000001000300");
+        assert_eq!(query(&mut target, &format!("G{}", values)), "OK");
+        assert_eq!(target.core.register(4), 1);
+        assert_eq!(target.core.register(3), 12);
+        assert_eq!(target.core.register(15), 13);
+        assert_eq!(target.core.flags(), 0b1010);
+        assert_eq!(target.core.program_counter(), 3);
+
+        assert_eq!(query(&mut target, "G0000"), "E01");
+    }
+
+    #[test]
+    fn p_and_big_p_access_single_registers() {
+        let mut target = target(&STORE);
+        assert_eq!(query(&mut target, "P1=7856"), "OK");
+        assert_eq!(target.core.register(5), 0x5678);
+        assert_eq!(query(&mut target, "p1"), "7856");
+
+        assert_eq!(query(&mut target, "P11=0200"), "OK");
+        assert_eq!(target.core.program_counter(), 2);
+        assert_eq!(query(&mut target, "p11"), "0200");
+
+        // The carry flag.
+        assert_eq!(query(&mut target, "Pf=0100"), "OK");
+        assert_eq!(target.core.flags(), 0b0100);
+        assert_eq!(query(&mut target, "pf"), "0100");
+
+        assert_eq!(query(&mut target, "p12"), "E01");
+        assert_eq!(query(&mut target, "P0=12"), "E01");
+    }
+
+    #[test]
+    fn m_and_big_m_map_bytes_to_word_halves() {
+        let mut target = target(&STORE);
+        target.core.set_cell(3, 0x1234);
+        assert_eq!(query(&mut target, "m6,2"), "3412");
+        assert_eq!(query(&mut target, "m7,1"), "12");
+        assert_eq!(query(&mut target, "m5,4"), "00341200");
+
+        assert_eq!(query(&mut target, "M8,2:cdab"), "OK");
+        assert_eq!(target.core.cell(4) as u16, 0xabcd);
+        assert_eq!(query(&mut target, "M9,1:ff"), "OK");
+        assert_eq!(target.core.cell(4) as u16, 0xffcd);
+
+        assert_eq!(query(&mut target, "M8,2:cd"), "E01");
+        assert_eq!(query(&mut target, "m1ffff,2"), "E01");
+    }
+
+    #[test]
+    fn breakpoints_stop_before_the_instruction() {
+        let mut target = target(&STORE);
+        assert_eq!(query(&mut target, "Z0,3,1"), "OK");
+        assert_eq!(target.resume(false, || false), Stop::Trap);
+        assert_eq!(target.core.program_counter(), 3);
+        assert_eq!(query(&mut target, "?"), "S05");
+
+        // Continuing from the breakpoint executes it.
+        assert_eq!(query(&mut target, "z0,3,1"), "OK");
+        assert_eq!(target.resume(false, || false), Stop::Exited);
+        assert_eq!(query(&mut target, "?"), "W00");
+
+        assert_eq!(query(&mut target, "Z0,100,1"), "E22");
+    }
+
+    #[test]
+    fn single_step_onto_a_breakpoint_stops_once() {
+        let mut target = target(&STORE);
+        query(&mut target, "Z0,1,1");
+        assert_eq!(target.resume(true, || false), Stop::Trap);
+        assert_eq!(target.core.program_counter(), 1);
+        assert_eq!(target.resume(true, || false), Stop::Trap);
+        assert_eq!(target.core.program_counter(), 2);
+        assert_eq!(target.resume(false, || false), Stop::Exited);
+    }
+
+    #[test]
+    fn write_watchpoints_stop_after_the_completed_transfer() {
+        let mut target = target(&STORE);
+        // Cell 1 is bytes 2 and 3.
+        assert_eq!(query(&mut target, "Z2,3,1"), "OK");
+        let stop = target.resume(false, || false);
+        assert_eq!(stop,
+                   Stop::Watch {
+                       kind: WatchKind::Write,
+                       cell: 1,
+                   });
+        assert_eq!(query(&mut target, "?"), "T05watch:2;");
+        assert_eq!(target.core.program_counter(), 4);
+        assert_eq!(query(&mut target, "m2,2"), "ffff");
+    }
+
+    #[test]
+    fn read_watchpoints_ignore_writes() {
+        let mut target = target(&STORE);
+        assert_eq!(query(&mut target, "Z3,2,2"), "OK");
+        assert_eq!(target.resume(false, || false), Stop::Exited);
+    }
+}
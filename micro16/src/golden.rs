@@ -0,0 +1,486 @@
+//! Golden-state tests: programs annotated with the state they must reach.
+//!
+//! ```text
+//! ; @init R0=5 mem[10]=3
+//! ; @expect R1=120 cycles<=200
+//! R1 <- 1
+//! ...
+//! ```
+//!
+//! `@init` and `@expect` take `state` assignments, `@expect` additionally
+//! accepts `cycles` bounds with `<`, `<=`, `=`, `>=` or `>` and the flags
+//! `N`, `Z`, `C` and `V` as 0 or 1, e.g. `C=1`. `@max_cycles`
+//! overrides how long a program may run before it counts as hung and
+//! `@map_control_store BASE` lets the program patch its own control store
+//! through memory, see `Cpu::map_control_store`. `@call_stack DEPTH` enables
+//! micro-calls with a return stack of `DEPTH` entries. `@profile extended`
+//! runs the Cpu with the extended profile, which decodes the program in the
+//! `micro16x` format. `@format NAME` decodes it in another format instead,
+//! whose profile the test then runs and `@profile` must agree with; as the
+//! program is decoded once, `@format` applies to the whole file and must
+//! come before the first `@case`.
+//! `@error TEXT` expects the run to stop with an error whose message
+//! contains `TEXT`; `@expect` then checks the state at the error. `.m16` and
+//! `.mc` sources as well as hex files may carry annotations; any other
+//! `@` word at the start of a comment is an error.
+//!
+//! `@case NAME` starts another test of the same program. Annotations before
+//! the first `@case` are shared by every case, the ones after it only apply
+//! to that case:
+//!
+//! ```text
+//! ; @max_cycles 500
+//! ; @case positive
+//! ; @init R0=7 R1=2
+//! ; @expect R2=3
+//! ; @case negative
+//! ; @init R0=-7 R1=2
+//! ; @expect R2=-3
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use cpu::Profile;
+use format::Layout;
+use loader;
+use machine::{self, Config, Machine, Status};
+use state::{self, Assignment};
+
+const DEFAULT_MAX_CYCLES: u64 = 100_000;
+const FLAGS: [&str; 4] = ["N", "Z", "C", "V"];
+/// Searched when `micro16 test` is given no paths: the regression tests and
+/// the routine library.
+const DEFAULT_PATHS: [&str; 2] = ["testdata", "stdlib"];
+
+pub const USAGE: &str = "Usage: micro16 test [PATH...]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessEqual,
+    Equal,
+    GreaterEqual,
+    Greater,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleBound {
+    pub comparison: Comparison,
+    pub value: u64,
+}
+
+impl CycleBound {
+    fn parse(text: &str) -> Result<CycleBound, String> {
+        let rest = &text["cycles".len()..];
+        let (comparison, value) = if let Some(v) = rest.strip_prefix("<=") {
+            (Comparison::LessEqual, v)
+        } else if let Some(v) = rest.strip_prefix(">=") {
+            (Comparison::GreaterEqual, v)
+        } else if let Some(v) = rest.strip_prefix('<') {
+            (Comparison::Less, v)
+        } else if let Some(v) = rest.strip_prefix('>') {
+            (Comparison::Greater, v)
+        } else if let Some(v) = rest.strip_prefix('=') {
+            (Comparison::Equal, v)
+        } else {
+            return Err(format!("invalid cycle bound '{}'", text));
+        };
+        let value = value.parse().map_err(|_| format!("invalid cycle bound '{}'", text))?;
+        Ok(CycleBound { comparison, value })
+    }
+
+    pub fn holds(&self, cycles: u64) -> bool {
+        match self.comparison {
+            Comparison::Less => cycles < self.value,
+            Comparison::LessEqual => cycles <= self.value,
+            Comparison::Equal => cycles == self.value,
+            Comparison::GreaterEqual => cycles >= self.value,
+            Comparison::Greater => cycles > self.value,
+        }
+    }
+
+    fn operator(&self) -> &'static str {
+        match self.comparison {
+            Comparison::Less => "<",
+            Comparison::LessEqual => "<=",
+            Comparison::Equal => "=",
+            Comparison::GreaterEqual => ">=",
+            Comparison::Greater => ">",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GoldenTest {
+    /// The `@case` name, if the file has several.
+    pub name: Option<String>,
+    pub program: Vec<u32>,
+    pub init: Vec<Assignment>,
+    pub expect: Vec<Assignment>,
+    pub cycles: Vec<CycleBound>,
+    /// Expected flags by name, one of `N`, `Z`, `C` and `V`.
+    pub flags: Vec<(&'static str, bool)>,
+    pub max_cycles: u64,
+    pub control_store_window: Option<u16>,
+    pub call_stack_depth: Option<usize>,
+    pub profile: Profile,
+    /// The `@format` of the file, if not the one of the profile.
+    pub layout: Option<Arc<Layout>>,
+    /// Part of the message of the error the run must stop with.
+    pub error: Option<String>,
+}
+
+impl GoldenTest {
+    /// Reads a test file, returning one test per `@case` or a single one for
+    /// files without cases. Files that carry no `@expect` yield no tests.
+    pub fn read(path: &str) -> Result<Vec<GoldenTest>, String> {
+        let text = loader::read_source(path)?;
+        let mut tests = vec![GoldenTest {
+            name: None,
+            program: Vec::new(),
+            init: Vec::new(),
+            expect: Vec::new(),
+            cycles: Vec::new(),
+            flags: Vec::new(),
+            max_cycles: DEFAULT_MAX_CYCLES,
+            control_store_window: None,
+            call_stack_depth: None,
+            profile: Profile::Classic,
+            layout: None,
+            error: None,
+        }];
+        let mut annotated = false;
+        let mut layout = None;
+        // Whether each test names its profile, which must then match the
+        // one of the format.
+        let mut profiled = vec![false];
+
+        for (i, line) in text.lines().enumerate() {
+            let annotation = match line.trim().strip_prefix(';') {
+                Some(rest) => rest.trim(),
+                None => continue,
+            };
+            if !annotation.starts_with('@') {
+                continue;
+            }
+            let (keyword, rest) = annotation.split_once(char::is_whitespace)
+                .unwrap_or((annotation, ""));
+            let err = |e: String| format!("{}: line {}: {}", path, i + 1, e);
+            let test = tests.last_mut().unwrap();
+            match keyword {
+                "@case" => {
+                    let name = rest.trim();
+                    if name.is_empty() {
+                        return Err(err("@case needs a name".to_string()));
+                    }
+                    let mut case = tests[0].clone();
+                    case.name = Some(name.to_string());
+                    tests.push(case);
+                    profiled.push(profiled[0]);
+                }
+                "@init" => test.init.extend(state::parse_assignments(rest).map_err(&err)?),
+                "@expect" => {
+                    annotated = true;
+                    for item in rest.split_whitespace() {
+                        if item.starts_with("cycles") {
+                            test.cycles.push(CycleBound::parse(item).map_err(&err)?);
+                        } else if let Some(flag) = parse_flag(item).map_err(&err)? {
+                            test.flags.push(flag);
+                        } else {
+                            test.expect.extend(state::parse_assignments(item).map_err(&err)?);
+                        }
+                    }
+                }
+                "@max_cycles" => {
+                    test.max_cycles = rest.trim()
+                        .parse()
+                        .map_err(|_| err(format!("invalid cycle count '{}'", rest.trim())))?;
+                }
+                "@map_control_store" => {
+                    let base = state::parse_value(rest.trim()).map_err(&err)?;
+                    test.control_store_window = Some(base as u16);
+                }
+                "@call_stack" => {
+                    let depth = rest.trim()
+                        .parse()
+                        .map_err(|_| err(format!("invalid stack depth '{}'", rest.trim())))?;
+                    test.call_stack_depth = Some(depth);
+                }
+                "@profile" => {
+                    test.profile = Profile::parse(rest.trim()).map_err(&err)?;
+                    *profiled.last_mut().unwrap() = true;
+                }
+                "@format" => {
+                    // The program is decoded once, so the format is one of
+                    // the file rather than of a case.
+                    if tests.len() > 1 {
+                        return Err(err("@format must come before the first @case".to_string()));
+                    }
+                    if layout.is_some() {
+                        return Err(err("the format is already given".to_string()));
+                    }
+                    layout = Some(Layout::load(rest.trim()).map_err(&err)?);
+                }
+                "@error" => {
+                    annotated = true;
+                    test.error = Some(rest.trim().to_string());
+                }
+                _ => return Err(err(format!("unknown annotation '{}'", keyword))),
+            }
+        }
+
+        if !annotated {
+            return Ok(Vec::new());
+        }
+        if tests.len() > 1 {
+            tests.remove(0);
+            profiled.remove(0);
+        }
+        for (test, profiled) in tests.iter_mut().zip(profiled) {
+            if let Some(ref layout) = layout {
+                if profiled {
+                    layout.check_profile(test.profile).map_err(|e| format!("{}: {}", path, e))?;
+                }
+                test.profile = layout.profile();
+            }
+            test.layout = layout.clone();
+            let layout = layout.clone().unwrap_or_else(|| Layout::for_profile(test.profile));
+            test.program = loader::read_program_with(path, &layout)?;
+        }
+        Ok(tests)
+    }
+}
+
+/// Parses a flag expectation like `C=1`, or returns `None` for other items.
+fn parse_flag(item: &str) -> Result<Option<(&'static str, bool)>, String> {
+    let (name, value) = match item.split_once('=') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    let name = match FLAGS.iter().find(|&&f| f == name) {
+        Some(&name) => name,
+        None => return Ok(None),
+    };
+    match value {
+        "0" => Ok(Some((name, false))),
+        "1" => Ok(Some((name, true))),
+        _ => Err(format!("flag {} must be 0 or 1, not '{}'", name, value)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Pass { cycles: u64 },
+    /// Expected and actual values of every mismatch.
+    Fail { cycles: u64, diff: Vec<(String, String)> },
+    Error(String),
+}
+
+pub fn run(test: &GoldenTest) -> Outcome {
+    let config = Config {
+        max_cycles: Some(test.max_cycles),
+        control_store_window: test.control_store_window,
+        call_stack_depth: test.call_stack_depth,
+        profile: test.profile,
+    };
+    let mut machine = match Machine::new(&test.program, config) {
+        Ok(machine) => machine,
+        Err(e) => return Outcome::Error(e.to_string()),
+    };
+    if let Some(ref layout) = test.layout {
+        machine.set_layout(layout.clone());
+    }
+    let init: Vec<machine::Assignment> = test.init.iter().map(|&a| a.into()).collect();
+    machine.apply(&init);
+
+    match (machine.run(), test.error.as_ref()) {
+        (Ok(Status::CycleLimit), _) => {
+            return Outcome::Error(format!("did not finish within {} cycles", test.max_cycles))
+        }
+        (Err(ref e), Some(expected)) if e.to_string().contains(expected.as_str()) => (),
+        (Err(e), _) => return Outcome::Error(format!("cycle {}: {}", machine.cycles(), e)),
+        (Ok(_), Some(expected)) => {
+            return Outcome::Error(format!("finished without the error '{}'", expected))
+        }
+        (Ok(_), None) => (),
+    }
+    let cycles = machine.cycles();
+
+    let mut diff = Vec::new();
+    for a in &test.expect {
+        let actual = machine.read(a.location.into());
+        if actual != a.value {
+            diff.push((a.to_string(), format!("{}={}", a.location, actual)));
+        }
+    }
+    let flags = machine.flags();
+    for &(name, expected) in &test.flags {
+        let actual = match name {
+            "N" => flags.negative,
+            "Z" => flags.zero,
+            "C" => flags.carry,
+            _ => flags.overflow,
+        };
+        if actual != expected {
+            diff.push((format!("{}={}", name, expected as u8),
+                       format!("{}={}", name, actual as u8)));
+        }
+    }
+    for bound in &test.cycles {
+        if !bound.holds(cycles) {
+            diff.push((format!("cycles{}{}", bound.operator(), bound.value),
+                       format!("cycles={}", cycles)));
+        }
+    }
+
+    if diff.is_empty() {
+        Outcome::Pass { cycles }
+    } else {
+        Outcome::Fail { cycles, diff }
+    }
+}
+
+fn collect(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    if path.is_dir() {
+        let entries = fs::read_dir(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut children: Vec<PathBuf> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+        children.sort();
+        for child in children {
+            collect(&child, files)?;
+        }
+    } else if path.extension().is_some_and(|ext| ext == "m16" || ext == "mc" || ext == "hex") {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+/// Finds all candidate test files below the given paths.
+pub fn discover(paths: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    for path in paths {
+        let path = Path::new(path);
+        if !path.exists() {
+            return Err(format!("{}: no such file or directory", path.display()));
+        }
+        collect(path, &mut files)?;
+    }
+    Ok(files)
+}
+
+/// Entry point of `micro16 test`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    if args.iter().any(|a| a.starts_with('-')) {
+        return Err(USAGE.to_string());
+    }
+    let paths = if args.is_empty() {
+        let mut paths: Vec<String> = DEFAULT_PATHS.iter()
+            .filter(|p| Path::new(p).exists())
+            .map(|p| p.to_string())
+            .collect();
+        if paths.is_empty() {
+            paths.push(DEFAULT_PATHS[0].to_string());
+        }
+        paths
+    } else {
+        args.to_vec()
+    };
+
+    let mut passed = 0;
+    let mut failed = 0;
+    for file in discover(&paths)? {
+        let tests = match GoldenTest::read(&file.to_string_lossy()) {
+            Ok(tests) => tests,
+            Err(e) => {
+                println!("FAIL {}\n     {}", file.display(), e);
+                failed += 1;
+                continue;
+            }
+        };
+        for test in tests {
+            let name = match test.name {
+                Some(ref case) => format!("{} [{}]", file.display(), case),
+                None => file.display().to_string(),
+            };
+            match run(&test) {
+                Outcome::Pass { cycles } => {
+                    println!("ok   {} ({} cycles)", name, cycles);
+                    passed += 1;
+                }
+                Outcome::Fail { cycles, diff } => {
+                    println!("FAIL {} ({} cycles)", name, cycles);
+                    for (expected, actual) in diff {
+                        println!("     - {}", expected);
+                        println!("     + {}", actual);
+                    }
+                    failed += 1;
+                }
+                Outcome::Error(e) => {
+                    println!("FAIL {}\n     {}", name, e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", passed, failed);
+    if failed > 0 {
+        Err(format!("{} test(s) failed", failed))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Writes `text` to a test file of its own and reads it back.
+    fn read(name: &str, text: &str) -> Result<Vec<GoldenTest>, String> {
+        let path = env::temp_dir().join(format!("micro16-golden-{}.m16", name));
+        fs::write(&path, text).unwrap();
+        let tests = GoldenTest::read(&path.to_string_lossy());
+        fs::remove_file(&path).unwrap();
+        tests.map_err(|e| e.rsplit(": line ").next().unwrap().to_string())
+    }
+
+    #[test]
+    fn cases_share_the_annotations_before_them() {
+        let tests = read("cases",
+                         "; @max_cycles 50\n; @init R0=1\n; @case one\n; @expect R1=1\n\
+                          ; @case two\n; @init R0=2\n; @expect R1=2\n; a comment\nR1 <- R0\n")
+            .unwrap();
+        assert_eq!(tests.len(), 2);
+        assert_eq!(tests[1].name.as_deref(), Some("two"));
+        assert_eq!(tests[1].init.len(), 2);
+        assert!(tests.iter().all(|t| t.max_cycles == 50 && t.program.len() == 1));
+        assert!(tests.iter().all(|t| run(t) == Outcome::Pass { cycles: 1 }));
+    }
+
+    #[test]
+    fn unknown_annotations_are_errors() {
+        assert_eq!(read("unknown", "; @expected R1=1\nR1 <- 1\n").unwrap_err(),
+                   "1: unknown annotation '@expected'");
+        assert_eq!(read("bare", "; @expect R1=1\n; @flaky\nR1 <- 1\n").unwrap_err(),
+                   "2: unknown annotation '@flaky'");
+        assert!(read("comment", "; expects @home\nR1 <- 1\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_format_is_one_of_the_file() {
+        let tests = read("format",
+                         "; @format micro16x\n; @case a\n; @expect R1=1\n; @case b\n\
+                          ; @expect R1=1\nR1 <- 1\n")
+            .unwrap();
+        assert!(tests.iter().all(|t| t.profile == Profile::Extended && t.layout.is_some()));
+        assert_eq!(read("late_format", "; @case a\n; @format micro16x\n; @expect R1=1\nR1 <- 1\n")
+                       .unwrap_err(),
+                   "2: @format must come before the first @case");
+        assert_eq!(read("two_formats",
+                        "; @format micro16x\n; @format micro16\n; @expect R1=1\nR1 <- 1\n")
+                       .unwrap_err(),
+                   "2: the format is already given");
+    }
+}
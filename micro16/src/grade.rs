@@ -0,0 +1,519 @@
+//! Batch grading of microprogram submissions against a test spec.
+//!
+//! A spec is a line based text file:
+//!
+//! ```text
+//! # multiply R0 by R1 into R2
+//! max_cycles 1000
+//! timeout_ms 2000
+//!
+//! case small
+//! init R0=3 R1=4
+//! expect R2=12
+//!
+//! case memory
+//! max_cycles 50
+//! init mem[10]=3
+//! expect mem[11]=6
+//! ```
+//!
+//! `max_cycles` and `timeout_ms` before the first case are defaults that a
+//! case may override. Every `.hex` or `.m16` submission runs every case on a
+//! fresh Machine.
+
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use json::Value;
+use loader;
+use machine::{self, Config, Machine, Status};
+use state::{self, Assignment};
+
+const DEFAULT_MAX_CYCLES: u64 = 10_000;
+const DEFAULT_TIMEOUT_MS: u64 = 5_000;
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
+
+pub const USAGE: &str = "Usage: micro16 grade SPEC DIR [--json FILE] [--csv FILE] [--jobs N]";
+
+#[derive(Debug, Clone)]
+pub struct Case {
+    pub name: String,
+    pub init: Vec<Assignment>,
+    pub expect: Vec<Assignment>,
+    pub max_cycles: u64,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct Spec {
+    pub cases: Vec<Case>,
+}
+
+impl Spec {
+    pub fn parse(text: &str) -> Result<Spec, String> {
+        let mut max_cycles = DEFAULT_MAX_CYCLES;
+        let mut timeout = Duration::from_millis(DEFAULT_TIMEOUT_MS);
+        let mut cases: Vec<Case> = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |e: String| format!("line {}: {}", i + 1, e);
+            let (key, rest) = match line.find(char::is_whitespace) {
+                Some(pos) => (&line[..pos], line[pos..].trim()),
+                None => (line, ""),
+            };
+
+            match key {
+                "case" => {
+                    cases.push(Case {
+                        name: rest.to_string(),
+                        init: Vec::new(),
+                        expect: Vec::new(),
+                        max_cycles,
+                        timeout,
+                    })
+                }
+                "max_cycles" | "timeout_ms" => {
+                    let value = rest.parse::<u64>()
+                        .map_err(|_| err(format!("invalid number '{}'", rest)))?;
+                    match (key, cases.last_mut()) {
+                        ("max_cycles", Some(case)) => case.max_cycles = value,
+                        ("max_cycles", None) => max_cycles = value,
+                        (_, Some(case)) => case.timeout = Duration::from_millis(value),
+                        (_, None) => timeout = Duration::from_millis(value),
+                    }
+                }
+                "init" | "expect" => {
+                    let assignments = state::parse_assignments(rest).map_err(&err)?;
+                    let case = cases.last_mut()
+                        .ok_or_else(|| err(format!("'{}' outside of a case", key)))?;
+                    if key == "init" {
+                        case.init.extend(assignments);
+                    } else {
+                        case.expect.extend(assignments);
+                    }
+                }
+                _ => return Err(err(format!("unknown directive '{}'", key))),
+            }
+        }
+
+        if cases.is_empty() {
+            return Err("spec contains no cases".to_string());
+        }
+        Ok(Spec { cases })
+    }
+
+    pub fn read(path: &str) -> Result<Spec, String> {
+        let text = loader::read_source(path)?;
+        Spec::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CaseResult {
+    pub name: String,
+    pub passed: bool,
+    pub cycles: u64,
+    pub error: Option<String>,
+    pub mismatches: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub student: String,
+    pub path: PathBuf,
+    /// Set when the submission could not be run at all.
+    pub error: Option<String>,
+    pub cases: Vec<CaseResult>,
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.cases.iter().filter(|c| c.passed).count()
+    }
+}
+
+fn run_case(program: &[u32], case: &Case) -> CaseResult {
+    let mut result = CaseResult {
+        name: case.name.clone(),
+        passed: false,
+        cycles: 0,
+        error: None,
+        mismatches: Vec::new(),
+    };
+    let deadline = Instant::now() + case.timeout;
+    let config = Config {
+        max_cycles: Some(case.max_cycles),
+        ..Config::default()
+    };
+    let mut machine = match Machine::new(program, config) {
+        Ok(machine) => machine,
+        Err(e) => {
+            result.error = Some(e.to_string());
+            return result;
+        }
+    };
+    let init: Vec<machine::Assignment> = case.init.iter().map(|&a| a.into()).collect();
+    machine.apply(&init);
+
+    loop {
+        let status = machine.run_for(TIMEOUT_CHECK_INTERVAL);
+        result.cycles = machine.cycles();
+        match status {
+            Ok(Status::Finished) => break,
+            Ok(Status::CycleLimit) => {
+                result.error = Some(format!("cycle limit of {} exceeded", case.max_cycles));
+                return result;
+            }
+            Ok(Status::Running) if Instant::now() > deadline => {
+                result.error = Some(format!("timed out after {} ms", case.timeout.as_millis()));
+                return result;
+            }
+            Ok(Status::Running) => (),
+            Err(e) => {
+                result.error = Some(e.to_string());
+                return result;
+            }
+        }
+    }
+
+    result.mismatches = case.expect
+        .iter()
+        .filter(|a| machine.read(a.location.into()) != a.value)
+        .map(|a| {
+            format!("{}: expected {}, got {}",
+                    a.location,
+                    a.value,
+                    machine.read(a.location.into()))
+        })
+        .collect();
+    result.passed = result.mismatches.is_empty();
+    result
+}
+
+pub fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Runs every case of the spec against a single submission.
+pub fn grade_submission(spec: &Spec, path: &Path) -> Report {
+    let mut report = Report {
+        student: path.file_stem().unwrap_or_else(|| OsStr::new("")).to_string_lossy().into_owned(),
+        path: path.to_path_buf(),
+        error: None,
+        cases: Vec::new(),
+    };
+    let program = match loader::read_program(&path.to_string_lossy()) {
+        Ok(program) => program,
+        Err(e) => {
+            report.error = Some(e);
+            return report;
+        }
+    };
+
+    for case in &spec.cases {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| run_case(&program, case)));
+        report.cases.push(result.unwrap_or_else(|payload| {
+            CaseResult {
+                name: case.name.clone(),
+                passed: false,
+                cycles: 0,
+                error: Some(format!("emulator panicked: {}", panic_message(payload))),
+                mismatches: Vec::new(),
+            }
+        }));
+    }
+    report
+}
+
+/// Grades all submissions on `jobs` worker threads, preserving their order.
+///
+/// A case whose run panics fails with the panic message. The panic hook is
+/// left alone, so the message also reaches it.
+pub fn grade_all(spec: &Spec, paths: &[PathBuf], jobs: usize) -> Vec<Report> {
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            let tx = tx.clone();
+            let next = &next;
+            scope.spawn(move || {
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= paths.len() {
+                        break;
+                    }
+                    let _ = tx.send((i, grade_submission(spec, &paths[i])));
+                }
+            });
+        }
+    });
+    drop(tx);
+
+    let mut reports: Vec<_> = rx.into_iter().collect();
+    reports.sort_by_key(|&(i, _)| i);
+    reports.into_iter().map(|(_, report)| report).collect()
+}
+
+fn has_extension(path: &Path, ext: &str) -> bool {
+    path.extension() == Some(OsStr::new(ext))
+}
+
+/// Lists the program files in a submission directory, sorted by name.
+pub fn submissions(dir: &str) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir, e))?;
+    let mut paths: Vec<PathBuf> = entries.filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && (has_extension(p, "hex") || has_extension(p, "m16")))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn json_option(s: &Option<String>) -> Value {
+    s.clone().map_or(Value::Null, Value::from)
+}
+
+fn json_report(report: &Report) -> Value {
+    let cases = report.cases
+        .iter()
+        .map(|case| {
+            let mismatches = case.mismatches.iter().map(|m| Value::from(m.as_str())).collect();
+            Value::object(vec![("name", Value::from(case.name.as_str())),
+                               ("passed", Value::from(case.passed)),
+                               ("cycles", Value::from(case.cycles as i64)),
+                               ("error", json_option(&case.error)),
+                               ("mismatches", Value::Array(mismatches))])
+        })
+        .collect();
+    Value::object(vec![("student", Value::from(report.student.as_str())),
+                       ("file", Value::from(report.path.to_string_lossy().into_owned())),
+                       ("passed", Value::from(report.passed() as i64)),
+                       ("total", Value::from(report.cases.len() as i64)),
+                       ("error", json_option(&report.error)),
+                       ("cases", Value::Array(cases))])
+}
+
+/// An array with one report per line.
+pub fn write_json<W: Write>(out: &mut W, reports: &[Report]) -> io::Result<()> {
+    writeln!(out, "[")?;
+    for (i, report) in reports.iter().enumerate() {
+        writeln!(out, "{}{}", json_report(report), if i + 1 < reports.len() { "," } else { "" })?;
+    }
+    writeln!(out, "]")
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// One row per student with a pass/fail column per case. Columns follow the
+/// order of the cases, so cases sharing a name keep their own column.
+pub fn write_csv<W: Write>(out: &mut W, spec: &Spec, reports: &[Report]) -> io::Result<()> {
+    let mut header = vec!["student".to_string(), "passed".to_string(), "total".to_string()];
+    header.extend(spec.cases.iter().map(|c| csv_field(&c.name)));
+    header.push("error".to_string());
+    writeln!(out, "{}", header.join(","))?;
+
+    for report in reports {
+        let mut row = vec![csv_field(&report.student),
+                           report.passed().to_string(),
+                           spec.cases.len().to_string()];
+        let mut errors = Vec::new();
+        for i in 0..spec.cases.len() {
+            match report.cases.get(i) {
+                Some(result) => {
+                    row.push(if result.passed { "pass" } else { "fail" }.to_string());
+                    if let Some(ref e) = result.error {
+                        errors.push(format!("{}: {}", result.name, e));
+                    }
+                }
+                None => row.push(String::new()),
+            }
+        }
+        if let Some(ref e) = report.error {
+            errors.insert(0, e.clone());
+        }
+        row.push(csv_field(&errors.join("; ")));
+        writeln!(out, "{}", row.join(","))?;
+    }
+    Ok(())
+}
+
+fn write_file<F>(path: &str, write: F) -> Result<(), String>
+    where F: FnOnce(&mut BufWriter<File>) -> io::Result<()>
+{
+    let mut out = BufWriter::new(File::create(path).map_err(|e| format!("{}: {}", path, e))?);
+    write(&mut out).and_then(|_| out.flush()).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Entry point of `micro16 grade`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    let mut positional = Vec::new();
+    let mut json = None;
+    let mut csv = None;
+    let mut jobs = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--json" => json = Some(iter.next().ok_or(USAGE)?.clone()),
+            "--csv" => csv = Some(iter.next().ok_or(USAGE)?.clone()),
+            "--jobs" => {
+                jobs = iter.next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or(USAGE)?
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+    if positional.len() != 2 {
+        return Err(USAGE.to_string());
+    }
+
+    let spec = Spec::read(&positional[0])?;
+    let paths = submissions(&positional[1])?;
+    let reports = grade_all(&spec, &paths, jobs);
+
+    if let Some(path) = json {
+        write_file(&path, |out| write_json(out, &reports))?;
+    }
+    if let Some(path) = csv {
+        write_file(&path, |out| write_csv(out, &spec, &reports))?;
+    }
+
+    let total = spec.cases.len();
+    for report in &reports {
+        match report.error {
+            Some(ref e) => println!("{:<24} error: {}", report.student, e),
+            None => println!("{:<24} {}/{}", report.student, report.passed(), total),
+        }
+    }
+    let complete = reports.iter().filter(|r| r.passed() == total).count();
+    let average = if reports.is_empty() {
+        0.0
+    } else {
+        reports.iter().map(|r| r.passed() as f64 / total as f64).sum::<f64>() * 100.0 /
+        reports.len() as f64
+    };
+    println!("{} submissions, {} passed every case, average score {:.1}%",
+             reports.len(),
+             complete,
+             average);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+    use json;
+
+    const SPEC: &str = "# doubles R0 into R1
+max_cycles 100
+
+case small
+init R0=3
+expect R1=6
+
+case small
+max_cycles 2
+init R0=-4
+expect R1=-8 mem[0]=0
+";
+
+    fn graded(student: &str, program: &[u32], spec: &Spec) -> Report {
+        Report {
+            student: student.to_string(),
+            path: PathBuf::from(format!("{}.m16", student)),
+            error: None,
+            cases: spec.cases.iter().map(|case| run_case(program, case)).collect(),
+        }
+    }
+
+    #[test]
+    fn specs_parse_with_defaults() {
+        let spec = Spec::parse(SPEC).unwrap();
+        assert_eq!(spec.cases.len(), 2);
+        assert_eq!(spec.cases[0].max_cycles, 100);
+        assert_eq!(spec.cases[1].max_cycles, 2);
+        assert_eq!(spec.cases[0].timeout, Duration::from_millis(DEFAULT_TIMEOUT_MS));
+        assert_eq!(spec.cases[1].expect.len(), 2);
+
+        assert_eq!(Spec::parse("init R0=1").unwrap_err(), "line 1: 'init' outside of a case");
+        assert_eq!(Spec::parse("case a\nrun").unwrap_err(), "line 2: unknown directive 'run'");
+        assert_eq!(Spec::parse("max_cycles 5").unwrap_err(), "spec contains no cases");
+    }
+
+    #[test]
+    fn cases_check_the_final_state() {
+        let spec = Spec::parse(SPEC).unwrap();
+        // R1 <- R0 + R0
+        let report = graded("right", &assemble("R1 <- R0 + R0").unwrap(), &spec);
+        assert_eq!(report.passed(), 2);
+
+        // R1 <- lsh(R0 + 1)
+        let report = graded("wrong", &assemble("R1 <- lsh(R0 + 1)").unwrap(), &spec);
+        assert_eq!(report.passed(), 0);
+        assert_eq!(report.cases[0].mismatches, vec!["R1: expected 6, got 8"]);
+
+        // Runs out of cycles in the second case.
+        let program = assemble("R1 <- R0\nR1 <- R1 + R0\nR1 <- R1").unwrap();
+        let report = graded("slow", &program, &spec);
+        assert!(report.cases[0].passed);
+        assert_eq!(report.cases[1].error.as_deref(), Some("cycle limit of 2 exceeded"));
+    }
+
+    #[test]
+    fn csv_columns_follow_the_cases() {
+        let spec = Spec::parse(SPEC).unwrap();
+        // Too slow for the second case.
+        let program = assemble("R1 <- R0\nR1 <- R1 + R0\nR1 <- R1").unwrap();
+        let mut reports = vec![graded("a", &program, &spec)];
+        reports.push(Report {
+            student: "b, c".to_string(),
+            path: PathBuf::from("b.hex"),
+            error: Some("b.hex: invalid word 'x'".to_string()),
+            cases: Vec::new(),
+        });
+        let mut csv = Vec::new();
+        write_csv(&mut csv, &spec, &reports).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(),
+                   "student,passed,total,small,small,error\n\
+                    a,1,2,pass,fail,small: cycle limit of 2 exceeded\n\
+                    \"b, c\",0,2,,,b.hex: invalid word 'x'\n");
+    }
+
+    #[test]
+    fn json_reports_parse_back() {
+        let spec = Spec::parse(SPEC).unwrap();
+        let reports = vec![graded("a", &assemble("R1 <- R0 + R0").unwrap(), &spec)];
+        let mut out = Vec::new();
+        write_json(&mut out, &reports).unwrap();
+        let value = json::Value::parse(&String::from_utf8(out).unwrap()).unwrap();
+        let report = &value.as_array().unwrap()[0];
+        assert_eq!(report.get("student").and_then(|s| s.as_str()), Some("a"));
+        assert_eq!(report.get("passed").and_then(|p| p.as_i64()), Some(2));
+        assert_eq!(report.get("cases").and_then(|c| c.as_array()).map(|c| c.len()), Some(2));
+    }
+}
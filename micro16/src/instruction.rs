@@ -1,65 +1,212 @@
-use bitset32::BitSet32;
-use cpu::{AluMode, ShifterMode, CondMode};
+use std::fmt;
+use std::sync::Arc;
 
-#[derive(Debug)]
+use cpu::{AluMode, ShifterMode, CondMode, CpuError, MAR};
+use format::Layout;
+
+/// A microinstruction word, decoded through the `Layout` of its format.
+#[derive(Clone, PartialEq, Eq)]
 pub struct Instruction {
-    bits: BitSet32,
+    raw: u32,
+    layout: Arc<Layout>,
 }
 
 impl Instruction {
+    /// An instruction in the built-in Micro16 format.
     pub fn new(raw: u32) -> Instruction {
-        Instruction { bits: BitSet32::new(raw) }
+        Instruction::with_layout(raw, Layout::micro16())
+    }
+
+    pub fn with_layout(raw: u32, layout: Arc<Layout>) -> Instruction {
+        Instruction { raw, layout }
+    }
+
+    pub fn raw(&self) -> u32 {
+        self.raw
+    }
+
+    pub fn layout(&self) -> &Arc<Layout> {
+        &self.layout
     }
 
     pub fn addr(&self) -> u8 {
-        self.bits.get_many(0, 8) as u8
+        self.layout.addr.get(self.raw) as u8
     }
 
     pub fn a_bus(&self) -> u8 {
-        self.bits.get_many(8, 12) as u8
+        self.layout.a_bus.get(self.raw) as u8
     }
 
     pub fn b_bus(&self) -> u8 {
-        self.bits.get_many(12, 16) as u8
+        self.layout.b_bus.get(self.raw) as u8
     }
 
     pub fn s_bus(&self) -> u8 {
-        self.bits.get_many(16, 20) as u8
+        self.layout.s_bus.get(self.raw) as u8
     }
 
+    /// Whether the S-bus is written back; in formats without `ens` every
+    /// S-bus register but 0 is.
     pub fn ens(&self) -> bool {
-        self.bits.get(20)
+        match self.layout.ens {
+            Some(ens) => ens.get(self.raw) != 0,
+            None => self.s_bus() != 0,
+        }
     }
 
     pub fn ms(&self) -> bool {
-        self.bits.get(21)
+        self.layout.ms.get(self.raw) != 0
     }
 
     pub fn rd_wr(&self) -> bool {
-        self.bits.get(22)
+        self.layout.rd_wr.get(self.raw) != 0
     }
 
     pub fn mar(&self) -> bool {
-        self.bits.get(23)
+        self.layout.mar.get(self.raw) != 0
     }
 
     pub fn mbr(&self) -> bool {
-        self.bits.get(24)
+        self.layout.mbr.get(self.raw) != 0
+    }
+
+    /// Whether the shifter field holds one of the defined modes. Calls and
+    /// returns do not count.
+    pub fn has_valid_sh(&self) -> bool {
+        self.layout.shifter_mode(self.layout.sh.get(self.raw)).is_some()
     }
 
-    pub fn sh(&self) -> ShifterMode {
-        ShifterMode::from_u8(self.bits.get_many(25, 27) as u8)
+    /// Whether the shifter field holds the call code: the word calls its
+    /// jump target, or returns if it does not jump. The shifter passes the
+    /// ALU result through unchanged.
+    pub fn call(&self) -> bool {
+        self.layout.call_code() == Some(self.layout.sh.get(self.raw))
     }
 
-    pub fn alu(&self) -> AluMode {
-        AluMode::from_u8(self.bits.get_many(27, 29) as u8)
+    /// The first part of the word that the format does not define: `sh`,
+    /// `alu` or `cond` holding an undefined code, or `unused` for set bits
+    /// outside all fields.
+    pub fn invalid_field(&self) -> Option<&'static str> {
+        if !self.has_valid_sh() && !self.call() {
+            Some("sh")
+        } else if self.layout.alu_mode(self.layout.alu.get(self.raw)).is_none() {
+            Some("alu")
+        } else if self.layout.cond_mode(self.layout.cond.get(self.raw)).is_none() {
+            Some("cond")
+        } else if self.raw & self.layout.unused_bits() != 0 {
+            Some("unused")
+        } else {
+            None
+        }
     }
 
-    pub fn cond(&self) -> CondMode {
-        CondMode::from_u8(self.bits.get_many(29, 31) as u8)
+    /// The shifter mode, `NoOp` for calls and returns. Fails if the format
+    /// does not define the code, as `invalid_field` reports.
+    pub fn sh(&self) -> Result<ShifterMode, CpuError> {
+        if self.call() {
+            return Ok(ShifterMode::NoOp);
+        }
+        let code = self.layout.sh.get(self.raw);
+        self.layout.shifter_mode(code).ok_or(CpuError::UndefinedCode { field: "sh", code })
+    }
+
+    pub fn alu(&self) -> Result<AluMode, CpuError> {
+        let code = self.layout.alu.get(self.raw);
+        self.layout.alu_mode(code).ok_or(CpuError::UndefinedCode { field: "alu", code })
+    }
+
+    pub fn cond(&self) -> Result<CondMode, CpuError> {
+        let code = self.layout.cond.get(self.raw);
+        self.layout.cond_mode(code).ok_or(CpuError::UndefinedCode { field: "cond", code })
+    }
+
+    /// The shifter, ALU and condition modes at once.
+    pub fn modes(&self) -> Result<(ShifterMode, AluMode, CondMode), CpuError> {
+        Ok((self.sh()?, self.alu()?, self.cond()?))
     }
 
     pub fn a_mux(&self) -> bool {
-        self.bits.get(31)
+        self.layout.a_mux.is_some_and(|a_mux| a_mux.get(self.raw) != 0)
+    }
+
+    pub fn set_addr(&mut self, value: u8) {
+        self.raw = self.layout.addr.set(self.raw, value as u32);
+    }
+
+    pub fn set_a_bus(&mut self, value: u8) {
+        self.raw = self.layout.a_bus.set(self.raw, value as u32);
+    }
+
+    pub fn set_b_bus(&mut self, value: u8) {
+        self.raw = self.layout.b_bus.set(self.raw, value as u32);
+    }
+
+    pub fn set_s_bus(&mut self, value: u8) {
+        self.raw = self.layout.s_bus.set(self.raw, value as u32);
+    }
+
+    /// In formats without `ens`, clearing it selects S-bus 0 and setting it
+    /// leaves the S-bus register alone.
+    pub fn set_ens(&mut self, value: bool) {
+        match self.layout.ens {
+            Some(ens) => self.raw = ens.set(self.raw, value as u32),
+            None if !value => self.set_s_bus(0),
+            None => (),
+        }
+    }
+
+    pub fn set_ms(&mut self, value: bool) {
+        self.raw = self.layout.ms.set(self.raw, value as u32);
+    }
+
+    pub fn set_rd_wr(&mut self, value: bool) {
+        self.raw = self.layout.rd_wr.set(self.raw, value as u32);
+    }
+
+    pub fn set_mar(&mut self, value: bool) {
+        self.raw = self.layout.mar.set(self.raw, value as u32);
+    }
+
+    pub fn set_mbr(&mut self, value: bool) {
+        self.raw = self.layout.mbr.set(self.raw, value as u32);
+    }
+
+    /// Panics if the format has no code for `value`, as for the other modes.
+    pub fn set_sh(&mut self, value: ShifterMode) {
+        let code = self.layout.shifter_code(value).expect("the format has no such shifter mode");
+        self.raw = self.layout.sh.set(self.raw, code);
+    }
+
+    /// Marks the word as a call or return, replacing its shifter mode.
+    ///
+    /// Panics if the format has no call code.
+    pub fn set_call(&mut self) {
+        let code = self.layout.call_code().expect("the format has no call code");
+        self.raw = self.layout.sh.set(self.raw, code);
+    }
+
+    pub fn set_alu(&mut self, value: AluMode) {
+        let code = self.layout.alu_code(value).expect("the format has no such ALU mode");
+        self.raw = self.layout.alu.set(self.raw, code);
+    }
+
+    pub fn set_cond(&mut self, value: CondMode) {
+        let code = self.layout.cond_code(value).expect("the format has no such condition");
+        self.raw = self.layout.cond.set(self.raw, code);
+    }
+
+    /// In formats without `a_mux`, setting it selects MAR on the A-bus.
+    pub fn set_a_mux(&mut self, value: bool) {
+        match self.layout.a_mux {
+            Some(a_mux) => self.raw = a_mux.set(self.raw, value as u32),
+            None if value => self.set_a_bus(MAR),
+            None => (),
+        }
+    }
+}
+
+impl fmt::Debug for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Instruction({:#010x}, {})", self.raw, self.layout.format().name)
     }
 }
@@ -0,0 +1,460 @@
+//! A small JSON value type with a parser and a compact printer, enough for
+//! the JSON-RPC server without external dependencies.
+
+use std::fmt;
+
+/// Nesting depth beyond which parsing gives up, so that hostile input
+/// cannot exhaust the stack.
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// Members in their order of appearance.
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Builds an object from `(key, value)` pairs.
+    pub fn object(members: Vec<(&str, Value)>) -> Value {
+        Value::Object(members.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    /// The member `key` of an object; `None` for other values.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Object(ref members) => members.iter().find(|m| m.0 == key).map(|m| &m.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// The value as an integer, if it is a number without a fraction.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < 9.0e15 => Some(n as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match *self {
+            Value::Array(ref items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Value, String> {
+        let mut parser = Parser {
+            bytes: text.as_bytes(),
+            pos: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.pos < parser.bytes.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Value {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < 9.0e15 => write!(f, "{}", n as i64),
+            Value::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Value::Number(_) => f.write_str("null"),
+            Value::String(ref s) => write_string(f, s),
+            Value::Array(ref items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            Value::Object(ref members) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> String {
+        format!("{} at offset {}", message, self.pos)
+    }
+
+    fn whitespace(&mut self) {
+        while self.pos < self.bytes.len() && b" \t\r\n".contains(&self.bytes[self.pos]) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        if self.peek() != Some(byte) {
+            return Err(self.error(&format!("expected '{}'", byte as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        if !self.bytes[self.pos..].starts_with(word.as_bytes()) {
+            return Err(self.error("invalid literal"));
+        }
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.whitespace();
+        match self.peek() {
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') | Some(b'{') => {
+                self.depth += 1;
+                if self.depth > MAX_DEPTH {
+                    return Err(self.error("nested too deeply"));
+                }
+                let value = if self.peek() == Some(b'[') {
+                    self.array()
+                } else {
+                    self.object()
+                };
+                self.depth -= 1;
+                value
+            }
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        self.whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect(b'{')?;
+        let mut members = Vec::new();
+        self.whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.whitespace();
+            let key = self.string()?;
+            self.whitespace();
+            self.expect(b':')?;
+            members.push((key, self.value()?));
+            self.whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        // No leading zeros, and digits on both sides of a point.
+        let mut valid = if self.peek() == Some(b'0') {
+            self.pos += 1;
+            true
+        } else {
+            self.digits() > 0
+        };
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            valid &= self.digits() > 0;
+        }
+        if matches!(self.peek(), Some(b'e') | Some(b'E')) {
+            self.pos += 1;
+            if matches!(self.peek(), Some(b'+') | Some(b'-')) {
+                self.pos += 1;
+            }
+            valid &= self.digits() > 0;
+        }
+        let text = ::std::str::from_utf8(&self.bytes[start..self.pos]).unwrap();
+        match text.parse::<f64>() {
+            Ok(n) if valid && n.is_finite() => Ok(Value::Number(n)),
+            _ => Err(format!("invalid number '{}'", text)),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.bytes.get(self.pos..self.pos + 4).ok_or_else(|| {
+            self.error("truncated escape")
+        })?;
+        if !digits.iter().all(|b| b.is_ascii_hexdigit()) {
+            return Err(self.error("invalid escape"));
+        }
+        let digits = ::std::str::from_utf8(digits).unwrap();
+        self.pos += 4;
+        Ok(u32::from_str_radix(digits, 16).unwrap())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escape = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let code = self.hex4()?;
+                            // A surrogate pair encodes one character; a lone
+                            // surrogate becomes U+FFFD.
+                            let low = self.bytes.get(self.pos..self.pos + 6)
+                                .filter(|next| next.starts_with(b"\\u"))
+                                .and_then(|next| ::std::str::from_utf8(&next[2..]).ok())
+                                .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                                .filter(|low| (0xdc00..0xe000).contains(low));
+                            let code = match low {
+                                Some(low) if (0xd800..0xdc00).contains(&code) => {
+                                    self.pos += 6;
+                                    0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00)
+                                }
+                                _ => code,
+                            };
+                            char::from_u32(code).unwrap_or('\u{fffd}')
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                Some(b) if b < 0x20 => return Err(self.error("control character in string")),
+                Some(b) => {
+                    bytes.push(b);
+                    self.pos += 1;
+                }
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("invalid UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_round_trip() {
+        let text = "quote \" backslash \\ slash / newline \n tab \t bell \u{7} é \u{1f600}";
+        let printed = Value::from(text).to_string();
+        assert_eq!(printed,
+                   "\"quote \\\" backslash \\\\ slash / newline \\n tab \\u0009 bell \\u0007 \
+                    é \u{1f600}\"");
+        assert_eq!(Value::parse(&printed), Ok(Value::from(text)));
+        assert_eq!(Value::parse(r#""\/\b\f\ré""#), Ok(Value::from("/\u{8}\u{c}\ré")));
+        assert!(Value::parse(r#""\x""#).is_err());
+        assert!(Value::parse(r#""\u12g4""#).is_err());
+        assert!(Value::parse(r#""\u+123""#).is_err());
+        assert!(Value::parse("\"a\nb\"").is_err());
+        assert!(Value::parse("\"open").is_err());
+    }
+
+    #[test]
+    fn surrogate_pairs() {
+        assert_eq!(Value::parse(r#""\ud83d\ude00""#), Ok(Value::from("\u{1f600}")));
+        assert_eq!(Value::parse(r#""\ud83dx""#), Ok(Value::from("\u{fffd}x")));
+        assert_eq!(Value::parse(r#""\ude00""#), Ok(Value::from("\u{fffd}")));
+        // A high surrogate does not swallow the escape after it.
+        assert_eq!(Value::parse(r#""\ud83d\u0041""#), Ok(Value::from("\u{fffd}A")));
+        assert_eq!(Value::parse(r#""\ud83d\ud83d\ude00""#),
+                   Ok(Value::from("\u{fffd}\u{1f600}")));
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(Value::parse("-0"), Ok(Value::Number(0.0)));
+        assert_eq!(Value::parse("12.5e-1"), Ok(Value::Number(1.25)));
+        assert_eq!(Value::parse("1E+2").unwrap().as_i64(), Some(100));
+        assert_eq!(Value::parse("1.5").unwrap().as_i64(), None);
+        for text in ["1e400", "-1e400", "01", "1.", ".5", "-", "1e", "+1", "1-2", "0x10"] {
+            assert!(Value::parse(text).is_err(), "{} parsed", text);
+        }
+        assert_eq!(Value::Number(f64::INFINITY).to_string(), "null");
+        assert_eq!(Value::from(-42).to_string(), "-42");
+        assert_eq!(Value::Number(0.25).to_string(), "0.25");
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Value::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(Value::parse(&nested(MAX_DEPTH + 1)).unwrap_err().contains("nested too deeply"));
+        let objects = format!("{}1{}",
+                              "{\"a\":".repeat(MAX_DEPTH + 1),
+                              "}".repeat(MAX_DEPTH + 1));
+        assert!(Value::parse(&objects).is_err());
+        // Siblings do not add up.
+        let siblings = format!("[{}]", vec![nested(MAX_DEPTH - 1); 3].join(","));
+        assert!(Value::parse(&siblings).is_ok());
+    }
+
+    #[test]
+    fn trailing_characters_are_rejected() {
+        assert_eq!(Value::parse(" true \n"), Ok(Value::Bool(true)));
+        for text in ["true false", "{} x", "[1]]", "nullx", "1 2", "\"a\"\"b\""] {
+            assert!(Value::parse(text).is_err(), "{} parsed", text);
+        }
+        assert!(Value::parse("").is_err());
+        assert!(Value::parse("[1,]").is_err());
+        assert!(Value::parse("{\"a\" 1}").is_err());
+        assert!(Value::parse("tru").is_err());
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let value = Value::object(vec![("jsonrpc", Value::from("2.0")),
+                                       ("id", Value::from(7)),
+                                       ("ok", Value::from(true)),
+                                       ("none", Value::Null),
+                                       ("list", Value::Array(vec![Value::Number(-1.5),
+                                                                  Value::Array(vec![]),
+                                                                  Value::object(vec![])])),
+                                       ("name", Value::from("R0 \"x\""))]);
+        let printed = value.to_string();
+        assert_eq!(printed,
+                   concat!(r#"{"jsonrpc":"2.0","id":7,"ok":true,"none":null,"#,
+                           r#""list":[-1.5,[],{}],"name":"R0 \"x\""}"#));
+        assert_eq!(Value::parse(&printed), Ok(value.clone()));
+        assert_eq!(Value::parse(" { \"id\" : 7 , \"ok\" : true } ").unwrap().get("id"),
+                   Some(&Value::from(7)));
+        assert_eq!(value.get("missing"), None);
+        assert_eq!(value.get("list").and_then(Value::as_array).map(|l| l.len()), Some(3));
+    }
+}
@@ -0,0 +1,44 @@
+//! Emulator and tooling for the Micro16 microarchitecture.
+//!
+//! `machine::Machine` is the interface meant for other crates: it runs a
+//! microprogram and gives access to registers, flags and memory. The
+//! remaining modules make up the `micro16` command line tool and are public
+//! for tools that need more, such as the assembler in `asm` or the raw
+//! `cpu::Cpu`.
+//!
+//! `mic1` emulates Tanenbaum's MIC-1 on the same memory; `arch::Core` is
+//! what tracing and the debugger need from either machine.
+
+pub mod arch;
+pub mod asm;
+pub mod bitset32;
+pub mod cache;
+pub mod cli;
+pub mod compiler;
+pub mod cpu;
+pub mod disasm;
+pub mod equiv;
+pub mod fault;
+pub mod format;
+pub mod fuzz;
+pub mod gdb;
+pub mod golden;
+pub mod grade;
+pub mod instruction;
+pub mod json;
+pub mod loader;
+pub mod machine;
+pub mod mic1;
+pub mod multicore;
+pub mod optimize;
+pub mod reference;
+pub mod report;
+pub mod rng;
+pub mod rpc;
+pub mod state;
+pub mod superopt;
+pub mod trace;
+pub mod tui;
+pub mod vcd;
+
+pub use machine::{Machine, MachineError, Status};
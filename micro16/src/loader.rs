@@ -0,0 +1,75 @@
+//! Loading of microprograms from disk.
+//!
+//! Files ending in `.m16` are assembler sources, `.mc` files are compiled
+//! with `compiler` and anything else is read as hex words.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+use asm;
+use compiler;
+use cpu::PROGRAM_LENGTH;
+use format::Layout;
+
+pub fn read_source(path: &str) -> Result<String, String> {
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    Ok(text)
+}
+
+pub fn is_assembly(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|ext| ext == "m16")
+}
+
+pub fn is_compiled(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|ext| ext == "mc")
+}
+
+/// Reads a program, assembling or compiling it if necessary.
+pub fn read_program(path: &str) -> Result<Vec<u32>, String> {
+    read_program_with(path, &Layout::micro16())
+}
+
+/// Reads a program, assembling or compiling sources into the format of
+/// `layout`. Hex words are taken as they are.
+pub fn read_program_with(path: &str, layout: &Arc<Layout>) -> Result<Vec<u32>, String> {
+    let text = read_source(path)?;
+    let program = if is_assembly(path) {
+        asm::assemble_with(&text, layout).map_err(|e| e.to_string())
+    } else if is_compiled(path) {
+        compiler::compile(&text)
+            .map_err(|e| e.to_string())
+            .and_then(|c| asm::assemble_with(&c.assembly, layout).map_err(|e| e.to_string()))
+    } else {
+        parse_hex(&text)
+    };
+    program.map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Parses whitespace or comma separated hex words; `#` and `;` start a
+/// comment.
+pub fn parse_hex(text: &str) -> Result<Vec<u32>, String> {
+    let mut program = Vec::new();
+    for line in text.lines() {
+        let line = line.split(['#', ';']).next().unwrap();
+        for word in line.split(|c: char| c.is_whitespace() || c == ',') {
+            if word.is_empty() {
+                continue;
+            }
+            let digits = word.trim_start_matches("0x");
+            let value = u32::from_str_radix(digits, 16)
+                .map_err(|_| format!("invalid word '{}'", word))?;
+            program.push(value);
+        }
+    }
+    if program.len() > PROGRAM_LENGTH {
+        return Err(format!("program has {} words, the control store holds {}",
+                           program.len(),
+                           PROGRAM_LENGTH));
+    }
+    Ok(program)
+}
@@ -0,0 +1,564 @@
+//! `Machine`, the stable interface for embedding the emulator.
+//!
+//! A Machine wraps a `Cpu` with its memory, counts cycles, enforces an
+//! optional cycle limit and addresses registers by name. Unlike the other
+//! modules, whose interfaces follow the needs of the `micro16` tool, this one
+//! only changes in backwards compatible ways. `Config` and `Flags` may gain
+//! fields, so start from `Config::default()` and set the fields you need.
+//! `Machine::cpu` and `Machine::cpu_mut` are the one way out to the unstable
+//! `Cpu` and are not covered.
+//!
+//! A Machine runs on the built-in `Memory` unless `Machine::with_memory`
+//! puts another `MemoryPort` behind it, such as a cache model. Cloning a
+//! Machine on `Memory` is cheap: the clone shares memory pages with the
+//! original until one of them writes a page, so clones serve as snapshots.
+//!
+//! ```
+//! use micro16::asm::assemble;
+//! use micro16::machine::{Config, Machine, Status};
+//!
+//! let program = assemble("R1 <- R0 + R0\nMAR <- R0; MBR <- R1; wr\nwr").unwrap();
+//! let mut config = Config::default();
+//! config.max_cycles = Some(100);
+//! let mut machine = Machine::new(&program, config).unwrap();
+//! machine.set_register("R0", 21).unwrap();
+//! let start = machine.clone();
+//! assert_eq!(machine.run().unwrap(), Status::Finished);
+//! assert_eq!(machine.register("R1").unwrap(), 42);
+//! assert_eq!(machine.read_memory(21, 1).unwrap(), vec![42]);
+//! assert_eq!(machine.cycles(), 3);
+//! assert_eq!(start.read_memory(21, 1).unwrap(), vec![0]);
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use arch::{Core, Cycle, Info};
+use cpu::{Cpu, CpuError, Memory, MemoryPort, Phase, Profile, PROGRAM_LENGTH};
+use disasm::register_index;
+use format::Layout;
+use state;
+
+const MEMORY_SIZE: usize = 1 << 16;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Config {
+    /// Cycles after which `run` and `step` stop with `Status::CycleLimit`.
+    pub max_cycles: Option<u64>,
+    /// Maps the control store into memory, see `Cpu::map_control_store`.
+    pub control_store_window: Option<u16>,
+    /// Enables micro-calls with a return stack of this many entries, see
+    /// `Cpu::set_call_stack_depth`.
+    pub call_stack_depth: Option<usize>,
+    /// The operations the Cpu executes. The program is decoded in the
+    /// built-in format of the profile unless `Machine::set_layout` picks
+    /// another format, whose profile then replaces this one.
+    pub profile: Profile,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The program can execute further cycles.
+    Running,
+    /// The program counter has left the program.
+    Finished,
+    /// The configured number of cycles has been executed.
+    CycleLimit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MachineError {
+    Cpu(CpuError),
+    UnknownRegister(String),
+    /// The constant registers 0, 1 and -1 cannot be written.
+    ReadOnlyRegister(String),
+    /// A memory range reaching past the last cell.
+    OutOfRange { start: usize, len: usize },
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MachineError::Cpu(ref e) => write!(f, "{}", e),
+            MachineError::UnknownRegister(ref name) => write!(f, "unknown register '{}'", name),
+            MachineError::ReadOnlyRegister(ref name) => {
+                write!(f, "register {} is read-only", name)
+            }
+            MachineError::OutOfRange { start, len } => {
+                write!(f,
+                       "{} cells from {} reach past the end of memory",
+                       len,
+                       start)
+            }
+        }
+    }
+}
+
+impl Error for MachineError {}
+
+impl From<CpuError> for MachineError {
+    fn from(e: CpuError) -> MachineError {
+        MachineError::Cpu(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Flags {
+    pub negative: bool,
+    pub zero: bool,
+    /// Carry out of, or borrow into, the last addition or subtraction.
+    pub carry: bool,
+    /// Signed overflow of the last addition or subtraction.
+    pub overflow: bool,
+}
+
+fn check_range(start: u16, len: usize) -> Result<(), MachineError> {
+    if start as usize + len > MEMORY_SIZE {
+        return Err(MachineError::OutOfRange {
+            start: start as usize,
+            len,
+        });
+    }
+    Ok(())
+}
+
+/// A register or memory cell, as `Machine::read` and `Machine::apply`
+/// address them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location(state::Location);
+
+impl Location {
+    /// A writable register by its assembler name, see `Machine::register`.
+    pub fn register(name: &str) -> Result<Location, MachineError> {
+        match register_index(name) {
+            Some(idx) if idx >= 3 => Ok(Location(state::Location::Register(idx))),
+            Some(_) => Err(MachineError::ReadOnlyRegister(name.to_string())),
+            None => Err(MachineError::UnknownRegister(name.to_string())),
+        }
+    }
+
+    pub fn memory(addr: u16) -> Location {
+        Location(state::Location::Memory(addr))
+    }
+
+    /// Parses a register name or a cell such as `mem[10]` or `mem[0x1f]`.
+    pub fn parse(text: &str) -> Result<Location, String> {
+        state::parse_location(text).map(Location)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<state::Location> for Location {
+    fn from(location: state::Location) -> Location {
+        Location(location)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Assignment {
+    pub location: Location,
+    pub value: i16,
+}
+
+impl Assignment {
+    /// Parses a whitespace separated list such as `R0=5 mem[10]=-3`, the
+    /// syntax of `--init`.
+    pub fn parse_list(text: &str) -> Result<Vec<Assignment>, String> {
+        Ok(state::parse_assignments(text)?.into_iter().map(Assignment::from).collect())
+    }
+}
+
+impl fmt::Display for Assignment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.location, self.value)
+    }
+}
+
+impl From<state::Assignment> for Assignment {
+    fn from(a: state::Assignment) -> Assignment {
+        Assignment {
+            location: a.location.into(),
+            value: a.value,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Machine<M: MemoryPort = Memory> {
+    cpu: Cpu<M>,
+    /// The program as loaded, restored by `reset`.
+    program: Vec<u32>,
+    config: Config,
+    cycles: u64,
+}
+
+impl Machine {
+    pub fn new(program: &[u32], config: Config) -> Result<Machine, MachineError> {
+        Machine::with_memory(program, config, Memory::new())
+    }
+}
+
+impl<M: MemoryPort> Machine<M> {
+    /// Runs `program` on `memory` instead of the built-in `Memory`.
+    pub fn with_memory(program: &[u32],
+                       config: Config,
+                       memory: M)
+                       -> Result<Machine<M>, MachineError> {
+        if program.len() > PROGRAM_LENGTH {
+            return Err(CpuError::ProgramTooLong { len: program.len() }.into());
+        }
+        let mut cpu = Cpu::with_memory(program, memory);
+        cpu.map_control_store(config.control_store_window);
+        cpu.set_call_stack_depth(config.call_stack_depth);
+        cpu.set_profile(config.profile);
+        Ok(Machine {
+            cpu,
+            program: program.to_vec(),
+            config,
+            cycles: 0,
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Decodes the program in the format of `layout` instead of the one of
+    /// the profile, and runs the profile of `layout`.
+    pub fn set_layout(&mut self, layout: Arc<Layout>) {
+        self.config.profile = layout.profile();
+        self.cpu.set_layout(layout);
+    }
+
+    /// Changes the cycle limit, e.g. to continue after `Status::CycleLimit`.
+    pub fn set_max_cycles(&mut self, max_cycles: Option<u64>) {
+        self.config.max_cycles = max_cycles;
+    }
+
+    pub fn status(&self) -> Status {
+        if self.cpu.done() {
+            Status::Finished
+        } else if self.config.max_cycles.is_some_and(|max| self.cycles >= max) {
+            Status::CycleLimit
+        } else {
+            Status::Running
+        }
+    }
+
+    /// Executes one cycle, unless the machine is no longer running.
+    pub fn step(&mut self) -> Result<Status, MachineError> {
+        if self.status() == Status::Running {
+            self.cpu.step()?;
+            self.cycles += 1;
+        }
+        Ok(self.status())
+    }
+
+    /// Executes one clock phase, see `phase` for which one. A cycle counts
+    /// once its write back phase has executed.
+    pub fn step_phase(&mut self) -> Result<Status, MachineError> {
+        if self.status() == Status::Running && self.cpu.step_phase()? == Phase::WriteBack {
+            self.cycles += 1;
+        }
+        Ok(self.status())
+    }
+
+    /// Executes cycles until the program finishes or hits the cycle limit.
+    /// Without a limit, a program that loops forever never returns.
+    pub fn run(&mut self) -> Result<Status, MachineError> {
+        loop {
+            match self.step()? {
+                Status::Running => (),
+                status => return Ok(status),
+            }
+        }
+    }
+
+    /// Executes at most `cycles` more cycles.
+    pub fn run_for(&mut self, cycles: u64) -> Result<Status, MachineError> {
+        for _ in 0..cycles {
+            if self.step()? != Status::Running {
+                break;
+            }
+        }
+        Ok(self.status())
+    }
+
+    /// Cycles executed since construction or the last reset.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// The phase the next call to `step_phase` executes.
+    pub fn phase(&self) -> Phase {
+        self.cpu.phase()
+    }
+
+    pub fn program_counter(&self) -> u8 {
+        self.cpu.program_counter()
+    }
+
+    pub fn flags(&self) -> Flags {
+        Flags {
+            negative: self.cpu.negative_flag(),
+            zero: self.cpu.zero_flag(),
+            carry: self.cpu.carry_flag(),
+            overflow: self.cpu.overflow_flag(),
+        }
+    }
+
+    /// Reads a register by its assembler name, e.g. `R0`, `MAR` or `MBR`.
+    /// Names are case insensitive.
+    pub fn register(&self, name: &str) -> Result<i16, MachineError> {
+        let idx = register_index(name)
+            .ok_or_else(|| MachineError::UnknownRegister(name.to_string()))?;
+        Ok(self.cpu.registers().get(idx))
+    }
+
+    pub fn set_register(&mut self, name: &str, value: i16) -> Result<(), MachineError> {
+        let location = Location::register(name)?;
+        self.apply(&[Assignment { location, value }]);
+        Ok(())
+    }
+
+    /// Reads `len` memory cells from `start` on, bypassing the handshake.
+    pub fn read_memory(&self, start: u16, len: usize) -> Result<Vec<i16>, MachineError> {
+        check_range(start, len)?;
+        Ok((start as usize..start as usize + len).map(|i| self.cpu.memory().get(i)).collect())
+    }
+
+    /// Writes `values` to memory from `start` on, bypassing the handshake.
+    pub fn write_memory(&mut self, start: u16, values: &[i16]) -> Result<(), MachineError> {
+        check_range(start, values.len())?;
+        for (i, &value) in values.iter().enumerate() {
+            self.cpu.memory_mut().set(start as usize + i, value);
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, location: Location) -> i16 {
+        state::read(&self.cpu, location.0)
+    }
+
+    /// Writes registers and memory cells in order, so a later assignment to
+    /// the same location wins.
+    pub fn apply(&mut self, assignments: &[Assignment]) {
+        let assignments: Vec<state::Assignment> = assignments.iter()
+            .map(|a| {
+                state::Assignment {
+                    location: a.location.0,
+                    value: a.value,
+                }
+            })
+            .collect();
+        state::apply(&mut self.cpu, &assignments);
+    }
+
+    /// Returns to the state after construction: registers, flags, program
+    /// counter, return stack and cycle count are cleared and the control
+    /// store is reloaded. Memory is cleared as well unless `keep_memory` is set.
+    pub fn reset(&mut self, keep_memory: bool) {
+        self.cpu.reset(keep_memory);
+        self.cpu.load_program(&self.program).expect("program was checked on construction");
+        self.cycles = 0;
+    }
+
+    /// The underlying Cpu, for everything the Machine does not wrap.
+    ///
+    /// Unlike the Machine, `Cpu` changes along with the `micro16` tool, so
+    /// code going through it may break with any release.
+    pub fn cpu(&self) -> &Cpu<M> {
+        &self.cpu
+    }
+
+    /// The underlying Cpu for changes the Machine does not offer, such as
+    /// patching the control store or injecting faults. Like `cpu`, it is not
+    /// covered by the compatibility promise, and loading another program
+    /// through it is undone by `reset`.
+    pub fn cpu_mut(&mut self) -> &mut Cpu<M> {
+        &mut self.cpu
+    }
+}
+
+/// Tracing and the debugger drive a Machine like its Cpu, counting cycles.
+impl Core for Machine {
+    fn info(&self) -> &'static Info {
+        self.cpu.info()
+    }
+
+    fn register(&self, idx: u8) -> i32 {
+        Core::register(&self.cpu, idx)
+    }
+
+    fn set_register(&mut self, idx: u8, value: i32) -> bool {
+        Core::set_register(&mut self.cpu, idx, value)
+    }
+
+    fn flags(&self) -> u8 {
+        Core::flags(&self.cpu)
+    }
+
+    fn set_flags(&mut self, flags: u8) {
+        Core::set_flags(&mut self.cpu, flags);
+    }
+
+    fn program_counter(&self) -> u16 {
+        Core::program_counter(&self.cpu)
+    }
+
+    fn set_program_counter(&mut self, addr: u16) -> bool {
+        Core::set_program_counter(&mut self.cpu, addr)
+    }
+
+    fn done(&self) -> bool {
+        self.cpu.done()
+    }
+
+    fn cycle(&mut self) -> Result<Cycle, String> {
+        let cycle = self.cpu.cycle()?;
+        self.cycles += 1;
+        Ok(cycle)
+    }
+
+    fn cell(&self, idx: usize) -> i16 {
+        self.cpu.cell(idx)
+    }
+
+    fn set_cell(&mut self, idx: usize, value: i16) {
+        self.cpu.set_cell(idx, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+    use trace;
+
+    fn machine(source: &str, max_cycles: Option<u64>) -> Machine {
+        let config = Config {
+            max_cycles,
+            ..Config::default()
+        };
+        Machine::new(&assemble(source).unwrap(), config).unwrap()
+    }
+
+    #[test]
+    fn the_cycle_limit_stops_and_can_be_raised() {
+        let mut machine = machine(":loop\nR0 <- R0 + 1; goto .loop", Some(10));
+        assert_eq!(machine.run(), Ok(Status::CycleLimit));
+        assert_eq!(machine.cycles(), 10);
+        assert_eq!(machine.step(), Ok(Status::CycleLimit));
+        assert_eq!(machine.register("R0"), Ok(10));
+
+        machine.set_max_cycles(Some(15));
+        assert_eq!(machine.run_for(3), Ok(Status::Running));
+        assert_eq!(machine.run(), Ok(Status::CycleLimit));
+        assert_eq!(machine.register("r0"), Ok(15));
+    }
+
+    #[test]
+    fn a_cycle_counts_once_its_phases_have_executed() {
+        let mut machine = machine("R0 <- 1\nR1 <- R0 + 1", None);
+        assert_eq!(machine.phase(), Phase::LoadMir);
+        while machine.phase() != Phase::WriteBack {
+            assert_eq!(machine.step_phase(), Ok(Status::Running));
+            assert_eq!(machine.cycles(), 0);
+        }
+        assert_eq!(machine.step_phase(), Ok(Status::Running));
+        assert_eq!(machine.cycles(), 1);
+        assert_eq!(machine.program_counter(), 1);
+        assert_eq!(machine.run(), Ok(Status::Finished));
+        assert_eq!(machine.register("R1"), Ok(2));
+    }
+
+    #[test]
+    fn registers_are_named_like_in_the_assembler() {
+        let mut machine = machine("R1 <- R0 + -1", None);
+        assert_eq!(machine.set_register("mbr", 7), Ok(()));
+        assert_eq!(machine.register("MBR"), Ok(7));
+        assert_eq!(machine.register("-1"), Ok(-1));
+        assert_eq!(machine.set_register("1", 5),
+                   Err(MachineError::ReadOnlyRegister("1".to_string())));
+        assert_eq!(machine.register("R11"),
+                   Err(MachineError::UnknownRegister("R11".to_string())));
+
+        machine.set_register("R0", 3).unwrap();
+        assert_eq!(machine.run(), Ok(Status::Finished));
+        assert_eq!(machine.register("R1"), Ok(2));
+        let flags = machine.flags();
+        assert!(flags.carry && !flags.zero && !flags.negative && !flags.overflow);
+    }
+
+    #[test]
+    fn memory_ranges_stay_inside_memory() {
+        let mut machine = machine("R0 <- 1", None);
+        machine.write_memory(0xfffe, &[1, 2]).unwrap();
+        assert_eq!(machine.read_memory(0xfffd, 3), Ok(vec![0, 1, 2]));
+        assert_eq!(machine.read_memory(0xffff, 2),
+                   Err(MachineError::OutOfRange {
+                       start: 0xffff,
+                       len: 2,
+                   }));
+        assert!(machine.write_memory(0xffff, &[1, 2]).is_err());
+        assert_eq!(machine.read_memory(0xffff, 1), Ok(vec![2]));
+    }
+
+    #[test]
+    fn assignments_apply_in_order() {
+        let mut machine = machine("R1 <- R0", None);
+        let assignments = Assignment::parse_list("R0=1 mem[0x10]=-3 R0=2").unwrap();
+        machine.apply(&assignments);
+        assert_eq!(machine.read(Location::register("R0").unwrap()), 2);
+        assert_eq!(machine.read(Location::memory(16)), -3);
+        assert_eq!(Location::parse("mem[0x10]"), Ok(Location::memory(16)));
+        assert_eq!(assignments[1].to_string(), "mem[16]=-3");
+
+        assert!(Location::register("0").is_err());
+        assert!(Location::parse("R0").is_ok());
+        assert!(Assignment::parse_list("R0").is_err());
+    }
+
+    #[test]
+    fn reset_restores_the_loaded_program() {
+        let mut machine = machine("R0 <- 1\nMAR <- R0; MBR <- R0; wr\nwr", None);
+        machine.cpu_mut().patch(0, assemble("R0 <- -1").unwrap()[0]);
+        machine.write_memory(100, &[5]).unwrap();
+        assert_eq!(machine.run(), Ok(Status::Finished));
+        assert_eq!(machine.read_memory(0xffff, 1), Ok(vec![-1]));
+
+        machine.reset(true);
+        assert_eq!((machine.cycles(), machine.program_counter()), (0, 0));
+        assert_eq!(machine.register("R0"), Ok(0));
+        assert_eq!(machine.read_memory(100, 1), Ok(vec![5]));
+        assert_eq!(machine.run(), Ok(Status::Finished));
+        assert_eq!(machine.read_memory(1, 1), Ok(vec![1]));
+
+        machine.reset(false);
+        assert_eq!(machine.read_memory(100, 1), Ok(vec![0]));
+    }
+
+    #[test]
+    fn programs_must_fit_the_control_store() {
+        let program = vec![0; PROGRAM_LENGTH + 1];
+        assert_eq!(Machine::new(&program, Config::default()).err(),
+                   Some(MachineError::Cpu(CpuError::ProgramTooLong { len: PROGRAM_LENGTH + 1 })));
+    }
+
+    #[test]
+    fn tracing_counts_cycles() {
+        let mut machine = machine("R0 <- 1\nR1 <- R0 + R0", None);
+        let trace = trace::record(&mut machine, 100);
+        assert_eq!(trace.records.len(), 2);
+        assert_eq!(machine.cycles(), 2);
+        assert_eq!(machine.status(), Status::Finished);
+    }
+}
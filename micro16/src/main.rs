@@ -1,17 +1,195 @@
-use cpu::Cpu;
+extern crate micro16;
 
-pub mod bitset32;
-pub mod cpu;
-pub mod instruction;
+use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::Arc;
 
-fn main() {
-    let program = &[0x081e1100, 0x001d0e00, 0x021e0e00, 0x0080e000, 0x01200d00, 0x00200000,
-                    0x00200000];
+use micro16::cpu::{Phase, Profile};
+use micro16::format::{self, Layout};
+use micro16::machine::{Config, Machine, Status};
+use micro16::vcd::VcdWriter;
+use micro16::{cache, compiler, equiv, fault, fuzz, gdb, golden, grade, loader, mic1, multicore,
+              optimize, report, rpc, state, superopt, trace, tui};
+
+const USAGE: &str = "Usage: micro16 [run] [--program FILE] [--vcd FILE] [--phases] [--verbose]
+                     [--map-control-store BASE] [--call-stack DEPTH] [--format FILE]
+                     [--profile classic|extended]
+       micro16 tui [--program FILE]
+       micro16 grade SPEC DIR [--json FILE] [--csv FILE] [--jobs N]
+       micro16 test [PATH...]
+       micro16 fuzz [--seed N] [--iterations N] [--length N] [--cycles N]
+       micro16 compile FILE [--hex] [-o OUT]
+       micro16 optimize FILE [--hex] [-o OUT]
+       micro16 equiv A B [--input LOC=VALUES]... [--observe LOC,...] [--cycles N]
+                     [--limit N] [--seed N]
+       micro16 superopt [SPEC] [--example 'INIT -> EXPECT']... [--max-length N] [--scratch N]
+                        [--solutions N] [--verify N] [--seed N] [--max-states N]
+                        [--profile classic|extended]
+       micro16 faults PROGRAM [--init ASSIGNMENTS] [--cycles N] [--observe LOC,...] [--verbose]
+                      [--fault FAULT]... | [--random N] [--seed N] [--kinds KIND,...] |
+                      [--enumerate] [--kinds KIND,...]
+       micro16 multicore PROGRAM... [--cores N] [--policy POLICY] [--seed N]
+                         [--jitter PERCENT] [--tas ADDR] [--core-id REG]
+                         [--init ASSIGNMENTS] [--cycles N] [--trace FILE]
+       micro16 cache --program FILE [--size N] [--line N] [--ways N]
+                     [--write-back | --write-through] [--replacement lru|fifo|random]
+                     [--seed N] [--miss-penalty N] [--write-penalty N]
+                     [--region NAME=START..END]... [--init ASSIGNMENTS] [--cycles N]
+       micro16 gdb --program FILE [--port N] [--init ASSIGNMENTS] [--map-control-store BASE]
+                   [--call-stack DEPTH]
+       micro16 trace PROGRAM [-o OUT] [--init ASSIGNMENTS] [--cycles N]
+                     [--map-control-store BASE] [--call-stack DEPTH]
+       micro16 trace --show FILE
+       micro16 report PROGRAM [-o OUT] [--init ASSIGNMENTS] [--cycles N]
+                      [--map-control-store BASE] [--call-stack DEPTH]
+       micro16 report --trace FILE [-o OUT]
+       micro16 trace-diff A B [--context N] [--ignore FIELD,...]
+       micro16 serve [--port N] [--max-sessions N] [--cycle-budget N] [--max-run N]
+       micro16 format [--format FILE] [decode HEXWORD... | encode FIELD=VALUE...]
+       micro16 mic1 [PROGRAM] [--microprogram FILE] [--cycles N] [--listing]";
 
-    let mut cpu = Cpu::new(program);
+/// The registers `run` prints once the program finishes.
+const REGISTERS: [&str; 13] = ["R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7", "R8", "R9", "R10",
+                               "MAR", "MBR"];
 
-    while !cpu.done() {
-        println!("{:?}", cpu);
-        cpu.step();
+const DEMO_PROGRAM: [u32; 7] = [0x081e1100, 0x001d0e00, 0x021e0e00, 0x0080e000, 0x01200d00,
+                                0x00200000, 0x00200000];
+
+fn option_value(args: &[String], name: &str) -> Result<Option<String>, String> {
+    match args.iter().position(|a| a == name) {
+        Some(i) => {
+            match args.get(i + 1) {
+                Some(value) => Ok(Some(value.clone())),
+                None => Err(USAGE.to_string()),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+fn program_arg(args: &[String]) -> Result<Vec<u32>, String> {
+    match option_value(args, "--program")? {
+        Some(path) => loader::read_program(&path),
+        None => Ok(DEMO_PROGRAM.to_vec()),
+    }
+}
+
+fn profile_arg(args: &[String]) -> Result<Profile, String> {
+    match option_value(args, "--profile")? {
+        Some(name) => Profile::parse(&name),
+        None => Ok(Profile::Classic),
+    }
+}
+
+/// The `--format` and `--profile` options. The profile follows from the
+/// format if only that is given, and must agree with it if both are.
+fn layout_arg(args: &[String]) -> Result<(Arc<Layout>, Profile), String> {
+    let profile = profile_arg(args)?;
+    match option_value(args, "--format")? {
+        Some(path) => {
+            let layout = Layout::load(&path)?;
+            if option_value(args, "--profile")?.is_some() {
+                layout.check_profile(profile)?;
+            }
+            let profile = layout.profile();
+            Ok((layout, profile))
+        }
+        None => Ok((Layout::for_profile(profile), profile)),
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let vcd_path = option_value(args, "--vcd")?;
+    let phases = args.iter().any(|a| a == "--phases");
+    let verbose = args.iter().any(|a| a == "--verbose");
+    let (layout, profile) = layout_arg(args)?;
+    let program = match option_value(args, "--program")? {
+        Some(path) => loader::read_program_with(&path, &layout)?,
+        None => DEMO_PROGRAM.to_vec(),
+    };
+
+    let mut config = Config::default();
+    config.profile = profile;
+    if let Some(base) = option_value(args, "--map-control-store")? {
+        config.control_store_window = Some(state::parse_value(&base)? as u16);
+    }
+    if let Some(depth) = option_value(args, "--call-stack")? {
+        config.call_stack_depth = Some(depth.parse().map_err(|_| USAGE.to_string())?);
+    }
+    let mut machine = Machine::new(&program, config).map_err(|e| e.to_string())?;
+    machine.set_layout(layout);
+    let mut vcd = match vcd_path {
+        Some(path) => {
+            let file = File::create(&path).map_err(|e| format!("{}: {}", path, e))?;
+            Some(VcdWriter::new(BufWriter::new(file)).map_err(|e| e.to_string())?)
+        }
+        None => None,
+    };
+
+    while machine.status() == Status::Running {
+        if verbose {
+            println!("{:?}", machine.cpu());
+        }
+        if let Some(ref mut vcd) = vcd {
+            vcd.sample(machine.cpu()).map_err(|e| e.to_string())?;
+        }
+        if phases {
+            loop {
+                let phase = machine.phase();
+                machine.step_phase().map_err(|e| e.to_string())?;
+                println!("{:?}: {:?}", phase, machine.cpu().datapath());
+                if phase == Phase::WriteBack {
+                    break;
+                }
+            }
+        } else {
+            machine.step().map_err(|e| e.to_string())?;
+        }
+    }
+
+    if let Some(mut vcd) = vcd {
+        vcd.sample(machine.cpu()).map_err(|e| e.to_string())?;
+        vcd.finish().map_err(|e| e.to_string())?;
+    }
+    println!("finished after {} cycles", machine.cycles());
+    let registers: Vec<String> = REGISTERS.iter()
+        .map(|name| format!("{}={}", name, machine.register(name).expect("a known register")))
+        .collect();
+    println!("{}", registers.join(" "));
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(|a| a.as_str()) {
+        Some("run") => run(&args[1..]),
+        Some("tui") => program_arg(&args[1..]).and_then(|p| tui::run(&p).map_err(|e| e.to_string())),
+        Some("grade") => grade::main(&args[1..]),
+        Some("test") => golden::main(&args[1..]),
+        Some("fuzz") => fuzz::main(&args[1..]),
+        Some("compile") => compiler::main(&args[1..]),
+        Some("optimize") => optimize::main(&args[1..]),
+        Some("equiv") => equiv::main(&args[1..]),
+        Some("superopt") => superopt::main(&args[1..]),
+        Some("faults") => fault::main(&args[1..]),
+        Some("multicore") => multicore::main(&args[1..]),
+        Some("cache") => cache::main(&args[1..]),
+        Some("gdb") => gdb::main(&args[1..]),
+        Some("trace") => trace::main(&args[1..]),
+        Some("trace-diff") => trace::diff_main(&args[1..]),
+        Some("report") => report::main(&args[1..]),
+        Some("serve") => rpc::main(&args[1..]),
+        Some("format") => format::main(&args[1..]),
+        Some("mic1") => mic1::main(&args[1..]),
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => run(&args),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
 }
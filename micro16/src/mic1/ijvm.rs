@@ -0,0 +1,386 @@
+//! IJVM, the stack machine that the MIC-1 microprogram of the book
+//! interprets, and an assembler for it in the style of `ijvm-asm`:
+//!
+//! ```text
+//! .constant
+//!     limit 10
+//! .end-constant
+//!
+//! .main
+//! .var
+//!     i
+//! .end-var
+//!     BIPUSH 0
+//!     ISTORE i
+//! loop:
+//!     IINC i 1
+//!     ILOAD i
+//!     LDC_W limit
+//!     IF_ICMPEQ done
+//!     GOTO loop
+//! done:
+//!     HALT
+//! .end-main
+//! ```
+//!
+//! Methods are written `.method NAME(PARAM, ...)` ... `.end-method` and
+//! called with `LDC_W OBJREF`, the arguments and `INVOKEVIRTUAL NAME`.
+//! Labels are local to their method and `//` starts a comment.
+//!
+//! The method area starts at byte 0 with the main program, followed by
+//! the other methods. The constant pool starts at word `CONSTANT_POOL` and
+//! the stack at word `STACK`, where the locals of the main program live.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use cpu::MemoryPort;
+use super::{mal, Mic1, CPP, LV, MBR, PC, SP};
+
+pub const CONSTANT_POOL: i32 = 0x2000;
+pub const STACK: i32 = 0x3000;
+/// Bytes available to the method area, below the constant pool.
+const CODE_LENGTH: usize = CONSTANT_POOL as usize * 4;
+
+/// The IJVM interpreter of the book in MAL.
+pub const MICROPROGRAM: &str = include_str!("ijvm.mal");
+
+const WIDE: u8 = 0xc4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    None,
+    Byte,
+    Var,
+    VarByte,
+    Offset,
+    Constant,
+    Method,
+}
+
+const INSTRUCTIONS: [(&str, u8, Operand); 21] = [("NOP", 0x00, Operand::None),
+                                                  ("BIPUSH", 0x10, Operand::Byte),
+                                                  ("LDC_W", 0x13, Operand::Constant),
+                                                  ("ILOAD", 0x15, Operand::Var),
+                                                  ("ISTORE", 0x36, Operand::Var),
+                                                  ("POP", 0x57, Operand::None),
+                                                  ("DUP", 0x59, Operand::None),
+                                                  ("SWAP", 0x5f, Operand::None),
+                                                  ("IADD", 0x60, Operand::None),
+                                                  ("ISUB", 0x64, Operand::None),
+                                                  ("IAND", 0x7e, Operand::None),
+                                                  ("IINC", 0x84, Operand::VarByte),
+                                                  ("IFEQ", 0x99, Operand::Offset),
+                                                  ("IFLT", 0x9b, Operand::Offset),
+                                                  ("IF_ICMPEQ", 0x9f, Operand::Offset),
+                                                  ("GOTO", 0xa7, Operand::Offset),
+                                                  ("IRETURN", 0xac, Operand::None),
+                                                  ("IOR", 0xb0, Operand::None),
+                                                  ("INVOKEVIRTUAL", 0xb6, Operand::Method),
+                                                  ("HALT", 0xff, Operand::None),
+                                                  ("WIDE", WIDE, Operand::None)];
+
+/// The control store words of `MICROPROGRAM`.
+pub fn microprogram() -> Vec<u64> {
+    mal::assemble(MICROPROGRAM).expect("the IJVM microprogram assembles")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IjvmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for IjvmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// An assembled program, ready to be loaded into a MIC-1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub code: Vec<u8>,
+    pub constants: Vec<i32>,
+    /// Local variables of the main program.
+    pub main_locals: u16,
+}
+
+impl Image {
+    /// Writes the method area and the constant pool and points the
+    /// registers at the start of the main program.
+    pub fn load<M: MemoryPort>(&self, mic1: &mut Mic1<M>) {
+        for (addr, &byte) in self.code.iter().enumerate() {
+            mic1.write_byte(addr as i32, byte);
+        }
+        for (i, &value) in self.constants.iter().enumerate() {
+            mic1.write_word(CONSTANT_POOL + i as i32, value);
+        }
+        mic1.set_register(CPP, CONSTANT_POOL);
+        mic1.set_register(LV, STACK);
+        mic1.set_register(SP, STACK + self.main_locals as i32 - 1);
+        mic1.set_register(PC, -1);
+        mic1.set_register(MBR, 0);
+        mic1.set_mpc(0);
+    }
+}
+
+/// The constant pool by name, in pool order.
+type Constants = Vec<(String, i32)>;
+
+struct Method {
+    name: String,
+    line: usize,
+    /// Parameters including the object reference, locals after them.
+    params: Vec<String>,
+    locals: Vec<String>,
+    body: Vec<(usize, String)>,
+}
+
+impl Method {
+    fn variable(&self, name: &str) -> Option<usize> {
+        self.params.iter().chain(&self.locals).position(|v| v == name)
+    }
+}
+
+fn parse_int(text: &str) -> Option<i64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Splits the source into the constant pool and the methods, the main
+/// program first.
+fn parse(source: &str) -> Result<(Constants, Vec<Method>), IjvmError> {
+    let mut constants = vec![("OBJREF".to_string(), 0)];
+    let mut methods: Vec<Method> = Vec::new();
+    let mut section = "";
+    for (i, line) in source.lines().enumerate() {
+        let err = |message: String| IjvmError { line: i + 1, message };
+        let text = line.split("//").next().unwrap().trim();
+        if text.is_empty() {
+            continue;
+        }
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match (section, words[0]) {
+            ("", ".constant") => section = ".constant",
+            (".constant", ".end-constant") => section = "",
+            (".constant", name) => {
+                let value = match words.as_slice() {
+                    [_, value] => parse_int(value).filter(|v| *v as i32 as i64 == *v),
+                    _ => None,
+                };
+                let value =
+                    value.ok_or_else(|| err(format!("expected NAME VALUE, got '{}'", text)))?;
+                if constants.iter().any(|c| c.0 == name) {
+                    return Err(err(format!("constant '{}' defined twice", name)));
+                }
+                constants.push((name.to_string(), value as i32));
+            }
+            ("", ".main") | ("", ".method") => {
+                let (name, params) = if words[0] == ".main" {
+                    ("main".to_string(), Vec::new())
+                } else {
+                    let signature = text[".method".len()..].trim();
+                    let (name, params) = signature.strip_suffix(')')
+                        .and_then(|s| s.split_once('('))
+                        .ok_or_else(|| {
+                            err(format!("expected .method NAME(PARAMS), got '{}'", text))
+                        })?;
+                    let params = params.split(',')
+                        .map(|p| p.trim().to_string())
+                        .filter(|p| !p.is_empty())
+                        .collect();
+                    (name.trim().to_string(), params)
+                };
+                if methods.iter().any(|m| m.name == name) {
+                    return Err(err(format!("method '{}' defined twice", name)));
+                }
+                // Local 0 of a method is the object reference, which the
+                // call turns into the link pointer.
+                let params = if words[0] == ".main" {
+                    params
+                } else {
+                    let mut all = vec!["OBJREF".to_string()];
+                    all.extend(params);
+                    all
+                };
+                methods.push(Method {
+                    name,
+                    line: i + 1,
+                    params,
+                    locals: Vec::new(),
+                    body: Vec::new(),
+                });
+                section = if words[0] == ".main" { ".main" } else { ".method" };
+            }
+            (".main", ".end-main") | (".method", ".end-method") => section = "",
+            (".main", ".var") | (".method", ".var") => {
+                section = if section == ".main" { ".main.var" } else { ".method.var" };
+            }
+            (".main.var", ".end-var") => section = ".main",
+            (".method.var", ".end-var") => section = ".method",
+            (".main.var", _) | (".method.var", _) => {
+                let method = methods.last_mut().unwrap();
+                for name in words {
+                    if method.variable(name).is_some() {
+                        return Err(err(format!("variable '{}' defined twice", name)));
+                    }
+                    method.locals.push(name.to_string());
+                }
+            }
+            (".main", _) | (".method", _) if !words[0].starts_with('.') => {
+                methods.last_mut().unwrap().body.push((i + 1, text.to_string()));
+            }
+            _ => return Err(err(format!("unexpected '{}'", words[0]))),
+        }
+    }
+    if !section.is_empty() {
+        return Err(IjvmError {
+            line: source.lines().count(),
+            message: format!("unterminated {}", section.trim_end_matches(".var")),
+        });
+    }
+    match methods.iter().position(|m| m.name == "main") {
+        Some(i) => {
+            let main = methods.remove(i);
+            methods.insert(0, main);
+        }
+        None => {
+            return Err(IjvmError {
+                line: 0,
+                message: "the program has no .main".to_string(),
+            })
+        }
+    }
+    Ok((constants, methods))
+}
+
+/// Assembles an IJVM program.
+pub fn assemble(source: &str) -> Result<Image, IjvmError> {
+    let (mut constants, methods) = parse(source)?;
+    let user_constants = constants.len();
+    for method in &methods[1..] {
+        constants.push((method.name.clone(), 0));
+    }
+
+    let mut code = Vec::new();
+    for (m, method) in methods.iter().enumerate() {
+        if m > 0 {
+            constants[user_constants + m - 1].1 = code.len() as i32;
+            code.extend(&(method.params.len() as u16).to_be_bytes());
+            code.extend(&(method.locals.len() as u16).to_be_bytes());
+        }
+        let mut labels = HashMap::new();
+        // Offsets to patch: position, opcode address, label, line.
+        let mut fixups = Vec::new();
+        for &(line, ref text) in &method.body {
+            let err = |message: String| IjvmError { line, message };
+            let mut text = text.as_str();
+            if let Some((label, rest)) = text.split_once(':') {
+                let label = label.trim();
+                if labels.insert(label.to_string(), code.len()).is_some() {
+                    return Err(err(format!("label '{}' defined twice", label)));
+                }
+                text = rest.trim();
+                if text.is_empty() {
+                    continue;
+                }
+            }
+            let words: Vec<&str> = text.split_whitespace().collect();
+            let &(_, opcode, operand) = INSTRUCTIONS.iter()
+                .find(|i| i.0.eq_ignore_ascii_case(words[0]))
+                .ok_or_else(|| err(format!("unknown instruction '{}'", words[0])))?;
+            let args = &words[1..];
+            let expected = match operand {
+                Operand::None => 0,
+                Operand::VarByte => 2,
+                _ => 1,
+            };
+            if args.len() != expected {
+                return Err(err(format!("{} takes {} operand(s)", words[0], expected)));
+            }
+            let variable = |name: &str| {
+                method.variable(name)
+                    .or_else(|| parse_int(name).filter(|&v| v >= 0).map(|v| v as usize))
+                    .filter(|&v| v <= u16::MAX as usize)
+                    .ok_or_else(|| err(format!("unknown variable '{}'", name)))
+            };
+            let byte = |text: &str| {
+                parse_int(text)
+                    .filter(|&v| (-128..=127).contains(&v))
+                    .map(|v| v as i8 as u8)
+                    .ok_or_else(|| err(format!("'{}' is not a signed byte", text)))
+            };
+            let constant = |name: &str, kind: &str| {
+                constants.iter()
+                    .position(|c| c.0 == name)
+                    .filter(|_| kind == "constant" || name != "OBJREF")
+                    .or_else(|| parse_int(name).filter(|&v| v >= 0).map(|v| v as usize))
+                    .filter(|&v| v <= u16::MAX as usize)
+                    .ok_or_else(|| err(format!("unknown {} '{}'", kind, name)))
+            };
+            let start = code.len();
+            match operand {
+                Operand::Var => {
+                    let var = variable(args[0])?;
+                    if var > 0xff {
+                        code.push(WIDE);
+                        code.push(opcode);
+                        code.extend(&(var as u16).to_be_bytes());
+                    } else {
+                        code.extend(&[opcode, var as u8]);
+                    }
+                }
+                Operand::VarByte => {
+                    let var = variable(args[0])?;
+                    if var > 0xff {
+                        return Err(err(format!("{} cannot reach variable {}", words[0], var)));
+                    }
+                    code.extend(&[opcode, var as u8, byte(args[1])?]);
+                }
+                Operand::Byte => code.extend(&[opcode, byte(args[0])?]),
+                Operand::Constant => {
+                    code.push(opcode);
+                    code.extend(&(constant(args[0], "constant")? as u16).to_be_bytes());
+                }
+                Operand::Method => {
+                    code.push(opcode);
+                    code.extend(&(constant(args[0], "method")? as u16).to_be_bytes());
+                }
+                Operand::Offset => {
+                    fixups.push((code.len() + 1, start, args[0].to_string(), line));
+                    code.extend(&[opcode, 0, 0]);
+                }
+                Operand::None => code.push(opcode),
+            }
+        }
+        for (pos, start, label, line) in fixups {
+            let target = *labels.get(&label).ok_or_else(|| IjvmError {
+                line,
+                message: format!("undefined label '{}'", label),
+            })?;
+            let offset = target as i64 - start as i64;
+            if offset < i16::MIN as i64 || offset > i16::MAX as i64 {
+                return Err(IjvmError {
+                    line,
+                    message: format!("label '{}' is out of reach", label),
+                });
+            }
+            code[pos..pos + 2].copy_from_slice(&(offset as i16).to_be_bytes());
+        }
+        if code.len() > CODE_LENGTH {
+            return Err(IjvmError {
+                line: method.line,
+                message: format!("the program exceeds the {} byte method area", CODE_LENGTH),
+            });
+        }
+    }
+
+    Ok(Image {
+        code,
+        constants: constants.into_iter().map(|c| c.1).collect(),
+        main_locals: methods[0].locals.len() as u16,
+    })
+}
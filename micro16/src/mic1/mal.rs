@@ -0,0 +1,496 @@
+//! The MIC-1 micro assembly language of Tanenbaum's book, e.g.
+//!
+//! ```text
+//! .label nop1 0x00
+//! Main1   PC = PC + 1; fetch; goto (MBR)
+//! nop1    goto Main1
+//! iflt4   N = OPC; if (N) goto T; else goto F
+//! ```
+//!
+//! A line holds one microinstruction, optionally led by a label, and `//`
+//! starts a comment. Statements are assignments `DEST = ... = EXPR`, where
+//! EXPR is one of the ALU functions below, optionally followed by `<< 8` or
+//! `>> 1`, `rd`, `wr`, `fetch` and the jumps `goto LABEL`, `goto (MBR)`,
+//! `goto (MBR OR 0x100)` and `if (N) goto A; else goto B` (likewise for Z).
+//! Assigning to `N` or `Z` only sets the flags. An instruction without a jump
+//! continues with the next line.
+//!
+//! Labels are placed by the assembler unless `.label NAME ADDR` fixes
+//! them, as the targets of `goto (MBR)` require; the two targets of a
+//! conditional jump end up 0x100 apart. Jump targets may also be plain
+//! addresses, which is what the disassembler prints.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use super::{MicroInstruction, B_BUS, C_BUS, CONTROL_STORE_LENGTH, HALT_ADDRESS,
+            REGISTER_NAMES};
+
+/// ALU functions by their control bits F0 F1 ENA ENB INVA INC, with `H` for
+/// the A input and `B` for the B bus. The first spelling of a function is
+/// the one the disassembler prints.
+const FUNCTIONS: [(u8, &str); 20] = [(0x18, "H"),
+                                     (0x14, "B"),
+                                     (0x1a, "NOT H"),
+                                     (0x2c, "NOT B"),
+                                     (0x3c, "B + H"),
+                                     (0x3c, "H + B"),
+                                     (0x3d, "B + H + 1"),
+                                     (0x3d, "H + B + 1"),
+                                     (0x39, "H + 1"),
+                                     (0x35, "B + 1"),
+                                     (0x3f, "B - H"),
+                                     (0x36, "B - 1"),
+                                     (0x3b, "-H"),
+                                     (0x0c, "B AND H"),
+                                     (0x0c, "H AND B"),
+                                     (0x1c, "B OR H"),
+                                     (0x1c, "H OR B"),
+                                     (0x10, "0"),
+                                     (0x31, "1"),
+                                     (0x32, "-1")];
+
+const APART: &str = "the targets of a conditional jump must be 0x100 apart";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MalError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for MalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    Label(String),
+    Addr(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Next {
+    /// Continue with the next line.
+    Line,
+    Goto(Target),
+    /// `goto (MBR OR value)`.
+    Mbr(u16),
+    Branch { then: Target, otherwise: Target },
+}
+
+#[derive(Debug, Clone)]
+struct Micro {
+    line: usize,
+    instr: MicroInstruction,
+    next: Next,
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c.is_alphanumeric() || c == '_' {
+            let mut word = c.to_string();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_') {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(word);
+        } else if (c == '<' || c == '>') && chars.peek() == Some(&c) {
+            chars.next();
+            tokens.push(format!("{}{}", c, c));
+        } else {
+            tokens.push(c.to_string());
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<u16> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn is_label(text: &str) -> bool {
+    let reserved = ["rd", "wr", "fetch", "goto", "if", "else", "N", "Z", "MBRU"];
+    text.chars().next().is_some_and(|c| c.is_alphabetic() || c == '_') &&
+    text.chars().all(|c| c.is_alphanumeric() || c == '_') &&
+    !reserved.contains(&text) && !REGISTER_NAMES.contains(&text)
+}
+
+fn parse_target(text: &str) -> Result<Target, String> {
+    if let Some(addr) = parse_number(text) {
+        if addr as usize >= CONTROL_STORE_LENGTH {
+            return Err(format!("address {:#x} is outside the control store", addr));
+        }
+        Ok(Target::Addr(addr))
+    } else if is_label(text) {
+        Ok(Target::Label(text.to_string()))
+    } else {
+        Err(format!("invalid jump target '{}'", text))
+    }
+}
+
+/// Sets the ALU, shifter and B bus fields for `expr`.
+fn encode_expr(instr: &mut MicroInstruction, expr: &[&str]) -> Result<(), String> {
+    let (expr, sll8, sra1) = match expr {
+        [rest @ .., "<<", "8"] => (rest, true, false),
+        [rest @ .., ">>", "1"] => (rest, false, true),
+        _ => (expr, false, false),
+    };
+    for &(code, function) in FUNCTIONS.iter() {
+        let pattern: Vec<String> = tokenize(function);
+        if pattern.len() != expr.len() {
+            continue;
+        }
+        let mut source = None;
+        let matches = pattern.iter().zip(expr).all(|(p, &t)| {
+            if p == "B" {
+                source = B_BUS.iter().position(|&b| b == t);
+                source.is_some()
+            } else {
+                p == t
+            }
+        });
+        if matches {
+            instr.set_alu(code);
+            instr.set_b(source.unwrap_or(0) as u8);
+            instr.set_sll8(sll8);
+            instr.set_sra1(sra1);
+            return Ok(());
+        }
+    }
+    Err(format!("'{}' is not an ALU function", expr.join(" ")))
+}
+
+fn statement(micro: &mut Micro,
+             otherwise: &mut Option<Target>,
+             tokens: &[&str])
+             -> Result<(), String> {
+    let instr = &mut micro.instr;
+    let jump = |micro_next: &Next| -> Result<(), String> {
+        if *micro_next != Next::Line {
+            return Err("more than one jump".to_string());
+        }
+        Ok(())
+    };
+    match tokens {
+        ["rd"] => instr.set_read(true),
+        ["wr"] => instr.set_write(true),
+        ["fetch"] => instr.set_fetch(true),
+        ["goto", "(", "MBR", ")"] => {
+            jump(&micro.next)?;
+            micro.next = Next::Mbr(0);
+        }
+        ["goto", "(", "MBR", "OR", value, ")"] => {
+            jump(&micro.next)?;
+            let value = parse_number(value)
+                .filter(|&v| (v as usize) < CONTROL_STORE_LENGTH)
+                .ok_or_else(|| format!("invalid address '{}'", value))?;
+            micro.next = Next::Mbr(value);
+        }
+        ["goto", target] => {
+            jump(&micro.next)?;
+            micro.next = Next::Goto(parse_target(target)?);
+        }
+        ["if", "(", flag, ")", "goto", target] if *flag == "N" || *flag == "Z" => {
+            jump(&micro.next)?;
+            if *flag == "N" {
+                instr.set_jamn(true);
+            } else {
+                instr.set_jamz(true);
+            }
+            micro.next = Next::Branch {
+                then: parse_target(target)?,
+                otherwise: Target::Addr(0),
+            };
+        }
+        ["else", "goto", target] => *otherwise = Some(parse_target(target)?),
+        _ if tokens.contains(&"=") => {
+            let parts: Vec<&[&str]> = tokens.split(|&t| t == "=").collect();
+            let (expr, dests) = parts.split_last().unwrap();
+            let mut c = instr.c();
+            for dest in dests {
+                match *dest {
+                    ["N"] | ["Z"] => (),
+                    [reg] => {
+                        let bit = C_BUS.iter()
+                            .position(|&r| REGISTER_NAMES[r as usize] == *reg)
+                            .ok_or_else(|| format!("cannot assign to '{}'", reg))?;
+                        c |= 1 << bit;
+                    }
+                    _ => return Err(format!("invalid assignment '{}'", tokens.join(" "))),
+                }
+            }
+            instr.set_c(c);
+            encode_expr(instr, expr)?;
+        }
+        _ => return Err(format!("invalid statement '{}'", tokens.join(" "))),
+    }
+    Ok(())
+}
+
+/// Places every instruction: fixed labels first, then both halves of each
+/// conditional jump, then the rest at the lowest free address.
+fn place(micros: &[Micro],
+         labels: &HashMap<String, usize>,
+         fixed: &HashMap<String, (u16, usize)>)
+         -> Result<Vec<u16>, MalError> {
+    let mut addrs: Vec<Option<u16>> = vec![None; micros.len()];
+    let mut used = [false; CONTROL_STORE_LENGTH];
+    used[HALT_ADDRESS as usize] = true;
+
+    let mut fixed: Vec<(&String, &(u16, usize))> = fixed.iter().collect();
+    fixed.sort_by_key(|&(_, &(addr, _))| addr);
+    for (name, &(addr, line)) in fixed {
+        if used[addr as usize] && addr != HALT_ADDRESS {
+            return Err(MalError {
+                line,
+                message: format!("address {:#x} is taken twice", addr),
+            });
+        }
+        used[addr as usize] = true;
+        if let Some(&idx) = labels.get(name) {
+            addrs[idx] = Some(addr);
+        }
+    }
+
+    let resolve = |target: &Target, addrs: &[Option<u16>]| -> Option<u16> {
+        match *target {
+            Target::Addr(addr) => Some(addr),
+            Target::Label(ref name) => labels.get(name).and_then(|&i| addrs[i]),
+        }
+    };
+    for micro in micros {
+        let (then, otherwise) = match micro.next {
+            Next::Branch { ref then, ref otherwise } => (then, otherwise),
+            _ => continue,
+        };
+        let err = |message: String| MalError { line: micro.line, message };
+        let slot = |target: &Target| match *target {
+            Target::Label(ref name) => labels.get(name).cloned(),
+            Target::Addr(_) => None,
+        };
+        let (then_addr, else_addr) = match (resolve(then, &addrs), resolve(otherwise, &addrs)) {
+            (Some(t), Some(e)) => (t, e),
+            (Some(t), None) if t >= 0x100 => (t, t - 0x100),
+            (None, Some(e)) if e < 0x100 => (e + 0x100, e),
+            (None, None) => {
+                let free = (0..0x100).find(|&a| !used[a] && !used[a + 0x100]);
+                let a = free.ok_or_else(|| err("no room for a conditional jump".to_string()))?;
+                (a as u16 + 0x100, a as u16)
+            }
+            _ => return Err(err(APART.to_string())),
+        };
+        if then_addr != else_addr + 0x100 {
+            return Err(err(APART.to_string()));
+        }
+        for &(target, addr) in &[(then, then_addr), (otherwise, else_addr)] {
+            if let Some(idx) = slot(target) {
+                if addrs[idx].is_none() {
+                    if used[addr as usize] {
+                        return Err(err(format!("address {:#x} is taken twice", addr)));
+                    }
+                    used[addr as usize] = true;
+                    addrs[idx] = Some(addr);
+                }
+            }
+        }
+    }
+
+    let mut free = (0..CONTROL_STORE_LENGTH).filter(|&a| !used[a]).collect::<Vec<_>>().into_iter();
+    addrs.into_iter()
+        .zip(micros)
+        .map(|(addr, micro)| match addr {
+            Some(addr) => Ok(addr),
+            None => free.next().map(|a| a as u16).ok_or_else(|| MalError {
+                line: micro.line,
+                message: "the control store is full".to_string(),
+            }),
+        })
+        .collect()
+}
+
+/// Assembles a microprogram into control store words.
+pub fn assemble(source: &str) -> Result<Vec<u64>, MalError> {
+    let mut micros = Vec::new();
+    let mut labels = HashMap::new();
+    let mut fixed = HashMap::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let err = |message: String| MalError { line: i + 1, message };
+        let text = line.split("//").next().unwrap().trim();
+        if text.is_empty() {
+            continue;
+        }
+        if let Some(rest) = text.strip_prefix(".label") {
+            let words: Vec<&str> = rest.split_whitespace().collect();
+            let (name, addr) = match words.as_slice() {
+                [name, addr] if is_label(name) => (name.to_string(), parse_number(addr)),
+                _ => return Err(err(format!("expected .label NAME ADDR, got '{}'", text))),
+            };
+            let addr = addr.filter(|&a| (a as usize) < CONTROL_STORE_LENGTH)
+                .ok_or_else(|| err(format!("invalid address in '{}'", text)))?;
+            if fixed.insert(name.clone(), (addr, i + 1)).is_some() {
+                return Err(err(format!("label '{}' placed twice", name)));
+            }
+            continue;
+        }
+
+        let mut body = text;
+        let first = text.split_whitespace().next().unwrap();
+        let rest = text[first.len()..].trim_start();
+        if is_label(first) && !rest.starts_with('=') {
+            if labels.insert(first.to_string(), micros.len()).is_some() {
+                return Err(err(format!("label '{}' defined twice", first)));
+            }
+            body = rest;
+        }
+
+        let mut micro = Micro {
+            line: i + 1,
+            instr: MicroInstruction::new(0),
+            next: Next::Line,
+        };
+        let mut otherwise = None;
+        for statement_text in body.split(';') {
+            let tokens = tokenize(statement_text);
+            let tokens: Vec<&str> = tokens.iter().map(|t| t.as_str()).collect();
+            if !tokens.is_empty() {
+                statement(&mut micro, &mut otherwise, &tokens).map_err(&err)?;
+            }
+        }
+        match (&mut micro.next, otherwise) {
+            (&mut Next::Branch { otherwise: ref mut o, .. }, Some(target)) => *o = target,
+            (&mut Next::Branch { .. }, None) => {
+                return Err(err("a conditional jump needs an else branch".to_string()))
+            }
+            (_, Some(_)) => return Err(err("else without if".to_string())),
+            _ => (),
+        }
+        micros.push(micro);
+    }
+
+    for micro in &micros {
+        let targets = match micro.next {
+            Next::Goto(ref t) => vec![t],
+            Next::Branch { ref then, ref otherwise } => vec![then, otherwise],
+            _ => Vec::new(),
+        };
+        for target in targets {
+            if let Target::Label(ref name) = *target {
+                if !labels.contains_key(name) && !fixed.contains_key(name) {
+                    return Err(MalError {
+                        line: micro.line,
+                        message: format!("undefined label '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    let addrs = place(&micros, &labels, &fixed)?;
+    let target_addr = |target: &Target| match *target {
+        Target::Addr(addr) => addr,
+        Target::Label(ref name) => match labels.get(name) {
+            Some(&idx) => addrs[idx],
+            None => fixed[name].0,
+        },
+    };
+
+    let mut program = vec![0u64; addrs.iter().map(|&a| a as usize + 1).max().unwrap_or(0)];
+    for (idx, micro) in micros.iter().enumerate() {
+        let mut instr = micro.instr;
+        let next = match micro.next {
+            Next::Line => match addrs.get(idx + 1) {
+                Some(&addr) => addr,
+                None => {
+                    return Err(MalError {
+                        line: micro.line,
+                        message: "the last instruction needs a jump".to_string(),
+                    })
+                }
+            },
+            Next::Goto(ref target) => target_addr(target),
+            Next::Mbr(value) => {
+                instr.set_jmpc(true);
+                value
+            }
+            Next::Branch { ref otherwise, .. } => target_addr(otherwise),
+        };
+        instr.set_next_address(next);
+        program[addrs[idx] as usize] = instr.raw();
+    }
+    Ok(program)
+}
+
+/// The ALU expression of `instr`, including the shift.
+pub fn expression(instr: &MicroInstruction) -> String {
+    let b = B_BUS.get(instr.b() as usize).cloned().unwrap_or("?");
+    let expr = match FUNCTIONS.iter().find(|&&(code, _)| code == instr.alu()) {
+        Some(&(_, function)) => function.replace('B', b),
+        None => format!("ALU({:#04x}, {})", instr.alu(), b),
+    };
+    if instr.sll8() {
+        format!("{} << 8", expr)
+    } else if instr.sra1() {
+        format!("{} >> 1", expr)
+    } else {
+        expr
+    }
+}
+
+/// Disassembles a microinstruction into MAL with numeric jump targets.
+pub fn disassemble(instr: &MicroInstruction) -> String {
+    let mut parts = Vec::new();
+    let mut dests: Vec<&str> = C_BUS.iter()
+        .enumerate()
+        .filter(|&(bit, _)| instr.c() & 1 << bit != 0)
+        .map(|(_, &r)| REGISTER_NAMES[r as usize])
+        .collect();
+    if dests.is_empty() && instr.jamn() {
+        dests.push("N");
+    } else if dests.is_empty() && instr.jamz() {
+        dests.push("Z");
+    }
+    if !dests.is_empty() {
+        parts.push(format!("{} = {}", dests.join(" = "), expression(instr)));
+    }
+    if instr.read() {
+        parts.push("rd".to_string());
+    }
+    if instr.write() {
+        parts.push("wr".to_string());
+    }
+    if instr.fetch() {
+        parts.push("fetch".to_string());
+    }
+    let next = instr.next_address();
+    if instr.jmpc() {
+        if next == 0 {
+            parts.push("goto (MBR)".to_string());
+        } else {
+            parts.push(format!("goto (MBR OR {:#x})", next));
+        }
+    } else if instr.jamn() || instr.jamz() {
+        let flag = if instr.jamn() { "N" } else { "Z" };
+        parts.push(format!("if ({}) goto {:#x}", flag, next | 0x100));
+        parts.push(format!("else goto {:#x}", next));
+    } else {
+        parts.push(format!("goto {:#x}", next));
+    }
+    parts.join("; ")
+}
+
+pub fn disassemble_word(word: u64) -> String {
+    disassemble(&MicroInstruction::new(word))
+}
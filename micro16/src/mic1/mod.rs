@@ -0,0 +1,798 @@
+//! Tanenbaum's MIC-1, the microarchitecture of "Structured Computer
+//! Organization", next to the Micro16.
+//!
+//! A microinstruction has 36 bits, from the most significant down:
+//!
+//! ```text
+//! 35..27  NEXT_ADDRESS
+//! 26..24  JMPC JAMN JAMZ
+//! 23..16  SLL8 SRA1 F0 F1 ENA ENB INVA INC
+//! 15..7   C bus: H OPC TOS CPP LV SP PC MDR MAR
+//! 6..4    WRITE READ FETCH
+//! 3..0    B bus: 0 MDR, 1 PC, 2 MBR, 3 MBRU, 4 SP, 5 LV, 6 CPP, 7 TOS, 8 OPC
+//! ```
+//!
+//! The A input of the ALU is always H. `rd`, `wr` and `fetch` start with
+//! the register values after the cycle's C bus writes; the memory delivers
+//! the data at the end of the following cycle, so MDR and MBR can be used
+//! two cycles after the access started. Writes land at the same time.
+//! `JMPC` is the exception: the MPC is computed last, so it already sees a
+//! byte that arrives at the end of the cycle, as `wide1` relies on.
+//!
+//! MIC-1 words are 32 bits wide and share the Micro16 memory cells: word
+//! `w` occupies cells `2w` (low half) and `2w + 1`, and byte `b`, as read by
+//! `fetch`, is the low byte of cell `b / 2` if `b` is even and its high byte
+//! otherwise. The control store holds 512 words; the machine halts when the
+//! MPC reaches `HALT_ADDRESS`, the slot of the IJVM `HALT` instruction.
+//!
+//! `mal` assembles microprograms written in Tanenbaum's MAL, and `ijvm`
+//! assembles IJVM programs for the microprogram of the book.
+
+pub mod ijvm;
+pub mod mal;
+
+use std::error::Error;
+use std::fmt;
+use std::mem;
+
+use arch::{Access, Core, Cycle, Info, MemoryOp};
+use cpu::{Memory, MemoryPort};
+use loader;
+
+pub const USAGE: &str = "Usage: micro16 mic1 [PROGRAM] [--microprogram FILE] [--cycles N]
+       micro16 mic1 --listing [--microprogram FILE]
+PROGRAM is IJVM assembly for the default microprogram, the IJVM interpreter.
+A microprogram is MAL if its name ends in .mal and hex words otherwise.";
+
+pub const CONTROL_STORE_LENGTH: usize = 512;
+pub const HALT_ADDRESS: u16 = 0xff;
+const WORD_BITS: u32 = 36;
+const MEMORY_CELLS: usize = 1 << 16;
+const DEFAULT_MAX_CYCLES: u64 = 1_000_000;
+const STACK_LINES: i32 = 32;
+
+pub const MAR: u8 = 0;
+pub const MDR: u8 = 1;
+pub const PC: u8 = 2;
+pub const MBR: u8 = 3;
+pub const SP: u8 = 4;
+pub const LV: u8 = 5;
+pub const CPP: u8 = 6;
+pub const TOS: u8 = 7;
+pub const OPC: u8 = 8;
+pub const H: u8 = 9;
+
+pub const REGISTER_NAMES: [&str; 10] = ["MAR", "MDR", "PC", "MBR", "SP", "LV", "CPP", "TOS",
+                                        "OPC", "H"];
+
+/// Registers on the C bus, from its least significant bit up.
+pub const C_BUS: [u8; 9] = [MAR, MDR, PC, SP, LV, CPP, TOS, OPC, H];
+
+/// Register driving the B bus for each B field value. `MBRU` is MBR
+/// without sign extension; it reads `MBR`.
+pub const B_BUS: [&str; 9] = ["MDR", "PC", "MBR", "MBRU", "SP", "LV", "CPP", "TOS", "OPC"];
+
+pub const INFO: Info = Info {
+    name: "mic1",
+    registers: &REGISTER_NAMES,
+    debug_registers: &[MAR, MDR, PC, MBR, SP, LV, CPP, TOS, OPC, H],
+    pc_name: "mpc",
+    flags: &["N", "Z"],
+    register_bits: 32,
+    word_digits: 9,
+    control_store_length: CONTROL_STORE_LENGTH,
+    word_cells: 2,
+    disassemble: mal::disassemble_word,
+};
+
+/// A MIC-1 microinstruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MicroInstruction {
+    raw: u64,
+}
+
+impl MicroInstruction {
+    pub fn new(raw: u64) -> MicroInstruction {
+        MicroInstruction { raw }
+    }
+
+    pub fn raw(&self) -> u64 {
+        self.raw
+    }
+
+    fn get(&self, lo: u32, width: u32) -> u64 {
+        (self.raw >> lo) & ((1 << width) - 1)
+    }
+
+    fn set(&mut self, lo: u32, width: u32, value: u64) {
+        let mask = ((1 << width) - 1) << lo;
+        self.raw = (self.raw & !mask) | ((value << lo) & mask);
+    }
+
+    pub fn next_address(&self) -> u16 {
+        self.get(27, 9) as u16
+    }
+
+    pub fn jmpc(&self) -> bool {
+        self.get(26, 1) != 0
+    }
+
+    pub fn jamn(&self) -> bool {
+        self.get(25, 1) != 0
+    }
+
+    pub fn jamz(&self) -> bool {
+        self.get(24, 1) != 0
+    }
+
+    pub fn sll8(&self) -> bool {
+        self.get(23, 1) != 0
+    }
+
+    pub fn sra1(&self) -> bool {
+        self.get(22, 1) != 0
+    }
+
+    /// The ALU control bits F0, F1, ENA, ENB, INVA and INC, F0 highest.
+    pub fn alu(&self) -> u8 {
+        self.get(16, 6) as u8
+    }
+
+    /// The C bus bits, MAR lowest; see `C_BUS`.
+    pub fn c(&self) -> u16 {
+        self.get(7, 9) as u16
+    }
+
+    pub fn write(&self) -> bool {
+        self.get(6, 1) != 0
+    }
+
+    pub fn read(&self) -> bool {
+        self.get(5, 1) != 0
+    }
+
+    pub fn fetch(&self) -> bool {
+        self.get(4, 1) != 0
+    }
+
+    pub fn b(&self) -> u8 {
+        self.get(0, 4) as u8
+    }
+
+    pub fn set_next_address(&mut self, value: u16) {
+        self.set(27, 9, value as u64);
+    }
+
+    pub fn set_jmpc(&mut self, value: bool) {
+        self.set(26, 1, value as u64);
+    }
+
+    pub fn set_jamn(&mut self, value: bool) {
+        self.set(25, 1, value as u64);
+    }
+
+    pub fn set_jamz(&mut self, value: bool) {
+        self.set(24, 1, value as u64);
+    }
+
+    pub fn set_sll8(&mut self, value: bool) {
+        self.set(23, 1, value as u64);
+    }
+
+    pub fn set_sra1(&mut self, value: bool) {
+        self.set(22, 1, value as u64);
+    }
+
+    pub fn set_alu(&mut self, value: u8) {
+        self.set(16, 6, value as u64);
+    }
+
+    pub fn set_c(&mut self, value: u16) {
+        self.set(7, 9, value as u64);
+    }
+
+    pub fn set_write(&mut self, value: bool) {
+        self.set(6, 1, value as u64);
+    }
+
+    pub fn set_read(&mut self, value: bool) {
+        self.set(5, 1, value as u64);
+    }
+
+    pub fn set_fetch(&mut self, value: bool) {
+        self.set(4, 1, value as u64);
+    }
+
+    pub fn set_b(&mut self, value: u8) {
+        self.set(0, 4, value as u64);
+    }
+
+    /// Whether the B bus is enabled into the ALU.
+    pub fn uses_b(&self) -> bool {
+        self.alu() & 0x04 != 0
+    }
+}
+
+/// Errors raised while decoding a microinstruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mic1Error {
+    /// The instruction at `addr` enables the B bus from a field value that
+    /// selects no register.
+    InvalidBusSource { addr: u16, source: u8 },
+    /// The instruction at `addr` sets both SLL8 and SRA1.
+    ConflictingShift { addr: u16 },
+    /// The instruction at `addr` starts a read and a write at once.
+    ReadAndWrite { addr: u16 },
+    /// The word at `addr` has bits set above bit 35.
+    WordTooWide { addr: u16 },
+    /// A program of `len` words does not fit into the control store.
+    ProgramTooLong { len: usize },
+}
+
+impl fmt::Display for Mic1Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Mic1Error::InvalidBusSource { addr, source } => {
+                write!(f,
+                       "instruction {:#x} drives the B bus from undefined source {}",
+                       addr,
+                       source)
+            }
+            Mic1Error::ConflictingShift { addr } => {
+                write!(f, "instruction {:#x} sets both SLL8 and SRA1", addr)
+            }
+            Mic1Error::ReadAndWrite { addr } => {
+                write!(f, "instruction {:#x} reads and writes at once", addr)
+            }
+            Mic1Error::WordTooWide { addr } => {
+                write!(f, "instruction {:#x} has bits set above bit 35", addr)
+            }
+            Mic1Error::ProgramTooLong { len } => {
+                write!(f,
+                       "program has {} words, the control store holds {}",
+                       len,
+                       CONTROL_STORE_LENGTH)
+            }
+        }
+    }
+}
+
+impl Error for Mic1Error {}
+
+/// Computes the ALU function selected by `alu`, see `MicroInstruction::alu`.
+pub fn alu_op(alu: u8, a: i32, b: i32) -> i32 {
+    let a = if alu & 0x08 != 0 { a } else { 0 };
+    let b = if alu & 0x04 != 0 { b } else { 0 };
+    let a = if alu & 0x02 != 0 { !a } else { a };
+    match alu >> 4 {
+        0 => a & b,
+        1 => a | b,
+        2 => !b,
+        _ => a.wrapping_add(b).wrapping_add((alu & 0x01) as i32),
+    }
+}
+
+pub struct Mic1<M: MemoryPort = Memory> {
+    memory: M,
+    control_store: Vec<u64>,
+    registers: [i32; 10],
+    mpc: u16,
+    negative: bool,
+    zero: bool,
+    /// Accesses started by the last cycle, which the next one completes.
+    pending: Vec<MemoryOp>,
+}
+
+impl Mic1 {
+    /// Creates a MIC-1 with `program` in its control store and its own
+    /// zeroed memory.
+    pub fn new(program: &[u64]) -> Result<Mic1, Mic1Error> {
+        Mic1::with_memory(program, Memory::new())
+    }
+}
+
+impl<M: MemoryPort> Mic1<M> {
+    pub fn with_memory(program: &[u64], memory: M) -> Result<Mic1<M>, Mic1Error> {
+        if program.len() > CONTROL_STORE_LENGTH {
+            return Err(Mic1Error::ProgramTooLong { len: program.len() });
+        }
+        Ok(Mic1 {
+            memory,
+            control_store: program.to_vec(),
+            registers: [0; 10],
+            mpc: 0,
+            negative: false,
+            zero: false,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn done(&self) -> bool {
+        self.mpc == HALT_ADDRESS
+    }
+
+    pub fn mpc(&self) -> u16 {
+        self.mpc
+    }
+
+    pub fn set_mpc(&mut self, addr: u16) {
+        self.mpc = addr % CONTROL_STORE_LENGTH as u16;
+    }
+
+    /// The value of register `idx`; MBR reads as an unsigned byte.
+    pub fn register(&self, idx: u8) -> i32 {
+        self.registers[idx as usize]
+    }
+
+    pub fn set_register(&mut self, idx: u8, value: i32) {
+        self.registers[idx as usize] = if idx == MBR { value & 0xff } else { value };
+    }
+
+    pub fn negative_flag(&self) -> bool {
+        self.negative
+    }
+
+    pub fn zero_flag(&self) -> bool {
+        self.zero
+    }
+
+    pub fn set_flags(&mut self, negative: bool, zero: bool) {
+        self.negative = negative;
+        self.zero = zero;
+    }
+
+    pub fn control_store(&self) -> &[u64] {
+        &self.control_store
+    }
+
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
+    pub fn read_word(&self, addr: i32) -> i32 {
+        let cell = (addr as u32 as usize * 2) % MEMORY_CELLS;
+        let lo = self.memory.get(cell) as u16 as u32;
+        let hi = self.memory.get(cell + 1) as u16 as u32;
+        (lo | hi << 16) as i32
+    }
+
+    pub fn write_word(&mut self, addr: i32, value: i32) {
+        let cell = (addr as u32 as usize * 2) % MEMORY_CELLS;
+        self.memory.set(cell, value as i16);
+        self.memory.set(cell + 1, (value >> 16) as i16);
+    }
+
+    pub fn read_byte(&self, addr: i32) -> u8 {
+        let cell = self.memory.get((addr as u32 as usize / 2) % MEMORY_CELLS) as u16;
+        if addr % 2 == 0 {
+            cell as u8
+        } else {
+            (cell >> 8) as u8
+        }
+    }
+
+    pub fn write_byte(&mut self, addr: i32, value: u8) {
+        let idx = (addr as u32 as usize / 2) % MEMORY_CELLS;
+        let cell = self.memory.get(idx) as u16;
+        let cell = if addr % 2 == 0 {
+            (cell & 0xff00) | value as u16
+        } else {
+            (cell & 0x00ff) | (value as u16) << 8
+        };
+        self.memory.set(idx, cell as i16);
+    }
+
+    fn b_bus(&self, source: u8) -> i32 {
+        match source {
+            0 => self.registers[MDR as usize],
+            1 => self.registers[PC as usize],
+            2 => self.registers[MBR as usize] as i8 as i32,
+            3 => self.registers[MBR as usize],
+            4 => self.registers[SP as usize],
+            5 => self.registers[LV as usize],
+            6 => self.registers[CPP as usize],
+            7 => self.registers[TOS as usize],
+            _ => self.registers[OPC as usize],
+        }
+    }
+
+    fn complete(&mut self, op: MemoryOp, written: &mut [bool; 10]) -> MemoryOp {
+        let value = match op.access {
+            Access::Read => {
+                let value = self.read_word(op.addr as i32);
+                self.registers[MDR as usize] = value;
+                written[MDR as usize] = true;
+                value
+            }
+            Access::Write => {
+                self.write_word(op.addr as i32, op.value);
+                op.value
+            }
+            Access::Fetch => {
+                let value = self.read_byte(op.addr as i32) as i32;
+                self.registers[MBR as usize] = value;
+                written[MBR as usize] = true;
+                value
+            }
+        };
+        MemoryOp {
+            value,
+            completed: true,
+            ..op
+        }
+    }
+
+    /// Executes one cycle and reports what it did. A cycle that jumps to
+    /// the halt address also completes the accesses it started.
+    pub fn step(&mut self) -> Result<Cycle, Mic1Error> {
+        let addr = self.mpc;
+        let word = self.control_store.get(addr as usize).cloned().unwrap_or(0);
+        let mi = MicroInstruction::new(word);
+        if word >> WORD_BITS != 0 {
+            return Err(Mic1Error::WordTooWide { addr });
+        }
+        if mi.uses_b() && mi.b() as usize >= B_BUS.len() {
+            return Err(Mic1Error::InvalidBusSource { addr, source: mi.b() });
+        }
+        if mi.sll8() && mi.sra1() {
+            return Err(Mic1Error::ConflictingShift { addr });
+        }
+        if mi.read() && mi.write() {
+            return Err(Mic1Error::ReadAndWrite { addr });
+        }
+
+        let b = if mi.uses_b() { self.b_bus(mi.b()) } else { 0 };
+        let alu_out = alu_op(mi.alu(), self.registers[H as usize], b);
+        self.negative = alu_out < 0;
+        self.zero = alu_out == 0;
+        let out = if mi.sll8() {
+            alu_out << 8
+        } else if mi.sra1() {
+            alu_out >> 1
+        } else {
+            alu_out
+        };
+
+        let mut written = [false; 10];
+        for (bit, &reg) in C_BUS.iter().enumerate() {
+            if mi.c() & 1 << bit != 0 {
+                self.registers[reg as usize] = out;
+                written[reg as usize] = true;
+            }
+        }
+
+        let mut memory = Vec::new();
+        for op in mem::take(&mut self.pending) {
+            memory.push(self.complete(op, &mut written));
+        }
+        let started = [(mi.read(), Access::Read, MAR),
+                       (mi.write(), Access::Write, MAR),
+                       (mi.fetch(), Access::Fetch, PC)];
+        for &(_, access, reg) in started.iter().filter(|s| s.0) {
+            self.pending.push(MemoryOp {
+                access,
+                addr: self.registers[reg as usize] as u32,
+                value: if access == Access::Write { self.registers[MDR as usize] } else { 0 },
+                completed: false,
+            });
+        }
+
+        let mut next = mi.next_address();
+        if (mi.jamn() && self.negative) || (mi.jamz() && self.zero) {
+            next |= 0x100;
+        }
+        if mi.jmpc() {
+            next |= self.registers[MBR as usize] as u16;
+        }
+        self.mpc = next;
+        if self.done() {
+            for op in mem::take(&mut self.pending) {
+                memory.push(self.complete(op, &mut written));
+            }
+        } else {
+            memory.extend(&self.pending);
+        }
+
+        let writes = (0..10u8)
+            .filter(|&r| written[r as usize])
+            .map(|r| (r, self.registers[r as usize]))
+            .collect();
+        Ok(Cycle {
+            pc: addr,
+            word,
+            writes,
+            memory,
+        })
+    }
+
+    /// Executes at most `max_cycles` cycles and returns how many ran.
+    pub fn run(&mut self, max_cycles: u64) -> Result<u64, Mic1Error> {
+        let mut cycles = 0;
+        while !self.done() && cycles < max_cycles {
+            self.step()?;
+            cycles += 1;
+        }
+        Ok(cycles)
+    }
+}
+
+impl<M: MemoryPort + fmt::Debug> fmt::Debug for Mic1<M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "MIC-1 {{")?;
+        for (name, value) in REGISTER_NAMES.iter().zip(&self.registers) {
+            writeln!(f, "\t{}: {}", name, value)?;
+        }
+        writeln!(f, "\tN: {}, Z: {}", self.negative as u8, self.zero as u8)?;
+        writeln!(f, "\t{:?},", self.memory)?;
+        writeln!(f, "\tmpc: {:#x}", self.mpc)?;
+        write!(f, "}}")
+    }
+}
+
+impl Core for Mic1<Memory> {
+    fn info(&self) -> &'static Info {
+        &INFO
+    }
+
+    fn register(&self, idx: u8) -> i32 {
+        Mic1::register(self, idx)
+    }
+
+    fn set_register(&mut self, idx: u8, value: i32) -> bool {
+        if idx as usize >= REGISTER_NAMES.len() {
+            return false;
+        }
+        Mic1::set_register(self, idx, value);
+        true
+    }
+
+    fn flags(&self) -> u8 {
+        self.negative as u8 | (self.zero as u8) << 1
+    }
+
+    fn set_flags(&mut self, flags: u8) {
+        Mic1::set_flags(self, flags & 1 != 0, flags & 2 != 0);
+    }
+
+    fn program_counter(&self) -> u16 {
+        self.mpc
+    }
+
+    fn set_program_counter(&mut self, addr: u16) -> bool {
+        if addr as usize >= CONTROL_STORE_LENGTH {
+            return false;
+        }
+        self.mpc = addr;
+        true
+    }
+
+    fn done(&self) -> bool {
+        Mic1::done(self)
+    }
+
+    fn cycle(&mut self) -> Result<Cycle, String> {
+        self.step().map_err(|e| e.to_string())
+    }
+
+    fn cell(&self, idx: usize) -> i16 {
+        self.memory.get(idx)
+    }
+
+    fn set_cell(&mut self, idx: usize, value: i16) {
+        self.memory.set(idx, value);
+    }
+}
+
+/// Parses hex microinstructions, one or more per line; `//` starts a
+/// comment.
+pub fn parse_hex(text: &str) -> Result<Vec<u64>, String> {
+    let mut program = Vec::new();
+    for line in text.lines() {
+        let line = line.split("//").next().unwrap();
+        for word in line.split(|c: char| c.is_whitespace() || c == ',') {
+            if word.is_empty() {
+                continue;
+            }
+            let value = u64::from_str_radix(word.trim_start_matches("0x"), 16)
+                .map_err(|_| format!("invalid word '{}'", word))?;
+            program.push(value);
+        }
+    }
+    Ok(program)
+}
+
+/// Reads a microprogram: MAL sources end in `.mal`, anything else is read
+/// as hex words.
+pub fn read_microprogram(path: &str) -> Result<Vec<u64>, String> {
+    let text = loader::read_source(path)?;
+    let program = if path.ends_with(".mal") {
+        mal::assemble(&text).map_err(|e| e.to_string())
+    } else {
+        parse_hex(&text)
+    };
+    program.map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Builds the machine the `--machine mic1` options describe: the
+/// microprogram in `microprogram`, by default the IJVM interpreter, and
+/// optionally an IJVM program loaded for it.
+pub fn load(microprogram: Option<&str>, program: Option<&str>) -> Result<Mic1, String> {
+    let control_store = match microprogram {
+        Some(path) => read_microprogram(path)?,
+        None => ijvm::microprogram(),
+    };
+    let mut mic1 = Mic1::new(&control_store).map_err(|e| e.to_string())?;
+    if let Some(path) = program {
+        let image = ijvm::assemble(&loader::read_source(path)?)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        image.load(&mut mic1);
+    }
+    Ok(mic1)
+}
+
+/// One line with address, word and MAL per used control store slot.
+pub fn listing(control_store: &[u64]) -> String {
+    let mut text = String::new();
+    for (addr, &word) in control_store.iter().enumerate() {
+        if word != 0 {
+            text.push_str(&format!("{:03x}  {:09x}  {}\n",
+                                   addr,
+                                   word,
+                                   mal::disassemble_word(word)));
+        }
+    }
+    text
+}
+
+/// Entry point of `micro16 mic1`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    let mut program = None;
+    let mut microprogram = None;
+    let mut max_cycles = DEFAULT_MAX_CYCLES;
+    let mut listing = false;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--microprogram" => microprogram = Some(iter.next().ok_or(USAGE)?.clone()),
+            "--cycles" => {
+                max_cycles = iter.next().and_then(|c| c.parse().ok()).ok_or(USAGE)?;
+            }
+            "--listing" => listing = true,
+            _ if !arg.starts_with('-') && program.is_none() => program = Some(arg.clone()),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    let mut mic1 = load(microprogram.as_deref(), program.as_deref())?;
+    if listing {
+        print!("{}", self::listing(mic1.control_store()));
+        return Ok(());
+    }
+
+    let cycles = mic1.run(max_cycles).map_err(|e| e.to_string())?;
+    println!("{} cycles, {}",
+             cycles,
+             if mic1.done() { "halted" } else { "cycle limit reached" });
+    for (name, value) in REGISTER_NAMES.iter().zip(&mic1.registers) {
+        println!("{:>4} {:>11} {:#010x}", name, value, value);
+    }
+    // The frame of the current method, or its top if it is long.
+    let sp = mic1.register(SP);
+    let bottom = mic1.register(LV).max(sp.saturating_sub(STACK_LINES - 1));
+    println!("stack:");
+    for addr in bottom..=sp {
+        println!("{:>6x} {:>11}", addr, mic1.read_word(addr));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "
+.constant
+    limit 4
+.end-constant
+
+.main
+.var
+    i
+.end-var
+    BIPUSH 0
+    ISTORE i
+loop:
+    IINC i 1
+    ILOAD i
+    LDC_W limit
+    IF_ICMPEQ done
+    GOTO loop
+done:
+    LDC_W OBJREF
+    BIPUSH 2
+    ILOAD i
+    INVOKEVIRTUAL sub
+    DUP
+    IFLT negative
+    HALT
+negative:
+    BIPUSH 1
+    IADD
+    HALT
+.end-main
+
+.method sub(a, b)
+    ILOAD a
+    ILOAD b
+    ISUB
+    IRETURN
+.end-method
+";
+
+    /// Runs `source` on the IJVM interpreter until it halts.
+    fn run(source: &str) -> Mic1 {
+        let mut mic1 = Mic1::new(&ijvm::microprogram()).unwrap();
+        ijvm::assemble(source).unwrap_or_else(|e| panic!("{}", e)).load(&mut mic1);
+        mic1.run(10_000).unwrap();
+        assert!(mic1.done());
+        mic1
+    }
+
+    #[test]
+    fn microprograms_load() {
+        assert_eq!(parse_hex("0x010000000 // goto 2\n004350211, 0\n").unwrap(),
+                   vec![0x010000000, 0x004350211, 0]);
+        assert_eq!(parse_hex("12 zz").unwrap_err(), "invalid word 'zz'");
+
+        let program = mal::assemble("start H = H + 1\n.label end 0xff\ngoto end").unwrap();
+        assert_eq!(program.len(), 2);
+        let mut mic1 = Mic1::new(&program).unwrap();
+        assert_eq!(mic1.run(10).unwrap(), 2);
+        assert!(mic1.done());
+        assert_eq!(mic1.register(H), 1);
+
+        assert_eq!(Mic1::new(&[0; CONTROL_STORE_LENGTH + 1]).unwrap_err(),
+                   Mic1Error::ProgramTooLong { len: CONTROL_STORE_LENGTH + 1 });
+        let mut mic1 = Mic1::new(&[1 << 36]).unwrap();
+        assert_eq!(mic1.step().unwrap_err(), Mic1Error::WordTooWide { addr: 0 });
+    }
+
+    #[test]
+    fn ijvm_programs_run() {
+        // 2 - 4 is negative, so the result is incremented once.
+        let mic1 = run(PROGRAM);
+        assert_eq!(mic1.register(TOS), -1);
+        assert_eq!(mic1.read_word(ijvm::STACK), 4);
+        assert_eq!(mic1.register(SP), ijvm::STACK + 1);
+
+        let mic1 = run(&PROGRAM.replace("BIPUSH 2", "BIPUSH 9"));
+        assert_eq!(mic1.register(TOS), 5);
+    }
+
+    #[test]
+    fn ijvm_errors() {
+        let error = |source: &str| ijvm::assemble(source).unwrap_err().to_string();
+        assert_eq!(error(".main\n    GOTO nowhere\n.end-main"),
+                   "line 2: undefined label 'nowhere'");
+        assert_eq!(error(".main\n    BIPUSH 200\n.end-main"),
+                   "line 2: '200' is not a signed byte");
+        assert_eq!(error(".main\n    FOO\n.end-main"), "line 2: unknown instruction 'FOO'");
+    }
+
+    #[test]
+    fn listings_disassemble_to_mal() {
+        let program = ijvm::microprogram();
+        let listing = listing(&program);
+        assert_eq!(listing.lines().next(), Some("000  010000000  goto 0x2"));
+        assert_eq!(listing.lines().count(), program.iter().filter(|&&w| w != 0).count());
+        // Every listed word assembles back to itself.
+        for &word in program.iter().filter(|&&w| w != 0) {
+            let text = mal::disassemble_word(word);
+            assert_eq!(mal::assemble(&text).unwrap(), vec![word], "{}", text);
+        }
+    }
+}
@@ -0,0 +1,581 @@
+//! Several Cpus sharing one memory through an arbitrated bus.
+//!
+//! Every core has its own registers, control store and handshake state; only
+//! memory is shared. Each bus cycle all cores execute one microinstruction,
+//! except that at most one core may run a word with `ms` set. The arbiter
+//! picks that core, the other requesting cores stall for the cycle. A core
+//! that has started a two cycle access keeps the bus until the access
+//! completes, so accesses of different cores never interleave.
+//!
+//! Reading the test-and-set cell returns its value and sets it to 1 in the
+//! same transfer; writes to it behave normally.
+//!
+//! With `jitter`, every core sits out a cycle with that probability in
+//! percent, which varies the interleaving between runs. All randomness comes
+//! from `seed`, so a run is reproducible.
+
+use std::cell::{Ref, RefCell};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::rc::Rc;
+
+use cli;
+use cpu::{Cpu, CpuError, Memory, MemoryPort};
+use disasm::{register_index, register_name};
+use loader;
+use rng::Rng;
+use state::{self, Assignment, Location};
+
+const DEFAULT_MAX_CYCLES: u64 = 100_000;
+
+pub const USAGE: &str = "Usage: micro16 multicore PROGRAM... [--cores N] [--policy POLICY] \
+                         [--seed N] [--jitter PERCENT] [--tas ADDR] [--core-id REG] \
+                         [--init ASSIGNMENTS] [--cycles N] [--trace FILE]
+POLICY is round-robin, priority or random";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// The bus goes to the next requesting core after the last owner.
+    RoundRobin,
+    /// The requesting core with the lowest index wins.
+    FixedPriority,
+    /// A requesting core is picked at random.
+    Random,
+}
+
+impl Policy {
+    pub fn parse(text: &str) -> Result<Policy, String> {
+        match text {
+            "round-robin" => Ok(Policy::RoundRobin),
+            "priority" => Ok(Policy::FixedPriority),
+            "random" => Ok(Policy::Random),
+            _ => Err(format!("unknown arbitration policy '{}'", text)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub policy: Policy,
+    pub seed: u64,
+    pub jitter: u64,
+    pub test_and_set: Option<u16>,
+    /// Register that is preset to each core's index.
+    pub core_id: Option<u8>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            policy: Policy::RoundRobin,
+            seed: 0,
+            jitter: 0,
+            test_and_set: None,
+            core_id: None,
+        }
+    }
+}
+
+pub struct Shared {
+    pub memory: Memory,
+    pub test_and_set: Option<u16>,
+    /// Every cell a core has transferred data to or from.
+    pub accessed: BTreeSet<u16>,
+}
+
+/// A core's connection to the shared memory.
+pub struct Port {
+    shared: Rc<RefCell<Shared>>,
+    ready: bool,
+}
+
+impl Port {
+    /// Whether the core is halfway through an access.
+    pub fn busy(&self) -> bool {
+        self.ready
+    }
+}
+
+impl MemoryPort for Port {
+    fn read(&mut self, idx: usize) -> Option<i16> {
+        self.ready = !self.ready;
+        if self.ready {
+            return None;
+        }
+        let mut shared = self.shared.borrow_mut();
+        let value = shared.memory.get(idx);
+        if shared.test_and_set == Some(idx as u16) {
+            shared.memory.set(idx, 1);
+        }
+        shared.accessed.insert(idx as u16);
+        Some(value)
+    }
+
+    fn write(&mut self, idx: usize, value: i16) -> bool {
+        self.ready = !self.ready;
+        if self.ready {
+            return false;
+        }
+        let mut shared = self.shared.borrow_mut();
+        shared.memory.set(idx, value);
+        shared.accessed.insert(idx as u16);
+        true
+    }
+
+    fn get(&self, idx: usize) -> i16 {
+        self.shared.borrow().memory.get(idx)
+    }
+
+    fn set(&mut self, idx: usize, value: i16) {
+        self.shared.borrow_mut().memory.set(idx, value)
+    }
+
+    /// Shared memory belongs to all cores and is never cleared through a
+    /// single port.
+    fn reset(&mut self, _keep_contents: bool) {
+        self.ready = false;
+    }
+}
+
+/// What a core did in one bus cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Executed a word without memory access.
+    Ran,
+    /// Executed a word with memory access.
+    Accessed,
+    /// Waited for the bus, which `owner` had.
+    Stalled { owner: usize },
+    /// Sat out the cycle because of jitter.
+    Idle,
+    Halted,
+    Failed(CpuError),
+}
+
+impl Event {
+    /// One character for the trace timeline.
+    pub fn symbol(&self) -> char {
+        match *self {
+            Event::Ran => '.',
+            Event::Accessed => 'M',
+            Event::Stalled { .. } => 's',
+            Event::Idle => '-',
+            Event::Halted => ' ',
+            Event::Failed(_) => '!',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CoreStats {
+    /// Cycles in which the core executed a word.
+    pub executed: u64,
+    pub accesses: u64,
+    pub stalls: u64,
+    pub idle: u64,
+    /// Cycles in which another core stalled because this one had the bus.
+    pub blocking: u64,
+}
+
+impl CoreStats {
+    /// Share of the core's bus requests that had to wait, in percent.
+    pub fn contention(&self) -> f64 {
+        let requests = self.accesses + self.stalls;
+        if requests == 0 {
+            0.0
+        } else {
+            100.0 * self.stalls as f64 / requests as f64
+        }
+    }
+}
+
+pub struct System {
+    pub cores: Vec<Cpu<Port>>,
+    pub stats: Vec<CoreStats>,
+    pub errors: Vec<Option<CpuError>>,
+    pub cycle: u64,
+    shared: Rc<RefCell<Shared>>,
+    policy: Policy,
+    jitter: u64,
+    rng: Rng,
+    /// Core that gets precedence next under round robin.
+    next: usize,
+    /// Core in the middle of an access.
+    owner: Option<usize>,
+}
+
+impl System {
+    pub fn new(programs: &[Vec<u32>], config: &Config) -> System {
+        let shared = Rc::new(RefCell::new(Shared {
+            memory: Memory::new(),
+            test_and_set: config.test_and_set,
+            accessed: BTreeSet::new(),
+        }));
+        let cores: Vec<Cpu<Port>> = programs.iter()
+            .enumerate()
+            .map(|(i, program)| {
+                let port = Port {
+                    shared: shared.clone(),
+                    ready: false,
+                };
+                let mut cpu = Cpu::with_memory(program, port);
+                if let Some(reg) = config.core_id {
+                    cpu.registers_mut().set(reg, i as i16);
+                }
+                cpu
+            })
+            .collect();
+        System {
+            stats: vec![CoreStats::default(); cores.len()],
+            errors: vec![None; cores.len()],
+            cores,
+            cycle: 0,
+            shared,
+            policy: config.policy,
+            jitter: config.jitter,
+            rng: Rng::new(config.seed),
+            next: 0,
+            owner: None,
+        }
+    }
+
+    pub fn shared(&self) -> Ref<'_, Shared> {
+        self.shared.borrow()
+    }
+
+    /// Presets registers on every core and cells in the shared memory.
+    pub fn apply(&mut self, assignments: &[Assignment]) {
+        for a in assignments {
+            match a.location {
+                Location::Register(idx) => {
+                    for cpu in &mut self.cores {
+                        cpu.registers_mut().set(idx, a.value);
+                    }
+                }
+                Location::Memory(addr) => {
+                    self.shared.borrow_mut().memory.set(addr as usize, a.value)
+                }
+            }
+        }
+    }
+
+    fn halted(&self, core: usize) -> bool {
+        self.cores[core].done() || self.errors[core].is_some()
+    }
+
+    pub fn done(&self) -> bool {
+        (0..self.cores.len()).all(|i| self.halted(i))
+    }
+
+    fn arbitrate(&mut self, requests: &[usize]) -> Option<usize> {
+        if let Some(owner) = self.owner {
+            if requests.contains(&owner) {
+                return Some(owner);
+            }
+        }
+        if requests.is_empty() {
+            return None;
+        }
+        let granted = match self.policy {
+            Policy::FixedPriority => requests[0],
+            Policy::Random => requests[self.rng.below(requests.len() as u64) as usize],
+            Policy::RoundRobin => {
+                *requests.iter().find(|&&i| i >= self.next).unwrap_or(&requests[0])
+            }
+        };
+        self.next = (granted + 1) % self.cores.len();
+        Some(granted)
+    }
+
+    /// Runs one bus cycle and returns what every core did.
+    pub fn step(&mut self) -> Vec<Event> {
+        let mut events = vec![Event::Halted; self.cores.len()];
+        let mut active = Vec::new();
+        for (i, event) in events.iter_mut().enumerate() {
+            if self.halted(i) {
+                continue;
+            }
+            if self.jitter > 0 && self.rng.chance(self.jitter, 100) {
+                *event = Event::Idle;
+                self.stats[i].idle += 1;
+            } else {
+                active.push(i);
+            }
+        }
+        // An owner sitting out the cycle keeps the bus locked.
+        let locked = self.owner.filter(|&o| events[o] == Event::Idle);
+        let requests: Vec<usize> = active.iter()
+            .cloned()
+            .filter(|&i| self.cores[i].current_instruction().is_some_and(|instr| instr.ms()))
+            .collect();
+        let granted = match locked {
+            Some(_) => None,
+            None => self.arbitrate(&requests),
+        };
+
+        for i in active {
+            if requests.contains(&i) && granted != Some(i) {
+                let owner = granted.or(locked).unwrap();
+                events[i] = Event::Stalled { owner };
+                self.stats[i].stalls += 1;
+                self.stats[owner].blocking += 1;
+                continue;
+            }
+            match self.cores[i].step() {
+                Ok(()) => {
+                    self.stats[i].executed += 1;
+                    if granted == Some(i) {
+                        self.stats[i].accesses += 1;
+                        events[i] = Event::Accessed;
+                    } else {
+                        events[i] = Event::Ran;
+                    }
+                }
+                Err(e) => {
+                    self.errors[i] = Some(e);
+                    events[i] = Event::Failed(e);
+                }
+            }
+        }
+
+        if locked.is_none() {
+            self.owner = granted.filter(|&g| self.cores[g].memory().busy() && !self.halted(g));
+        }
+        self.cycle += 1;
+        events
+    }
+}
+
+/// Entry point of `micro16 multicore`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    let mut paths = Vec::new();
+    let mut config = Config::default();
+    let mut cores = None;
+    let mut init = Vec::new();
+    let mut max_cycles = DEFAULT_MAX_CYCLES;
+    let mut trace_path = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--cores" => cores = Some(cli::parse_arg::<usize>(iter.next(), USAGE)?),
+            "--policy" => config.policy = Policy::parse(iter.next().ok_or(USAGE)?)?,
+            "--seed" => config.seed = cli::parse_arg(iter.next(), USAGE)?,
+            "--jitter" => config.jitter = cli::parse_arg::<u64>(iter.next(), USAGE)?.min(100),
+            "--tas" => {
+                let addr = state::parse_value(iter.next().ok_or(USAGE)?)?;
+                config.test_and_set = Some(addr as u16);
+            }
+            "--core-id" => {
+                let name = iter.next().ok_or(USAGE)?;
+                match register_index(name) {
+                    Some(idx) if idx >= 3 => config.core_id = Some(idx),
+                    _ => return Err(format!("cannot use '{}' for the core id", name)),
+                }
+            }
+            "--init" => init.extend(state::parse_assignments(iter.next().ok_or(USAGE)?)?),
+            "--cycles" => max_cycles = cli::parse_arg(iter.next(), USAGE)?,
+            "--trace" => trace_path = Some(iter.next().ok_or(USAGE)?.clone()),
+            _ if !arg.starts_with('-') => paths.push(arg.clone()),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    let mut programs = paths.iter()
+        .map(|p| loader::read_program(p))
+        .collect::<Result<Vec<_>, _>>()?;
+    match (programs.len(), cores) {
+        (0, _) => return Err(USAGE.to_string()),
+        (1, Some(n)) if n > 0 => programs = vec![programs[0].clone(); n],
+        (_, None) => (),
+        _ => return Err("--cores needs exactly one program and at least one core".to_string()),
+    }
+
+    let mut trace = match trace_path {
+        Some(ref path) => {
+            let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+            let mut out = BufWriter::new(file);
+            writeln!(out, "# . ran  M memory access  s stalled  - idle  ! failed")
+                .map_err(|e| e.to_string())?;
+            Some(out)
+        }
+        None => None,
+    };
+
+    let mut system = System::new(&programs, &config);
+    system.apply(&init);
+    while !system.done() && system.cycle < max_cycles {
+        let cycle = system.cycle;
+        let events = system.step();
+        if let Some(ref mut out) = trace {
+            let timeline: String = events.iter().map(|e| e.symbol()).collect();
+            let mut line = format!("{:6} {}", cycle, timeline.trim_end());
+            for (i, event) in events.iter().enumerate() {
+                if let Event::Stalled { owner } = *event {
+                    line.push_str(&format!("  core {} waits for {}", i, owner));
+                }
+            }
+            writeln!(out, "{}", line).map_err(|e| e.to_string())?;
+        }
+    }
+    if let Some(mut out) = trace {
+        out.flush().map_err(|e| e.to_string())?;
+    }
+
+    println!("{} cycles{}",
+             system.cycle,
+             if system.done() { "" } else { ", cycle limit reached" });
+    for (i, cpu) in system.cores.iter().enumerate() {
+        let stats = &system.stats[i];
+        println!("core {}: {} executed, {} accesses, {} stalls ({:.1}% contention), {} idle, \
+                  blocked others {} times",
+                 i,
+                 stats.executed,
+                 stats.accesses,
+                 stats.stalls,
+                 stats.contention(),
+                 stats.idle,
+                 stats.blocking);
+        if let Some(e) = system.errors[i] {
+            println!("  error: {}", e);
+        }
+        let regs: Vec<String> = (4..15)
+            .map(|r| format!("{}={}", register_name(r), cpu.registers().get(r)))
+            .collect();
+        println!("  {}", regs.join(" "));
+    }
+    let shared = system.shared();
+    let cells: Vec<String> = shared.accessed
+        .iter()
+        .map(|&c| format!("mem[{}]={}", c, shared.memory.get(c as usize)))
+        .collect();
+    if !cells.is_empty() {
+        println!("memory: {}", cells.join(" "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+
+    /// Runs `programs` to the end and returns one line of event symbols per
+    /// core.
+    fn timelines(programs: &[&str], config: &Config) -> (System, Vec<String>) {
+        let programs: Vec<Vec<u32>> = programs.iter().map(|p| assemble(p).unwrap()).collect();
+        let mut system = System::new(&programs, config);
+        let mut lines = vec![String::new(); programs.len()];
+        while !system.done() {
+            assert!(system.cycle < 1000, "the cores did not finish");
+            for (line, event) in lines.iter_mut().zip(system.step()) {
+                line.push(event.symbol());
+            }
+        }
+        (system, lines.iter().map(|l| l.trim_end().to_string()).collect())
+    }
+
+    const READ: &str = "MAR <- R0; rd\nrd";
+    const READ_TWICE: &str = "MAR <- R0; rd\nrd\nMAR <- R0; rd\nrd";
+
+    #[test]
+    fn the_arbiter_follows_its_policy() {
+        let config = Config {
+            policy: Policy::FixedPriority,
+            ..Config::default()
+        };
+        let (system, lines) = timelines(&[READ_TWICE, READ_TWICE], &config);
+        assert_eq!(lines, ["MMMM", "ssssMMMM"]);
+        assert_eq!(system.stats[1].stalls, 4);
+        assert_eq!(system.stats[0].blocking, 4);
+        assert_eq!(system.stats[1].contention(), 50.0);
+
+        // Round robin hands the bus on after every access.
+        let (_, lines) = timelines(&[READ_TWICE, READ_TWICE], &Config::default());
+        assert_eq!(lines, ["MMssMM", "ssMMssMM"]);
+    }
+
+    #[test]
+    fn cores_without_accesses_run_on() {
+        let (_, lines) = timelines(&[READ, "R0 <- 1\nMAR <- R0; rd\nrd\nR1 <- 1"],
+                                   &Config::default());
+        assert_eq!(lines, ["MM", ".sMM."]);
+    }
+
+    #[test]
+    fn accesses_never_interleave() {
+        for seed in 0..20 {
+            let config = Config {
+                policy: Policy::Random,
+                seed,
+                jitter: 30,
+                ..Config::default()
+            };
+            let (_, lines) = timelines(&[READ_TWICE, READ_TWICE, READ_TWICE], &config);
+            // Between the halves of an access only idle cycles of its core
+            // pass, and no other core accesses memory.
+            let mut owner = None;
+            for cycle in 0..lines.iter().map(|l| l.len()).max().unwrap() {
+                let accessing: Vec<usize> = (0..3)
+                    .filter(|&core| lines[core].as_bytes().get(cycle) == Some(&b'M'))
+                    .collect();
+                assert!(accessing.len() <= 1, "seed {}: {:?}", seed, lines);
+                match (owner, accessing.first()) {
+                    (Some(o), Some(&core)) => {
+                        assert_eq!(o, core, "seed {}: {:?}", seed, lines);
+                        owner = None;
+                    }
+                    (None, Some(&core)) => owner = Some(core),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_and_set_guards_the_counter() {
+        let source = include_str!("../examples/spinlock.m16");
+        let config = Config {
+            policy: Policy::Random,
+            jitter: 20,
+            test_and_set: Some(0),
+            ..Config::default()
+        };
+        let program = assemble(source).unwrap();
+        let mut system = System::new(&vec![program; 4], &config);
+        system.apply(&state::parse_assignments("R0=10").unwrap());
+        while !system.done() {
+            system.step();
+        }
+        assert!(system.errors.iter().all(|e| e.is_none()));
+        assert_eq!(system.shared().memory.get(1), 40);
+        // The last core to leave released the lock.
+        assert_eq!(system.shared().memory.get(0), 0);
+        assert_eq!(system.shared().accessed.iter().cloned().collect::<Vec<_>>(), [0, 1]);
+    }
+
+    #[test]
+    fn reading_the_test_and_set_cell_sets_it() {
+        let config = Config {
+            test_and_set: Some(1),
+            core_id: Some(4),
+            ..Config::default()
+        };
+        // Every core reads the cell at its index twice.
+        let program = "MAR <- R0; rd\nrd\nR1 <- MBR; MAR <- R0; rd\nrd\nR2 <- MBR";
+        let programs = vec![assemble(program).unwrap(); 2];
+        let mut system = System::new(&programs, &config);
+        while !system.done() {
+            system.step();
+        }
+        let registers = |core: usize| {
+            let cpu = &system.cores[core];
+            (cpu.registers().get(5), cpu.registers().get(6))
+        };
+        assert_eq!(registers(0), (0, 0));
+        assert_eq!(registers(1), (0, 1));
+        assert_eq!(system.shared().memory.get(0), 0);
+        assert_eq!(system.shared().memory.get(1), 1);
+    }
+}
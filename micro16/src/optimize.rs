@@ -0,0 +1,495 @@
+//! Peephole optimization of microprograms.
+//!
+//! The optimizer works on decoded instructions and applies one rewrite at a
+//! time:
+//!
+//! * a jump whose target is the next word becomes a fall through,
+//! * register, MAR and MBR writes that are never read are dropped,
+//! * words left without any effect are removed,
+//! * two neighbouring words are merged when one ALU, one set of buses and
+//!   one memory access suffice for both.
+//!
+//! Removing a word retargets every jump behind it.
+//!
+//! Dead writes are found by a liveness analysis over all jump targets, and
+//! removals and merges keep every read behind the writes it depends on. No
+//! rewrite is applied on that reasoning alone: each must pass an `equiv`
+//! check against the program before it that tries every input of a bounded
+//! domain. The registers either program reads before writing them take
+//! edge values of the ALU and the conditions, as many per register as keep
+//! the combinations within `LIMIT`, and the memory cells those values and
+//! the first `SEEDED_CELLS` addresses point to hold fixed data. A rewrite
+//! that differs on any of these inputs, or on which a program runs into
+//! `MAX_CYCLES`, is rejected. The check proves equivalence on this domain
+//! only, not on all inputs.
+//!
+//! Only the final registers and memory are considered observable: flags,
+//! the memory handshake state and the cycle count may change.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+
+use bitset32::BitSet32;
+use cpu::{AluMode, CondMode, MAR, MBR, PROGRAM_LENGTH};
+use disasm::disassemble;
+use equiv::{self, Config, Domain, Verdict};
+use instruction::Instruction;
+use loader;
+use state::Location;
+
+/// Most inputs the check of one rewrite tries.
+const LIMIT: u64 = 4096;
+const SEEDED_CELLS: u16 = 16;
+/// Values the check gives input registers: the edges of the ALU and the
+/// conditions, and small addresses, in the order they are dropped from the
+/// back when there are many input registers.
+const VALUES: [i16; 12] = [0, -1, 1, i16::MIN, i16::MAX, 2, 0x5555, -0x5556, 3, 15, -2, 0x4000];
+/// Registers but the constants 0, 1 and -1.
+const WRITABLE: u32 = 0xfff8;
+const MAX_CYCLES: u64 = 10_000;
+
+pub const USAGE: &str = "Usage: micro16 optimize FILE [--hex] [-o OUT]";
+
+#[derive(Debug, Clone)]
+pub struct Optimized {
+    pub program: Vec<u32>,
+    /// Descriptions of the rewrites that were applied, in order.
+    pub applied: Vec<String>,
+    /// Rewrites the equivalence check did not prove.
+    pub rejected: Vec<String>,
+}
+
+fn without(set: BitSet32, other: BitSet32) -> BitSet32 {
+    set.and(BitSet32::new(!other.value()))
+}
+
+/// Whether the ALU result of the word matters, either as a value or for a
+/// conditional jump.
+fn uses_alu(instr: &Instruction) -> bool {
+    instr.ens() || instr.mbr() ||
+    !matches!(instr.cond(), Ok(CondMode::NoOp | CondMode::GoTo))
+}
+
+fn alu_reads_b(instr: &Instruction) -> bool {
+    !matches!(instr.alu(), Ok(AluMode::NoOp | AluMode::BitNot | AluMode::Inc))
+}
+
+/// Registers read through the A and B buses.
+fn bus_reads(instr: &Instruction) -> BitSet32 {
+    let mut set = BitSet32::new(0);
+    if uses_alu(instr) {
+        set.set(if instr.a_mux() { MAR as usize } else { instr.a_bus() as usize }, true);
+        if alu_reads_b(instr) {
+            set.set(instr.b_bus() as usize, true);
+        }
+    }
+    if instr.mar() {
+        set.set(instr.b_bus() as usize, true);
+    }
+    set
+}
+
+/// Registers read by the memory access, after the word's own writes.
+fn memory_reads(instr: &Instruction) -> BitSet32 {
+    let mut set = BitSet32::new(0);
+    if instr.ms() {
+        set.set(MAR as usize, true);
+        if !instr.rd_wr() {
+            set.set(MBR as usize, true);
+        }
+    }
+    set
+}
+
+/// Registers the word always overwrites.
+fn definite_writes(instr: &Instruction) -> BitSet32 {
+    let mut set = BitSet32::new(0);
+    if instr.ens() {
+        set.set(instr.s_bus() as usize, true);
+    }
+    if instr.mbr() {
+        set.set(MBR as usize, true);
+    }
+    if instr.mar() {
+        set.set(MAR as usize, true);
+    }
+    set
+}
+
+/// Registers the word may overwrite, including a completed memory read.
+fn writes(instr: &Instruction) -> BitSet32 {
+    let mut set = definite_writes(instr);
+    if instr.ms() && instr.rd_wr() {
+        set.set(MBR as usize, true);
+    }
+    set
+}
+
+fn is_nop(instr: &Instruction) -> bool {
+    !instr.ens() && !instr.mbr() && !instr.mar() && !instr.ms() &&
+    instr.cond() == Ok(CondMode::NoOp)
+}
+
+/// Control store addresses execution may continue at, `None` meaning the
+/// program ends.
+fn successors(words: &[Instruction], idx: usize) -> Vec<Option<usize>> {
+    let target = |addr: usize| if addr < words.len() { Some(addr) } else { None };
+    let next = target((idx + 1) % PROGRAM_LENGTH);
+    let jump = target(words[idx].addr() as usize);
+    match words[idx].cond() {
+        Ok(CondMode::NoOp) => vec![next],
+        Ok(CondMode::GoTo) => vec![jump],
+        _ => vec![next, jump],
+    }
+}
+
+/// Registers live before and after each word. Everything is live when the
+/// program ends.
+fn liveness(words: &[Instruction]) -> (Vec<BitSet32>, Vec<BitSet32>) {
+    let at_exit = BitSet32::new(WRITABLE);
+    let mut live_in = vec![BitSet32::new(0); words.len()];
+    let mut live_out = vec![BitSet32::new(0); words.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for idx in (0..words.len()).rev() {
+            let out = successors(words, idx)
+                .into_iter()
+                .fold(BitSet32::new(0), |set, succ| {
+                    set.or(succ.map_or(at_exit, |s| live_in[s]))
+                });
+            let instr = &words[idx];
+            let after_writes = out.or(memory_reads(instr));
+            let inp = bus_reads(instr).or(without(after_writes, definite_writes(instr)));
+            if out != live_out[idx] || inp != live_in[idx] {
+                live_out[idx] = out;
+                live_in[idx] = inp;
+                changed = true;
+            }
+        }
+    }
+    (live_in, live_out)
+}
+
+/// Registers the program may read before writing them.
+fn inputs(words: &[Instruction]) -> BitSet32 {
+    let reads = words.iter()
+        .fold(BitSet32::new(0), |set, w| set.or(bus_reads(w)).or(memory_reads(w)));
+    match liveness(words).0.first() {
+        Some(live) => live.and(reads).and(BitSet32::new(WRITABLE)),
+        None => BitSet32::new(0),
+    }
+}
+
+fn is_jump_target(words: &[Instruction], idx: usize) -> bool {
+    words.iter().any(|w| w.cond() != Ok(CondMode::NoOp) && w.addr() as usize == idx)
+}
+
+/// Removes the word at `idx`, moving jumps behind it down by one.
+fn remove(words: &[Instruction], idx: usize) -> Vec<Instruction> {
+    let mut result = words.to_vec();
+    result.remove(idx);
+    for instr in result.iter_mut() {
+        if instr.cond() != Ok(CondMode::NoOp) && instr.addr() as usize > idx {
+            let addr = instr.addr() - 1;
+            instr.set_addr(addr);
+        }
+    }
+    result
+}
+
+/// Combines two consecutive words into one, if the datapath allows it.
+fn merge(first: &Instruction, second: &Instruction) -> Option<Instruction> {
+    if first.cond() != Ok(CondMode::NoOp) || (uses_alu(first) && uses_alu(second)) ||
+       (first.ms() && second.ms()) || (first.mar() && second.mar()) {
+        return None;
+    }
+    let first_writes = writes(first);
+    if bus_reads(second).and(first_writes).value() != 0 ||
+       writes(second).and(first_writes).value() != 0 {
+        return None;
+    }
+    // The memory access would see the second word's MAR or MBR.
+    if first.ms() && definite_writes(second).and(memory_reads(first)).value() != 0 {
+        return None;
+    }
+
+    let (user, other) = if uses_alu(first) {
+        (first, second)
+    } else {
+        (second, first)
+    };
+    let mut merged = user.clone();
+    if other.mar() {
+        if uses_alu(user) && alu_reads_b(user) && user.b_bus() != other.b_bus() {
+            return None;
+        }
+        merged.set_mar(true);
+        merged.set_b_bus(other.b_bus());
+    }
+    if other.ms() {
+        merged.set_ms(true);
+        merged.set_rd_wr(other.rd_wr());
+    }
+    merged.set_cond(second.cond().ok()?);
+    merged.set_addr(second.addr());
+    Some(merged)
+}
+
+/// All single rewrites of the program, cheapest first.
+fn candidates(words: &[Instruction]) -> Vec<(String, Vec<Instruction>)> {
+    let mut result = Vec::new();
+
+    for (idx, instr) in words.iter().enumerate() {
+        if instr.cond() != Ok(CondMode::NoOp) && instr.addr() as usize == idx + 1 {
+            let mut rewritten = words.to_vec();
+            rewritten[idx].set_cond(CondMode::NoOp);
+            rewritten[idx].set_addr(0);
+            result.push((format!("{}: jump to the next word", idx), rewritten));
+        }
+    }
+
+    let (_, live) = liveness(words);
+    for (idx, instr) in words.iter().enumerate() {
+        let needed = live[idx].or(memory_reads(instr));
+        let mut rewritten = instr.clone();
+        if instr.ens() && !needed.get(instr.s_bus() as usize) {
+            rewritten.set_ens(false);
+        }
+        if instr.mbr() && !needed.get(MBR as usize) {
+            rewritten.set_mbr(false);
+        }
+        if instr.mar() && !needed.get(MAR as usize) {
+            rewritten.set_mar(false);
+        }
+        if rewritten != *instr {
+            let mut program = words.to_vec();
+            program[idx] = rewritten;
+            result.push((format!("{}: dead write", idx), program));
+        }
+    }
+
+    for (idx, instr) in words.iter().enumerate() {
+        if is_nop(instr) {
+            result.push((format!("{}: word without effect", idx), remove(words, idx)));
+        }
+    }
+
+    for idx in 1..words.len() {
+        if is_jump_target(words, idx) {
+            continue;
+        }
+        if let Some(merged) = merge(&words[idx - 1], &words[idx]) {
+            let mut program = words.to_vec();
+            program[idx - 1] = merged;
+            let program = remove(&program, idx);
+            result.push((format!("{}: merged with {}", idx - 1, idx), program));
+        }
+    }
+    result
+}
+
+/// Checks a rewrite on every input of the domain the module documentation
+/// describes.
+fn proven(original: &[Instruction], rewritten: &[Instruction]) -> bool {
+    let registers = inputs(original).or(inputs(rewritten));
+    let count = registers.value().count_ones();
+    let mut per_register = VALUES.len();
+    while per_register > 1 && (per_register as u64).pow(count) > LIMIT {
+        per_register -= 1;
+    }
+    let values = &VALUES[..per_register];
+
+    let mut inputs: Vec<Domain> = (0..16)
+        .filter(|&r| registers.get(r))
+        .map(|r| {
+            Domain {
+                location: Location::Register(r as u8),
+                values: values.to_vec(),
+            }
+        })
+        .collect();
+    let mut cells: Vec<u16> = (0..SEEDED_CELLS).chain(values.iter().map(|&v| v as u16)).collect();
+    cells.sort();
+    cells.dedup();
+    inputs.extend(cells.into_iter().map(|addr| {
+        Domain {
+            location: Location::Memory(addr),
+            values: vec![!(addr as i16)],
+        }
+    }));
+
+    let config = Config {
+        inputs,
+        limit: LIMIT,
+        max_cycles: MAX_CYCLES,
+        ..Config::default()
+    };
+    let raw = |words: &[Instruction]| words.iter().map(|i| i.raw()).collect::<Vec<u32>>();
+    matches!(equiv::check(&raw(original), &raw(rewritten), &config),
+             Verdict::Equivalent { exhaustive: true, .. })
+}
+
+fn check_valid(words: &[Instruction]) -> Result<(), String> {
+    for (idx, instr) in words.iter().enumerate() {
+        if instr.invalid_field().is_some() || instr.call() ||
+           (instr.ens() && instr.s_bus() < MAR) {
+            return Err(format!("word {} ({:08x}) is not a valid instruction", idx, instr.raw()));
+        }
+    }
+    Ok(())
+}
+
+pub fn optimize(program: &[u32]) -> Result<Optimized, String> {
+    let mut words: Vec<Instruction> = program.iter().map(|&w| Instruction::new(w)).collect();
+    check_valid(&words)?;
+
+    let raw = |words: &[Instruction]| words.iter().map(|i| i.raw()).collect::<Vec<u32>>();
+    let mut applied = Vec::new();
+    let mut rejected = Vec::new();
+    let mut seen = HashSet::new();
+    'rewrite: loop {
+        for (description, candidate) in candidates(&words) {
+            if !seen.insert(raw(&candidate)) {
+                continue;
+            }
+            if proven(&words, &candidate) {
+                applied.push(description);
+                words = candidate;
+                continue 'rewrite;
+            }
+            rejected.push(description);
+        }
+        break;
+    }
+
+    Ok(Optimized {
+        program: raw(&words),
+        applied,
+        rejected,
+    })
+}
+
+/// Entry point of `micro16 optimize`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    let mut input = None;
+    let mut output = None;
+    let mut hex = false;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--hex" => hex = true,
+            "-o" => output = Some(iter.next().ok_or(USAGE)?.clone()),
+            _ if input.is_none() && !arg.starts_with('-') => input = Some(arg.clone()),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let input = input.ok_or(USAGE)?;
+
+    let program = loader::read_program(&input)?;
+    let optimized = optimize(&program).map_err(|e| format!("{}: {}", input, e))?;
+
+    let mut text = format!("# {} -> {} words\n", program.len(), optimized.program.len());
+    for description in &optimized.applied {
+        text.push_str(&format!("# {}\n", description));
+    }
+    for description in &optimized.rejected {
+        text.push_str(&format!("# not proven, skipped: {}\n", description));
+    }
+    for &word in &optimized.program {
+        if hex {
+            text.push_str(&format!("{:08x}\n", word));
+        } else {
+            text.push_str(&format!("{}\n", disassemble(&Instruction::new(word))));
+        }
+    }
+
+    match output {
+        Some(path) => {
+            File::create(&path)
+                .and_then(|mut f| f.write_all(text.as_bytes()))
+                .map_err(|e| format!("{}: {}", path, e))
+        }
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+
+    fn words(source: &str) -> Vec<Instruction> {
+        assemble(source).unwrap().into_iter().map(Instruction::new).collect()
+    }
+
+    fn rewrite(words: &[Instruction], description: &str) -> Vec<Instruction> {
+        candidates(words)
+            .into_iter()
+            .find(|(d, _)| d == description)
+            .unwrap_or_else(|| panic!("no rewrite '{}'", description))
+            .1
+    }
+
+    #[test]
+    fn jumps_to_the_next_word_fall_through() {
+        let program = words("R1 <- R0; goto .next\n:next\nR2 <- R1");
+        let rewritten = rewrite(&program, "0: jump to the next word");
+        assert_eq!(rewritten[0].cond(), Ok(CondMode::NoOp));
+        assert_eq!(rewritten[1], program[1]);
+    }
+
+    #[test]
+    fn dead_writes_are_dropped() {
+        let program = words("R1 <- R0\nR1 <- R2");
+        let rewritten = rewrite(&program, "0: dead write");
+        assert!(!rewritten[0].ens());
+
+        let optimized = optimize(&assemble("R1 <- R0\nR1 <- R2").unwrap()).unwrap();
+        assert_eq!(optimized.program, assemble("R1 <- R2").unwrap());
+        assert_eq!(optimized.applied, vec!["0: dead write", "0: word without effect"]);
+    }
+
+    #[test]
+    fn removal_retargets_the_jumps_behind_the_word() {
+        let program = words("(R0); if Z goto 3\nR1 <- R0; goto 0\nR2 <- R0\nR3 <- R0");
+        let rewritten = remove(&program, 2);
+        assert_eq!(rewritten.len(), 3);
+        assert_eq!(rewritten[0].addr(), 2);
+        assert_eq!(rewritten[1].addr(), 0);
+
+        // 0: (R0); if Z goto 3, 1: nop, 2: R1 <- R0, 3: R2 <- R0
+        let mut program = assemble("(R0); if Z goto 3\nR1 <- R0\nR2 <- R0").unwrap();
+        program.insert(1, 0);
+        let optimized = optimize(&program).unwrap();
+        assert_eq!(optimized.program,
+                   assemble("(R0); if Z goto 2\nR1 <- R0\nR2 <- R0").unwrap());
+    }
+
+    #[test]
+    fn neighbouring_words_merge() {
+        let program = words("MAR <- R0; rd\nR1 <- R2");
+        let rewritten = rewrite(&program, "0: merged with 1");
+        assert_eq!(rewritten.len(), 1);
+        assert_eq!(disassemble(&rewritten[0]), "R1 <- R2; MAR <- R0; rd");
+
+        // Both words need the ALU.
+        assert!(merge(&words("R1 <- R0")[0], &words("R2 <- R0")[0]).is_none());
+        // The second word reads what the first writes.
+        assert!(merge(&words("MAR <- R0; rd")[0], &words("R1 <- MBR")[0]).is_none());
+    }
+
+    #[test]
+    fn unproven_rewrites_are_rejected() {
+        assert!(proven(&words("R1 <- R0 + R0"), &words("R1 <- lsh(R0)")));
+        assert!(!proven(&words("R1 <- R0"), &words("R1 <- R0 + 1")));
+        // A program that never finishes proves nothing.
+        let program = words(":loop\ngoto .loop");
+        assert!(!proven(&program, &program));
+    }
+}
@@ -0,0 +1,185 @@
+//! Deliberately simple reference model of the Micro16 semantics, written
+//! independently of `Cpu` so the two can be checked against each other.
+//!
+//! One call to `step` executes a whole microinstruction straight from the
+//! raw control word.
+
+use std::collections::HashMap;
+
+pub struct Reference {
+    pub regs: [i16; 16],
+    pub memory: HashMap<u16, i16>,
+    pub ready: bool,
+    pub pc: u8,
+    pub n: bool,
+    pub z: bool,
+}
+
+fn field(word: u32, start: u32, width: u32) -> u32 {
+    (word >> start) & ((1 << width) - 1)
+}
+
+impl Reference {
+    pub fn new() -> Reference {
+        let mut regs = [0; 16];
+        regs[1] = 1;
+        regs[2] = -1;
+        Reference {
+            regs,
+            memory: HashMap::new(),
+            ready: false,
+            pc: 0,
+            n: false,
+            z: false,
+        }
+    }
+
+    pub fn done(&self, program: &[u32]) -> bool {
+        self.pc as usize >= program.len()
+    }
+
+    pub fn mem(&self, addr: u16) -> i16 {
+        self.memory.get(&addr).cloned().unwrap_or(0)
+    }
+
+    pub fn step(&mut self, program: &[u32]) -> Result<(), String> {
+        let word = program[self.pc as usize];
+        let addr = field(word, 0, 8) as u8;
+        let a_idx = field(word, 8, 4) as usize;
+        let b_idx = field(word, 12, 4) as usize;
+        let s_idx = field(word, 16, 4) as usize;
+        let ens = field(word, 20, 1) == 1;
+        let ms = field(word, 21, 1) == 1;
+        let rd = field(word, 22, 1) == 1;
+        let mar = field(word, 23, 1) == 1;
+        let mbr = field(word, 24, 1) == 1;
+        let sh = field(word, 25, 2);
+        let alu = field(word, 27, 2);
+        let cond = field(word, 29, 2);
+        let a_mux = field(word, 31, 1) == 1;
+
+        if ens && s_idx < 3 {
+            return Err(format!("write to read-only register {}", s_idx));
+        }
+        if sh == 3 {
+            return Err("reserved shifter mode".to_string());
+        }
+
+        let a = if a_mux { self.regs[3] } else { self.regs[a_idx] };
+        let b = self.regs[b_idx];
+        let result = match alu {
+            0 => a,
+            1 => a.wrapping_add(b),
+            2 => a & b,
+            _ => !a,
+        };
+        self.n = result < 0;
+        self.z = result == 0;
+        let shifted = match sh {
+            0 => result,
+            1 => result << 1,
+            _ => result >> 1,
+        };
+
+        if mar {
+            self.regs[3] = b;
+        }
+        if ens {
+            self.regs[s_idx] = shifted;
+        }
+        if mbr {
+            self.regs[15] = shifted;
+        }
+        if ms {
+            // Every access needs two consecutive cycles; the second one
+            // transfers the data.
+            if self.ready {
+                let cell = self.regs[3] as u16;
+                if rd {
+                    self.regs[15] = self.mem(cell);
+                } else {
+                    self.memory.insert(cell, self.regs[15]);
+                }
+            }
+            self.ready = !self.ready;
+        }
+
+        let jump = match cond {
+            0 => false,
+            1 => self.n,
+            2 => self.z,
+            _ => true,
+        };
+        self.pc = if jump { addr } else { self.pc.wrapping_add(1) };
+        Ok(())
+    }
+}
+
+impl Default for Reference {
+    fn default() -> Reference {
+        Reference::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+
+    /// Runs `source` to its end with R0 and R1 preset.
+    fn run(source: &str, r0: i16, r1: i16) -> Reference {
+        let program = assemble(source).unwrap();
+        let mut reference = Reference::new();
+        reference.regs[4] = r0;
+        reference.regs[5] = r1;
+        for _ in 0..100 {
+            if reference.done(&program) {
+                return reference;
+            }
+            reference.step(&program).unwrap();
+        }
+        panic!("{} did not finish", source);
+    }
+
+    #[test]
+    fn datapath() {
+        let reference = run("R2 <- R0 + R1\nR3 <- ~R0\nR4 <- R0 & R1\nR5 <- lsh(R1)\n\
+                             R6 <- rsh(R1)",
+                            12,
+                            -6);
+        assert_eq!(reference.regs[6..11], [6, !12, 12 & -6, -12, -3]);
+        assert!(reference.n && !reference.z);
+    }
+
+    #[test]
+    fn accesses_take_two_cycles() {
+        let reference = run("MAR <- R0; MBR <- R1; wr\nwr\nMBR <- 0\nMAR <- R0; rd\n\
+                             R2 <- MBR; rd\nR3 <- MBR",
+                            40,
+                            9);
+        assert_eq!(reference.mem(40), 9);
+        // The read only completes in its second cycle.
+        assert_eq!(reference.regs[6], 0);
+        assert_eq!(reference.regs[7], 9);
+        assert!(!reference.ready);
+    }
+
+    #[test]
+    fn jumps_follow_the_flags() {
+        let source = "(R0); if N goto .neg\nR2 <- 1; goto .end\n:neg\nR2 <- -1\n:end";
+        assert_eq!(run(source, 3, 0).regs[6], 1);
+        assert_eq!(run(source, -3, 0).regs[6], -1);
+        let source = "(R0); if Z goto .zero\nR2 <- 1; goto .end\n:zero\nR2 <- -1\n:end";
+        assert_eq!(run(source, 0, 0).regs[6], -1);
+        assert_eq!(run(source, 3, 0).regs[6], 1);
+    }
+
+    #[test]
+    fn malformed_words_are_errors() {
+        // ens with S = 1, then the reserved shifter mode.
+        for &word in &[(1 << 20) | (1 << 16), 3 << 25] {
+            let mut reference = Reference::new();
+            assert!(reference.step(&[word]).is_err());
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! Self-contained HTML reports of a run, to share without the tool.
+//!
+//! A report lists the program and every cycle of the run: the control store
+//! address, the word and its disassembly, the registers written, the flags
+//! and the memory accesses. Register writes that changed the value are
+//! highlighted. A slider, the arrow keys or a click on a cycle select a
+//! cycle; the register panel then shows the registers after it and the
+//! program listing marks the word it executed.
+//!
+//! Reports are made from a fresh run of a Micro16 program or from a trace
+//! file of any machine. Traces only record writes, so registers a traced
+//! run never writes show as `?`; the listing of a trace holds the words
+//! the run executed.
+
+use std::fs::File;
+use std::io::Write;
+
+use arch::{Core, Info};
+use cli::{self, MachineOptions};
+use cpu::Memory;
+use json::Value;
+use loader;
+use trace::{self, Trace};
+
+const DEFAULT_MAX_CYCLES: u64 = 10_000;
+
+pub const USAGE: &str = "Usage: micro16 report PROGRAM [-o OUT] [--init ASSIGNMENTS] [--cycles N] \
+                         [--map-control-store BASE] [--call-stack DEPTH]
+       micro16 report --trace FILE [-o OUT]";
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 1em 2em; }
+table { border-collapse: collapse; font-family: monospace; }
+td, th { padding: 0.1em 0.6em; text-align: left; white-space: nowrap; }
+th { border-bottom: 1px solid #888; }
+.panels { display: flex; gap: 3em; align-items: flex-start; }
+.controls { margin: 1em 0; position: sticky; top: 0; background: white; padding: 0.5em 0; }
+#scrub { width: 40em; vertical-align: middle; }
+#cycles tbody tr { cursor: pointer; }
+#cycles tbody tr:hover { background: #f0f0f0; }
+tr.selected, tr.selected:hover { background: #ffe680 !important; }
+.changed { background: #ffb3b3; font-weight: bold; }
+.written { background: #e0e0e0; }
+.error { color: #c00; }
+";
+
+const SCRIPT: &str = "
+var states = [];
+var registers = initial.slice();
+writes.forEach(function (cycle) {
+  cycle.forEach(function (write) { registers[write[0]] = write[1]; });
+  states.push(registers.slice());
+});
+var selected = null;
+
+function show(cycle) {
+  if (writes.length === 0) return;
+  cycle = Math.max(0, Math.min(writes.length - 1, cycle));
+  if (selected !== null) {
+    document.getElementById('cycle-' + selected).classList.remove('selected');
+    var old = document.getElementById('word-' + pcs[selected]);
+    if (old) old.classList.remove('selected');
+  }
+  selected = cycle;
+  var row = document.getElementById('cycle-' + cycle);
+  row.classList.add('selected');
+  row.scrollIntoView({ block: 'nearest' });
+  var word = document.getElementById('word-' + pcs[cycle]);
+  if (word) word.classList.add('selected');
+
+  var before = cycle > 0 ? states[cycle - 1] : initial;
+  var written = writes[cycle].map(function (write) { return write[0]; });
+  shown.forEach(function (r) {
+    var cell = document.getElementById('register-' + r);
+    var value = states[cycle][r];
+    cell.textContent = value === null ? '?' : value;
+    cell.className = value !== before[r] ? 'changed' : written.indexOf(r) >= 0 ? 'written' : '';
+  });
+  document.getElementById('flags').textContent = flags[cycle];
+  document.getElementById('scrub').value = cycle;
+  document.getElementById('position').textContent =
+    'cycle ' + cycle + ' of ' + writes.length;
+}
+
+document.getElementById('scrub').addEventListener('input', function (e) {
+  show(parseInt(e.target.value, 10));
+});
+document.getElementById('prev').addEventListener('click', function () { show(selected - 1); });
+document.getElementById('next').addEventListener('click', function () { show(selected + 1); });
+document.getElementById('cycles').addEventListener('click', function (e) {
+  var row = e.target.closest('tr');
+  if (row && row.id.indexOf('cycle-') === 0) show(parseInt(row.id.slice(6), 10));
+});
+document.addEventListener('keydown', function (e) {
+  if (e.target.tagName === 'INPUT') return;
+  if (e.key === 'ArrowLeft' || e.key === 'ArrowUp') { show(selected - 1); e.preventDefault(); }
+  if (e.key === 'ArrowRight' || e.key === 'ArrowDown') { show(selected + 1); e.preventDefault(); }
+  if (e.key === 'Home') show(0);
+  if (e.key === 'End') show(writes.length - 1);
+});
+show(0);
+";
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders `trace` as an HTML page titled `title`. `listing` is the program
+/// as control store addresses and words, `initial` the registers before the
+/// run, where known.
+pub fn render(title: &str, trace: &Trace, listing: &[(u16, u64)], initial: &[Option<i32>])
+              -> String {
+    let info: &Info = trace.info();
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!("<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n",
+                           escape(title),
+                           STYLE));
+    html.push_str(&format!("<h1>{}</h1>\n<p>{}, {} cycles, {}</p>\n",
+                           escape(title),
+                           info.name,
+                           trace.records.len(),
+                           escape(&trace.end.describe())));
+    html.push_str("<div class=\"controls\"><button id=\"prev\">&#9664;</button> \
+                   <input type=\"range\" id=\"scrub\" min=\"0\" value=\"0\" ");
+    html.push_str(&format!("max=\"{}\"> ", trace.records.len().saturating_sub(1)));
+    html.push_str("<button id=\"next\">&#9654;</button> <span id=\"position\"></span></div>\n");
+
+    html.push_str("<div class=\"panels\">\n<section>\n<h2>Registers</h2>\n<table>\n");
+    for &r in info.debug_registers {
+        html.push_str(&format!("<tr><th>{}</th><td id=\"register-{}\"></td></tr>\n",
+                               info.registers[r as usize],
+                               r));
+    }
+    html.push_str("<tr><th>flags</th><td id=\"flags\"></td></tr>\n</table>\n</section>\n");
+    html.push_str("<section>\n<h2>Program</h2>\n<table>\n");
+    for &(addr, word) in listing {
+        html.push_str(&format!("<tr id=\"word-{}\"><td>{}</td><td>{:0digits$x}</td>\
+                                <td>{}</td></tr>\n",
+                               addr,
+                               addr,
+                               word,
+                               escape(&(info.disassemble)(word)),
+                               digits = info.word_digits));
+    }
+    html.push_str("</table>\n</section>\n</div>\n");
+
+    html.push_str("<h2>Cycles</h2>\n<table id=\"cycles\">\n<thead><tr><th>cycle</th><th>pc</th>\
+                   <th>word</th><th>microinstruction</th><th>registers</th><th>flags</th>\
+                   <th>memory</th></tr></thead>\n<tbody>\n");
+    let mut registers = initial.to_vec();
+    for (cycle, rec) in trace.records.iter().enumerate() {
+        let writes: Vec<String> = rec.writes
+            .iter()
+            .map(|&(r, v)| {
+                let class = if registers[r as usize] == Some(v) { "written" } else { "changed" };
+                registers[r as usize] = Some(v);
+                format!("<span class=\"{}\">{}={}</span>", class, info.registers[r as usize], v)
+            })
+            .collect();
+        let memory: Vec<String> = rec.memory.iter().map(|op| op.describe()).collect();
+        html.push_str(&format!("<tr id=\"cycle-{}\"><td>{}</td><td>{}</td>\
+                                <td>{:0digits$x}</td><td>{}</td><td>{}</td>\
+                                <td>{}</td><td>{}</td></tr>\n",
+                               cycle,
+                               cycle,
+                               rec.pc,
+                               rec.word,
+                               escape(&(info.disassemble)(rec.word)),
+                               writes.join(" "),
+                               info.describe_flags(rec.flags),
+                               escape(&memory.join(" ")),
+                               digits = info.word_digits));
+    }
+    html.push_str("</tbody>\n</table>\n");
+    let class = match trace.end {
+        trace::End::Error(_) => " class=\"error\"",
+        _ => "",
+    };
+    html.push_str(&format!("<p{}>{}</p>\n", class, escape(&trace.end.describe())));
+
+    // The run as data for the script: the writes and the flags of every
+    // cycle, the executed addresses and the registers before the run.
+    html.push_str("<script>\n");
+    let initial = initial.iter().map(|&v| v.map_or(Value::Null, |v| Value::from(v as i64)));
+    let shown = info.debug_registers.iter().map(|&r| Value::from(r as i64));
+    let writes = trace.records.iter().map(|rec| {
+        let writes = rec.writes.iter().map(|&(r, v)| {
+            Value::Array(vec![Value::from(r as i64), Value::from(v as i64)])
+        });
+        Value::Array(writes.collect())
+    });
+    let pcs = trace.records.iter().map(|rec| Value::from(rec.pc as i64));
+    let flags = trace.records.iter().map(|rec| Value::from(info.describe_flags(rec.flags)));
+    html.push_str(&format!("var initial = {};\n", Value::Array(initial.collect())));
+    html.push_str(&format!("var shown = {};\n", Value::Array(shown.collect())));
+    html.push_str(&format!("var writes = {};\n", Value::Array(writes.collect())));
+    html.push_str(&format!("var pcs = {};\n", Value::Array(pcs.collect())));
+    html.push_str(&format!("var flags = {};\n", Value::Array(flags.collect())));
+    html.push_str(SCRIPT);
+    html.push_str("</script>\n</body>\n</html>\n");
+    html
+}
+
+/// The words a trace executed, by control store address.
+fn executed(trace: &Trace) -> Vec<(u16, u64)> {
+    let mut listing: Vec<(u16, u64)> = trace.records.iter().map(|rec| (rec.pc, rec.word)).collect();
+    listing.sort();
+    listing.dedup();
+    listing
+}
+
+/// Entry point of `micro16 report`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    let mut program = None;
+    let mut trace_path = None;
+    let mut output = None;
+    let mut options = MachineOptions::default();
+    let mut max_cycles = DEFAULT_MAX_CYCLES;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--trace" => trace_path = Some(iter.next().ok_or(USAGE)?.clone()),
+            "-o" => output = Some(iter.next().ok_or(USAGE)?.clone()),
+            "--cycles" => max_cycles = cli::parse_arg(iter.next(), USAGE)?,
+            _ if options.parse(arg, &mut iter, USAGE)? => {}
+            _ if !arg.starts_with('-') && program.is_none() => program = Some(arg.clone()),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    let html = match (program, trace_path) {
+        (Some(path), None) => {
+            let words = loader::read_program(&path)?;
+            let mut machine = options.machine(&words, Memory::new())?;
+            let initial: Vec<Option<i32>> = (0..machine.info().registers.len() as u8)
+                .map(|r| Some(Core::register(&machine, r)))
+                .collect();
+            let listing: Vec<(u16, u64)> = words.iter()
+                .enumerate()
+                .map(|(addr, &word)| (addr as u16, word as u64))
+                .collect();
+            let trace = trace::record(&mut machine, max_cycles);
+            render(&path, &trace, &listing, &initial)
+        }
+        (None, Some(path)) => {
+            if !options.is_empty() {
+                return Err(USAGE.to_string());
+            }
+            let trace = Trace::load(&path)?;
+            let initial = vec![None; trace.info().registers.len()];
+            render(&path, &trace, &executed(&trace), &initial)
+        }
+        _ => return Err(USAGE.to_string()),
+    };
+
+    match output {
+        Some(path) => {
+            File::create(&path)
+                .and_then(|mut f| f.write_all(html.as_bytes()))
+                .map_err(|e| format!("{}: {}", path, e))
+        }
+        None => {
+            print!("{}", html);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+    use machine::{Config, Machine};
+
+    fn run(words: &[u32]) -> (Trace, String) {
+        let mut machine = Machine::new(words, Config::default()).unwrap();
+        let initial: Vec<Option<i32>> = (0..machine.info().registers.len() as u8)
+            .map(|r| Some(Core::register(&machine, r)))
+            .collect();
+        let listing: Vec<(u16, u64)> = words.iter()
+            .enumerate()
+            .map(|(addr, &word)| (addr as u16, word as u64))
+            .collect();
+        let trace = trace::record(&mut machine, 100);
+        let html = render("<a & \"b\">", &trace, &listing, &initial);
+        (trace, html)
+    }
+
+    #[test]
+    fn text_is_escaped() {
+        assert_eq!(escape("R1 <- R0 & \"x\" > 1"), "R1 &lt;- R0 &amp; &quot;x&quot; &gt; 1");
+
+        let (_, html) = run(&assemble("R1 <- R0").unwrap());
+        assert!(html.contains("<title>&lt;a &amp; &quot;b&quot;&gt;</title>"));
+        assert!(html.contains("<td>R1 &lt;- R0</td>"));
+        assert!(!html.contains("R1 <- R0"));
+    }
+
+    #[test]
+    fn cycles_list_writes_and_accesses() {
+        let (trace, html) = run(&assemble("R0 <- 1\nR0 <- 1\nMAR <- R0; MBR <- R0; wr\nwr")
+            .unwrap());
+        assert_eq!(trace.records.len(), 4);
+        assert!(html.contains("<p>micro16, 4 cycles, finished</p>"));
+        // The first write changes R0, the second leaves it as it is.
+        assert!(html.contains("<tr id=\"cycle-0\"><td>0</td><td>0</td>"));
+        assert!(html.contains("<span class=\"changed\">R0=1</span>"));
+        assert!(html.contains("<span class=\"written\">R0=1</span>"));
+        assert!(html.contains("<tr id=\"word-3\"><td>3</td>"));
+        assert!(html.contains("wr mem[1]=1"), "{}", html);
+        assert!(html.contains("var pcs = [0,1,2,3];"));
+        assert!(html.contains("var writes = [[[4,1]],[[4,1]],[[3,1],[15,1]],[]];"));
+        assert!(html.ends_with("</script>\n</body>\n</html>\n"));
+    }
+
+    #[test]
+    fn errors_end_the_report() {
+        // A word with the reserved shifter mode.
+        let (_, html) = run(&[0x06000000]);
+        assert!(html.contains("<p class=\"error\">"), "{}", html);
+        assert!(html.contains("reserved shifter mode"));
+    }
+
+    #[test]
+    fn traces_list_the_executed_words() {
+        let (trace, _) = run(&assemble("R0 <- 1\n:loop\nR0 <- R0 + -1; if Z goto .done\n\
+                                        goto .loop\n:done")
+            .unwrap());
+        let listing: Vec<u16> = executed(&trace).iter().map(|&(addr, _)| addr).collect();
+        assert_eq!(listing, [0, 1]);
+    }
+}
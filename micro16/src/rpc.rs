@@ -0,0 +1,699 @@
+//! JSON-RPC 2.0 server through which front-ends drive emulator sessions.
+//!
+//! `micro16 serve` reads one request per line from stdin and answers each
+//! on a line of stdout. With `--port N` it listens on 127.0.0.1:N instead,
+//! speaking the same protocol on every connection; all connections share
+//! the sessions. Batches are supported, notifications get no response.
+//!
+//! A session is a `Machine` of its own with breakpoints and limits. Params
+//! are passed by name, and every method but `create` names its `session`:
+//!
+//! ```text
+//! create           profile?, call_stack?, map_control_store?, cycle_budget?  -> {session}
+//! close            session
+//! load             session, source | words                        -> {words}
+//! reset            session, keep_memory?
+//! step             session, cycles?                               -> stop
+//! run              session, max_cycles?                           -> stop
+//! set_breakpoints  session, addresses
+//! state            session          -> {registers, flags, pc, cycles, status}
+//! set_registers    session, registers: {"R0": 5, ...}
+//! read_memory      session, start, length                         -> {values}
+//! write_memory     session, start, values
+//! ```
+//!
+//! `load` assembles `source` in the format of the session's profile or takes
+//! the control store `words` as numbers; memory and registers start over,
+//! breakpoints stay. `step` executes `cycles` cycles, 1 by default, `run`
+//! executes until the program finishes, a breakpoint is reached or
+//! `max_cycles` have passed. Breakpoints are control store addresses and
+//! stop before the word executes, except when a run starts at one. Both
+//! answer `{reason, pc, cycles}` with the reason `finished`, `breakpoint`,
+//! `limit` for the requested number of cycles, `budget` once the session
+//! has used up its cycles, or `error` along with an `error` message.
+//!
+//! The server limits the number of sessions (`--max-sessions`), the cycles
+//! a session may execute over its lifetime (`--cycle-budget`; `create` may
+//! ask for fewer) and the cycles one request may execute (`--max-run`).
+//! Errors use the JSON-RPC codes, plus -32000 for requests the emulator
+//! rejects, -32001 for unknown sessions and -32002 for exceeded limits.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use arch;
+use asm::assemble_with;
+use cli;
+use cpu::{Profile, PROGRAM_LENGTH};
+use disasm::{register_index, register_name};
+use format::Layout;
+use json::Value;
+use machine::{self, Machine, Status};
+
+const DEFAULT_MAX_SESSIONS: usize = 16;
+const DEFAULT_CYCLE_BUDGET: u64 = 100_000_000;
+const DEFAULT_MAX_RUN: u64 = 10_000_000;
+/// Longest request line accepted, in bytes.
+const MAX_LINE: u64 = 1 << 20;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const EMULATOR_ERROR: i64 = -32000;
+const UNKNOWN_SESSION: i64 = -32001;
+const LIMIT_EXCEEDED: i64 = -32002;
+
+pub const USAGE: &str = "Usage: micro16 serve [--port N] [--max-sessions N] [--cycle-budget N] \
+                         [--max-run N]";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: String) -> RpcError {
+        RpcError { code, message }
+    }
+
+    fn invalid_params(message: String) -> RpcError {
+        RpcError::new(INVALID_PARAMS, message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_sessions: usize,
+    /// Cycles a session may execute over its lifetime.
+    pub cycle_budget: u64,
+    /// Cycles a single `step` or `run` may execute.
+    pub max_run: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_sessions: DEFAULT_MAX_SESSIONS,
+            cycle_budget: DEFAULT_CYCLE_BUDGET,
+            max_run: DEFAULT_MAX_RUN,
+        }
+    }
+}
+
+/// A named integer param in `min..=max`, if present.
+fn int_param(params: &Value, name: &str, min: i64, max: i64) -> Result<Option<i64>, RpcError> {
+    match params.get(name) {
+        None | Some(&Value::Null) => Ok(None),
+        Some(value) => {
+            match value.as_i64() {
+                Some(n) if n >= min && n <= max => Ok(Some(n)),
+                _ => {
+                    Err(RpcError::invalid_params(format!("'{}' must be an integer from {} to {}",
+                                                         name,
+                                                         min,
+                                                         max)))
+                }
+            }
+        }
+    }
+}
+
+fn required<T>(value: Option<T>, name: &str) -> Result<T, RpcError> {
+    value.ok_or_else(|| RpcError::invalid_params(format!("missing '{}'", name)))
+}
+
+/// A list of integers in `min..=max`.
+fn int_list(params: &Value, name: &str, min: i64, max: i64) -> Result<Vec<i64>, RpcError> {
+    let items = required(params.get(name), name)?.as_array().ok_or_else(|| {
+        RpcError::invalid_params(format!("'{}' must be an array", name))
+    })?;
+    items.iter()
+        .map(|item| match item.as_i64() {
+            Some(n) if n >= min && n <= max => Ok(n),
+            _ => {
+                Err(RpcError::invalid_params(format!("'{}' must hold integers from {} to {}",
+                                                     name,
+                                                     min,
+                                                     max)))
+            }
+        })
+        .collect()
+}
+
+fn emulator_error<E: ToString>(e: E) -> RpcError {
+    RpcError::new(EMULATOR_ERROR, e.to_string())
+}
+
+/// One front-end's machine.
+struct Session {
+    machine: Machine,
+    breakpoints: BTreeSet<u8>,
+    /// Cycles the session may still execute.
+    budget: u64,
+}
+
+impl Session {
+    fn status(&self) -> &'static str {
+        match self.machine.status() {
+            Status::Finished => "finished",
+            _ => "running",
+        }
+    }
+
+    /// Executes up to `cycles` cycles, stopping at breakpoints after the
+    /// first one if `breakpoints` is set.
+    fn execute(&mut self, cycles: u64, breakpoints: bool) -> Value {
+        let mut reason = "limit";
+        let mut error = None;
+        for i in 0..cycles {
+            if self.machine.status() == Status::Finished {
+                break;
+            }
+            if breakpoints && i > 0 && self.breakpoints.contains(&self.machine.program_counter()) {
+                reason = "breakpoint";
+                break;
+            }
+            if self.budget == 0 {
+                reason = "budget";
+                break;
+            }
+            self.budget -= 1;
+            if let Err(e) = self.machine.step() {
+                reason = "error";
+                error = Some(e.to_string());
+                break;
+            }
+        }
+        if self.machine.status() == Status::Finished {
+            reason = "finished";
+        } else if reason == "limit" && self.budget == 0 {
+            reason = "budget";
+        }
+
+        let mut stop = vec![("reason", Value::from(reason)),
+                            ("pc", Value::from(self.machine.program_counter() as i64)),
+                            ("cycles", Value::from(self.machine.cycles() as i64))];
+        if let Some(e) = error {
+            stop.push(("error", Value::from(e)));
+        }
+        Value::object(stop)
+    }
+
+    fn state(&self) -> Value {
+        let registers = arch::MICRO16.debug_registers
+            .iter()
+            .map(|&idx| {
+                let value = self.machine.cpu().registers().get(idx);
+                (register_name(idx).to_string(), Value::from(value as i64))
+            })
+            .collect();
+        let flags = self.machine.flags();
+        Value::object(vec![("registers", Value::Object(registers)),
+                           ("flags",
+                            Value::object(vec![("N", Value::from(flags.negative)),
+                                               ("Z", Value::from(flags.zero)),
+                                               ("C", Value::from(flags.carry)),
+                                               ("V", Value::from(flags.overflow))])),
+                           ("pc", Value::from(self.machine.program_counter() as i64)),
+                           ("cycles", Value::from(self.machine.cycles() as i64)),
+                           ("status", Value::from(self.status()))])
+    }
+}
+
+struct Sessions {
+    sessions: HashMap<u64, Arc<Mutex<Session>>>,
+    next_id: u64,
+}
+
+/// The sessions and the limits they run under, shared by all connections.
+pub struct Server {
+    limits: Limits,
+    sessions: Mutex<Sessions>,
+}
+
+impl Server {
+    pub fn new(limits: Limits) -> Server {
+        Server {
+            limits,
+            sessions: Mutex::new(Sessions {
+                sessions: HashMap::new(),
+                next_id: 1,
+            }),
+        }
+    }
+
+    fn session(&self, params: &Value) -> Result<Arc<Mutex<Session>>, RpcError> {
+        let id = required(int_param(params, "session", 0, i64::MAX)?, "session")?;
+        let sessions = self.sessions.lock().unwrap();
+        sessions.sessions
+            .get(&(id as u64))
+            .cloned()
+            .ok_or_else(|| RpcError::new(UNKNOWN_SESSION, format!("no session {}", id)))
+    }
+
+    fn create(&self, params: &Value) -> Result<Value, RpcError> {
+        let profile = match params.get("profile") {
+            None | Some(&Value::Null) => Profile::Classic,
+            Some(name) => {
+                let name = name.as_str()
+                    .ok_or_else(|| RpcError::invalid_params("'profile' must be a string".into()))?;
+                Profile::parse(name).map_err(RpcError::invalid_params)?
+            }
+        };
+        let config = machine::Config {
+            max_cycles: None,
+            control_store_window: int_param(params, "map_control_store", 0, 0xffff)?
+                .map(|base| base as u16),
+            call_stack_depth: int_param(params, "call_stack", 0, PROGRAM_LENGTH as i64)?
+                .map(|depth| depth as usize),
+            profile,
+        };
+        let budget = int_param(params, "cycle_budget", 0, self.limits.cycle_budget as i64)?
+            .map_or(self.limits.cycle_budget, |budget| budget as u64);
+        let session = Session {
+            machine: Machine::new(&[], config).map_err(emulator_error)?,
+            breakpoints: BTreeSet::new(),
+            budget,
+        };
+
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.sessions.len() >= self.limits.max_sessions {
+            return Err(RpcError::new(LIMIT_EXCEEDED,
+                                     format!("at most {} sessions may be open",
+                                             self.limits.max_sessions)));
+        }
+        let id = sessions.next_id;
+        sessions.next_id += 1;
+        sessions.sessions.insert(id, Arc::new(Mutex::new(session)));
+        Ok(Value::object(vec![("session", Value::from(id as i64))]))
+    }
+
+    fn close(&self, params: &Value) -> Result<Value, RpcError> {
+        let id = required(int_param(params, "session", 0, i64::MAX)?, "session")?;
+        match self.sessions.lock().unwrap().sessions.remove(&(id as u64)) {
+            Some(_) => Ok(Value::Null),
+            None => Err(RpcError::new(UNKNOWN_SESSION, format!("no session {}", id))),
+        }
+    }
+
+    /// Executes `method` with `params`, which are an object.
+    fn call(&self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "create" => return self.create(params),
+            "close" => return self.close(params),
+            "load" | "reset" | "step" | "run" | "set_breakpoints" | "state" |
+            "set_registers" | "read_memory" | "write_memory" => (),
+            _ => {
+                return Err(RpcError::new(METHOD_NOT_FOUND, format!("no method '{}'", method)))
+            }
+        }
+        let session = self.session(params)?;
+        let mut session = session.lock().unwrap();
+        match method {
+            "load" => {
+                let config = session.machine.config().clone();
+                let program = match (params.get("source"), params.get("words")) {
+                    (Some(source), None) => {
+                        let source = source.as_str().ok_or_else(|| {
+                            RpcError::invalid_params("'source' must be a string".to_string())
+                        })?;
+                        assemble_with(source, &Layout::for_profile(config.profile))
+                            .map_err(emulator_error)?
+                    }
+                    (None, Some(_)) => {
+                        int_list(params, "words", 0, u32::MAX as i64)?
+                            .into_iter()
+                            .map(|word| word as u32)
+                            .collect()
+                    }
+                    _ => {
+                        let message = "expected either 'source' or 'words'".to_string();
+                        return Err(RpcError::invalid_params(message));
+                    }
+                };
+                session.machine = Machine::new(&program, config).map_err(emulator_error)?;
+                Ok(Value::object(vec![("words", Value::from(program.len() as i64))]))
+            }
+            "reset" => {
+                let keep_memory = match params.get("keep_memory") {
+                    None => false,
+                    Some(keep) => {
+                        keep.as_bool().ok_or_else(|| {
+                            RpcError::invalid_params("'keep_memory' must be a boolean".to_string())
+                        })?
+                    }
+                };
+                session.machine.reset(keep_memory);
+                Ok(Value::Null)
+            }
+            "step" => {
+                let cycles = int_param(params, "cycles", 0, i64::MAX)?.unwrap_or(1) as u64;
+                Ok(session.execute(cycles.min(self.limits.max_run), false))
+            }
+            "run" => {
+                let max_run = self.limits.max_run;
+                let cycles = int_param(params, "max_cycles", 0, i64::MAX)?
+                    .map_or(max_run, |cycles| (cycles as u64).min(max_run));
+                Ok(session.execute(cycles, true))
+            }
+            "set_breakpoints" => {
+                let addresses = int_list(params, "addresses", 0, PROGRAM_LENGTH as i64 - 1)?;
+                session.breakpoints = addresses.into_iter().map(|addr| addr as u8).collect();
+                Ok(Value::Null)
+            }
+            "state" => Ok(session.state()),
+            "set_registers" => {
+                let registers = match params.get("registers") {
+                    Some(Value::Object(members)) => members,
+                    _ => {
+                        return Err(RpcError::invalid_params("'registers' must be an object"
+                            .to_string()))
+                    }
+                };
+                // Check every value before changing anything.
+                let mut values = Vec::new();
+                for (name, value) in registers {
+                    match value.as_i64() {
+                        Some(v) if v >= i16::MIN as i64 && v <= u16::MAX as i64 => {
+                            values.push((name, v as u16 as i16))
+                        }
+                        _ => {
+                            return Err(RpcError::invalid_params(format!("invalid value for {}",
+                                                                        name)))
+                        }
+                    }
+                }
+                for &(name, _) in &values {
+                    match register_index(name) {
+                        Some(idx) if idx >= 3 => (),
+                        _ => {
+                            let message = format!("cannot set register '{}'", name);
+                            return Err(RpcError::invalid_params(message));
+                        }
+                    }
+                }
+                for (name, value) in values {
+                    session.machine
+                        .set_register(name, value)
+                        .map_err(|e| RpcError::invalid_params(e.to_string()))?;
+                }
+                Ok(Value::Null)
+            }
+            "read_memory" => {
+                let start = required(int_param(params, "start", 0, 0xffff)?, "start")?;
+                let length = required(int_param(params, "length", 0, 0x10000)?, "length")?;
+                let values = session.machine
+                    .read_memory(start as u16, length as usize)
+                    .map_err(|e| RpcError::invalid_params(e.to_string()))?;
+                let values = values.into_iter().map(|v| Value::from(v as i64)).collect();
+                Ok(Value::object(vec![("values", Value::Array(values))]))
+            }
+            "write_memory" => {
+                let start = required(int_param(params, "start", 0, 0xffff)?, "start")?;
+                let values: Vec<i16> = int_list(params, "values", i16::MIN as i64, u16::MAX as i64)?
+                    .into_iter()
+                    .map(|v| v as u16 as i16)
+                    .collect();
+                session.machine
+                    .write_memory(start as u16, &values)
+                    .map_err(|e| RpcError::invalid_params(e.to_string()))?;
+                Ok(Value::Null)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Handles one request object, returning its response unless it is a
+    /// notification.
+    fn request(&self, request: &Value) -> Option<Value> {
+        let id = request.get("id").cloned();
+        let result = match (request.get("jsonrpc").and_then(|v| v.as_str()),
+                            request.get("method").and_then(|v| v.as_str()),
+                            request.get("params")) {
+            (Some("2.0"), Some(method), None) => self.call(method, &Value::Object(Vec::new())),
+            (Some("2.0"), Some(method), Some(params @ &Value::Object(_))) => {
+                self.call(method, params)
+            }
+            (Some("2.0"), Some(_), Some(_)) => {
+                Err(RpcError::invalid_params("params must be passed by name".to_string()))
+            }
+            _ => {
+                return Some(response(Value::Null,
+                                     Err(RpcError::new(INVALID_REQUEST,
+                                                       "invalid request".to_string()))))
+            }
+        };
+        id.map(|id| response(id, result))
+    }
+
+    /// Handles one line of input, a request or a batch, returning the line
+    /// to answer with, if any.
+    pub fn handle(&self, text: &str) -> Option<String> {
+        let message = match Value::parse(text) {
+            Ok(message) => message,
+            Err(e) => {
+                return Some(response(Value::Null, Err(RpcError::new(PARSE_ERROR, e))).to_string())
+            }
+        };
+        match message {
+            Value::Array(ref requests) if !requests.is_empty() => {
+                let responses: Vec<Value> =
+                    requests.iter().filter_map(|request| self.request(request)).collect();
+                if responses.is_empty() {
+                    None
+                } else {
+                    Some(Value::Array(responses).to_string())
+                }
+            }
+            ref request => self.request(request).map(|response| response.to_string()),
+        }
+    }
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    let outcome = match result {
+        Ok(result) => ("result", result),
+        Err(e) => {
+            ("error",
+             Value::object(vec![("code", Value::from(e.code)),
+                                ("message", Value::from(e.message))]))
+        }
+    };
+    Value::object(vec![("jsonrpc", Value::from("2.0")), outcome, ("id", id)])
+}
+
+/// Answers the requests on `input` until it ends.
+pub fn serve<R: Read, W: Write>(server: &Server, input: R, mut output: W) -> io::Result<()> {
+    let mut input = BufReader::new(input);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let len = input.by_ref().take(MAX_LINE).read_until(b'\n', &mut line)?;
+        if len == 0 {
+            return Ok(());
+        }
+        if len as u64 == MAX_LINE && line.last() != Some(&b'\n') {
+            let error = RpcError::new(INVALID_REQUEST, "request too long".to_string());
+            writeln!(output, "{}", response(Value::Null, Err(error)))?;
+            return output.flush();
+        }
+        let text = String::from_utf8_lossy(&line);
+        if text.trim().is_empty() {
+            continue;
+        }
+        if let Some(reply) = server.handle(&text) {
+            writeln!(output, "{}", reply)?;
+            output.flush()?;
+        }
+    }
+}
+
+/// Entry point of `micro16 serve`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    let mut limits = Limits::default();
+    let mut port = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--port" => port = Some(cli::parse_arg::<u16>(iter.next(), USAGE)?),
+            "--max-sessions" => limits.max_sessions = cli::parse_arg(iter.next(), USAGE)?,
+            "--cycle-budget" => limits.cycle_budget = cli::parse_arg(iter.next(), USAGE)?,
+            "--max-run" => limits.max_run = cli::parse_arg(iter.next(), USAGE)?,
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let server = Server::new(limits);
+
+    let port = match port {
+        Some(port) => port,
+        None => {
+            let stdin = io::stdin();
+            return serve(&server, stdin.lock(), io::stdout()).map_err(|e| e.to_string());
+        }
+    };
+    let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+    println!("listening on 127.0.0.1:{}", port);
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => return Err(e.to_string()),
+            };
+            let server = &server;
+            scope.spawn(move || {
+                if let Ok(input) = stream.try_clone() {
+                    // A connection that fails only ends itself.
+                    let _ = serve(server, input, stream);
+                }
+            });
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        max_sessions: 2,
+        cycle_budget: 10,
+        max_run: 4,
+    };
+
+    /// The result or error of calling `method` with `params`.
+    fn call(server: &Server, method: &str, params: &str) -> String {
+        let request = format!(r#"{{"jsonrpc":"2.0","id":1,"method":"{}","params":{}}}"#,
+                              method,
+                              params);
+        let response = Value::parse(&server.handle(&request).unwrap()).unwrap();
+        response.get("result").or_else(|| response.get("error")).unwrap().to_string()
+    }
+
+    /// The response to request `id` failing with `code`.
+    fn error(code: i64, message: &str, id: &str) -> String {
+        format!(r#"{{"jsonrpc":"2.0","error":{{"code":{},"message":"{}"}},"id":{}}}"#,
+                code,
+                message,
+                id)
+    }
+
+    /// A server with session 1 running a loop that increments R0.
+    fn looping() -> Server {
+        let server = Server::new(LIMITS);
+        assert_eq!(call(&server, "create", "{}"), r#"{"session":1}"#);
+        assert_eq!(call(&server,
+                        "load",
+                        r#"{"session":1,"source":"R0 <- R0 + 1\nR0 <- R0 + 1; goto 0"}"#),
+                   r#"{"words":2}"#);
+        server
+    }
+
+    #[test]
+    fn sessions_are_limited() {
+        let server = looping();
+        assert_eq!(call(&server, "create", "{}"), r#"{"session":2}"#);
+        assert_eq!(call(&server, "create", "{}"),
+                   r#"{"code":-32002,"message":"at most 2 sessions may be open"}"#);
+        assert_eq!(call(&server, "close", r#"{"session":1}"#), "null");
+        assert_eq!(call(&server, "close", r#"{"session":1}"#),
+                   r#"{"code":-32001,"message":"no session 1"}"#);
+        assert_eq!(call(&server, "state", r#"{"session":1}"#),
+                   r#"{"code":-32001,"message":"no session 1"}"#);
+        // Ids are not reused.
+        assert_eq!(call(&server, "create", "{}"), r#"{"session":3}"#);
+    }
+
+    #[test]
+    fn the_cycle_budget_spans_requests() {
+        let server = looping();
+        assert_eq!(call(&server, "run", r#"{"session":1}"#),
+                   r#"{"reason":"limit","pc":0,"cycles":4}"#);
+        assert_eq!(call(&server, "step", r#"{"session":1,"cycles":100}"#),
+                   r#"{"reason":"limit","pc":0,"cycles":8}"#);
+        assert_eq!(call(&server, "run", r#"{"session":1}"#),
+                   r#"{"reason":"budget","pc":0,"cycles":10}"#);
+        assert_eq!(call(&server, "step", r#"{"session":1}"#),
+                   r#"{"reason":"budget","pc":0,"cycles":10}"#);
+        // Loading a program does not refill the budget.
+        call(&server, "load", r#"{"session":1,"words":[0]}"#);
+        assert_eq!(call(&server, "step", r#"{"session":1}"#),
+                   r#"{"reason":"budget","pc":0,"cycles":0}"#);
+
+        assert_eq!(call(&server, "create", r#"{"cycle_budget":1}"#), r#"{"session":2}"#);
+        call(&server, "load", r#"{"session":2,"words":[0,0]}"#);
+        assert_eq!(call(&server, "run", r#"{"session":2}"#),
+                   r#"{"reason":"budget","pc":1,"cycles":1}"#);
+        assert_eq!(call(&server, "create", r#"{"cycle_budget":11}"#),
+                   r#"{"code":-32602,"message":"'cycle_budget' must be an integer from 0 to 10"}"#);
+    }
+
+    #[test]
+    fn runs_stop_at_breakpoints() {
+        let server = looping();
+        call(&server, "set_breakpoints", r#"{"session":1,"addresses":[1]}"#);
+        assert_eq!(call(&server, "run", r#"{"session":1}"#),
+                   r#"{"reason":"breakpoint","pc":1,"cycles":1}"#);
+        // A run starting at a breakpoint leaves it.
+        assert_eq!(call(&server, "run", r#"{"session":1}"#),
+                   r#"{"reason":"breakpoint","pc":1,"cycles":3}"#);
+        assert_eq!(call(&server, "read_memory", r#"{"session":1,"start":0,"length":2}"#),
+                   r#"{"values":[0,0]}"#);
+
+        call(&server, "load", r#"{"session":1,"source":"R0 <- 1"}"#);
+        assert_eq!(call(&server, "run", r#"{"session":1}"#),
+                   r#"{"reason":"finished","pc":1,"cycles":1}"#);
+    }
+
+    #[test]
+    fn malformed_requests_are_rejected() {
+        let server = looping();
+        let handle = |line: &str| server.handle(line).unwrap();
+        assert_eq!(handle(r#"{"jsonrpc"#),
+                   error(PARSE_ERROR, "unterminated string at offset 9", "null"));
+        for line in &[r#"{"id":8}"#, "[]", r#"{"jsonrpc":"1.0","id":1,"method":"state"}"#] {
+            assert_eq!(handle(line), error(INVALID_REQUEST, "invalid request", "null"));
+        }
+        assert_eq!(handle(r#"{"jsonrpc":"2.0","id":2,"method":"state","params":[1]}"#),
+                   error(INVALID_PARAMS, "params must be passed by name", "2"));
+        assert_eq!(call(&server, "nope", "{}"),
+                   r#"{"code":-32601,"message":"no method 'nope'"}"#);
+        assert_eq!(call(&server, "state", "{}"),
+                   r#"{"code":-32602,"message":"missing 'session'"}"#);
+        assert_eq!(call(&server, "load", r#"{"session":1,"words":[-1]}"#),
+                   concat!(r#"{"code":-32602,"message":"'words' must hold integers "#,
+                           r#"from 0 to 4294967295"}"#));
+        assert_eq!(call(&server, "load", r#"{"session":1}"#),
+                   r#"{"code":-32602,"message":"expected either 'source' or 'words'"}"#);
+        assert_eq!(call(&server, "set_registers", r#"{"session":1,"registers":{"R0":1,"0":2}}"#),
+                   r#"{"code":-32602,"message":"cannot set register '0'"}"#);
+        // Nothing changed.
+        assert!(call(&server, "state", r#"{"session":1}"#).contains(r#""R0":0,"#));
+
+        // Notifications get no answer, not even inside a batch.
+        assert_eq!(server.handle(r#"{"jsonrpc":"2.0","method":"state","params":{"session":1}}"#),
+                   None);
+        let batch = concat!(r#"[{"jsonrpc":"2.0","method":"nope"},"#,
+                            r#"{"jsonrpc":"2.0","id":3,"method":"close","params":{"session":1}}]"#);
+        assert_eq!(handle(batch), r#"[{"jsonrpc":"2.0","result":null,"id":3}]"#);
+    }
+
+    #[test]
+    fn overlong_lines_end_the_connection() {
+        let server = Server::new(LIMITS);
+        let mut input = b"\n{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"create\"}\n".to_vec();
+        input.extend(vec![b' '; MAX_LINE as usize]);
+        input.extend(b"\n{\"jsonrpc\":\"2.0\",\"id\":2,\"method\":\"create\"}\n");
+        let mut output = Vec::new();
+        serve(&server, &input[..], &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   format!("{}\n{}\n",
+                           r#"{"jsonrpc":"2.0","result":{"session":1},"id":1}"#,
+                           error(INVALID_REQUEST, "request too long", "null")));
+    }
+}
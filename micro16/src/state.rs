@@ -0,0 +1,97 @@
+//! Register and memory assignments such as `R0=5 mem[10]=3`, used to set up
+//! a Cpu before a run and to check its state afterwards.
+
+use std::fmt;
+
+use cpu::{Cpu, MemoryPort};
+use disasm::{register_index, register_name};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Register(u8),
+    Memory(u16),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Location::Register(idx) => write!(f, "{}", register_name(idx)),
+            Location::Memory(addr) => write!(f, "mem[{}]", addr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Assignment {
+    pub location: Location,
+    pub value: i16,
+}
+
+impl fmt::Display for Assignment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.location, self.value)
+    }
+}
+
+/// Parses a decimal or `0x` prefixed hex number that fits into 16 bits.
+pub fn parse_value(text: &str) -> Result<i16, String> {
+    let (negative, digits) = if let Some(rest) = text.strip_prefix('-') {
+        (true, rest)
+    } else {
+        (false, text)
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else {
+        digits.parse::<i64>()
+    };
+    let value = value.map_err(|_| format!("invalid number '{}'", text))?;
+    let value = if negative { -value } else { value };
+    if value < i16::MIN as i64 || value > u16::MAX as i64 {
+        return Err(format!("{} does not fit into 16 bits", text));
+    }
+    Ok(value as u16 as i16)
+}
+
+pub fn parse_location(text: &str) -> Result<Location, String> {
+    if let Some(addr) = text.strip_prefix("mem[").and_then(|t| t.strip_suffix(']')) {
+        let addr = parse_value(addr.trim())?;
+        return Ok(Location::Memory(addr as u16));
+    }
+    match register_index(text) {
+        Some(idx) if idx >= 3 => Ok(Location::Register(idx)),
+        Some(_) => Err(format!("register {} is read-only", text)),
+        None => Err(format!("unknown register '{}'", text)),
+    }
+}
+
+/// Parses a whitespace separated list of `location=value` pairs.
+pub fn parse_assignments(text: &str) -> Result<Vec<Assignment>, String> {
+    text.split_whitespace()
+        .map(|item| {
+            let mut parts = item.splitn(2, '=');
+            let location = parts.next().unwrap();
+            let value = parts.next().ok_or_else(|| format!("expected '=' in '{}'", item))?;
+            Ok(Assignment {
+                location: parse_location(location)?,
+                value: parse_value(value)?,
+            })
+        })
+        .collect()
+}
+
+pub fn read<M: MemoryPort>(cpu: &Cpu<M>, location: Location) -> i16 {
+    match location {
+        Location::Register(idx) => cpu.registers().get(idx),
+        Location::Memory(addr) => cpu.memory().get(addr as usize),
+    }
+}
+
+pub fn apply<M: MemoryPort>(cpu: &mut Cpu<M>, assignments: &[Assignment]) {
+    for a in assignments {
+        match a.location {
+            Location::Register(idx) => cpu.registers_mut().set(idx, a.value),
+            Location::Memory(addr) => cpu.memory_mut().set(addr as usize, a.value),
+        }
+    }
+}
@@ -0,0 +1,606 @@
+//! Superoptimization: searching for the shortest microprogram that maps
+//! given inputs to given outputs.
+//!
+//! ```text
+//! micro16 superopt --example "R0=3 R1=4 -> R2=14" --example "R0=-1 R1=0 -> R2=-2"
+//! micro16 superopt stdlib/routine.m16 --scratch 2
+//! ```
+//!
+//! Examples come from `--example INIT -> EXPECT` and from the `@init` and
+//! `@expect` annotations of a spec file, one example per `@case`. They may
+//! only name the registers R0 to R10; registers an example does not
+//! initialize start out as zero.
+//!
+//! The search considers straight-line programs without jumps or memory
+//! accesses. Each word applies one ALU operation and one shift, as far as
+//! the format combines them, to the constants and the registers of the
+//! examples and writes the result to one of these registers, or to one of
+//! `--scratch` further registers. Registers outside the expectations may be
+//! clobbered. Words are executed on `Cpu` for every example, and a program
+//! whose results for all examples equal those of a shorter one is not
+//! extended, since it can only lead to the same programs. The search
+//! proceeds by length, so the programs found are the shortest.
+//!
+//! If the spec file contains a program, each program found is checked
+//! against it on `--verify` random inputs to the example registers;
+//! programs that differ are reported and the search goes on.
+
+use std::collections::HashSet;
+
+use cli;
+use cpu::{AluMode, Cpu, Profile, ShifterMode};
+use disasm::disassemble;
+use format::Layout;
+use golden::GoldenTest;
+use instruction::Instruction;
+use machine::{self, Machine, Status};
+use rng::Rng;
+use state::{self, Assignment, Location};
+
+/// Bus index of R0; R0 to R10 are the registers examples may name.
+const R0: u8 = 4;
+const R10: u8 = 14;
+/// The constant registers 0, 1 and -1.
+const CONSTANTS: [u8; 3] = [0, 1, 2];
+const DEFAULT_MAX_LENGTH: usize = 4;
+const DEFAULT_MAX_STATES: usize = 2_000_000;
+const DEFAULT_VERIFY: u64 = 10_000;
+
+pub const USAGE: &str = "Usage: micro16 superopt [SPEC] [--example 'INIT -> EXPECT']... \
+                         [--max-length N] [--scratch N] [--solutions N] [--verify N] [--seed N] \
+                         [--max-states N] [--profile classic|extended]";
+
+/// An input state and the registers it must lead to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Example {
+    pub init: Vec<Assignment>,
+    pub expect: Vec<Assignment>,
+}
+
+impl Example {
+    /// Parses `INIT -> EXPECT`, both sides lists of assignments.
+    pub fn parse(text: &str) -> Result<Example, String> {
+        let (init, expect) = text.split_once("->")
+            .ok_or_else(|| format!("expected '->' in example '{}'", text))?;
+        Ok(Example {
+            init: state::parse_assignments(init)?,
+            expect: state::parse_assignments(expect)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub profile: Profile,
+    pub max_length: usize,
+    /// Registers beyond those of the examples that programs may use.
+    pub scratch: usize,
+    /// How many programs of the shortest length to return.
+    pub solutions: usize,
+    /// Distinct states after which the search gives up.
+    pub max_states: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            profile: Profile::Classic,
+            max_length: DEFAULT_MAX_LENGTH,
+            scratch: 0,
+            solutions: 1,
+            max_states: DEFAULT_MAX_STATES,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The shortest programs that satisfy the examples and were accepted.
+    Found { programs: Vec<Vec<u32>>, states: usize },
+    /// No program up to the maximum length satisfies the examples.
+    NotFound { states: usize },
+}
+
+fn register(location: Location) -> Result<u8, String> {
+    match location {
+        Location::Register(idx) if (R0..=R10).contains(&idx) => Ok(idx),
+        _ => Err(format!("examples may only name R0 to R10, not {}", location)),
+    }
+}
+
+/// The registers the search tracks: those of the examples, then `scratch`
+/// unused ones.
+fn registers(examples: &[Example], scratch: usize) -> Result<Vec<u8>, String> {
+    let mut registers = Vec::new();
+    for a in examples.iter().flat_map(|e| e.init.iter().chain(&e.expect)) {
+        let idx = register(a.location)?;
+        if !registers.contains(&idx) {
+            registers.push(idx);
+        }
+    }
+    let unused: Vec<u8> = (R0..=R10).filter(|idx| !registers.contains(idx)).collect();
+    if scratch > unused.len() {
+        return Err(format!("only {} registers are left for scratch", unused.len()));
+    }
+    registers.extend(&unused[..scratch]);
+    registers.sort();
+    Ok(registers)
+}
+
+/// Every word that computes one operation of `profile` on `sources` and
+/// writes it to one of `targets`.
+fn candidates(profile: Profile, sources: &[u8], targets: &[u8]) -> Vec<u32> {
+    let layout = Layout::for_profile(profile);
+    let extended = profile == Profile::Extended;
+    let mut unary = vec![AluMode::NoOp, AluMode::BitNot];
+    let mut commutative = vec![AluMode::Add, AluMode::BitAnd];
+    let mut shifts = vec![ShifterMode::NoOp, ShifterMode::Left, ShifterMode::Right];
+    if extended {
+        unary.push(AluMode::Inc);
+        commutative.extend(&[AluMode::BitOr, AluMode::BitXor]);
+        shifts.push(ShifterMode::Rotate);
+    }
+
+    let mut operands = Vec::new();
+    for &alu in &unary {
+        operands.extend(sources.iter().map(|&a| (alu, a, 0)));
+    }
+    for &alu in &commutative {
+        // Constants come first in `sources`, so they end up on the B side.
+        for (i, &b) in sources.iter().enumerate() {
+            operands.extend(sources[i..].iter().map(|&a| (alu, a, b)));
+        }
+    }
+    if extended {
+        for &a in sources {
+            operands.extend(sources.iter().map(|&b| (AluMode::Sub, a, b)));
+        }
+    }
+
+    let mut words = Vec::new();
+    for &target in targets {
+        for &sh in &shifts {
+            for &(alu, a, b) in &operands {
+                let mut instr = Instruction::with_layout(0, layout.clone());
+                instr.set_a_bus(a);
+                instr.set_b_bus(b);
+                instr.set_s_bus(target);
+                instr.set_ens(true);
+                instr.set_sh(sh);
+                instr.set_alu(alu);
+                words.push(instr.raw());
+            }
+        }
+    }
+    words
+}
+
+/// Runs single words on the tracked registers of every example.
+struct Evaluator {
+    cpu: Cpu,
+    registers: Vec<u8>,
+}
+
+impl Evaluator {
+    fn new(profile: Profile, registers: Vec<u8>) -> Evaluator {
+        let mut cpu = Cpu::new(&[0]);
+        cpu.set_profile(profile);
+        Evaluator { cpu, registers }
+    }
+
+    /// The state is the tracked registers of one example after another.
+    fn initial(&self, examples: &[Example]) -> Vec<i16> {
+        let mut state = Vec::new();
+        for example in examples {
+            state.extend(self.registers.iter().map(|&idx| {
+                let location = Location::Register(idx);
+                example.init.iter().rev().find(|a| a.location == location).map_or(0, |a| a.value)
+            }));
+        }
+        state
+    }
+
+    fn apply(&mut self, state: &[i16], word: u32) -> Vec<i16> {
+        self.cpu.patch(0, word);
+        let mut next = Vec::with_capacity(state.len());
+        for values in state.chunks(self.registers.len()) {
+            for (&idx, &value) in self.registers.iter().zip(values) {
+                self.cpu.registers_mut().set(idx, value);
+            }
+            self.cpu.set_program_counter(0);
+            self.cpu.step().expect("candidate words are valid");
+            next.extend(self.registers.iter().map(|&idx| self.cpu.registers().get(idx)));
+        }
+        next
+    }
+
+    fn satisfies(&self, state: &[i16], examples: &[Example]) -> bool {
+        let width = self.registers.len();
+        examples.iter().zip(state.chunks(width)).all(|(example, values)| {
+            example.expect.iter().all(|a| {
+                let position = self.registers.iter().position(|&idx| {
+                    Location::Register(idx) == a.location
+                });
+                values[position.unwrap()] == a.value
+            })
+        })
+    }
+}
+
+/// Searches for the shortest programs that satisfy `examples`. Programs
+/// that do are passed to `accept`; the search returns the first
+/// `config.solutions` accepted ones of the shortest length any was accepted
+/// at.
+pub fn search<F>(examples: &[Example], config: &Config, mut accept: F) -> Result<Outcome, String>
+    where F: FnMut(&[u32]) -> bool
+{
+    if examples.is_empty() {
+        return Err("no examples to search for".to_string());
+    }
+    let registers = registers(examples, config.scratch)?;
+    let sources: Vec<u8> = CONSTANTS.iter().chain(&registers).cloned().collect();
+    let words = candidates(config.profile, &sources, &registers);
+    let mut evaluator = Evaluator::new(config.profile, registers);
+
+    let initial = evaluator.initial(examples);
+    if evaluator.satisfies(&initial, examples) && accept(&[]) {
+        return Ok(Outcome::Found {
+            programs: vec![Vec::new()],
+            states: 1,
+        });
+    }
+
+    // The word that led to each state and the index of the state before it.
+    let mut nodes: Vec<(usize, u32)> = Vec::new();
+    let mut visited = HashSet::new();
+    visited.insert(initial.clone());
+    let mut frontier = vec![(None, initial)];
+    for _ in 0..config.max_length {
+        let mut next = Vec::new();
+        let mut goals = Vec::new();
+        for &(parent, ref state) in &frontier {
+            for &word in &words {
+                let after = evaluator.apply(state, word);
+                if visited.contains(&after) {
+                    continue;
+                }
+                if visited.len() == config.max_states {
+                    return Err(format!("gave up after {} states; try fewer scratch registers",
+                                       visited.len()));
+                }
+                visited.insert(after.clone());
+                nodes.push((parent.unwrap_or(usize::MAX), word));
+                let node = nodes.len() - 1;
+                if evaluator.satisfies(&after, examples) {
+                    goals.push(node);
+                }
+                next.push((Some(node), after));
+            }
+        }
+
+        let mut programs = Vec::new();
+        for goal in goals {
+            let mut program = Vec::new();
+            let mut node = goal;
+            while node != usize::MAX {
+                program.push(nodes[node].1);
+                node = nodes[node].0;
+            }
+            program.reverse();
+            if accept(&program) {
+                programs.push(program);
+                if programs.len() == config.solutions {
+                    break;
+                }
+            }
+        }
+        if !programs.is_empty() {
+            return Ok(Outcome::Found {
+                programs,
+                states: visited.len(),
+            });
+        }
+        frontier = next;
+    }
+    Ok(Outcome::NotFound { states: visited.len() })
+}
+
+/// Compares `program` with `reference` on `count` random values of the
+/// `inputs` registers, returning the first input whose `outputs` differ.
+/// Inputs on which the reference fails or does not finish are skipped.
+pub fn verify(program: &[u32],
+              profile: Profile,
+              reference: &GoldenTest,
+              inputs: &[u8],
+              outputs: &[u8],
+              count: u64,
+              seed: u64)
+              -> Result<(), String> {
+    let reference_config = machine::Config {
+        max_cycles: Some(reference.max_cycles),
+        control_store_window: reference.control_store_window,
+        call_stack_depth: reference.call_stack_depth,
+        profile: reference.profile,
+    };
+    let config = machine::Config {
+        profile,
+        ..machine::Config::default()
+    };
+    let mut rng = Rng::new(seed);
+    for _ in 0..count {
+        let input: Vec<machine::Assignment> = inputs.iter()
+            .map(|&idx| {
+                machine::Assignment {
+                    location: Location::Register(idx).into(),
+                    value: rng.next_i16(),
+                }
+            })
+            .collect();
+
+        let mut expected = Machine::new(&reference.program, reference_config.clone())
+            .map_err(|e| e.to_string())?;
+        if let Some(ref layout) = reference.layout {
+            expected.set_layout(layout.clone());
+        }
+        expected.apply(&input);
+        if expected.run() != Ok(Status::Finished) {
+            continue;
+        }
+        let mut actual = Machine::new(program, config.clone()).map_err(|e| e.to_string())?;
+        actual.apply(&input);
+        actual.run().map_err(|e| e.to_string())?;
+
+        let differences: Vec<String> = outputs.iter()
+            .map(|&idx| machine::Location::from(Location::Register(idx)))
+            .filter(|&location| actual.read(location) != expected.read(location))
+            .map(|location| {
+                format!("{}: expected {}, got {}",
+                        location,
+                        expected.read(location),
+                        actual.read(location))
+            })
+            .collect();
+        if !differences.is_empty() {
+            let input: Vec<String> = input.iter().map(|a| a.to_string()).collect();
+            return Err(format!("differs on {}: {}", input.join(" "), differences.join(", ")));
+        }
+    }
+    Ok(())
+}
+
+fn listing(program: &[u32], profile: Profile) -> String {
+    let layout = Layout::for_profile(profile);
+    program.iter()
+        .map(|&word| format!("{}\n", disassemble(&Instruction::with_layout(word, layout.clone()))))
+        .collect()
+}
+
+/// Entry point of `micro16 superopt`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    let mut spec = None;
+    let mut examples = Vec::new();
+    let mut config = Config::default();
+    let mut profile = None;
+    let mut count = DEFAULT_VERIFY;
+    let mut seed = 0;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--example" => examples.push(Example::parse(iter.next().ok_or(USAGE)?)?),
+            "--max-length" => config.max_length = cli::parse_arg(iter.next(), USAGE)?,
+            "--scratch" => config.scratch = cli::parse_arg(iter.next(), USAGE)?,
+            "--solutions" => config.solutions = cli::parse_arg(iter.next(), USAGE)?,
+            "--verify" => count = cli::parse_arg(iter.next(), USAGE)?,
+            "--seed" => seed = cli::parse_arg(iter.next(), USAGE)?,
+            "--max-states" => config.max_states = cli::parse_arg(iter.next(), USAGE)?,
+            "--profile" => profile = Some(Profile::parse(iter.next().ok_or(USAGE)?)?),
+            _ if !arg.starts_with('-') && spec.is_none() => spec = Some(arg.clone()),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    if config.solutions == 0 {
+        return Err(USAGE.to_string());
+    }
+
+    let tests = match spec {
+        Some(ref path) => GoldenTest::read(path)?,
+        None => Vec::new(),
+    };
+    examples.extend(tests.iter().filter(|t| t.error.is_none()).map(|t| {
+        Example {
+            init: t.init.clone(),
+            expect: t.expect.clone(),
+        }
+    }));
+    config.profile = profile.or_else(|| tests.first().map(|t| t.profile)).unwrap_or_default();
+    let reference = tests.first().filter(|t| !t.program.is_empty());
+
+    let mut inputs = Vec::new();
+    let mut outputs = Vec::new();
+    for example in &examples {
+        for a in &example.init {
+            inputs.push(register(a.location)?);
+        }
+        for a in &example.expect {
+            outputs.push(register(a.location)?);
+        }
+    }
+    inputs.sort();
+    inputs.dedup();
+    outputs.sort();
+    outputs.dedup();
+
+    let profile = config.profile;
+    let outcome = search(&examples, &config, |program| {
+        let reference = match reference {
+            Some(reference) => reference,
+            None => return true,
+        };
+        match verify(program, profile, reference, &inputs, &outputs, count, seed) {
+            Ok(()) => true,
+            Err(e) => {
+                eprint!("rejected:\n{}  {}\n", listing(program, profile), e);
+                false
+            }
+        }
+    })?;
+
+    match outcome {
+        Outcome::Found { programs, states } => {
+            println!("; {} word(s), {} states searched", programs[0].len(), states);
+            for (i, program) in programs.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                print!("{}", listing(program, profile));
+            }
+            match reference {
+                Some(_) => println!("; verified on {} random inputs", count),
+                None => println!("; no reference program, checked on the examples only"),
+            }
+            Ok(())
+        }
+        Outcome::NotFound { states } => {
+            Err(format!("no program of up to {} words satisfies the examples ({} states \
+                         searched)",
+                        config.max_length,
+                        states))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+
+    fn examples(texts: &[&str]) -> Vec<Example> {
+        texts.iter().map(|t| Example::parse(t).unwrap()).collect()
+    }
+
+    fn reference(source: &str) -> GoldenTest {
+        GoldenTest {
+            name: None,
+            program: assemble(source).unwrap(),
+            init: Vec::new(),
+            expect: Vec::new(),
+            cycles: Vec::new(),
+            flags: Vec::new(),
+            max_cycles: 100,
+            control_store_window: None,
+            call_stack_depth: None,
+            profile: Profile::Classic,
+            layout: None,
+            error: None,
+        }
+    }
+
+    fn found(outcome: Outcome) -> Vec<String> {
+        match outcome {
+            Outcome::Found { programs, .. } => {
+                programs.iter().map(|p| listing(p, Profile::Classic)).collect()
+            }
+            Outcome::NotFound { .. } => panic!("no program found"),
+        }
+    }
+
+    #[test]
+    fn examples_name_general_registers() {
+        assert_eq!(Example::parse("R0=1 R1=2").unwrap_err(),
+                   "expected '->' in example 'R0=1 R1=2'");
+        let config = Config::default();
+        assert_eq!(search(&[], &config, |_| true).unwrap_err(), "no examples to search for");
+        assert_eq!(search(&examples(&["MAR=1 -> R0=1"]), &config, |_| true).unwrap_err(),
+                   "examples may only name R0 to R10, not MAR");
+        let config = Config {
+            scratch: 11,
+            ..Config::default()
+        };
+        assert_eq!(search(&examples(&["R0=1 -> R0=1"]), &config, |_| true).unwrap_err(),
+                   "only 10 registers are left for scratch");
+    }
+
+    #[test]
+    fn the_search_finds_the_shortest_programs() {
+        let doubled_sum = examples(&["R0=3 R1=4 -> R2=14", "R0=-1 R1=0 -> R2=-2"]);
+        assert_eq!(found(search(&doubled_sum, &Config::default(), |_| true).unwrap()),
+                   ["R2 <- lsh(R1 + R0)\n"]);
+
+        // Satisfied from the start.
+        let outcome = search(&examples(&["R0=3 -> R0=3"]), &Config::default(), |_| true);
+        assert_eq!(outcome.unwrap(),
+                   Outcome::Found {
+                       programs: vec![Vec::new()],
+                       states: 1,
+                   });
+
+        // R1 = 4 * R0 + 1 takes two words.
+        let quadrupled = examples(&["R0=3 -> R1=13", "R0=-2 -> R1=-7", "R0=0 -> R1=1"]);
+        let config = Config {
+            max_length: 1,
+            ..Config::default()
+        };
+        match search(&quadrupled, &config, |_| true).unwrap() {
+            Outcome::NotFound { states } => assert!(states > 1),
+            outcome => panic!("{:?}", outcome),
+        }
+        let programs = found(search(&quadrupled, &Config::default(), |_| true).unwrap());
+        assert_eq!(programs.len(), 1);
+        assert_eq!(programs[0].lines().count(), 2);
+    }
+
+    #[test]
+    fn rejected_programs_are_skipped() {
+        let negated = examples(&["R0=3 -> R1=-4", "R0=-1 -> R1=0"]);
+        let config = Config {
+            solutions: 10,
+            ..Config::default()
+        };
+        assert_eq!(found(search(&negated, &config, |_| true).unwrap()), ["R1 <- ~R0\n"]);
+        // Without the one word program the search moves on to two words.
+        let others = found(search(&negated, &config, |p| p.len() > 1).unwrap());
+        assert_eq!(others.len(), 10);
+        assert!(others.iter().all(|p| p.lines().count() == 2), "{:?}", others);
+
+        let config = Config {
+            max_states: 10,
+            ..Config::default()
+        };
+        assert_eq!(search(&negated, &config, |_| true).unwrap_err(),
+                   "gave up after 10 states; try fewer scratch registers");
+    }
+
+    #[test]
+    fn verification_compares_with_the_reference() {
+        let reference = reference("R2 <- lsh(R0 + R1)");
+        let verify = |source: &str| {
+            verify(&assemble(source).unwrap(),
+                   Profile::Classic,
+                   &reference,
+                   &[R0, R0 + 1],
+                   &[R0 + 2],
+                   100,
+                   0)
+        };
+        assert_eq!(verify("R2 <- lsh(R0 + R1)"), Ok(()));
+        assert_eq!(verify("R2 <- R0 + R1\nR2 <- R2 + R2"), Ok(()));
+        let error = verify("R2 <- lsh(R0)").unwrap_err();
+        assert!(error.starts_with("differs on R0="), "{}", error);
+        assert!(error.contains(": R2: expected "), "{}", error);
+    }
+
+    #[test]
+    fn inputs_the_reference_does_not_finish_on_are_skipped() {
+        // Spins for negative R0.
+        let reference = reference(":spin\n(R0); if N goto .spin\nR1 <- R0");
+        let result = verify(&assemble("R1 <- R0").unwrap(),
+                            Profile::Classic,
+                            &reference,
+                            &[R0],
+                            &[R0 + 1],
+                            100,
+                            0);
+        assert_eq!(result, Ok(()));
+    }
+}
@@ -0,0 +1,624 @@
+//! Cycle-by-cycle execution traces in a compact binary format, and diffing
+//! of two traces.
+//!
+//! A trace file starts with the magic `M16T`, a version byte and the name
+//! of the traced machine (`len: u8` and `len` bytes), followed by one record
+//! per cycle and a final record telling how the run ended. Every record
+//! starts with a tag byte; all numbers are little endian and words and
+//! register values are as wide as the machine's (`WORD` and `REG` below):
+//!
+//! ```text
+//! 0  cycle     pc: u16, word: WORD, bits: u8, written: u16,
+//!              one REG per bit set in `written`, in register order,
+//!              count: u8, then `count` memory accesses of
+//!              kind: u8, addr: u32, value: REG
+//! 1  finished  the machine halted
+//! 2  error     len: u16, followed by `len` bytes of UTF-8 message
+//! 3  limit     the cycle limit was reached
+//! ```
+//!
+//! `bits` holds the flags as `Core::flags` returns them: N (bit 0), Z
+//! (bit 1) and on the Micro16 C (bit 2) and V (bit 3). `written` has a bit for every
+//! register that the cycle wrote, whether or not its value changed; the
+//! values are those after the cycle. The low bits of `kind` are 0 for a
+//! read, 1 for a write and 2 for an instruction fetch, and bit 4 tells
+//! whether the cycle completed the transfer. The value of a completed read
+//! is the data read, otherwise the value being written.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use arch::{self, Access, Core, Info, MemoryOp};
+use cli::{self, MachineOptions};
+use cpu::Memory;
+use loader;
+use mic1;
+
+const MAGIC: &[u8; 4] = b"M16T";
+const VERSION: u8 = 1;
+const DEFAULT_MAX_CYCLES: u64 = 100_000;
+const DEFAULT_CONTEXT: usize = 5;
+
+const TAG_CYCLE: u8 = 0;
+const TAG_FINISHED: u8 = 1;
+const TAG_ERROR: u8 = 2;
+const TAG_LIMIT: u8 = 3;
+
+const BIT_COMPLETED: u8 = 16;
+
+pub const USAGE: &str = "Usage: micro16 trace PROGRAM [-o OUT] [--init ASSIGNMENTS] [--cycles N] \
+                         [--map-control-store BASE] [--call-stack DEPTH]
+       micro16 trace --machine mic1 [MICROPROGRAM] [--ijvm FILE] [-o OUT] [--cycles N]
+       micro16 trace --show FILE";
+
+pub const DIFF_USAGE: &str = "Usage: micro16 trace-diff A B [--context N] [--ignore FIELD,...]
+FIELD is one of pc, instruction, registers, flags, memory";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub pc: u16,
+    pub word: u64,
+    /// Registers written by the cycle, in register order, with their new
+    /// values.
+    pub writes: Vec<(u8, i32)>,
+    /// The flags after the cycle, as `Core::flags` returns them.
+    pub flags: u8,
+    /// Memory accesses started or completed by the cycle.
+    pub memory: Vec<MemoryOp>,
+}
+
+impl Record {
+    /// The effects of the cycle on a machine described by `info`, such as
+    /// `R1=5 N=0 Z=1 C=0 V=0 rd mem[7]=-5`.
+    pub fn effects(&self, info: &Info) -> String {
+        let mut parts: Vec<String> = self.writes
+            .iter()
+            .map(|&(r, v)| format!("{}={}", info.registers[r as usize], v))
+            .collect();
+        parts.push(info.describe_flags(self.flags));
+        parts.extend(self.memory.iter().map(|op| op.describe()));
+        parts.join(" ")
+    }
+
+    /// Names of the fields in which the records differ, skipping `ignore`.
+    pub fn differences(&self, other: &Record, ignore: &[Field]) -> Vec<Field> {
+        let differs = [(Field::Pc, self.pc != other.pc),
+                       (Field::Instruction, self.word != other.word),
+                       (Field::Registers, self.writes != other.writes),
+                       (Field::Flags, self.flags != other.flags),
+                       (Field::Memory, self.memory != other.memory)];
+        differs.iter()
+            .filter(|&&(field, differs)| differs && !ignore.contains(&field))
+            .map(|&(field, _)| field)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Pc,
+    Instruction,
+    Registers,
+    Flags,
+    Memory,
+}
+
+impl Field {
+    pub fn parse(text: &str) -> Result<Field, String> {
+        match text {
+            "pc" => Ok(Field::Pc),
+            "instruction" => Ok(Field::Instruction),
+            "registers" => Ok(Field::Registers),
+            "flags" => Ok(Field::Flags),
+            "memory" => Ok(Field::Memory),
+            _ => Err(format!("unknown trace field '{}'", text)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Pc => "pc",
+            Field::Instruction => "instruction",
+            Field::Registers => "registers",
+            Field::Flags => "flags",
+            Field::Memory => "memory",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum End {
+    Finished,
+    Error(String),
+    CycleLimit,
+}
+
+impl End {
+    pub fn describe(&self) -> String {
+        match *self {
+            End::Finished => "finished".to_string(),
+            End::Error(ref e) => format!("error: {}", e),
+            End::CycleLimit => "cycle limit reached".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace {
+    /// Name of the traced machine, one that `arch::info` knows.
+    pub machine: String,
+    pub records: Vec<Record>,
+    pub end: End,
+}
+
+/// Runs `core` for at most `max_cycles` cycles, recording every one.
+pub fn record<C: Core>(core: &mut C, max_cycles: u64) -> Trace {
+    let mut records = Vec::new();
+    let end = loop {
+        if core.done() {
+            break End::Finished;
+        }
+        if records.len() as u64 >= max_cycles {
+            break End::CycleLimit;
+        }
+        let cycle = match core.cycle() {
+            Ok(cycle) => cycle,
+            Err(e) => break End::Error(e),
+        };
+        records.push(Record {
+            pc: cycle.pc,
+            word: cycle.word,
+            writes: cycle.writes,
+            flags: core.flags(),
+            memory: cycle.memory,
+        });
+    };
+    Trace {
+        machine: core.info().name.to_string(),
+        records,
+        end,
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Reads an unsigned number of `bytes` bytes.
+fn read_uint<R: Read>(r: &mut R, bytes: usize) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf[..bytes])?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Reads a signed number of `bytes` bytes, at most 4.
+fn read_int<R: Read>(r: &mut R, bytes: usize) -> io::Result<i32> {
+    let shift = 64 - 8 * bytes as u32;
+    Ok(((read_uint(r, bytes)? << shift) as i64 >> shift) as i32)
+}
+
+fn read_record<R: Read>(r: &mut R, info: &Info) -> io::Result<Record> {
+    let pc = read_u16(r)?;
+    let word = read_uint(r, info.word_bytes())?;
+    let flags = read_u8(r)?;
+    let written = read_u16(r)?;
+    let mut writes = Vec::new();
+    for reg in (0..16u8).filter(|&i| written & 1 << i != 0) {
+        writes.push((reg, read_int(r, info.register_bytes())?));
+    }
+    let mut memory = Vec::new();
+    for _ in 0..read_u8(r)? {
+        let kind = read_u8(r)?;
+        let access = match kind & 3 {
+            0 => Access::Read,
+            1 => Access::Write,
+            2 => Access::Fetch,
+            _ => return Err(invalid("corrupt memory access")),
+        };
+        memory.push(MemoryOp {
+            access,
+            addr: read_u32(r)?,
+            value: read_int(r, info.register_bytes())?,
+            completed: kind & BIT_COMPLETED != 0,
+        });
+    }
+    Ok(Record {
+        pc,
+        word,
+        writes,
+        flags,
+        memory,
+    })
+}
+
+impl Trace {
+    /// The description of the traced machine.
+    pub fn info(&self) -> &'static Info {
+        arch::info(&self.machine).expect("traces name a known machine")
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let info = self.info();
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION, self.machine.len() as u8])?;
+        w.write_all(self.machine.as_bytes())?;
+        for rec in &self.records {
+            let written = rec.writes.iter().fold(0u16, |mask, &(r, _)| mask | 1 << r);
+            w.write_all(&[TAG_CYCLE])?;
+            w.write_all(&rec.pc.to_le_bytes())?;
+            w.write_all(&rec.word.to_le_bytes()[..info.word_bytes()])?;
+            w.write_all(&[rec.flags])?;
+            w.write_all(&written.to_le_bytes())?;
+            for &(_, value) in &rec.writes {
+                w.write_all(&value.to_le_bytes()[..info.register_bytes()])?;
+            }
+            w.write_all(&[rec.memory.len() as u8])?;
+            for op in &rec.memory {
+                let kind = match op.access {
+                    Access::Read => 0,
+                    Access::Write => 1,
+                    Access::Fetch => 2,
+                };
+                let completed = if op.completed { BIT_COMPLETED } else { 0 };
+                w.write_all(&[kind | completed])?;
+                w.write_all(&op.addr.to_le_bytes())?;
+                w.write_all(&op.value.to_le_bytes()[..info.register_bytes()])?;
+            }
+        }
+        match self.end {
+            End::Finished => w.write_all(&[TAG_FINISHED]),
+            End::Error(ref e) => {
+                let bytes = &e.as_bytes()[..e.len().min(u16::MAX as usize)];
+                w.write_all(&[TAG_ERROR])?;
+                w.write_all(&(bytes.len() as u16).to_le_bytes())?;
+                w.write_all(bytes)
+            }
+            End::CycleLimit => w.write_all(&[TAG_LIMIT]),
+        }
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> io::Result<Trace> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a micro16 trace"));
+        }
+        if read_u8(r)? != VERSION {
+            return Err(invalid("unsupported trace version"));
+        }
+        let mut name = vec![0; read_u8(r)? as usize];
+        r.read_exact(&mut name)?;
+        let name = String::from_utf8_lossy(&name);
+        let info =
+            arch::info(&name).ok_or_else(|| invalid(&format!("unknown machine '{}'", name)))?;
+        let mut records = Vec::new();
+        let end = loop {
+            match read_u8(r)? {
+                TAG_CYCLE => (),
+                TAG_FINISHED => break End::Finished,
+                TAG_LIMIT => break End::CycleLimit,
+                TAG_ERROR => {
+                    let mut message = vec![0; read_u16(r)? as usize];
+                    r.read_exact(&mut message)?;
+                    break End::Error(String::from_utf8_lossy(&message).into_owned());
+                }
+                _ => return Err(invalid("corrupt trace record")),
+            }
+            records.push(read_record(r, info)?);
+        };
+        Ok(Trace {
+            machine: info.name.to_string(),
+            records,
+            end,
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Trace, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        Trace::read(&mut BufReader::new(file)).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => format!("{}: truncated trace", path),
+            _ => format!("{}: {}", path, e),
+        })
+    }
+}
+
+fn format_record(info: &Info, cycle: usize, rec: &Record) -> String {
+    format!("{:>7} {:>3}  {:0digits$x}  {:<32} {}",
+            cycle,
+            rec.pc,
+            rec.word,
+            (info.disassemble)(rec.word),
+            rec.effects(info),
+            digits = info.word_digits)
+}
+
+/// Where two traces first part ways.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub cycle: usize,
+    /// Fields that differ; empty if one trace ended or the runs ended
+    /// differently.
+    pub fields: Vec<Field>,
+}
+
+/// Aligns the traces cycle by cycle and finds the first cycle in which they
+/// differ in a field not in `ignore`.
+pub fn diverge(a: &Trace, b: &Trace, ignore: &[Field]) -> Option<Divergence> {
+    let common = a.records.len().min(b.records.len());
+    for (cycle, (ra, rb)) in a.records.iter().zip(&b.records).enumerate() {
+        let fields = ra.differences(rb, ignore);
+        if !fields.is_empty() {
+            return Some(Divergence { cycle, fields });
+        }
+    }
+    if a.records.len() != b.records.len() || a.end != b.end {
+        return Some(Divergence {
+            cycle: common,
+            fields: Vec::new(),
+        });
+    }
+    None
+}
+
+fn print_side(sign: char, trace: &Trace, from: usize, context: usize) {
+    let to = (from + context).min(trace.records.len());
+    for cycle in from..to {
+        println!("{} {}", sign, format_record(trace.info(), cycle, &trace.records[cycle]));
+    }
+    if to < from + context {
+        println!("{} {:>7} {}", sign, "", trace.end.describe());
+    }
+}
+
+/// Entry point of `micro16 trace`.
+pub fn main(args: &[String]) -> Result<(), String> {
+    if args.first().map(|a| a.as_str()) == Some("--show") {
+        let path = match args {
+            [_, path] => path,
+            _ => return Err(USAGE.to_string()),
+        };
+        print_trace(&Trace::load(path)?);
+        return Ok(());
+    }
+
+    let mut machine = arch::MICRO16.name.to_string();
+    let mut program = None;
+    let mut ijvm = None;
+    let mut output = None;
+    let mut options = MachineOptions::default();
+    let mut max_cycles = DEFAULT_MAX_CYCLES;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--machine" => machine = iter.next().ok_or(USAGE)?.clone(),
+            "--ijvm" => ijvm = Some(iter.next().ok_or(USAGE)?.clone()),
+            "-o" => output = Some(iter.next().ok_or(USAGE)?.clone()),
+            "--cycles" => max_cycles = cli::parse_arg(iter.next(), USAGE)?,
+            _ if options.parse(arg, &mut iter, USAGE)? => {}
+            _ if !arg.starts_with('-') && program.is_none() => program = Some(arg.clone()),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let trace = if machine == mic1::INFO.name {
+        if !options.is_empty() ||
+           ijvm.is_none() && program.is_none() {
+            return Err(USAGE.to_string());
+        }
+        let mut mic1 = mic1::load(program.as_deref(), ijvm.as_deref())?;
+        record(&mut mic1, max_cycles)
+    } else if machine == arch::MICRO16.name && ijvm.is_none() {
+        let program = loader::read_program(&program.ok_or(USAGE)?)?;
+        record(&mut options.machine(&program, Memory::new())?, max_cycles)
+    } else {
+        return Err(USAGE.to_string());
+    };
+
+    match output {
+        Some(path) => {
+            let file = File::create(&path).map_err(|e| format!("{}: {}", path, e))?;
+            let mut out = BufWriter::new(file);
+            trace.write(&mut out)
+                .and_then(|()| out.flush())
+                .map_err(|e| format!("{}: {}", path, e))?;
+            println!("{} cycles, {}", trace.records.len(), trace.end.describe());
+        }
+        None => print_trace(&trace),
+    }
+    Ok(())
+}
+
+fn print_trace(trace: &Trace) {
+    for (cycle, rec) in trace.records.iter().enumerate() {
+        println!("{}", format_record(trace.info(), cycle, rec));
+    }
+    println!("{:>7} {}", "", trace.end.describe());
+}
+
+/// Entry point of `micro16 trace-diff`.
+pub fn diff_main(args: &[String]) -> Result<(), String> {
+    let mut paths = Vec::new();
+    let mut context = DEFAULT_CONTEXT;
+    let mut ignore = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--context" => {
+                context = iter.next().and_then(|c| c.parse().ok()).ok_or(DIFF_USAGE)?;
+            }
+            "--ignore" => {
+                for field in iter.next().ok_or(DIFF_USAGE)?.split(',') {
+                    ignore.push(Field::parse(field)?);
+                }
+            }
+            _ if !arg.starts_with('-') => paths.push(arg.clone()),
+            _ => return Err(DIFF_USAGE.to_string()),
+        }
+    }
+    if paths.len() != 2 {
+        return Err(DIFF_USAGE.to_string());
+    }
+    let a = Trace::load(&paths[0])?;
+    let b = Trace::load(&paths[1])?;
+    if a.machine != b.machine {
+        return Err(format!("cannot compare a {} trace with a {} trace", a.machine, b.machine));
+    }
+
+    let divergence = match diverge(&a, &b, &ignore) {
+        Some(divergence) => divergence,
+        None => {
+            println!("traces match over {} cycles, both {}",
+                     a.records.len(),
+                     a.end.describe());
+            return Ok(());
+        }
+    };
+    let cycle = divergence.cycle;
+    if divergence.fields.is_empty() {
+        println!("traces diverge at cycle {}: the runs end differently", cycle);
+    } else {
+        let fields: Vec<&str> = divergence.fields.iter().map(|f| f.name()).collect();
+        println!("traces diverge at cycle {} ({})", cycle, fields.join(", "));
+    }
+    println!("--- {}", paths[0]);
+    println!("+++ {}", paths[1]);
+    for c in cycle.saturating_sub(context)..cycle {
+        println!("  {}", format_record(a.info(), c, &a.records[c]));
+    }
+    print_side('-', &a, cycle, context + 1);
+    print_side('+', &b, cycle, context + 1);
+    Err(format!("traces diverge at cycle {}", cycle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::Cpu;
+
+    // R0 <- 1; R1 <- 1; R2 <- R0 + R1
+    const SUM: [u32; 3] = [0x00140100, 0x00150100, 0x08165400];
+
+    fn trace(program: &[u32], max_cycles: u64) -> Trace {
+        record(&mut Cpu::new(program), max_cycles)
+    }
+
+    fn round_trip(trace: &Trace) -> Trace {
+        let mut bytes = Vec::new();
+        trace.write(&mut bytes).unwrap();
+        Trace::read(&mut &bytes[..]).unwrap()
+    }
+
+    #[test]
+    fn micro16_traces_round_trip() {
+        // MBR <- -1; MAR <- 1; wr; wr; rd; rd
+        let store = [0x01000200, 0x00801000, 0x00200000, 0x00200000, 0x00600000, 0x00600000];
+        let finished = trace(&store, 100);
+        assert_eq!(finished.end, End::Finished);
+        assert_eq!(finished.records.len(), 6);
+        assert_eq!(finished.records[0].flags, 0b0001);
+        assert_eq!(finished.records[3].memory,
+                   vec![MemoryOp {
+                            access: Access::Write,
+                            addr: 1,
+                            value: -1,
+                            completed: true,
+                        }]);
+        assert_eq!(round_trip(&finished), finished);
+
+        let limited = trace(&store, 3);
+        assert_eq!(limited.end, End::CycleLimit);
+        assert_eq!(round_trip(&limited), limited);
+
+        // R0 <- 1; then the reserved shifter mode
+        let failed = trace(&[0x00140100, 0x06000000], 100);
+        assert!(matches!(failed.end, End::Error(_)));
+        assert_eq!(round_trip(&failed), failed);
+    }
+
+    #[test]
+    fn wide_traces_round_trip() {
+        let trace = Trace {
+            machine: "mic1".to_string(),
+            records: vec![Record {
+                              pc: 0x1ff,
+                              word: 0xf_ffff_fffe,
+                              writes: vec![(0, i32::MIN), (15, -1)],
+                              flags: 0b10,
+                              memory: vec![MemoryOp {
+                                               access: Access::Fetch,
+                                               addr: 0x1_0000,
+                                               value: 0x7fff_ffff,
+                                               completed: false,
+                                           }],
+                          }],
+            end: End::Error("stack underflow".to_string()),
+        };
+        assert_eq!(round_trip(&trace), trace);
+    }
+
+    #[test]
+    fn reading_rejects_foreign_and_truncated_files() {
+        assert!(Trace::read(&mut &b"M16X"[..]).is_err());
+
+        let mut bytes = Vec::new();
+        trace(&SUM, 100).write(&mut bytes).unwrap();
+        bytes.pop();
+        let err = Trace::read(&mut &bytes[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn diverge_finds_the_first_differing_cycle() {
+        let a = trace(&SUM, 100);
+        assert_eq!(diverge(&a, &a, &[]), None);
+
+        // R0 <- 1; R1 <- 1 & 1; R2 <- lsh(R0 + R1)
+        let b = trace(&[SUM[0], 0x10151100, 0x0a165400], 100);
+        assert_eq!(diverge(&a, &b, &[]),
+                   Some(Divergence {
+                       cycle: 1,
+                       fields: vec![Field::Instruction],
+                   }));
+        assert_eq!(diverge(&a, &b, &[Field::Instruction]),
+                   Some(Divergence {
+                       cycle: 2,
+                       fields: vec![Field::Registers],
+                   }));
+        assert_eq!(diverge(&a, &b, &[Field::Instruction, Field::Registers]), None);
+    }
+
+    #[test]
+    fn diverge_reports_a_trace_that_ends_early() {
+        let a = trace(&SUM, 100);
+        let longer = trace(&[SUM[0], SUM[1], SUM[2], SUM[0]], 100);
+        assert_eq!(diverge(&a, &longer, &[]),
+                   Some(Divergence {
+                       cycle: 3,
+                       fields: Vec::new(),
+                   }));
+
+        let limited = trace(&SUM, 2);
+        assert_eq!(diverge(&limited, &a, &[]),
+                   Some(Divergence {
+                       cycle: 2,
+                       fields: Vec::new(),
+                   }));
+    }
+}
@@ -0,0 +1,470 @@
+//! Terminal front-end that animates the Micro16 datapath.
+//!
+//! Keys: `s`/space step a cycle, `f` step a single phase, `r` run,
+//! `p` pause, `x` reset, `+`/`-` change the run speed, `q` quit.
+
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use cpu::{AluMode, CondMode, Cpu, ShifterMode};
+use disasm::{disassemble, expression, register_name};
+use instruction::Instruction;
+use machine::{Config, Machine, MachineError, Status};
+
+const DELAYS_MS: [u64; 8] = [1000, 500, 250, 100, 50, 20, 5, 0];
+const PROGRAM_CONTEXT: usize = 4;
+const MEMORY_CONTEXT: i32 = 4;
+
+const ACTIVE: &str = "\x1b[1;32m";
+const INACTIVE: &str = "\x1b[2m";
+const CHANGED: &str = "\x1b[1;33m";
+const CURRENT: &str = "\x1b[7m";
+const RESET: &str = "\x1b[0m";
+
+/// Puts the terminal into non-canonical, no-echo mode until dropped.
+struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<RawMode> {
+        let saved = stty(&["-g"])?;
+        stty(&["-icanon", "-echo", "min", "1"])?;
+        print!("\x1b[?25l");
+        Ok(RawMode { saved: saved.trim().to_string() })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        print!("\x1b[?25h{}", RESET);
+        let _ = io::stdout().flush();
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("stty failed, is stdin a terminal?"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn spawn_input() -> Receiver<u8> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; 16];
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if buf[..n].iter().any(|&b| tx.send(b).is_err()) {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    rx
+}
+
+struct State {
+    machine: Machine,
+    previous: [i16; 16],
+    running: bool,
+    speed: usize,
+    error: Option<MachineError>,
+}
+
+impl State {
+    fn new(program: &[u32]) -> Result<State, MachineError> {
+        let machine = Machine::new(program, Config::default())?;
+        let previous = snapshot(&machine);
+        Ok(State {
+            machine,
+            previous,
+            running: false,
+            speed: 3,
+            error: None,
+        })
+    }
+
+    fn halted(&self) -> bool {
+        self.machine.status() != Status::Running || self.error.is_some()
+    }
+
+    fn step(&mut self) {
+        if !self.halted() {
+            self.previous = snapshot(&self.machine);
+            if let Err(e) = self.machine.step() {
+                self.error = Some(e);
+            }
+        }
+        if self.halted() {
+            self.running = false;
+        }
+    }
+
+    fn step_phase(&mut self) {
+        if !self.halted() {
+            self.previous = snapshot(&self.machine);
+            if let Err(e) = self.machine.step_phase() {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Reloads the program and clears registers and memory.
+    fn reset(&mut self) {
+        self.machine.reset(false);
+        self.previous = snapshot(&self.machine);
+        self.running = false;
+        self.error = None;
+    }
+}
+
+fn snapshot(machine: &Machine) -> [i16; 16] {
+    let mut regs = [0; 16];
+    for (i, r) in regs.iter_mut().enumerate() {
+        *r = machine.cpu().registers().get(i as u8);
+    }
+    regs
+}
+
+/// Runs the interactive front-end until the user quits.
+pub fn run(program: &[u32]) -> io::Result<()> {
+    let _raw = RawMode::enable()?;
+    let keys = spawn_input();
+    let mut state = State::new(program).map_err(|e| io::Error::other(e.to_string()))?;
+    let stdout = io::stdout();
+
+    loop {
+        {
+            let mut out = stdout.lock();
+            render(&mut out, &state)?;
+            out.flush()?;
+        }
+
+        let key = if state.running {
+            match keys.recv_timeout(Duration::from_millis(DELAYS_MS[state.speed])) {
+                Ok(key) => Some(key),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        } else {
+            match keys.recv() {
+                Ok(key) => Some(key),
+                Err(_) => return Ok(()),
+            }
+        };
+
+        match key {
+            None => state.step(),
+            Some(b'q') => break,
+            Some(b's') | Some(b' ') => state.step(),
+            Some(b'f') => state.step_phase(),
+            Some(b'r') => state.running = !state.halted(),
+            Some(b'p') => state.running = false,
+            Some(b'x') => state.reset(),
+            Some(b'+') => state.speed = (state.speed + 1).min(DELAYS_MS.len() - 1),
+            Some(b'-') => state.speed = state.speed.saturating_sub(1),
+            Some(_) => (),
+        }
+    }
+
+    print!("\x1b[2J\x1b[H");
+    Ok(())
+}
+
+fn render<W: Write>(out: &mut W, state: &State) -> io::Result<()> {
+    let machine = &state.machine;
+    write!(out, "\x1b[2J\x1b[H")?;
+    writeln!(out,
+             "micro16  cycle {}  phase {:?}  {}  delay {} ms",
+             machine.cycles(),
+             machine.phase(),
+             if state.error.is_some() {
+                 "error"
+             } else if machine.status() == Status::Finished {
+                 "done"
+             } else if state.running {
+                 "running"
+             } else {
+                 "paused"
+             },
+             DELAYS_MS[state.speed])?;
+    match state.error {
+        Some(ref e) => writeln!(out, "{}{}{}", CHANGED, e, RESET)?,
+        None => writeln!(out)?,
+    }
+
+    render_registers(out, state)?;
+    writeln!(out)?;
+    render_program(out, state)?;
+    writeln!(out)?;
+    render_memory(out, machine.cpu())?;
+    writeln!(out)?;
+    render_datapath(out, machine)?;
+    writeln!(out)?;
+    writeln!(out,
+             "{}[s]tep  [f] phase  [r]un  [p]ause  [x] reset  [+/-] speed  [q]uit{}",
+             INACTIVE,
+             RESET)
+}
+
+fn render_registers<W: Write>(out: &mut W, state: &State) -> io::Result<()> {
+    let machine = &state.machine;
+    writeln!(out, "Registers")?;
+    for row in [[4u8, 5, 6, 7, 8, 9], [10, 11, 12, 13, 14, 3]].iter() {
+        for &idx in row.iter() {
+            let value = machine.cpu().registers().get(idx);
+            let color = if value != state.previous[idx as usize] {
+                CHANGED
+            } else {
+                ""
+            };
+            write!(out, "  {:>3} {}{:>6}{}", register_name(idx), color, value, RESET)?;
+        }
+        writeln!(out)?;
+    }
+    let mbr = machine.cpu().registers().get(15);
+    let color = if mbr != state.previous[15] { CHANGED } else { "" };
+    let flags = machine.flags();
+    writeln!(out,
+             "  MBR {}{:>6}{}  N {}  Z {}  C {}  V {}  PC {}",
+             color,
+             mbr,
+             RESET,
+             flags.negative as u8,
+             flags.zero as u8,
+             flags.carry as u8,
+             flags.overflow as u8,
+             machine.program_counter())
+}
+
+fn render_program<W: Write>(out: &mut W, state: &State) -> io::Result<()> {
+    writeln!(out, "Microprogram")?;
+    let cpu = state.machine.cpu();
+    let pc = cpu.program_counter() as usize;
+    let start = pc.saturating_sub(PROGRAM_CONTEXT);
+    let program = cpu.control_store();
+    let end = (pc + PROGRAM_CONTEXT + 1).min(program.len());
+    for (addr, &word) in program.iter().enumerate().take(end).skip(start) {
+        let text = disassemble(&Instruction::with_layout(word, cpu.layout().clone()));
+        if addr == pc {
+            writeln!(out, "{}> {:3}  {:08x}  {}{}", CURRENT, addr, word, text, RESET)?;
+        } else {
+            writeln!(out, "  {:3}  {:08x}  {}", addr, word, text)?;
+        }
+    }
+    if pc >= program.len() {
+        writeln!(out, "{}> {:3}  (end of program){}", CURRENT, pc, RESET)?;
+    }
+    Ok(())
+}
+
+fn render_memory<W: Write>(out: &mut W, cpu: &Cpu) -> io::Result<()> {
+    let mar = cpu.registers().get(3) as u16 as i32;
+    writeln!(out,
+             "Memory around MAR  (ready {})",
+             cpu.memory().ready() as u8)?;
+    for offset in -MEMORY_CONTEXT..MEMORY_CONTEXT + 1 {
+        let addr = mar + offset;
+        if !(0..=0xffff).contains(&addr) {
+            continue;
+        }
+        let value = cpu.memory().get(addr as usize);
+        if offset == 0 {
+            write!(out, " {}{:04x}: {:>6}{}", CURRENT, addr, value, RESET)?;
+        } else {
+            write!(out, " {:04x}: {:>6}", addr, value)?;
+        }
+    }
+    writeln!(out)
+}
+
+fn line<W: Write>(out: &mut W, active: bool, text: &str) -> io::Result<()> {
+    writeln!(out,
+             "  {}{}{}",
+             if active { ACTIVE } else { INACTIVE },
+             text,
+             RESET)
+}
+
+/// Draws the datapath of the cycle in flight (or the one just completed),
+/// highlighting the buses and control lines it drives.
+fn render_datapath<W: Write>(out: &mut W, machine: &Machine) -> io::Result<()> {
+    let cpu = machine.cpu();
+    let flags = machine.flags();
+    let dp = cpu.datapath();
+    let instr = Instruction::with_layout(dp.mir, cpu.layout().clone());
+    writeln!(out, "Datapath  [{}]", disassemble(&instr))?;
+    let (sh, alu, cond, expr) = match (instr.modes(), expression(&instr)) {
+        (Ok((sh, alu, cond)), Ok(expr)) => (sh, alu, cond, expr),
+        _ => return Ok(()),
+    };
+
+    let uses_b = match alu {
+        AluMode::Add | AluMode::BitAnd | AluMode::Sub | AluMode::BitOr | AluMode::BitXor => true,
+        AluMode::NoOp | AluMode::BitNot | AluMode::Inc => false,
+    };
+    line(out,
+         !instr.a_mux(),
+         &format!("A-bus  {:>3} --> A-latch {:>6}", register_name(instr.a_bus()), dp.a_latch))?;
+    line(out,
+         uses_b || instr.mar(),
+         &format!("B-bus  {:>3} --> B-latch {:>6}", register_name(instr.b_bus()), dp.b_latch))?;
+    line(out,
+         instr.a_mux(),
+         &format!("A-MUX  {}", if instr.a_mux() { "MAR" } else { "A-latch" }))?;
+    line(out,
+         alu != AluMode::NoOp,
+         &format!("ALU    {:<7} --> {:>6}   N {} Z {} C {} V {}",
+                  match alu {
+                      AluMode::NoOp => "pass",
+                      AluMode::Add => "add",
+                      AluMode::BitAnd => "and",
+                      AluMode::BitNot => "not",
+                      AluMode::Sub => "sub",
+                      AluMode::BitOr => "or",
+                      AluMode::BitXor => "xor",
+                      AluMode::Inc => "inc",
+                  },
+                  dp.alu_out,
+                  flags.negative as u8,
+                  flags.zero as u8,
+                  flags.carry as u8,
+                  flags.overflow as u8))?;
+    line(out,
+         sh != ShifterMode::NoOp,
+         &format!("Shift  {:<7} --> {:>6}",
+                  match sh {
+                      ShifterMode::NoOp => "pass",
+                      ShifterMode::Left => "left",
+                      ShifterMode::Right => "right",
+                      ShifterMode::Rotate => "rotate",
+                  },
+                  dp.shifter_out))?;
+    line(out,
+         instr.ens(),
+         &format!("S-bus  {:>6} --> {}", dp.s_bus, register_name(instr.s_bus())))?;
+    line(out,
+         instr.mar(),
+         &format!("MAR    <-- B-latch ({})", register_name(instr.b_bus())))?;
+    line(out, instr.mbr(), &format!("MBR    <-- {}", expr))?;
+    line(out,
+         instr.ms(),
+         &format!("Memory {}", if instr.rd_wr() { "rd" } else { "wr" }))?;
+    let kind = if instr.call() { "call" } else { "goto" };
+    let jump = match cond {
+        CondMode::NoOp if instr.call() => "return".to_string(),
+        CondMode::NoOp => "next".to_string(),
+        CondMode::IfNegative => format!("if N {} {}", kind, instr.addr()),
+        CondMode::IfZero => format!("if Z {} {}", kind, instr.addr()),
+        CondMode::GoTo => format!("{} {}", kind, instr.addr()),
+        CondMode::IfCarry => format!("if C {} {}", kind, instr.addr()),
+        CondMode::IfOverflow => format!("if V {} {}", kind, instr.addr()),
+        CondMode::IfLess => format!("if LT {} {}", kind, instr.addr()),
+    };
+    line(out,
+         cond != CondMode::NoOp || instr.call(),
+         &format!("MIC    {}", jump))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+
+    fn screen(state: &State) -> String {
+        let mut out = Vec::new();
+        render(&mut out, state).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    const PROGRAM: &str = "R0 <- 1\nMAR <- R0; MBR <- lsh(R0 + 1); wr\nwr";
+
+    fn state() -> State {
+        State::new(&assemble(PROGRAM).unwrap()).unwrap()
+    }
+
+    /// The lines of `screen` from the one starting with `title` to the next
+    /// empty one.
+    fn section(screen: &str, title: &str) -> Vec<String> {
+        screen.lines()
+            .skip_while(|l| !l.starts_with(title))
+            .take_while(|l| !l.is_empty())
+            .map(|l| l.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn the_screen_starts_paused_at_the_first_word() {
+        let text = screen(&state());
+        assert!(text.starts_with("\x1b[2J\x1b[Hmicro16  cycle 0  phase LoadMir  paused  \
+                                    delay 100 ms\n"));
+        assert_eq!(section(&text, "Microprogram"),
+                   ["Microprogram",
+                    "\x1b[7m>   0  00140100  R0 <- 1\x1b[0m",
+                    "    1  0ba04100  MBR <- lsh(1 + R0); MAR <- R0; wr",
+                    "    2  00200000  wr"]);
+        assert!(!text.contains(CHANGED));
+    }
+
+    #[test]
+    fn steps_highlight_what_changed() {
+        let mut state = state();
+        state.step();
+        state.step();
+        let text = screen(&state);
+        assert!(text.contains("micro16  cycle 2  phase LoadMir  paused"));
+        let registers = section(&text, "Registers");
+        // R0 was written a cycle earlier.
+        assert!(registers[1].starts_with("   R0      1\x1b[0m"));
+        assert!(registers[2].ends_with("  MAR \x1b[1;33m     1\x1b[0m"));
+        assert_eq!(registers[3], "  MBR \x1b[1;33m     4\x1b[0m  N 0  Z 0  C 0  V 0  PC 2");
+        assert_eq!(section(&text, "Memory")[1],
+                   " 0000:      0 \x1b[7m0001:      0\x1b[0m 0002:      0 0003:      0 \
+                    0004:      0 0005:      0");
+
+        // The datapath shows the word just executed.
+        let datapath = section(&text, "Datapath");
+        assert_eq!(datapath[0], "Datapath  [MBR <- lsh(1 + R0); MAR <- R0; wr]");
+        assert_eq!(datapath[4], "  \x1b[1;32mALU    add     -->      2   N 0 Z 0 C 0 V 0\x1b[0m");
+        assert_eq!(datapath[5], "  \x1b[1;32mShift  left    -->      4\x1b[0m");
+        assert_eq!(datapath[6], "  \x1b[2mS-bus       4 --> 0\x1b[0m");
+        assert_eq!(datapath[10], "  \x1b[2mMIC    next\x1b[0m");
+    }
+
+    #[test]
+    fn finished_and_failed_runs_say_so() {
+        let mut state = state();
+        state.running = true;
+        for _ in 0..3 {
+            state.step();
+        }
+        assert!(!state.running);
+        let text = screen(&state);
+        assert!(text.contains("cycle 3  phase LoadMir  done"));
+        assert!(text.contains("\x1b[7m>   3  (end of program)\x1b[0m\n"));
+
+        // A word with the reserved shifter mode.
+        let mut state = State::new(&[0x06000000]).unwrap();
+        state.step();
+        let text = screen(&state);
+        assert!(text.contains("  error  "));
+        assert_eq!(text.lines().nth(1),
+                   Some("\x1b[1;33minstruction 0 uses reserved shifter mode 3\x1b[0m"));
+
+        state.reset();
+        let text = screen(&state);
+        assert!(text.contains("cycle 0  phase LoadMir  paused"));
+        assert_eq!(text.lines().nth(1), Some(""));
+    }
+}
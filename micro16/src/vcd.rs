@@ -0,0 +1,209 @@
+//! Value Change Dump export of a Cpu run, viewable with GTKWave.
+//!
+//! Every call to `VcdWriter::sample` records one clock cycle: the state of
+//! the register file, flags and memory handshake at the start of the cycle
+//! together with the control signals of the microinstruction executed in it.
+
+use std::io::{self, Write};
+
+use cpu::Cpu;
+use instruction::Instruction;
+
+struct Signal {
+    name: &'static str,
+    width: u32,
+    value: fn(&Cpu, Option<&Instruction>) -> u64,
+}
+
+fn reg(cpu: &Cpu, idx: u8) -> u64 {
+    cpu.registers().get(idx) as u16 as u64
+}
+
+fn ctl(instr: Option<&Instruction>, f: fn(&Instruction) -> u64) -> u64 {
+    instr.map_or(0, f)
+}
+
+static SIGNALS: &[Signal] = &[
+    Signal { name: "pc", width: 8, value: |c, _| c.program_counter() as u64 },
+    Signal { name: "R0", width: 16, value: |c, _| reg(c, 4) },
+    Signal { name: "R1", width: 16, value: |c, _| reg(c, 5) },
+    Signal { name: "R2", width: 16, value: |c, _| reg(c, 6) },
+    Signal { name: "R3", width: 16, value: |c, _| reg(c, 7) },
+    Signal { name: "R4", width: 16, value: |c, _| reg(c, 8) },
+    Signal { name: "R5", width: 16, value: |c, _| reg(c, 9) },
+    Signal { name: "R6", width: 16, value: |c, _| reg(c, 10) },
+    Signal { name: "R7", width: 16, value: |c, _| reg(c, 11) },
+    Signal { name: "R8", width: 16, value: |c, _| reg(c, 12) },
+    Signal { name: "R9", width: 16, value: |c, _| reg(c, 13) },
+    Signal { name: "R10", width: 16, value: |c, _| reg(c, 14) },
+    Signal { name: "MAR", width: 16, value: |c, _| reg(c, 3) },
+    Signal { name: "MBR", width: 16, value: |c, _| reg(c, 15) },
+    Signal { name: "N", width: 1, value: |c, _| c.negative_flag() as u64 },
+    Signal { name: "Z", width: 1, value: |c, _| c.zero_flag() as u64 },
+    Signal { name: "C", width: 1, value: |c, _| c.carry_flag() as u64 },
+    Signal { name: "V", width: 1, value: |c, _| c.overflow_flag() as u64 },
+    Signal { name: "mem_ready", width: 1, value: |c, _| c.memory().ready() as u64 },
+    Signal { name: "a_mux", width: 1, value: |_, i| ctl(i, |i| i.a_mux() as u64) },
+    Signal { name: "a_bus", width: 4, value: |_, i| ctl(i, |i| i.a_bus() as u64) },
+    Signal { name: "b_bus", width: 4, value: |_, i| ctl(i, |i| i.b_bus() as u64) },
+    Signal { name: "s_bus", width: 4, value: |_, i| ctl(i, |i| i.s_bus() as u64) },
+    Signal { name: "ens", width: 1, value: |_, i| ctl(i, |i| i.ens() as u64) },
+    Signal { name: "ms", width: 1, value: |_, i| ctl(i, |i| i.ms() as u64) },
+    Signal { name: "rd_wr", width: 1, value: |_, i| ctl(i, |i| i.rd_wr() as u64) },
+    Signal { name: "mar", width: 1, value: |_, i| ctl(i, |i| i.mar() as u64) },
+    Signal { name: "mbr", width: 1, value: |_, i| ctl(i, |i| i.mbr() as u64) },
+    Signal { name: "sh", width: 3, value: |_, i| ctl(i, |i| i.sh().map_or(0, |m| m as u64)) },
+    Signal { name: "alu", width: 3, value: |_, i| ctl(i, |i| i.alu().map_or(0, |m| m as u64)) },
+    Signal { name: "cond", width: 3, value: |_, i| ctl(i, |i| i.cond().map_or(0, |m| m as u64)) },
+    Signal { name: "addr", width: 8, value: |_, i| ctl(i, |i| i.addr() as u64) },
+];
+
+/// Writes a VCD waveform, one sample per clock cycle.
+pub struct VcdWriter<W: Write> {
+    out: W,
+    time: u64,
+    last: Vec<Option<u64>>,
+}
+
+impl<W: Write> VcdWriter<W> {
+    /// Creates a writer and emits the VCD header.
+    pub fn new(mut out: W) -> io::Result<VcdWriter<W>> {
+        writeln!(out, "$version micro16 $end")?;
+        writeln!(out, "$timescale 1ns $end")?;
+        writeln!(out, "$scope module micro16 $end")?;
+        writeln!(out, "$var wire 1 {} clk $end", identifier(0))?;
+        for (i, signal) in SIGNALS.iter().enumerate() {
+            writeln!(out,
+                     "$var wire {} {} {} $end",
+                     signal.width,
+                     identifier(i + 1),
+                     signal.name)?;
+        }
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+
+        Ok(VcdWriter {
+            out,
+            time: 0,
+            last: vec![None; SIGNALS.len()],
+        })
+    }
+
+    /// Records the cycle that the next `Cpu::step` will execute.
+    pub fn sample(&mut self, cpu: &Cpu) -> io::Result<()> {
+        let instr = cpu.current_instruction();
+        writeln!(self.out, "#{}", self.time)?;
+        if self.time == 0 {
+            writeln!(self.out, "$dumpvars")?;
+        }
+        writeln!(self.out, "1{}", identifier(0))?;
+        for (i, signal) in SIGNALS.iter().enumerate() {
+            let value = (signal.value)(cpu, instr.as_ref());
+            if self.last[i] != Some(value) {
+                self.last[i] = Some(value);
+                write_value(&mut self.out, signal.width, value, i + 1)?;
+            }
+        }
+        if self.time == 0 {
+            writeln!(self.out, "$end")?;
+        }
+        writeln!(self.out, "#{}", self.time + 1)?;
+        writeln!(self.out, "0{}", identifier(0))?;
+        self.time += 2;
+        Ok(())
+    }
+
+    /// Closes the final cycle and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        writeln!(self.out, "#{}", self.time)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn identifier(mut idx: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (idx % 94) as u8) as char);
+        idx /= 94;
+        if idx == 0 {
+            return id;
+        }
+    }
+}
+
+fn write_value<W: Write>(out: &mut W, width: u32, value: u64, idx: usize) -> io::Result<()> {
+    if width == 1 {
+        writeln!(out, "{}{}", value & 1, identifier(idx))
+    } else {
+        writeln!(out,
+                 "b{:0width$b} {}",
+                 value,
+                 identifier(idx),
+                 width = width as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+
+    fn dump(source: &str) -> String {
+        let mut cpu = Cpu::new(&assemble(source).unwrap());
+        let mut vcd = VcdWriter::new(Vec::new()).unwrap();
+        while !cpu.done() {
+            vcd.sample(&cpu).unwrap();
+            cpu.step().unwrap();
+        }
+        vcd.sample(&cpu).unwrap();
+        String::from_utf8(vcd.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn identifiers_are_printable() {
+        assert_eq!(identifier(0), "!");
+        assert_eq!(identifier(93), "~");
+        assert_eq!(identifier(94), "!\"");
+    }
+
+    #[test]
+    fn the_header_declares_every_signal() {
+        let text = dump("");
+        let header: Vec<&str> = text.lines().take_while(|l| *l != "$enddefinitions $end").collect();
+        assert_eq!(header[..4],
+                   ["$version micro16 $end",
+                    "$timescale 1ns $end",
+                    "$scope module micro16 $end",
+                    "$var wire 1 ! clk $end"]);
+        assert_eq!(header[4], "$var wire 8 \" pc $end");
+        assert_eq!(header[5], "$var wire 16 # R0 $end");
+        assert_eq!(header.len(), 4 + SIGNALS.len() + 1);
+        assert_eq!(header.last(), Some(&"$upscope $end"));
+    }
+
+    #[test]
+    fn samples_record_the_changes() {
+        let text = dump("R0 <- 1\nR0 <- 1");
+        let body: Vec<&str> = text.lines().skip_while(|l| *l != "$enddefinitions $end").collect();
+        // The first sample dumps every signal.
+        assert_eq!(body[1..4], ["#0", "$dumpvars", "1!"]);
+        assert_eq!(body[4], "b00000000 \"");
+        let end = body.iter().position(|l| *l == "$end").unwrap();
+        assert_eq!(end, 4 + SIGNALS.len());
+        assert_eq!(body[end + 1..end + 3], ["#1", "0!"]);
+
+        // R0 and pc change after the first word, then only pc: the second
+        // word writes the value R0 already has and decodes the same.
+        let second: Vec<&str> = body[end + 3..]
+            .iter()
+            .take_while(|l| **l != "#3")
+            .cloned()
+            .collect();
+        assert_eq!(second, ["#2", "1!", "b00000001 \"", "b0000000000000001 #"]);
+        let rest: Vec<&str> = body[end + 3 + second.len()..].to_vec();
+        assert_eq!(rest[..4], ["#3", "0!", "#4", "1!"]);
+        assert_eq!(rest[4], "b00000010 \"");
+        assert_eq!(rest.last(), Some(&"#6"));
+    }
+}
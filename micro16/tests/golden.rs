@@ -0,0 +1,47 @@
+//! Runs the golden-state tests shipped with the crate, see `golden`.
+
+extern crate micro16;
+
+use std::path::Path;
+
+use micro16::golden::{self, GoldenTest, Outcome};
+
+const PATHS: [&str; 2] = ["testdata", "stdlib"];
+
+#[test]
+fn golden_tests_pass() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let paths: Vec<String> = PATHS.iter()
+        .map(|p| root.join(p).to_string_lossy().into_owned())
+        .collect();
+
+    let mut total = 0;
+    let mut failures = Vec::new();
+    for file in golden::discover(&paths).unwrap() {
+        let tests = match GoldenTest::read(&file.to_string_lossy()) {
+            Ok(tests) => tests,
+            Err(e) => {
+                failures.push(format!("{}: {}", file.display(), e));
+                continue;
+            }
+        };
+        for test in tests {
+            total += 1;
+            let name = match test.name {
+                Some(ref case) => format!("{} [{}]", file.display(), case),
+                None => file.display().to_string(),
+            };
+            match golden::run(&test) {
+                Outcome::Pass { .. } => (),
+                outcome => failures.push(format!("{}: {:?}", name, outcome)),
+            }
+        }
+    }
+
+    assert!(total > 0, "no golden tests found in {:?}", paths);
+    assert!(failures.is_empty(),
+            "{} of {} golden tests failed:\n{}",
+            failures.len(),
+            total,
+            failures.join("\n"));
+}